@@ -0,0 +1,235 @@
+//! Maintenance of the package download cache (CACHE)
+
+use anyhow::Result;
+use console::style;
+use indicatif::HumanBytes;
+use std::{
+    collections::HashSet,
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+use crate::{config, info, warn};
+
+use super::get_output_directory;
+
+/// Host directory of the package download cache (see `DEFAULT_MOUNTS`)
+const APT_CACHE_DIR: &str = "CACHE";
+
+#[derive(Debug)]
+struct CacheEntry {
+    path: PathBuf,
+    size: u64,
+    /// Last access or modification time, whichever is newer
+    last_used: i64,
+    /// Device and inode number, for finding out whether the file is in use
+    id: (u64, u64),
+}
+
+/// List the downloaded packages in the cache (the `partial` directory is left alone)
+fn list_cache_entries<P: AsRef<Path>>(dir: P) -> Result<Vec<CacheEntry>> {
+    let mut entries = Vec::new();
+    let dir = match fs::read_dir(dir) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(entries),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in dir {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() || entry.path().extension() != Some("deb".as_ref()) {
+            continue;
+        }
+        entries.push(CacheEntry {
+            path: entry.path(),
+            size: metadata.len(),
+            last_used: metadata.atime().max(metadata.mtime()),
+            id: (metadata.dev(), metadata.ino()),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Collect the device and inode numbers of all the files opened by any process on the host,
+/// which includes the processes in the containers
+fn collect_open_files() -> HashSet<(u64, u64)> {
+    let mut open_files = HashSet::new();
+    let procs = match fs::read_dir("/proc") {
+        Ok(procs) => procs,
+        Err(_) => return open_files,
+    };
+    for proc in procs.flatten() {
+        // processes may exit at any time, ignore the errors
+        let fds = match fs::read_dir(proc.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        for fd in fds.flatten() {
+            if let Ok(metadata) = fs::metadata(fd.path()) {
+                if metadata.is_file() {
+                    open_files.insert((metadata.dev(), metadata.ino()));
+                }
+            }
+        }
+    }
+
+    open_files
+}
+
+/// Choose the least recently used entries to remove until the total size is under `limit`,
+/// entries that are in use are skipped
+fn select_evictions(
+    mut entries: Vec<CacheEntry>,
+    limit: u64,
+    in_use: &HashSet<(u64, u64)>,
+) -> Vec<CacheEntry> {
+    let mut total = entries
+        .iter()
+        .fold(0u64, |sum, x| sum.saturating_add(x.size));
+    entries.sort_by_key(|x| x.last_used);
+    let mut evictions = Vec::new();
+    for entry in entries {
+        if total <= limit {
+            break;
+        }
+        if in_use.contains(&entry.id) {
+            continue;
+        }
+        total = total.saturating_sub(entry.size);
+        evictions.push(entry);
+    }
+
+    evictions
+}
+
+/// Remove packages from the download cache until its size is under `limit` bytes,
+/// returns the number of the removed packages and the space freed
+pub fn trim_apt_cache(limit: u64) -> Result<(usize, u64)> {
+    let entries = list_cache_entries(APT_CACHE_DIR)?;
+    let in_use = collect_open_files();
+    let evictions = select_evictions(entries, limit, &in_use);
+    let mut freed = 0u64;
+    for entry in evictions.iter() {
+        fs::remove_file(&entry.path)?;
+        freed = freed.saturating_add(entry.size);
+    }
+
+    Ok((evictions.len(), freed))
+}
+
+/// The cache limit in the configuration (in MiB) in bytes
+fn limit_in_bytes(limit: u64) -> u64 {
+    limit.saturating_mul(1024 * 1024)
+}
+
+/// Trim the download cache to the limit in the workspace configuration, if any
+pub fn enforce_cache_limit() -> Result<()> {
+    let limit = match config::read_config()?.cache_limit {
+        Some(limit) => limit_in_bytes(limit),
+        None => return Ok(()),
+    };
+    let (count, freed) = trim_apt_cache(limit)?;
+    if count > 0 {
+        info!(
+            "Removed {} package(s) from the download cache, {} freed.",
+            count,
+            HumanBytes(freed)
+        );
+    }
+    let size = directory_size(APT_CACHE_DIR);
+    if size > limit {
+        warn!(
+            "The download cache ({}) is still over the limit, some packages are in use.",
+            HumanBytes(size)
+        );
+    }
+
+    Ok(())
+}
+
+/// Remove all the packages in the download cache, except the ones in use
+pub fn clean_apt_cache() -> Result<()> {
+    let (count, freed) = trim_apt_cache(0)?;
+    info!(
+        "Removed {} package(s) from the download cache, {} freed.",
+        count,
+        HumanBytes(freed)
+    );
+
+    Ok(())
+}
+
+/// Total size of the files under the directory
+pub(crate) fn directory_size<P: AsRef<Path>>(dir: P) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|x| x.metadata().ok())
+        .filter(|x| x.is_file())
+        .fold(0, |sum, x| sum.saturating_add(x.len()))
+}
+
+/// Show the disk space used by the caches and the outputs
+pub fn print_space_usage() -> Result<()> {
+    let config = config::read_config()?;
+    let output_dir = get_output_directory(config.sep_mount);
+    let cache_size = directory_size(APT_CACHE_DIR);
+    let limit = match config.cache_limit {
+        Some(limit) => format!(" (limit: {})", HumanBytes(limit_in_bytes(limit))),
+        None => String::new(),
+    };
+    eprintln!(
+        "{:<20}{}{}",
+        style("Download cache").bold(),
+        HumanBytes(cache_size),
+        limit
+    );
+    eprintln!(
+        "{:<20}{}",
+        style("Source cache").bold(),
+        HumanBytes(directory_size("SRCS"))
+    );
+    eprintln!(
+        "{:<20}{}",
+        style("Output").bold(),
+        HumanBytes(directory_size(output_dir))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_select_evictions() {
+    let entry = |name: &str, size, last_used, ino| CacheEntry {
+        path: PathBuf::from(name),
+        size,
+        last_used,
+        id: (1, ino),
+    };
+    let entries = vec![
+        entry("new.deb", 100, 30, 1),
+        entry("old.deb", 100, 10, 2),
+        entry("held.deb", 100, 5, 3),
+        entry("mid.deb", 100, 20, 4),
+    ];
+    let in_use = HashSet::from([(1, 3)]);
+    let evictions = select_evictions(entries, 250, &in_use);
+    let names = evictions
+        .iter()
+        .map(|x| x.path.to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["old.deb", "mid.deb"]);
+    assert_eq!(limit_in_bytes(2), 2 * 1024 * 1024);
+    assert_eq!(limit_in_bytes(u64::MAX), u64::MAX);
+    let huge = vec![
+        entry("a.deb", u64::MAX, 1, 1),
+        entry("b.deb", u64::MAX, 2, 2),
+    ];
+    assert_eq!(
+        select_evictions(huge, u64::MAX - 1, &HashSet::new()).len(),
+        1
+    );
+}
@@ -0,0 +1,128 @@
+//! Cancel the long-running operations from another thread
+
+use anyhow::Result;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, sleep, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// How often a `CancelToken` is checked while waiting
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Error returned by an operation stopped through its `CancelToken`,
+/// use `downcast_ref` on the returned error to tell it apart from a failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Operation cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// A handle for cancelling a build or an OS update from another thread.
+/// The operation stops at the next package or retry, the command running in the container
+/// is terminated, and `Cancelled` is returned.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request the cancellation of the operations using this token (or its clones)
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Return `Cancelled` if the cancellation has been requested
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+
+        Ok(())
+    }
+
+    /// Sleep for the given duration, returns `Cancelled` early if the cancellation is requested
+    pub fn sleep(&self, duration: Duration) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        loop {
+            self.check()?;
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            sleep(CANCEL_POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    /// Run `on_cancel` in the background as soon as the cancellation is requested,
+    /// until the returned guard is dropped
+    pub(super) fn watch<F: FnOnce() + Send + 'static>(&self, on_cancel: F) -> CancelWatch {
+        let token = self.clone();
+        let done = Arc::new(AtomicBool::new(false));
+        let finished = done.clone();
+        let handle = thread::spawn(move || {
+            while !finished.load(Ordering::SeqCst) {
+                if token.is_cancelled() {
+                    on_cancel();
+                    return;
+                }
+                sleep(CANCEL_POLL_INTERVAL);
+            }
+        });
+
+        CancelWatch {
+            done,
+            handle: Some(handle),
+        }
+    }
+}
+
+/// Stops watching for the cancellation when dropped, see `CancelToken::watch`
+pub(super) struct CancelWatch {
+    done: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for CancelWatch {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+#[test]
+fn test_cancel_token() {
+    let token = CancelToken::new();
+    assert!(token.check().is_ok());
+    assert!(token.sleep(Duration::from_millis(10)).is_ok());
+    let (tx, rx) = std::sync::mpsc::channel();
+    let watch = token.watch(move || tx.send(()).unwrap());
+    token.clone().cancel();
+    let err = token.sleep(Duration::from_secs(60)).unwrap_err();
+    assert_eq!(err.downcast_ref::<Cancelled>(), Some(&Cancelled));
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    drop(watch);
+    // dropping the guard before the cancellation does not run the callback
+    let token = CancelToken::new();
+    let (tx, rx) = std::sync::mpsc::channel::<()>();
+    drop(token.watch(move || tx.send(()).unwrap()));
+    token.cancel();
+    assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+}
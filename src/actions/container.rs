@@ -1,25 +1,60 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use console::{style, user_attended};
 use dialoguer::{theme::ColorfulTheme, Confirm, Input};
 use git2::Repository;
-use nix::unistd::sync;
+use nix::{
+    errno::Errno,
+    mount::{umount2, MntFlags},
+    unistd::sync,
+};
 use rand::random;
 use std::{
     ffi::OsStr,
-    fs,
+    fmt, fs,
+    io::Write,
     path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    thread::{self, sleep},
+    time::{Duration, SystemTime},
 };
 
 use crate::{
     actions::{ensure_host_sanity, OMA_UPDATE_SCRIPT},
     common::*,
-    config, error, info,
-    machine::{self, get_container_ns_name, inspect_instance, spawn_container},
+    config, error, info, lock,
+    machine::{
+        self, get_container_ns_name, inspect_instance, resolve_container_ns_name, spawn_container,
+        ContainerState, InstanceOrder,
+    },
     network::download_file_progress,
-    overlayfs, warn,
+    overlayfs, timing, warn,
+};
+
+use super::{
+    cancel::{CancelToken, CancelWatch, Cancelled},
+    directory_size,
+    failure::classify_update_output,
+    for_each_instance, is_lock_contention, output_tail, APT_UPDATE_SCRIPT,
 };
 
-use super::{for_each_instance, APT_UPDATE_SCRIPT};
+/// How many times to retry oma when the package manager lock is held by another process
+const OMA_LOCK_RETRIES: u32 = 4;
+/// How many random names to try when creating a temporary instance
+const EPHEMERAL_NAME_RETRIES: usize = 16;
+
+static STATE_OBSERVER: OnceLock<fn(&str, ContainerState)> = OnceLock::new();
+
+/// Set the function called with the instance and its new state whenever ciel changes
+/// the state of an instance, can only be set once
+pub fn set_state_observer(observer: fn(&str, ContainerState)) {
+    STATE_OBSERVER.set(observer).ok();
+}
+
+fn notify_state(instance: &str, state: ContainerState) {
+    if let Some(observer) = STATE_OBSERVER.get() {
+        observer(instance, state);
+    }
+}
 
 /// Get the branch name of the workspace TREE repository
 #[inline]
@@ -46,17 +81,104 @@ pub fn get_output_directory(sep_mount: bool) -> String {
     }
 }
 
-fn commit(instance: &str) -> Result<()> {
+/// Check the dpkg database in the upper layer for interrupted package operations
+fn check_dpkg_state(upper: &Path) -> Result<()> {
+    let dpkg_dir = upper.join("var/lib/dpkg");
+    if fs::read_dir(dpkg_dir.join("updates")).is_ok_and(|mut x| x.next().is_some()) {
+        return Err(anyhow!(
+            "dpkg has pending updates in /var/lib/dpkg/updates/, a package operation was interrupted"
+        ));
+    }
+    let status = match fs::read_to_string(dpkg_dir.join("status")) {
+        Ok(status) => status,
+        Err(_) => return Ok(()),
+    };
+    let mut broken = Vec::new();
+    for stanza in status.split("\n\n") {
+        let mut package = None;
+        let mut state = None;
+        for line in stanza.lines() {
+            if let Some(name) = line.strip_prefix("Package: ") {
+                package = Some(name);
+            } else if let Some(status) = line.strip_prefix("Status: ") {
+                state = status.split_whitespace().nth(2);
+            }
+        }
+        if let (Some(package), Some(state)) = (package, state) {
+            if !matches!(state, "installed" | "not-installed" | "config-files") {
+                broken.push(format!("{} ({})", package, state));
+            }
+        }
+    }
+    if !broken.is_empty() {
+        return Err(anyhow!(
+            "Some packages are not fully installed: {}",
+            broken.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Paths whose contents are discarded when committing,
+/// /tmp is always excluded if the instance has a private tmpfs on it
+fn commit_excludes(instance: &str, default_excludes: bool) -> Vec<PathBuf> {
+    let mut excludes = if default_excludes {
+        overlayfs::DEFAULT_COMMIT_EXCLUDES
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+    } else {
+        Vec::new()
+    };
+    if let Ok(config) = config::read_config() {
+        excludes.extend(config.commit_excludes);
+    }
+    let tmp = PathBuf::from("/tmp");
+    if config::InstanceConfig::load_or_default(instance)
+        .private_tmp
+        .is_some()
+        && !excludes.contains(&tmp)
+    {
+        excludes.push(tmp);
+    }
+
+    excludes
+}
+
+fn commit(instance: &str, paths: &[PathBuf], force: bool, default_excludes: bool) -> Result<()> {
     get_instance_ns_name(instance)?;
+    // changes in volatile instances are synced before committing
+    let man = &mut *get_overlay_manager(instance)?;
+    man.set_commit_excludes(&commit_excludes(instance, default_excludes));
+    if !force {
+        if let Err(e) = check_dpkg_state(&man.get_upper_layer()?) {
+            return Err(anyhow!(
+                "{}: refusing to commit: {}\nPlease run `dpkg --configure -a` in the instance first, or use `--force` to commit anyway.",
+                instance,
+                e
+            ));
+        }
+    }
     info!("Un-mounting all the instances...");
     // Un-mount all the instances
     for_each_instance(&container_down)?;
     info!("{}: committing instance...", instance);
     let spinner = create_spinner("Committing upper layer...", 200);
-    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
-    man.commit()?;
+    let excluded = if paths.is_empty() {
+        man.commit()
+    } else {
+        man.commit_paths(paths)
+    }
+    .with_instance(instance)?;
     sync();
     spinner.finish_and_clear();
+    if excluded > 0 {
+        info!(
+            "{}: {} entries under the excluded paths were discarded.",
+            instance, excluded
+        );
+    }
 
     Ok(())
 }
@@ -67,15 +189,55 @@ fn rollback(instance: &str) -> Result<()> {
     info!("{}: rolling back instance...", instance);
     let spinner = create_spinner("Removing upper layer...", 200);
     let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
-    man.rollback()?;
+    man.rollback().with_instance(instance)?;
     sync();
     spinner.finish_and_clear();
+    reset_build_count(instance)?;
+
+    Ok(())
+}
+
+/// File in the instance directory counting the builds since the last rollback
+const BUILD_COUNT_FILE: &str = "build-count";
+
+/// Number of builds run in the instance since it was last rolled back
+pub fn build_count(instance: &str) -> u32 {
+    fs::read_to_string(
+        Path::new(CIEL_INST_DIR)
+            .join(instance)
+            .join(BUILD_COUNT_FILE),
+    )
+    .ok()
+    .and_then(|x| x.trim().parse().ok())
+    .unwrap_or(0)
+}
+
+/// Count one more build in the instance
+pub fn increase_build_count(instance: &str) -> Result<()> {
+    fs::write(
+        Path::new(CIEL_INST_DIR)
+            .join(instance)
+            .join(BUILD_COUNT_FILE),
+        (build_count(instance) + 1).to_string(),
+    )?;
 
     Ok(())
 }
 
+fn reset_build_count(instance: &str) -> Result<()> {
+    match fs::remove_file(
+        Path::new(CIEL_INST_DIR)
+            .join(instance)
+            .join(BUILD_COUNT_FILE),
+    ) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
 /// Remove everything in the current workspace
 pub fn farewell(path: &Path) -> Result<()> {
+    print_destroy_plan(&destroy_plan(path)?)?;
     if !user_attended() {
         eprintln!("DELETE THIS CIEL WORKSPACE?");
         info!("Not controlled by an user. Automatically confirmed.");
@@ -115,8 +277,94 @@ pub fn farewell(path: &Path) -> Result<()> {
     Ok(())
 }
 
-/// Download the OS tarball and then extract it for use as the base layer
-pub fn load_os(url: &str, sha256: Option<String>, tarball: bool) -> Result<()> {
+/// What `ciel farewell` removes from the workspace and what it keeps
+#[derive(Debug)]
+pub struct DestroyPlan {
+    /// Instances (whose mount points are also removed) and their states
+    pub instances: Vec<(String, ContainerState)>,
+    /// The `.ciel` directory and its size in bytes
+    pub data_dir: PathBuf,
+    pub data_size: u64,
+    /// Everything else in the workspace, e.g. `TREE`, `OUTPUT` and `SRCS`
+    pub preserved: Vec<PathBuf>,
+}
+
+/// Describe what `ciel farewell` would do to the workspace at `path`, nothing is changed
+pub fn destroy_plan(path: &Path) -> Result<DestroyPlan> {
+    let data_dir = path.join(".ciel");
+    let names = machine::list_instances_simple().unwrap_or_default();
+    let mut instances = Vec::new();
+    for name in names.iter() {
+        let state = InstanceView::open(name)
+            .and_then(|view| view.state())
+            .unwrap_or(ContainerState::Down);
+        instances.push((name.clone(), state));
+    }
+    let mut preserved = fs::read_dir(path)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|entry| {
+            entry != &data_dir
+                && !entry
+                    .file_name()
+                    .is_some_and(|x| names.iter().any(|name| x == name.as_str()))
+        })
+        .collect::<Vec<_>>();
+    preserved.sort_unstable();
+
+    Ok(DestroyPlan {
+        instances,
+        data_size: directory_size(&data_dir),
+        data_dir,
+        preserved,
+    })
+}
+
+/// Print the plan of `ciel farewell`
+pub fn print_destroy_plan(plan: &DestroyPlan) -> Result<()> {
+    use indicatif::HumanBytes;
+
+    let mut stderr = std::io::stderr();
+    writeln!(
+        stderr,
+        "{} {} ({})",
+        style("Remove:").red().bold().for_stderr(),
+        plan.data_dir.display(),
+        HumanBytes(plan.data_size)
+    )?;
+    for (name, state) in plan.instances.iter() {
+        writeln!(stderr, "        instance {} ({})", name, state)?;
+    }
+    if plan
+        .instances
+        .iter()
+        .any(|(_, state)| *state != ContainerState::Down)
+    {
+        warn!("Instances that are up will be stopped and un-mounted.");
+    }
+    writeln!(stderr, "{}", style("Keep:").green().bold().for_stderr())?;
+    for path in plan.preserved.iter() {
+        writeln!(stderr, "        {}", path.display())?;
+    }
+
+    Ok(())
+}
+
+/// Download the OS tarball and then extract it for use as the base layer.
+/// When `update` is set and a system is already loaded, the download is skipped if the checksum
+/// has not changed, and the current system is only replaced after the extraction succeeds.
+pub fn load_os(
+    url: &str,
+    sha256: Option<String>,
+    format: RootfsFormat,
+    update: bool,
+    cancel: &CancelToken,
+) -> Result<()> {
+    let update = update && fs::read_dir(CIEL_DIST_DIR).is_ok_and(|mut x| x.next().is_some());
+    if update && sha256.is_some() && sha256 == get_rootfs_checksum() {
+        info!("The loaded base OS is already up to date.");
+        return Ok(());
+    }
     info!("Downloading base OS rootfs...");
     let path = Path::new(url);
     let filename = path
@@ -126,45 +374,65 @@ pub fn load_os(url: &str, sha256: Option<String>, tarball: bool) -> Result<()> {
         .ok_or_else(|| anyhow!("Unable to decode path string"))?;
     let is_local_file = path.is_file();
     let total = if !is_local_file {
-        download_file_progress(url, filename)?
+        download_file_progress(url, filename, cancel)?
     } else {
         let tarball = fs::File::open(path)?;
         tarball.metadata()?.len()
     };
-    if let Some(sha256) = sha256 {
-        info!("Verifying tarball checksum...");
-        let tarball = fs::File::open(Path::new(filename))?;
-        let checksum = sha256sum(tarball)?;
-        if sha256 == checksum {
-            info!("Checksum verified.");
-        } else {
-            return Err(anyhow!(
-                "Checksum mismatch: expected {} but got {}",
-                sha256,
-                checksum
-            ));
-        }
-    }
 
-    if is_local_file {
-        extract_system_rootfs(&PathBuf::from(path), total, tarball)?;
+    let tarball_path = if is_local_file {
+        PathBuf::from(path)
     } else {
-        extract_system_rootfs(Path::new(filename), total, tarball)?;
+        PathBuf::from(filename)
+    };
+    // the current system is left untouched if cancelled before the extraction
+    cancel.check()?;
+    if update {
+        // the instances are using the current system as their base layer
+        for_each_instance(&container_down)?;
+        replace_system_rootfs(&tarball_path, total, format, sha256.as_deref())?;
+    } else {
+        // the checksum is verified while extracting
+        extract_system_rootfs(&tarball_path, total, format, sha256.as_deref())?;
     }
+    set_rootfs_checksum(sha256.as_deref())?;
 
     Ok(())
 }
 
+/// The packages built so far stay in the old output directory when the branch-exclusive output
+/// is toggled, and the builds can no longer use them
+fn suggest_output_migration(prev: &config::CielConfig, new: &config::CielConfig) {
+    if prev.sep_mount == new.sep_mount {
+        return;
+    }
+    warn!(
+        "The output directory is now {}, the packages in {} are no longer used.",
+        get_output_directory(new.sep_mount),
+        get_output_directory(prev.sep_mount)
+    );
+    info!("Run `ciel repo migrate-output` to move them to the new output directory.");
+}
+
 /// Ask user for the configuration and then apply it
-pub fn config_os(instance: Option<&str>) -> Result<()> {
+/// With `live`, the workspace configuration is also applied to the mounted instances
+/// instead of shutting them down, see `apply_config_live`.
+pub fn config_os(instance: Option<&str>, live: bool) -> Result<()> {
     let config;
     let mut prev_volatile = None;
-    if let Ok(c) = config::read_config() {
+    let prev = config::read_config_or_warn();
+    if let Some(c) = prev.clone() {
         prev_volatile = Some(c.volatile_mount);
         config = config::ask_for_config(Some(c));
     } else {
         config = config::ask_for_config(None);
     }
+    if let (Some(prev), Ok(c)) = (&prev, &config) {
+        suggest_output_migration(prev, c);
+    }
+    if let (Some(prev), Ok(c), None, true) = (&prev, &config, instance, live) {
+        return apply_config_live(prev, c);
+    }
     let path;
     if let Some(instance) = instance {
         let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
@@ -181,10 +449,7 @@ pub fn config_os(instance: Option<&str>) -> Result<()> {
         }
         config::apply_config(path, &c)?;
         fs::create_dir_all(CIEL_DATA_DIR)?;
-        fs::write(
-            Path::new(CIEL_DATA_DIR).join("config.toml"),
-            c.save_config()?,
-        )?;
+        config::write_config(&c)?;
         info!("Configurations applied.");
         let volatile_changed = if let Some(prev_voltile) = prev_volatile {
             prev_voltile != c.volatile_mount
@@ -192,7 +457,16 @@ pub fn config_os(instance: Option<&str>) -> Result<()> {
             false
         };
         if volatile_changed {
-            warn!("You have changed the volatile mount option, please save your work and\x1b[1m\x1b[93m rollback \x1b[4mall the instances\x1b[0m.");
+            warn!(
+                "You have changed the volatile mount option, please save your work and {} {}.",
+                style("rollback").bold().yellow().bright().for_stderr(),
+                style("all the instances")
+                    .bold()
+                    .yellow()
+                    .bright()
+                    .underlined()
+                    .for_stderr()
+            );
             return Ok(());
         }
         warn!(
@@ -206,28 +480,172 @@ pub fn config_os(instance: Option<&str>) -> Result<()> {
     Ok(())
 }
 
+/// Change the workspace configuration non-interactively and apply it to the base system,
+/// also to the mounted instances with `live`
+pub fn update_config<F: FnOnce(&mut config::CielConfig) -> Result<()>>(
+    f: F,
+    live: bool,
+) -> Result<()> {
+    let prev = config::read_config()?;
+    let mut c = prev.clone();
+    f(&mut c)?;
+    if live {
+        return apply_config_live(&prev, &c);
+    }
+    info!("Shutting down instance(s) before applying config...");
+    for_each_instance(&container_down)?;
+    config::apply_config(CIEL_DIST_DIR, &c)?;
+    config::write_config(&c)?;
+    info!("Configurations applied.");
+    warn!("Please rollback all your instances for the new config to take effect!");
+
+    Ok(())
+}
+
+/// Rewrite the affected files in the mounted instances, so that they don't have to be
+/// shut down or rolled back. The base system is only updated once no instance is mounted
+/// on it anymore (see `apply_pending_config`).
+/// The settings that only take effect after a restart or a remount are reported.
+fn apply_config_live(prev: &config::CielConfig, c: &config::CielConfig) -> Result<()> {
+    config::write_config(c)?;
+    fs::write(CIEL_PENDING_CONFIG_FILE, b"")?;
+    let mut mounted = false;
+    for instance in machine::list_instances_simple()? {
+        if InstanceView::open(&instance)?.state()? == ContainerState::Down {
+            // the new base system is used when it is mounted
+            continue;
+        }
+        mounted = true;
+        let man = &mut *overlayfs::get_overlayfs_manager(&instance)?;
+        if config::has_own_config(man.get_config_layer()?) {
+            warn!(
+                "{}: instance has its own configuration (`ciel config -i`), skipped.",
+                instance
+            );
+            continue;
+        }
+        // written through the overlay, the files in the upper layer match the base system
+        config::apply_config(&instance, c).with_instance(&instance)?;
+        info!(
+            "{}: configuration applied to the mounted instance.",
+            instance
+        );
+    }
+    if mounted {
+        info!("The base system is updated once all the instances are unmounted.");
+    } else {
+        apply_pending_config()?;
+    }
+    info!("Configurations applied.");
+    let pending = config::settings_needing_restart(prev, c);
+    if pending.is_empty() {
+        return Ok(());
+    }
+    warn!("Some changed settings only take effect after the instances are restarted:");
+    for (key, remount) in pending {
+        if remount {
+            eprintln!("\t{} (remount with `ciel down`)", key);
+        } else {
+            eprintln!("\t{} (restart with `ciel stop`)", key);
+        }
+    }
+
+    Ok(())
+}
+
+/// Apply the workspace configuration left pending by `apply_config_live` to the base system,
+/// unless an instance is still mounted on it
+fn apply_pending_config() -> Result<()> {
+    if !Path::new(CIEL_PENDING_CONFIG_FILE).exists() {
+        return Ok(());
+    }
+    for instance in machine::list_instances_simple()? {
+        if InstanceView::open(&instance)?.state()? != ContainerState::Down {
+            return Ok(());
+        }
+    }
+    config::apply_config(CIEL_DIST_DIR, &config::read_config()?)?;
+    fs::remove_file(CIEL_PENDING_CONFIG_FILE)?;
+    info!("Configurations applied to the base system.");
+
+    Ok(())
+}
+
+/// Get the layer manager of the instance, set up according to the workspace and instance settings
+fn get_overlay_manager(instance: &str) -> Result<Box<dyn overlayfs::LayerManager>> {
+    let config = config::read_config_or_warn().unwrap_or_default();
+    let inst_config = config::InstanceConfig::load_or_default(instance);
+    let mut man = overlayfs::get_overlayfs_manager(instance)?;
+    man.set_volatile(inst_config.is_volatile(&config))?;
+
+    Ok(man)
+}
+
 /// Mount the filesystem of the instance
 pub fn mount_fs(instance: &str) -> Result<()> {
-    let config = config::read_config()?;
-    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
-    man.set_volatile(config.volatile_mount)?;
-    machine::mount_layers(man, instance)?;
+    apply_pending_config()?;
+    let man = &mut *get_overlay_manager(instance)?;
+    machine::mount_layers(man, instance).with_instance(instance)?;
     info!("{}: filesystem mounted.", instance);
+    notify_state(instance, ContainerState::Mounted);
 
     Ok(())
 }
 
+/// What to do when host processes keep the filesystem of an instance busy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusyMount {
+    /// Detach the filesystem lazily, it is freed once the processes are gone
+    Detach,
+    /// Refuse to un-mount, listing the processes
+    Refuse,
+    /// Terminate the processes
+    KillHolders,
+}
+
 /// Un-mount the filesystem of the container
-pub fn unmount_fs(instance: &str) -> Result<()> {
+pub fn unmount_fs(instance: &str, busy: BusyMount) -> Result<()> {
     let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
     let target = std::env::current_dir()?.join(instance);
     let mut retry = 0usize;
     while man.is_mounted(&target)? {
         retry += 1;
         if retry > 10 {
-            return Err(anyhow!("Unable to unmount filesystem after 10 attempts."));
+            return Err(anyhow!(
+                "{}: unable to unmount filesystem after 10 attempts.",
+                instance
+            ));
+        }
+        if let Err(e) = man.unmount(&target) {
+            let holders = match e.downcast_ref::<overlayfs::MountBusy>() {
+                Some(holders) => holders,
+                None => return Err(e).with_instance(instance),
+            };
+            let pids = holders
+                .holders
+                .iter()
+                .map(|x| format!("{} ({})", x.pid, x.comm))
+                .collect::<Vec<_>>()
+                .join(", ");
+            match busy {
+                BusyMount::Detach => {
+                    warn!(
+                        "{}: filesystem is still used by {}, detaching it lazily.",
+                        instance, pids
+                    );
+                    man.detach(&target).with_instance(instance)?;
+                }
+                BusyMount::Refuse => return Err(e).with_instance(instance),
+                BusyMount::KillHolders => {
+                    warn!(
+                        "{}: terminating the processes using the filesystem: {}",
+                        instance, pids
+                    );
+                    holders.terminate_holders();
+                    sleep(Duration::from_secs(1));
+                }
+            }
         }
-        man.unmount(&target)?;
     }
     info!("{}: filesystem un-mounted.", instance);
 
@@ -257,7 +675,9 @@ pub fn remove_mount(instance: &str) -> Result<()> {
             error!("Error when querying {:?}: {}", target, e);
         }
     }
-    fs::remove_dir(target)?;
+    fs::remove_dir(&target)
+        .map_err(|e| anyhow!("remove mount point {}: {}", target.display(), e))
+        .with_instance(instance)?;
     info!("{}: mount point removed.", instance);
 
     Ok(())
@@ -274,38 +694,236 @@ fn get_instance_ns_name(instance: &str) -> Result<String> {
     }
     let legacy = is_legacy_workspace()?;
 
-    get_container_ns_name(instance, legacy)
+    resolve_container_ns_name(instance, legacy)
+}
+
+/// Hostname of the container of the instance
+pub fn instance_hostname(instance: &str) -> Result<String> {
+    let ns_name = get_container_ns_name(instance, is_legacy_workspace()?)?;
+
+    Ok(config::InstanceConfig::load_or_default(instance).hostname(&ns_name))
+}
+
+/// Map the hostname to 127.0.1.1 in the `/etc/hosts` of the config layer,
+/// the hosts file of the base system is used as a template
+fn write_hosts_entry(instance: &str, hostname: &str) -> Result<()> {
+    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
+    let etc = man.get_config_layer()?.join("etc");
+    fs::create_dir_all(&etc)?;
+    let hosts = fs::read_to_string(etc.join("hosts"))
+        .or_else(|_| fs::read_to_string(Path::new(CIEL_DIST_DIR).join("etc/hosts")))
+        .unwrap_or_default();
+    fs::write(
+        etc.join("hosts"),
+        config::hosts_with_entry(&hosts, hostname),
+    )
+    .context("write /etc/hosts in the config layer")?;
+
+    Ok(())
 }
 
 /// Start the container/instance, also mounting the container filesystem prior to the action
 pub fn start_container(instance: &str) -> Result<String> {
+    start_container_with(instance, false)
+}
+
+/// Start the container/instance, without network access if `offline` is set
+fn start_container_with(instance: &str, offline: bool) -> Result<String> {
+    let _span = timing::span("boot", instance);
     let ns_name = get_instance_ns_name(instance)?;
     let inst = inspect_instance(instance, &ns_name)?;
-    let (mut extra_options, mounts) = ensure_host_sanity()?;
-    if std::env::var("CIEL_OFFLINE").is_ok() {
+    let inst_config = config::InstanceConfig::load(instance)?;
+    let (mut extra_options, mounts) = ensure_host_sanity(&inst_config)?;
+    if inst_config.network_zone.is_some() && !machine::is_networkd_running()? {
+        return Err(anyhow!(
+            "{}: network zones require systemd-networkd to be running on the host",
+            instance
+        ));
+    }
+    extra_options.extend(inst_config.nspawn_options());
+    if offline || env_flag("CIEL_OFFLINE") {
         // FIXME: does not work with current version of systemd
         // add the offline option (private-network means don't share the host network)
         extra_options.push("--private-network".to_string());
         info!("{}: network disconnected.", instance);
     }
+    let hostname = inst_config.hostname(&ns_name);
+    if config::check_hostname(&hostname).is_ok() {
+        extra_options.push(format!("--hostname={}", hostname));
+    } else {
+        warn!(
+            "{}: `{}` is not a valid hostname, the container hostname is left unchanged.",
+            instance, hostname
+        );
+    }
     if !inst.mounted {
+        // the config layer must not be changed while it is mounted
+        write_hosts_entry(instance, &hostname).with_instance(instance)?;
         mount_fs(instance)?;
     }
     if !inst.started {
-        spawn_container(&ns_name, instance, &extra_options, &mounts)?;
+        spawn_container(&ns_name, instance, &extra_options, &mounts).with_instance(instance)?;
+        notify_state(instance, ContainerState::Running);
     }
 
     Ok(ns_name)
 }
 
+/// Check that a base system has been loaded, which all the instances depend on
+fn check_base_system() -> Result<()> {
+    if !fs::read_dir(CIEL_DIST_DIR).is_ok_and(|mut x| x.next().is_some()) {
+        return Err(anyhow!(
+            "No base system is loaded, please run `ciel load-os` first."
+        ));
+    }
+
+    Ok(())
+}
+
+/// Boot the instances concurrently, at most `jobs` at a time.
+/// The base system is checked once beforehand, and the failure of an instance does not stop
+/// the others from booting. Returns the result for each instance, in the given order.
+pub fn boot_instances(instances: &[String], jobs: usize) -> Result<Vec<(String, Result<()>)>> {
+    check_base_system()?;
+    let queue = Mutex::new(instances.iter());
+    let results = Mutex::new(Vec::new());
+    thread::scope(|s| {
+        for _ in 0..jobs.clamp(1, instances.len().max(1)) {
+            s.spawn(|| loop {
+                let instance = match queue.lock().unwrap().next() {
+                    Some(instance) => instance,
+                    None => break,
+                };
+                let result = lock::lock_instance_shared(instance, "boot")
+                    .and_then(|_lock| start_container(instance).map(|_| ()));
+                results.lock().unwrap().push((instance.clone(), result));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(name, _)| instances.iter().position(|x| x == name));
+
+    Ok(results)
+}
+
 /// Execute the specified command in the container
-pub fn run_in_container<S: AsRef<OsStr>>(instance: &str, args: &[S]) -> Result<i32> {
+pub fn run_in_container<S: AsRef<OsStr>>(
+    instance: &str,
+    args: &[S],
+    options: &machine::ExecOptions,
+) -> Result<i32> {
+    let ns_name = start_container_with(instance, options.offline)?;
+    let status = machine::execute_container_command_in(&ns_name, args, None, options)?;
+
+    Ok(status)
+}
+
+/// Execute the specified command in the container for `ciel shell` and `ciel run`, starting in
+/// the specified directory. The instance is started if needed when `autostart` is set
+/// (telling the user about it), otherwise it must be running already.
+pub fn run_in_container_at<S: AsRef<OsStr>>(
+    instance: &str,
+    args: &[S],
+    workdir: Option<&Path>,
+    autostart: bool,
+) -> Result<i32> {
+    get_instance_ns_name(instance)?;
+    let state = InstanceView::open(instance)?.state()?;
+    if !autostart && state != ContainerState::Running {
+        return Err(anyhow!(
+            "{}: instance is {}, refusing to start it (--no-start)",
+            instance,
+            state
+        ));
+    }
     let ns_name = start_container(instance)?;
-    let status = machine::execute_container_command(&ns_name, args)?;
+    if matches!(state, ContainerState::Down | ContainerState::Mounted) {
+        info!(
+            "{}: instance was {}, auto-started by this command (use `ciel stop` to stop it).",
+            instance, state
+        );
+    }
+    if let Some(workdir) = workdir {
+        check_workdir(instance, &ns_name, workdir)?;
+    }
+    let status = machine::execute_container_command_in(
+        &ns_name,
+        args,
+        workdir,
+        &machine::ExecOptions::default(),
+    )?;
 
     Ok(status)
 }
 
+/// Check that the working directory for a command exists in the running container
+fn check_workdir(instance: &str, ns_name: &str, workdir: &Path) -> Result<()> {
+    if !workdir.is_absolute() {
+        return Err(anyhow!(
+            "Working directory must be an absolute path: {}",
+            workdir.display()
+        ));
+    }
+    // check inside the container, since the directory may reside in a bind mount
+    let test_args: [&OsStr; 3] = ["test".as_ref(), "-d".as_ref(), workdir.as_os_str()];
+    if machine::execute_container_command(ns_name, &test_args)? != 0 {
+        return Err(anyhow!(
+            "{}: directory {} does not exist in the container",
+            instance,
+            workdir.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Execute the specified command in the container and capture its output
+pub fn run_in_container_capture<S: AsRef<OsStr>>(
+    instance: &str,
+    args: &[S],
+    options: &machine::ExecOptions,
+) -> Result<(i32, String)> {
+    let ns_name = start_container_with(instance, options.offline)?;
+
+    machine::execute_container_command_capture(&ns_name, args, options)
+}
+
+/// Execute the specified command in the container, writing its output to `log` as well
+pub fn run_in_container_logged<S: AsRef<OsStr>>(
+    instance: &str,
+    args: &[S],
+    log: &Path,
+    options: &machine::ExecOptions,
+) -> Result<i32> {
+    let ns_name = start_container_with(instance, options.offline)?;
+
+    machine::execute_container_command_logged(&ns_name, args, log, options)
+}
+
+/// Execute the specified command in the container and return its standard output quietly
+pub fn run_in_container_output<S: AsRef<OsStr>>(
+    instance: &str,
+    args: &[S],
+) -> Result<(i32, String)> {
+    let ns_name = start_container(instance)?;
+
+    machine::execute_container_command_output(&ns_name, args)
+}
+
+/// Terminate the container of the instance as soon as `cancel` is triggered, stopping the command
+/// running in it, until the returned guard is dropped
+pub(super) fn terminate_on_cancel(instance: &str, cancel: &CancelToken) -> Result<CancelWatch> {
+    let ns_name = get_instance_ns_name(instance)?;
+    let instance = instance.to_owned();
+
+    Ok(cancel.watch(move || {
+        warn!("{}: cancelling, terminating the container ...", instance);
+        if let Err(e) = machine::terminate_container_by_name(&ns_name) {
+            warn!("{}: unable to terminate the container: {}", instance, e);
+        }
+    }))
+}
+
 /// Stop the container/instance (without un-mounting the filesystem)
 pub fn stop_container(instance: &str) -> Result<()> {
     let ns_name = get_instance_ns_name(instance)?;
@@ -315,31 +933,178 @@ pub fn stop_container(instance: &str) -> Result<()> {
         return Ok(());
     }
     info!("{}: stopping...", instance);
-    machine::terminate_container_by_name(&ns_name)?;
-    machine::clean_child_process();
+    let _span = timing::span("stop", instance);
+    machine::terminate_container_by_name(&ns_name).with_instance(instance)?;
+    machine::clean_child_process(&ns_name);
     info!("{}: instance stopped.", instance);
+    notify_state(instance, ContainerState::Mounted);
 
     Ok(())
 }
 
-/// Stop and un-mount the container and its filesystem
+/// Stop and un-mount the container and its filesystem,
+/// the filesystem is detached lazily if host processes are still using it
 pub fn container_down(instance: &str) -> Result<()> {
+    container_down_with(instance, BusyMount::Detach)
+}
+
+/// Stop and un-mount the container and its filesystem, handling a busy filesystem as requested
+pub fn container_down_with(instance: &str, busy: BusyMount) -> Result<()> {
     stop_container(instance)?;
-    unmount_fs(instance)?;
+    unmount_fs(instance, busy)?;
     remove_mount(instance)?;
+    notify_state(instance, ContainerState::Down);
+    if let Err(e) = apply_pending_config() {
+        warn!(
+            "Unable to apply the configuration to the base system: {:#}",
+            e
+        );
+    }
+
+    Ok(())
+}
+
+/// Stop and un-mount the containers running for longer than `idle_for`,
+/// the instances being used by another ciel process are skipped.
+/// A failure does not stop the other instances from being brought down.
+pub fn down_idle_instances(idle_for: Duration, busy: BusyMount) -> Result<()> {
+    use indicatif::HumanDuration;
+
+    let mut count = 0;
+    let mut failed = Vec::new();
+    for instance in machine::list_instances(InstanceOrder::Name)? {
+        match instance.uptime() {
+            Some(uptime) if uptime > idle_for => (),
+            _ => continue,
+        }
+        eprintln!(
+            "{} {}",
+            style(">>>").bold(),
+            style(&instance.name).cyan().bold()
+        );
+        let _lock = match lock::lock_instance_exclusive(&instance.name, "down") {
+            Ok(lock) => lock,
+            Err(e) => {
+                warn!("{}: skipped, {:#}", instance.name, e);
+                continue;
+            }
+        };
+        count += 1;
+        if let Err(e) = container_down_with(&instance.name, busy) {
+            error!(
+                "{}: unable to bring down the instance: {:#}",
+                instance.name, e
+            );
+            failed.push(instance.name);
+        }
+    }
+    if count == 0 {
+        info!(
+            "No instances have been running for longer than {}.",
+            HumanDuration(idle_for)
+        );
+    }
+    if !failed.is_empty() {
+        return Err(anyhow!(
+            "Unable to bring down {} instance(s): {}",
+            failed.len(),
+            failed.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Upgrade the workspace to the current format, all the instances are shut down first
+pub fn upgrade_workspace() -> Result<()> {
+    info!("Upgrading workspace...");
+    info!("First, shutting down all the instances...");
+    for_each_instance(&container_down)?;
+    ciel_init()?;
+    info!("Migrating workspace configuration...");
+    config::migrate_config()?;
 
     Ok(())
 }
 
 /// Commit the container/instance upper layer changes to the base layer of the filesystem
 pub fn commit_container(instance: &str) -> Result<()> {
+    commit_container_paths(instance, &[], false, true)
+}
+
+/// Commit only the changes under the specified paths to the base layer of the filesystem
+/// (commits everything if no path is specified).
+/// Unless `force` is set, the commit is refused if a package operation was interrupted.
+/// The contents of volatile paths like `/tmp` are discarded unless `default_excludes` is unset.
+pub fn commit_container_paths(
+    instance: &str,
+    paths: &[PathBuf],
+    force: bool,
+    default_excludes: bool,
+) -> Result<()> {
     container_down(instance)?;
-    commit(instance)?;
+    commit(instance, paths, force, default_excludes)?;
     info!("{}: instance has been committed.", instance);
 
     Ok(())
 }
 
+/// Summarize what committing the instance would change in the base layer.
+/// The instance is neither stopped nor un-mounted, and nothing is modified.
+pub fn commit_preview(
+    instance: &str,
+    paths: &[PathBuf],
+    default_excludes: bool,
+) -> Result<overlayfs::CommitSummary> {
+    get_instance_ns_name(instance)?;
+    let mut man = overlayfs::get_overlayfs_manager(instance)?;
+    man.set_commit_excludes(&commit_excludes(instance, default_excludes));
+
+    man.commit_preview(paths).with_instance(instance)
+}
+
+/// Print what committing the instance would change in the base layer
+pub fn print_commit_preview(
+    instance: &str,
+    paths: &[PathBuf],
+    default_excludes: bool,
+) -> Result<()> {
+    use indicatif::HumanBytes;
+    use tabwriter::TabWriter;
+
+    let summary = commit_preview(instance, paths, default_excludes)?;
+    if summary.is_empty() {
+        info!("{}: nothing to commit.", instance);
+        return Ok(());
+    }
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(&mut formatter, "CHANGE\tFILES\tSIZE")?;
+    for (kind, stats) in summary.by_kind.iter() {
+        writeln!(
+            &mut formatter,
+            "{}\t{}\t{}",
+            kind,
+            stats.files,
+            HumanBytes(stats.bytes)
+        )?;
+    }
+    writeln!(&mut formatter)?;
+    writeln!(&mut formatter, "DIRECTORY\tFILES\tSIZE")?;
+    for (dir, stats) in summary.by_directory.iter() {
+        writeln!(
+            &mut formatter,
+            "{}\t{}\t{}",
+            dir.display(),
+            stats.files,
+            HumanBytes(stats.bytes)
+        )?;
+    }
+    formatter.flush()?;
+    info!("{}: dry run, nothing has been committed.", instance);
+
+    Ok(())
+}
+
 /// Clear the upper layer of the container/instance filesystem
 pub fn rollback_container(instance: &str) -> Result<()> {
     container_down(instance)?;
@@ -349,58 +1114,763 @@ pub fn rollback_container(instance: &str) -> Result<()> {
     Ok(())
 }
 
+/// Clear the upper layer of the container/instance filesystem,
+/// the changes under the given paths are kept in `preserved/<timestamp>` of the instance directory
+pub fn rollback_container_preserving(instance: &str, paths: &[PathBuf]) -> Result<()> {
+    if paths.is_empty() {
+        return rollback_container(instance);
+    }
+    container_down(instance)?;
+    get_instance_ns_name(instance)?;
+    info!("{}: rolling back instance...", instance);
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let dest = Path::new(CIEL_INST_DIR)
+        .join(instance)
+        .join("preserved")
+        .join(timestamp.to_string());
+    let spinner = create_spinner("Removing upper layer...", 200);
+    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
+    man.rollback_preserving(paths, &dest)
+        .with_instance(instance)?;
+    sync();
+    spinner.finish_and_clear();
+    reset_build_count(instance)?;
+    if dest.exists() {
+        info!(
+            "{}: preserved files are saved to {}",
+            instance,
+            dest.display()
+        );
+    }
+    info!("{}: instance has been rolled back.", instance);
+
+    Ok(())
+}
+
 /// Create a new instance
 #[inline]
 pub fn add_instance(instance: &str) -> Result<()> {
-    overlayfs::create_new_instance_fs(CIEL_INST_DIR, instance)?;
+    if let Err(e) = overlayfs::create_new_instance_fs(CIEL_INST_DIR, instance) {
+        if e.kind() == std::io::ErrorKind::AlreadyExists {
+            return Err(InstanceError::AlreadyExists(instance.to_string()).into());
+        }
+        return Err(e.into());
+    }
     info!("{}: instance created.", instance);
 
     Ok(())
 }
 
+/// Errors from managing the instances
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InstanceError {
+    /// An instance with the same name already exists
+    AlreadyExists(String),
+}
+
+impl fmt::Display for InstanceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstanceError::AlreadyExists(name) => write!(f, "Instance `{}` already exists", name),
+        }
+    }
+}
+
+impl std::error::Error for InstanceError {}
+
 /// Remove the container/instance and its filesystem from the host filesystem
 pub fn remove_instance(instance: &str) -> Result<()> {
     container_down(instance)?;
     info!("{}: removing instance...", instance);
     let spinner = create_spinner("Removing the instance...", 200);
     let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
-    man.destroy()?;
+    man.destroy().with_instance(instance)?;
     spinner.finish_and_clear();
     info!("{}: instance removed.", instance);
 
     Ok(())
 }
 
-/// Update AOSC OS in the container/instance
-pub fn update_os(force_use_apt: bool) -> Result<()> {
-    info!("Updating base OS...");
-    let instance = format!("update-{:x}", random::<u32>());
-    add_instance(&instance)?;
+/// Remove the instance as far as possible, for instances whose filesystem can't be unmounted
+/// or removed. Every step is attempted even if the previous ones failed, and what can't be
+/// removed is moved to the trash of the workspace to be removed later (see `empty_trash`).
+pub fn remove_broken_instance(instance: &str) -> Result<()> {
+    warn!("{}: removing the instance in best-effort mode...", instance);
+    // the container name depends on the state of the workspace, which may be broken as well
+    let ns_name = get_instance_ns_name(instance)
+        .map_err(|e| warn!("{}: unable to find the container: {:#}", instance, e))
+        .ok();
+    if let Err(e) = stop_container(instance) {
+        warn!("{}: unable to stop the container: {:#}", instance, e);
+        if let Some(ns_name) = &ns_name {
+            if let Err(e) = machine::unregister_container(ns_name) {
+                warn!("{}: {:#}", instance, e);
+            }
+        }
+    }
+    let target = std::env::current_dir()?.join(instance);
+    match umount2(&target, MntFlags::MNT_DETACH) {
+        // not mounted
+        Ok(()) | Err(Errno::EINVAL) | Err(Errno::ENOENT) => (),
+        Err(e) => {
+            warn!("{}: unable to detach the filesystem: {}", instance, e);
+        }
+    }
+    if let Err(e) = remove_mount(instance) {
+        warn!("{}: unable to remove the mount point: {:#}", instance, e);
+    }
+    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
+    if let Err(e) = man.destroy() {
+        if e.downcast_ref::<overlayfs::ProtectedLayer>().is_some() {
+            return Err(e).with_instance(instance);
+        }
+        warn!("{}: {:#}", instance, e);
+        let dest = move_to_trash(
+            Path::new(CIEL_TRASH_DIR),
+            &Path::new(CIEL_INST_DIR).join(instance),
+            instance,
+        )
+        .with_instance(instance)?;
+        info!(
+            "{}: moved to {}, use `ciel clean --trash` to remove it later.",
+            instance,
+            dest.display()
+        );
+    }
+    notify_state(instance, ContainerState::Down);
+    info!("{}: instance removed.", instance);
 
-    if force_use_apt {
-        return apt_update_os(&instance);
+    Ok(())
+}
+
+/// Move the directory to `trash/<name>-<timestamp>`
+fn move_to_trash(trash: &Path, dir: &Path, name: &str) -> Result<PathBuf> {
+    fs::create_dir_all(trash)?;
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let dest = trash.join(format!("{}-{}", name, timestamp));
+    fs::rename(dir, &dest).with_context(|| format!("move {} to the trash", dir.display()))?;
+
+    Ok(dest)
+}
+
+/// Remove what can be removed from the trash, returns the number of entries removed and left
+fn empty_trash_in(trash: &Path) -> Result<(usize, usize)> {
+    let entries = match fs::read_dir(trash) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, 0)),
+        Err(e) => return Err(e.into()),
+    };
+    let (mut removed, mut left) = (0, 0);
+    for entry in entries {
+        let path = entry?.path();
+        let result = if path.is_dir() && !path.is_symlink() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        match result {
+            Ok(()) => removed += 1,
+            Err(_) => left += 1,
+        }
     }
 
-    let status = run_in_container(&instance, &["/bin/bash", "-ec", OMA_UPDATE_SCRIPT])?;
-    if status != 0 {
-        return apt_update_os(&instance);
+    Ok((removed, left))
+}
+
+/// Remove the instances left in the trash by `remove_broken_instance`,
+/// returns the number of entries removed and the number of those still pinned
+pub fn empty_trash() -> Result<(usize, usize)> {
+    empty_trash_in(Path::new(CIEL_TRASH_DIR))
+}
+
+/// A read-only view of an instance, which never takes the instance lock and thus never blocks.
+///
+/// Only queries are offered, apart from `attach`. The results may be outdated as soon as
+/// they are returned, since another process may be operating on the instance at the same time.
+/// To boot, stop, commit or roll back the instance, take the lock with
+/// `lock::lock_instance_shared` or `lock::lock_instance_exclusive` and use the functions
+/// in this module instead.
+pub struct InstanceView {
+    name: String,
+    ns_name: String,
+}
+
+impl InstanceView {
+    pub fn open(instance: &str) -> Result<Self> {
+        if !is_instance_exists(instance) {
+            return Err(anyhow!("Instance `{}` does not exist.", instance));
+        }
+        let ns_name = resolve_container_ns_name(instance, is_legacy_workspace()?)?;
+
+        Ok(InstanceView {
+            name: instance.to_owned(),
+            ns_name,
+        })
+    }
+
+    /// Current state of the instance
+    pub fn state(&self) -> Result<ContainerState> {
+        Ok(inspect_instance(&self.name, &self.ns_name)?.state())
+    }
+
+    /// How long the container has been running and when it was started,
+    /// `None` if it is not started
+    pub fn uptime(&self) -> Result<Option<(Duration, SystemTime)>> {
+        let instance = inspect_instance(&self.name, &self.ns_name)?;
+
+        Ok(instance.uptime().zip(instance.booted_at()))
+    }
+
+    /// Effective mount options of the filesystem, `None` if it is not mounted
+    pub fn mount_options(&self) -> Result<Option<overlayfs::MountOptions>> {
+        machine::instance_mount_options(&self.name)
     }
 
-    commit_container(&instance)?;
-    remove_instance(&instance)?;
+    /// Total size of the uncommitted changes
+    pub fn changes_size(&self) -> Result<u64> {
+        let upper = overlayfs::get_overlayfs_manager(&self.name)?.get_upper_layer()?;
+
+        Ok(directory_size(upper))
+    }
+
+    /// The process holding the instance lock exclusively, if any
+    pub fn lock_holder(&self) -> Result<Option<lock::LockInfo>> {
+        lock::instance_lock_holder(&self.name)
+    }
+
+    /// Hostname of the container
+    pub fn hostname(&self) -> String {
+        config::InstanceConfig::load_or_default(&self.name).hostname(&self.ns_name)
+    }
+
+    /// State of the system in the container, the container must be running
+    pub fn system_state(&self) -> Result<String> {
+        machine::system_state(&self.ns_name)
+    }
+
+    /// Automatic rollback policy of the instance and the number of builds since the last rollback
+    pub fn rollback_policy(&self) -> (config::RollbackPolicy, u32) {
+        (
+            config::InstanceConfig::load_or_default(&self.name).rollback_policy,
+            build_count(&self.name),
+        )
+    }
+
+    /// Size (in MiB) of the private tmpfs on /tmp, if enabled
+    pub fn private_tmp(&self) -> Option<usize> {
+        config::InstanceConfig::load_or_default(&self.name).private_tmp
+    }
+
+    /// Failed units in the container, the container must be running
+    pub fn failed_units(&self) -> Result<Vec<String>> {
+        machine::failed_units(&self.ns_name)
+    }
+
+    /// Run a command in the running container, e.g. to inspect a build in progress.
+    /// The container is never started, stopped or remounted, so this is refused unless it is
+    /// running. The changes made by the command land in the same upper layer as the changes
+    /// of whichever operation is holding the instance.
+    pub fn attach<S: AsRef<OsStr>>(&self, args: &[S], workdir: Option<&Path>) -> Result<i32> {
+        let state = self.state()?;
+        if state != ContainerState::Running {
+            return Err(anyhow!(
+                "{}: instance is {}, only running instances can be attached to",
+                self.name,
+                state
+            ));
+        }
+        if let Some(workdir) = workdir {
+            check_workdir(&self.name, &self.ns_name, workdir)?;
+        }
+        if let Some(holder) = self.lock_holder()? {
+            warn!(
+                "{}: attached while `{}` (PID {}) is using the instance, changes to the filesystem are mixed with its own.",
+                self.name, holder.operation, holder.pid
+            );
+        }
+
+        machine::execute_container_command_in(
+            &self.ns_name,
+            args,
+            workdir,
+            &machine::ExecOptions::default(),
+        )
+    }
+}
+
+/// Print the status of the instance, `units` also shows the failed units in the container
+/// to explain why it is degraded
+pub fn print_instance_status(instance: &str, units: bool) -> Result<()> {
+    use indicatif::{HumanBytes, HumanDuration};
+    use tabwriter::TabWriter;
+    use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+    let view = InstanceView::open(instance)?;
+    let state = view.state()?;
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(&mut formatter, "Instance:\t{}", instance)?;
+    writeln!(&mut formatter, "State:\t{}", state)?;
+    writeln!(&mut formatter, "Hostname:\t{}", view.hostname())?;
+    let (policy, builds) = view.rollback_policy();
+    writeln!(
+        &mut formatter,
+        "Rollback:\t{} ({} builds since the last rollback)",
+        policy, builds
+    )?;
+    if let Some((uptime, booted_at)) = view.uptime()? {
+        writeln!(
+            &mut formatter,
+            "Uptime:\t{} (started at {})",
+            HumanDuration(uptime),
+            OffsetDateTime::from(booted_at).format(&Rfc3339)?
+        )?;
+    }
+    if let Some(options) = view.mount_options()? {
+        writeln!(&mut formatter, "Mount options:\t{}", options)?;
+        if options.is_volatile() {
+            writeln!(
+                &mut formatter,
+                "\t{}",
+                style("volatile: the changes may be lost on a crash or power loss")
+                    .yellow()
+                    .for_stderr()
+            )?;
+        }
+    }
+    if let Some(size) = view.private_tmp() {
+        writeln!(&mut formatter, "Private /tmp:\t{} MiB (tmpfs)", size)?;
+    }
+    writeln!(
+        &mut formatter,
+        "Changes:\t{}",
+        view.changes_size()
+            .map_or_else(|_| "unknown".to_string(), |x| HumanBytes(x).to_string())
+    )?;
+    if let Some(holder) = view.lock_holder()? {
+        writeln!(
+            &mut formatter,
+            "Locked by:\t{} (PID {})",
+            holder.operation, holder.pid
+        )?;
+    }
+    if units && state == ContainerState::Running {
+        writeln!(&mut formatter, "System:\t{}", view.system_state()?)?;
+        let failed = view.failed_units()?;
+        writeln!(
+            &mut formatter,
+            "Failed units:\t{}",
+            if failed.is_empty() {
+                "none".to_string()
+            } else {
+                failed.join(", ")
+            }
+        )?;
+    }
+    formatter.flush()?;
+    if units && state != ContainerState::Running {
+        info!("{}: instance is not running, no units to show.", instance);
+    }
 
     Ok(())
 }
 
-fn apt_update_os(instance: &str) -> Result<()> {
-    let status = run_in_container(instance, &["/bin/bash", "-ec", APT_UPDATE_SCRIPT])?;
+/// A temporary instance that is removed when it goes out of scope
+pub struct EphemeralInstance {
+    name: String,
+    /// Removes the instance, `remove_instance` except in the tests
+    remove: fn(&str) -> Result<()>,
+}
+
+impl EphemeralInstance {
+    /// Create a new temporary instance with the given name prefix
+    pub fn new(prefix: &str) -> Result<Self> {
+        for _ in 0..EPHEMERAL_NAME_RETRIES {
+            let name = format!("{}-{:08x}", prefix, random::<u32>());
+            if is_instance_exists(&name) {
+                continue;
+            }
+            // another process may have taken the name in the meantime
+            match add_instance(&name) {
+                Ok(()) => {
+                    return Ok(Self {
+                        name,
+                        remove: remove_instance,
+                    })
+                }
+                Err(e) if matches!(e.downcast_ref(), Some(InstanceError::AlreadyExists(_))) => {
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+        }
 
-    if status != 0 {
-        return Err(anyhow!("Failed to update OS: {}", status));
+        Err(anyhow!(
+            "Unable to find an unused name for the temporary instance"
+        ))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Keep the instance around instead of removing it, returning its name
+    pub fn keep(mut self) -> String {
+        std::mem::take(&mut self.name)
+    }
+
+    /// Remove the instance now, returning any error to the caller
+    pub fn discard(mut self) -> Result<()> {
+        let name = std::mem::take(&mut self.name);
+
+        (self.remove)(&name)
+    }
+}
+
+impl Drop for EphemeralInstance {
+    fn drop(&mut self) {
+        if self.name.is_empty() {
+            return;
+        }
+        // never panic here: this may run during unwinding and the original error would be lost
+        if let Err(e) = (self.remove)(&self.name) {
+            error!(
+                "{}: failed to remove the temporary instance: {:?}",
+                self.name, e
+            );
+            info!("You can remove it later using `ciel del {}`.", self.name);
+        }
+    }
+}
+
+/// Whether the temporary instance of `ciel try` is kept after running the command
+fn should_keep_ephemeral(result: &Result<i32>, keep_on_failure: bool) -> bool {
+    keep_on_failure && !matches!(result, Ok(0))
+}
+
+/// Run a command in a new temporary instance with the given settings, then remove the instance.
+/// With `keep_on_failure`, the instance is kept if the command fails. Returns the exit code.
+pub fn run_ephemeral<S: AsRef<OsStr>>(
+    inst_config: &config::InstanceConfig,
+    args: &[S],
+    keep_on_failure: bool,
+) -> Result<i32> {
+    let instance = EphemeralInstance::new("try")?;
+    info!("Running in temporary instance {} ...", instance.name());
+    inst_config.save(instance.name())?;
+    let result = lock::lock_instance_exclusive(instance.name(), "try")
+        .and_then(|_lock| run_in_container(instance.name(), args, &Default::default()));
+    if should_keep_ephemeral(&result, keep_on_failure) {
+        let name = instance.keep();
+        info!("Keeping temporary instance {}.", name);
+        info!("You can remove it later using `ciel del {}`.", name);
+    } else {
+        let name = instance.name().to_owned();
+        // the exit code of the command is still returned if the instance can not be removed
+        if let Err(e) = instance.discard() {
+            error!("{}: failed to remove the temporary instance: {:?}", name, e);
+            info!("You can remove it later using `ciel del {}`.", name);
+        }
+    }
+
+    result
+}
+
+/// Remove several instances at once. The instances, their states and the size of their changes
+/// are listed first, and the user is asked once for confirmation unless `force` is set
+/// (which is required when there is nobody to ask).
+/// Failures do not stop the removal of the other instances, returns false if any of them failed.
+pub fn remove_instances(instances: &[String], force: bool, broken: bool) -> Result<bool> {
+    use indicatif::HumanBytes;
+    use tabwriter::TabWriter;
+
+    if let Some(missing) = instances.iter().find(|x| !is_instance_exists(x)) {
+        return Err(anyhow!("Instance `{}` does not exist.", missing));
+    }
+    if instances.is_empty() {
+        info!("No instances to remove.");
+        return Ok(true);
+    }
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(&mut formatter, "NAME\tSTATE\tCHANGES\tLOCKED")?;
+    for instance in instances {
+        let view = InstanceView::open(instance)?;
+        let state = view
+            .state()
+            .map_or_else(|_| "unknown".to_string(), |x| x.to_string());
+        let changes = view
+            .changes_size()
+            .map_or_else(|_| "unknown".to_string(), |x| HumanBytes(x).to_string());
+        let locked = match view.lock_holder()? {
+            Some(holder) => format!("{} (PID {})", holder.operation, holder.pid),
+            None => "-".to_string(),
+        };
+        writeln!(
+            &mut formatter,
+            "{}\t{}\t{}\t{}",
+            instance, state, changes, locked
+        )?;
+    }
+    formatter.flush()?;
+    if !force && !user_attended() {
+        // nobody to ask, removing everything must be explicitly requested
+        return Err(anyhow!(
+            "Refusing to remove {} instance(s) without confirmation, use `--force` to proceed",
+            instances.len()
+        ));
+    }
+    if !force {
+        let confirmed = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Remove these {} instance(s)?", instances.len()))
+            .default(false)
+            .interact()?;
+        if !confirmed {
+            info!("Not confirmed.");
+            return Ok(true);
+        }
+    }
+
+    let mut results = Vec::new();
+    for instance in instances {
+        eprintln!("{} {}", style(">>>").bold(), style(instance).cyan().bold());
+        let result = lock::lock_instance_exclusive(instance, "del").and_then(|_lock| {
+            if broken {
+                remove_broken_instance(instance)
+            } else {
+                remove_instance(instance)
+            }
+        });
+        if let Err(e) = &result {
+            error!("{}: {:#}", instance, e);
+        }
+        results.push((instance, result));
+    }
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(&mut formatter, "NAME\tRESULT")?;
+    for (instance, result) in results.iter() {
+        let status = match result {
+            Ok(()) => style("Removed").green().for_stderr().to_string(),
+            Err(e) => style(format!("Failed: {:#}", e))
+                .red()
+                .for_stderr()
+                .to_string(),
+        };
+        writeln!(&mut formatter, "{}\t{}", instance, status)?;
     }
+    formatter.flush()?;
+
+    Ok(results.iter().all(|(_, result)| result.is_ok()))
+}
+
+/// Where the system update is applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateTarget<'a> {
+    /// Update in a temporary instance and commit the changes to the base system
+    Base,
+    /// Update in an existing instance, the changes are left in its upper layer for review
+    Staged(&'a str),
+}
 
+impl UpdateTarget<'_> {
+    /// Whether the update is committed to the base system right away
+    fn commits(&self) -> bool {
+        matches!(self, UpdateTarget::Base)
+    }
+}
+
+/// Update AOSC OS in the container/instance
+pub fn update_os(force_use_apt: bool, target: UpdateTarget, cancel: &CancelToken) -> Result<()> {
+    let ephemeral = match target {
+        UpdateTarget::Base => {
+            info!("Updating base OS...");
+            Some(EphemeralInstance::new("update")?)
+        }
+        UpdateTarget::Staged(instance) => {
+            get_instance_ns_name(instance)?;
+            info!("{}: updating OS in the instance...", instance);
+            None
+        }
+    };
+    let instance = match (&ephemeral, target) {
+        (Some(ephemeral), _) => ephemeral.name(),
+        (None, UpdateTarget::Staged(instance)) => instance,
+        (None, UpdateTarget::Base) => unreachable!(),
+    };
+    if let Err(e) = update_system(instance, force_use_apt, cancel) {
+        if e.is::<Cancelled>() {
+            stop_container(instance)?;
+        }
+        return Err(e);
+    }
+    if !target.commits() {
+        info!("{}: the update is kept in the instance.", instance);
+        info!(
+            "Use `ciel commit -i {}` to apply it to the base OS, or `ciel rollback -i {}` to drop it.",
+            instance, instance
+        );
+        return Ok(());
+    }
     commit_container(instance)?;
-    remove_instance(instance)?;
+    if let Some(ephemeral) = ephemeral {
+        ephemeral.discard()?;
+    }
+
+    Ok(())
+}
+
+/// Update the system inside the instance using oma, falling back to apt when oma fails
+/// (unless oma merely failed to acquire the package manager lock, in which case oma is retried)
+/// The update stops with `Cancelled` when `cancel` is triggered.
+pub fn update_system(instance: &str, force_use_apt: bool, cancel: &CancelToken) -> Result<()> {
+    let _watch = terminate_on_cancel(instance, cancel)?;
+    let mut oma_output = None;
+    if !force_use_apt {
+        for i in 1..=OMA_LOCK_RETRIES {
+            cancel.check()?;
+            let (status, output) = run_in_container_capture(
+                instance,
+                &["/bin/bash", "-ec", OMA_UPDATE_SCRIPT],
+                &Default::default(),
+            )?;
+            cancel.check()?;
+            if status == 0 {
+                return Ok(());
+            }
+            let locked = is_lock_contention(&output);
+            oma_output = Some(output);
+            if !locked || i == OMA_LOCK_RETRIES {
+                break;
+            }
+            let interval = 2u64.pow(i);
+            warn!(
+                "Package manager is locked by another process, will retry oma in {} seconds ...",
+                interval
+            );
+            cancel.sleep(Duration::from_secs(interval))?;
+        }
+        warn!("oma failed to update the OS, falling back to apt ...");
+    }
+
+    cancel.check()?;
+    let (status, output) = run_in_container_capture(
+        instance,
+        &["/bin/bash", "-ec", APT_UPDATE_SCRIPT],
+        &Default::default(),
+    )?;
+    cancel.check()?;
+    if status != 0 {
+        let mut message = format!(
+            "Failed to update OS: {}\napt: {}",
+            status,
+            output_tail(&output, 10)
+        );
+        let clock_skew = classify_update_output(
+            [Some(&output), oma_output.as_ref()]
+                .into_iter()
+                .flatten()
+                .map(|x| x.as_str()),
+        );
+        if let Some(oma_output) = oma_output.as_ref() {
+            message.push_str(&format!("\noma: {}", output_tail(oma_output, 10)));
+        }
+        if let Some(failure) = clock_skew {
+            message.push_str(&format!("\nHint: {}", failure.kind.hint()));
+        }
+        return Err(anyhow!(message));
+    }
 
     Ok(())
 }
+
+#[test]
+fn test_ephemeral_instance_drop() {
+    let dir = tempfile::tempdir().unwrap();
+    let guard = |name: &str| {
+        let path = dir.path().join(name);
+        fs::create_dir(&path).unwrap();
+        let instance = EphemeralInstance {
+            name: path.to_string_lossy().into_owned(),
+            remove: |path| Ok(fs::remove_dir(path)?),
+        };
+        (instance, path)
+    };
+    // the removal fails while the instance is busy, dropping the guard must not panic
+    let (instance, busy) = guard("busy");
+    fs::write(busy.join("file"), b"").unwrap();
+    drop(instance);
+    assert!(busy.join("file").exists());
+    let (instance, busy) = guard("busy-discard");
+    fs::write(busy.join("file"), b"").unwrap();
+    let err = instance.discard().unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<std::io::Error>().unwrap().kind(),
+        std::io::ErrorKind::DirectoryNotEmpty
+    );
+    let (instance, removed) = guard("removed");
+    drop(instance);
+    assert!(!removed.exists());
+    let (instance, kept) = guard("kept");
+    assert_eq!(instance.keep(), kept.to_string_lossy());
+    assert!(kept.is_dir());
+}
+
+#[test]
+fn test_check_dpkg_state() {
+    let dir = tempfile::tempdir().unwrap();
+    assert!(check_dpkg_state(dir.path()).is_ok());
+    let dpkg_dir = dir.path().join("var/lib/dpkg");
+    fs::create_dir_all(dpkg_dir.join("updates")).unwrap();
+    fs::write(
+        dpkg_dir.join("status"),
+        "Package: a\nStatus: install ok installed\n\nPackage: b\nStatus: deinstall ok config-files\n",
+    )
+    .unwrap();
+    assert!(check_dpkg_state(dir.path()).is_ok());
+    fs::write(
+        dpkg_dir.join("status"),
+        "Package: a\nStatus: install ok half-configured\n",
+    )
+    .unwrap();
+    assert!(check_dpkg_state(dir.path())
+        .unwrap_err()
+        .to_string()
+        .contains("a (half-configured)"));
+    fs::write(dpkg_dir.join("status"), "").unwrap();
+    fs::write(dpkg_dir.join("updates/0001"), "").unwrap();
+    assert!(check_dpkg_state(dir.path()).is_err());
+}
+
+#[test]
+fn test_should_keep_ephemeral() {
+    assert!(!should_keep_ephemeral(&Ok(0), true));
+    assert!(should_keep_ephemeral(&Ok(1), true));
+    assert!(should_keep_ephemeral(&Err(anyhow!("boot failed")), true));
+    assert!(!should_keep_ephemeral(&Ok(1), false));
+}
+
+#[test]
+fn test_instance_view_missing() {
+    assert!(InstanceView::open("nonexistent-instance").is_err());
+}
+
+#[test]
+fn test_trash() {
+    let dir = tempfile::tempdir().unwrap();
+    let trash = dir.path().join("trash");
+    assert_eq!(empty_trash_in(&trash).unwrap(), (0, 0));
+    let inst = dir.path().join("instances/broken");
+    fs::create_dir_all(inst.join("layers/diff/usr")).unwrap();
+    let dest = move_to_trash(&trash, &inst, "broken").unwrap();
+    assert!(!inst.exists());
+    assert!(dest.join("layers/diff/usr").is_dir());
+    assert!(dest
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .starts_with("broken-"));
+    assert_eq!(empty_trash_in(&trash).unwrap(), (1, 0));
+    assert!(!dest.exists());
+}
@@ -0,0 +1,179 @@
+//! Classify the build failures by looking at the tail of the acbs output
+
+use std::fmt;
+
+/// Number of output lines kept for classifying the failure
+pub const BUILD_OUTPUT_TAIL_LINES: usize = 200;
+
+/// The probable cause of a failed build
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// apt rejected the repository metadata dated in the future, the clock is behind
+    ClockSkew,
+    /// The source checksum does not match the one in the spec
+    ChecksumMismatch,
+    /// Unable to download the sources
+    FetchFailure,
+    /// The package was rejected by the abbs/autobuild QA checks
+    QaError,
+    /// The compiler or the build system reported an error
+    CompilerError,
+}
+
+/// Patterns (in lower case) for each kind of failure, checked in this order
+const FAILURE_PATTERNS: &[(FailureKind, &[&str])] = &[
+    (
+        FailureKind::ClockSkew,
+        &["is not valid yet", "invalid for another"],
+    ),
+    (
+        FailureKind::ChecksumMismatch,
+        &[
+            "checksum mismatch",
+            "checksums mismatch",
+            "checksum verification failed",
+        ],
+    ),
+    (
+        FailureKind::FetchFailure,
+        &[
+            "failed to fetch",
+            "failed to download",
+            "unable to download",
+            "could not resolve host",
+            "fatal: unable to access",
+            "connection timed out",
+        ],
+    ),
+    (FailureKind::QaError, &["qa (e)", "qa error", "[qa]"]),
+    (
+        FailureKind::CompilerError,
+        &[
+            "fatal error:",
+            "collect2: error",
+            "error: could not compile",
+            "ninja: build stopped",
+            "make: ***",
+        ],
+    ),
+];
+
+impl FailureKind {
+    /// A hint for the user on how to deal with the failure
+    pub fn hint(&self) -> &'static str {
+        match self {
+            FailureKind::ClockSkew => {
+                "the clock of the host is behind the repository - sync it (e.g. `timedatectl set-ntp true`) and see `ciel doctor`"
+            }
+            FailureKind::ChecksumMismatch => {
+                "source checksum mismatch - consider `ciel clean` to remove the cached sources, or update the checksum in the spec"
+            }
+            FailureKind::FetchFailure => {
+                "unable to fetch the sources - check your network connection and the source URLs in the spec"
+            }
+            FailureKind::QaError => {
+                "the package was rejected by the QA checks - see the QA messages above"
+            }
+            FailureKind::CompilerError => {
+                "the package failed to compile - see the compiler messages above"
+            }
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FailureKind::ClockSkew => "clock skew",
+            FailureKind::ChecksumMismatch => "checksum mismatch",
+            FailureKind::FetchFailure => "fetch failure",
+            FailureKind::QaError => "QA error",
+            FailureKind::CompilerError => "compiler error",
+        })
+    }
+}
+
+/// Structured information about a failed build
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildFailure {
+    pub kind: FailureKind,
+    /// The output line that matched the classifier
+    pub excerpt: String,
+}
+
+/// Find out the probable cause of a failed build from its output
+pub fn classify_build_output(output: &str) -> Option<BuildFailure> {
+    let lines = output
+        .lines()
+        .map(|line| (line, line.to_lowercase()))
+        .collect::<Vec<_>>();
+    for (kind, patterns) in FAILURE_PATTERNS {
+        let found = lines
+            .iter()
+            .find(|(_, lower)| patterns.iter().any(|p| lower.contains(p)));
+        if let Some((line, _)) = found {
+            return Some(BuildFailure {
+                kind: *kind,
+                excerpt: line.trim().to_string(),
+            });
+        }
+    }
+
+    None
+}
+
+/// Find out the probable cause of a failed OS update from the outputs of the package managers,
+/// only the clock skew is recognized (the other causes are about building packages)
+pub fn classify_update_output<'a>(
+    outputs: impl IntoIterator<Item = &'a str>,
+) -> Option<BuildFailure> {
+    outputs
+        .into_iter()
+        .filter_map(classify_build_output)
+        .find(|x| x.kind == FailureKind::ClockSkew)
+}
+
+#[test]
+fn test_classify_build_output() {
+    let failure = classify_build_output(
+        "[I] Downloading sources...\n[E] Checksum mismatch for foo-1.0.tar.gz\nmake[1]: oops\n",
+    )
+    .unwrap();
+    assert_eq!(failure.kind, FailureKind::ChecksumMismatch);
+    assert_eq!(failure.excerpt, "[E] Checksum mismatch for foo-1.0.tar.gz");
+    assert_eq!(
+        classify_build_output("curl: (6) Could not resolve host: example.com")
+            .unwrap()
+            .kind,
+        FailureKind::FetchFailure
+    );
+    assert_eq!(
+        classify_build_output("QA (E) E321: File in /usr/local")
+            .unwrap()
+            .kind,
+        FailureKind::QaError
+    );
+    assert_eq!(
+        classify_build_output("foo.c:1:10: fatal error: bar.h: No such file or directory")
+            .unwrap()
+            .kind,
+        FailureKind::CompilerError
+    );
+    assert_eq!(
+        classify_build_output("E: Release file for https://repo.aosc.io/debs/dists/stable/InRelease is not valid yet (invalid for another 3h 2min 1s). Updates for this repository will not be applied.")
+            .unwrap()
+            .kind,
+        FailureKind::ClockSkew
+    );
+    assert!(classify_build_output("[I] Build finished").is_none());
+}
+
+#[test]
+fn test_classify_update_output() {
+    let skew = "E: Release file for https://repo.aosc.io/debs/dists/stable/InRelease is not valid yet (invalid for another 5s).";
+    let failure = classify_update_output(["oma: Failed to fetch", skew]).unwrap();
+    assert_eq!(failure.kind, FailureKind::ClockSkew);
+    assert_eq!(failure.excerpt, skew);
+    assert!(classify_update_output(["E: Failed to fetch https://repo.aosc.io/"]).is_none());
+    assert!(classify_update_output([]).is_none());
+}
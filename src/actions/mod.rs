@@ -1,13 +1,25 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use console::style;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
-use crate::machine;
+use crate::{
+    config::{BindMount, CielConfig, InstanceConfig},
+    machine,
+};
 
+mod cache;
+mod cancel;
 mod container;
+pub mod failure;
 mod onboarding;
 mod packaging;
 
 // re-export all the functions from the sub
+pub use self::cache::*;
+pub use self::cancel::{CancelToken, Cancelled};
 pub use self::container::*;
 pub use self::onboarding::onboarding;
 pub use self::packaging::*;
@@ -18,28 +30,77 @@ const DEFAULT_MOUNTS: &[(&str, &str)] = &[
     ("SRCS", "/var/cache/acbs/tarballs"),
     ("CACHE", "/var/cache/apt/archives"),
 ];
+/// CA bundle of the host, shared with the containers if `trust-host-ca` is enabled
+pub const HOST_CA_BUNDLE: &str = "/etc/ssl/certs/ca-certificates.crt";
+/// systemd-nspawn options set by ciel itself, which must not be overridden
+const MANAGED_NSPAWN_OPTIONS: &[&str] = &[
+    "--directory",
+    "--machine",
+    "--quiet",
+    "--boot",
+    "--register",
+];
+const MANAGED_NSPAWN_SHORT_OPTIONS: &[char] = &['D', 'M', 'q', 'b'];
+/// Short systemd-nspawn options taking a value, the rest of the argument is the value
+const NSPAWN_SHORT_OPTIONS_WITH_VALUE: &[char] = &['D', 'i', 'u', 'M', 'E', 'S', 'p', 'Z', 'L'];
 const APT_UPDATE_SCRIPT: &str = r#"export DEBIAN_FRONTEND=noninteractive;apt-get update -y --allow-releaseinfo-change && apt-get -y -o Dpkg::Options::="--force-confnew" full-upgrade --autoremove --purge && apt autoclean"#;
 const OMA_UPDATE_SCRIPT: &str = r#"oma upgrade -y --force-confnew --no-progress --force-unsafe-io && oma autoremove -y --no-progress --remove-config && oma clean --no-progress"#;
+/// Messages from oma/apt/dpkg indicating that another process is holding the package manager lock
+const LOCK_CONTENTION_PATTERNS: &[&str] = &[
+    "Could not get lock",
+    "Unable to acquire the dpkg frontend lock",
+    "Unable to lock the administration directory",
+    "is another process using it?",
+    "Waiting for cache lock",
+    "/var/lib/dpkg/lock",
+];
 
-type MountOptions = (Vec<String>, Vec<(String, &'static str)>);
-/// Ensure that the directories exist and mounted
-pub fn ensure_host_sanity() -> Result<MountOptions, std::io::Error> {
-    use crate::warn;
+type MountOptions = (Vec<String>, Vec<(String, String)>);
 
-    let mut extra_options = Vec::new();
-    let mut mounts: Vec<(String, &str)> = DEFAULT_MOUNTS
+/// The built-in bind mounts (host path, container path) according to the configurations
+fn builtin_mounts(config: Option<&CielConfig>, instance: &InstanceConfig) -> Vec<(String, String)> {
+    let mut mounts: Vec<(String, String)> = DEFAULT_MOUNTS
         .iter()
-        .map(|x| (x.0.to_string(), x.1))
+        .map(|x| (x.0.to_string(), x.1.to_string()))
         .collect();
-    if let Ok(c) = crate::config::read_config() {
-        extra_options = c.extra_options;
+    if let Some(c) = config {
         if !c.local_sources {
             // remove SRCS
-            mounts.swap_remove(2);
+            mounts.retain(|x| x.1 != "/var/cache/acbs/tarballs");
         }
         if c.sep_mount {
-            mounts.push((format!("{}/debs", get_output_directory(true)), "/debs/"));
-            mounts.swap_remove(0);
+            mounts[0].0 = format!("{}/debs", get_output_directory(true));
+        }
+    }
+    // without the local repository for the whole workspace, the output directory
+    // only collects the built packages, so it is still mounted to keep them
+    if instance.local_repo == Some(false) {
+        // do not let the builds in this instance touch the shared output directory
+        mounts.retain(|x| x.1 != "/debs/");
+    }
+
+    mounts
+}
+
+/// Ensure that the directories exist and mounted
+pub fn ensure_host_sanity(instance: &InstanceConfig) -> Result<MountOptions> {
+    use crate::warn;
+
+    let mut extra_options = Vec::new();
+    let mut extra_mounts = instance.bind_mounts.clone();
+    let config = crate::config::read_config_or_warn();
+    let mut mounts = builtin_mounts(config.as_ref(), instance);
+    let local_repo = config.as_ref().is_none_or(|c| instance.use_local_repo(c));
+    if let Some(c) = config {
+        extra_mounts = merge_bind_mounts(&c.extra_bind_mounts, &instance.bind_mounts);
+        check_nspawn_options(&c.extra_options)?;
+        extra_options = host_trust_options(&c)?;
+        extra_options.extend(c.extra_options);
+        // do not let nspawn overwrite the DNS configuration in the container
+        if !c.dns_servers.is_empty()
+            && !extra_options.iter().any(|x| x.starts_with("--resolv-conf"))
+        {
+            extra_options.push("--resolv-conf=off".to_string());
         }
     } else {
         warn!("This workspace is not yet configured, default settings are used.");
@@ -47,11 +108,195 @@ pub fn ensure_host_sanity() -> Result<MountOptions, std::io::Error> {
 
     for mount in &mounts {
         std::fs::create_dir_all(&mount.0)?;
+        // the sources list of the instance may already refer to the local repository
+        if local_repo && mount.1 == "/debs/" {
+            if let Some(root) = Path::new(&mount.0).parent() {
+                crate::repo::ensure_repo(root)?;
+            }
+        }
+    }
+    for mount in extra_mounts {
+        check_bind_mount(&mount)?;
+        if !mount.source.is_dir() {
+            return Err(anyhow!(
+                "Source of the bind mount {} does not exist: {}",
+                mount.target,
+                mount.source.display()
+            ));
+        }
+        let source = mount.source.to_str().ok_or_else(|| {
+            anyhow!(
+                "Unable to bind-mount {:?}: non-UTF-8 paths are not supported",
+                mount.source
+            )
+        })?;
+        mounts.push((source.to_string(), mount.target));
     }
 
     Ok((extra_options, mounts))
 }
 
+/// systemd-nspawn options for sharing the CA certificates and the DNS configuration of the host
+fn host_trust_options(config: &CielConfig) -> Result<Vec<String>> {
+    let mut options = Vec::new();
+    if config.trust_host_ca {
+        let bundle = resolve_ca_bundle(Path::new(HOST_CA_BUNDLE))?;
+        options.push(format!("--bind-ro={}:{}", bundle.display(), HOST_CA_BUNDLE));
+    }
+    if config.host_resolv_conf {
+        options.push("--resolv-conf=bind-host".to_string());
+    }
+
+    Ok(options)
+}
+
+/// The file the CA bundle of the host links to. The file itself is shared with the containers,
+/// as the symbolic links on the host would point to nothing inside them.
+pub fn resolve_ca_bundle(bundle: &Path) -> Result<PathBuf> {
+    match fs::canonicalize(bundle) {
+        Ok(path) if path.is_file() => Ok(path),
+        Ok(path) => Err(anyhow!(
+            "trust-host-ca is enabled, but {} is not a file",
+            path.display()
+        )),
+        Err(_) if bundle.is_symlink() => Err(anyhow!(
+            "trust-host-ca is enabled, but {} is a dangling symbolic link to {}",
+            bundle.display(),
+            fs::read_link(bundle)?.display()
+        )),
+        Err(e) => Err(anyhow!(
+            "trust-host-ca is enabled, but {} can not be read on the host: {}",
+            bundle.display(),
+            e
+        )),
+    }
+}
+
+/// Check that the bind mount does not conflict with the built-in mounts
+pub fn check_bind_mount(mount: &BindMount) -> Result<()> {
+    let target = Path::new(&mount.target);
+    if !target.is_absolute() {
+        return Err(anyhow!(
+            "Target of the bind mount must be an absolute path: {}",
+            mount.target
+        ));
+    }
+    // also refuse the parents of the built-in mounts, since they would be shadowed
+    if let Some(builtin) = DEFAULT_MOUNTS
+        .iter()
+        .find(|x| Path::new(x.1).starts_with(target))
+    {
+        return Err(anyhow!(
+            "Target of the bind mount {} conflicts with the built-in mount {}",
+            mount.target,
+            builtin.1
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that the extra systemd-nspawn options do not conflict with the options managed by ciel
+pub fn check_nspawn_options(options: &[String]) -> Result<()> {
+    let conflict = |option: &str| {
+        anyhow!(
+            "Extra systemd-nspawn option `{}` conflicts with the options managed by ciel",
+            option
+        )
+    };
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        if let Some(long) = option.strip_prefix("--") {
+            let (name, value) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_owned())),
+                None => (long, None),
+            };
+            if MANAGED_NSPAWN_OPTIONS.contains(&format!("--{}", name).as_str()) {
+                return Err(conflict(option));
+            }
+            if name == "bind" || name == "bind-ro" {
+                let value = value
+                    .or_else(|| options.next().cloned())
+                    .ok_or_else(|| conflict(option))?;
+                // SOURCE[:TARGET[:OPTIONS]], the target defaults to the source
+                let mut parts = value.split(':');
+                let source = parts.next().unwrap_or_default();
+                let target = parts.next().unwrap_or(source);
+                check_bind_mount(&BindMount {
+                    source: source.into(),
+                    target: target.to_owned(),
+                })
+                .map_err(|e| anyhow!("Extra systemd-nspawn option `{}`: {}", option, e))?;
+            }
+        } else if let Some(short) = option.strip_prefix('-') {
+            for (i, c) in short.char_indices() {
+                if MANAGED_NSPAWN_SHORT_OPTIONS.contains(&c) {
+                    return Err(conflict(option));
+                }
+                if NSPAWN_SHORT_OPTIONS_WITH_VALUE.contains(&c) {
+                    if i + c.len_utf8() == short.len() {
+                        options.next();
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Merge the workspace and instance bind mounts, instance mounts win on the same target
+pub fn merge_bind_mounts(workspace: &[BindMount], instance: &[BindMount]) -> Vec<BindMount> {
+    let mut mounts = workspace
+        .iter()
+        .filter(|x| {
+            !instance
+                .iter()
+                .any(|y| Path::new(&y.target) == Path::new(&x.target))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    mounts.extend_from_slice(instance);
+
+    mounts
+}
+
+/// Check if the package manager failed because of lock contention (retrying may help)
+fn is_lock_contention(output: &str) -> bool {
+    LOCK_CONTENTION_PATTERNS
+        .iter()
+        .any(|pattern| output.contains(pattern))
+}
+
+/// Return the last few lines of the command output (for error reporting)
+fn output_tail(output: &str, lines: usize) -> String {
+    let all_lines = output.trim_end().lines().collect::<Vec<_>>();
+    let start = all_lines.len().saturating_sub(lines);
+
+    all_lines[start..].join("\n")
+}
+
+/// Return the last few lines of a log file, without the terminal escape sequences
+fn log_tail(path: &Path, lines: usize) -> Result<String> {
+    use std::io::BufRead;
+
+    let mut tail = std::collections::VecDeque::new();
+    for line in std::io::BufReader::new(fs::File::open(path)?).split(b'\n') {
+        if tail.len() >= lines {
+            tail.pop_front();
+        }
+        tail.push_back(line?);
+    }
+    let tail = tail
+        .iter()
+        .map(|x| String::from_utf8_lossy(x).replace('\r', ""))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    Ok(console::strip_ansi_codes(&tail).into_owned())
+}
+
 /// A convenience function for iterating over all the instances while executing the actions
 #[inline]
 pub fn for_each_instance<F: Fn(&str) -> Result<()>>(func: &F) -> Result<()> {
@@ -63,3 +308,127 @@ pub fn for_each_instance<F: Fn(&str) -> Result<()>>(func: &F) -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_log_tail() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("build.log");
+    fs::write(&log, "a\r\nb\r\n\x1b[31mc\x1b[0m\r\n").unwrap();
+    assert_eq!(log_tail(&log, 2).unwrap(), "b\nc");
+    assert_eq!(log_tail(&log, 10).unwrap(), "a\nb\nc");
+}
+
+#[test]
+fn test_lock_contention() {
+    assert!(is_lock_contention(
+        "E: Could not get lock /var/lib/dpkg/lock-frontend. It is held by process 1234 (apt-get)"
+    ));
+    assert!(!is_lock_contention(
+        "E: Unable to locate package foo\nE: Failed to fetch https://repo.aosc.io/"
+    ));
+    assert_eq!(output_tail("a\nb\nc\n", 2), "b\nc");
+}
+
+#[test]
+fn test_host_trust_options() {
+    let mut config = CielConfig::default();
+    assert!(host_trust_options(&config).unwrap().is_empty());
+    config.host_resolv_conf = true;
+    assert_eq!(
+        host_trust_options(&config).unwrap(),
+        vec!["--resolv-conf=bind-host"]
+    );
+    config.trust_host_ca = true;
+    match host_trust_options(&config) {
+        Ok(options) => assert!(options[0].ends_with(":/etc/ssl/certs/ca-certificates.crt")),
+        Err(_) => assert!(!Path::new(HOST_CA_BUNDLE).is_file()),
+    }
+}
+
+#[test]
+fn test_resolve_ca_bundle() {
+    let dir = tempfile::tempdir().unwrap();
+    let bundle = dir.path().join("tls-ca-bundle.pem");
+    fs::write(&bundle, b"").unwrap();
+    let link = dir.path().join("ca-certificates.crt");
+    std::os::unix::fs::symlink("tls-ca-bundle.pem", &link).unwrap();
+    assert_eq!(
+        resolve_ca_bundle(&link).unwrap(),
+        fs::canonicalize(&bundle).unwrap()
+    );
+    let dangling = dir.path().join("dangling.crt");
+    std::os::unix::fs::symlink("missing.pem", &dangling).unwrap();
+    assert!(resolve_ca_bundle(&dangling)
+        .unwrap_err()
+        .to_string()
+        .contains("dangling symbolic link to missing.pem"));
+    assert!(resolve_ca_bundle(dir.path()).is_err());
+}
+
+#[test]
+fn test_bind_mounts() {
+    let mount = |x: &str| x.parse::<BindMount>().unwrap();
+    assert!(check_bind_mount(&mount("/srv/distfiles:/distfiles")).is_ok());
+    assert!(check_bind_mount(&mount("/srv:/tree")).is_err());
+    assert!(check_bind_mount(&mount("/srv:/tree/")).is_err());
+    assert!(check_bind_mount(&mount("/srv:/var/cache")).is_err());
+    assert!(check_bind_mount(&mount("/srv:relative")).is_err());
+    let merged = merge_bind_mounts(
+        &[mount("/a:/a"), mount("/b:/b")],
+        &[mount("/c:/b/"), mount("/d:/d")],
+    );
+    assert_eq!(
+        merged,
+        vec![mount("/a:/a"), mount("/c:/b/"), mount("/d:/d")]
+    );
+}
+
+#[test]
+fn test_nspawn_options() {
+    let check =
+        |x: &[&str]| check_nspawn_options(&x.iter().map(|x| x.to_string()).collect::<Vec<_>>());
+    assert!(check(&[
+        "--private-network",
+        "--resolv-conf=off",
+        "--bind=/opt:/mnt/opt"
+    ])
+    .is_ok());
+    assert!(check(&["-E", "FOO=-D", "-pfoo"]).is_ok());
+    let err = check(&["-D", "/somewhere"]).unwrap_err();
+    assert!(err.to_string().contains("`-D`"));
+    assert!(check(&["--register=no"]).is_err());
+    assert!(check(&["--machine", "foo"]).is_err());
+    assert!(check(&["-nq"]).is_err());
+    assert!(check(&["--bind", "/srv/debs:/debs"]).is_err());
+    assert!(check(&["--bind-ro=/var/cache"]).is_err());
+}
+
+#[test]
+fn test_builtin_mounts() {
+    let targets =
+        |mounts: Vec<(String, String)>| mounts.into_iter().map(|x| x.1).collect::<Vec<_>>();
+    let config = CielConfig::default();
+    let mut instance = InstanceConfig::default();
+    assert_eq!(
+        targets(builtin_mounts(Some(&config), &instance)),
+        vec![
+            "/debs/",
+            "/tree",
+            "/var/cache/acbs/tarballs",
+            "/var/cache/apt/archives"
+        ]
+    );
+    instance.local_repo = Some(false);
+    assert_eq!(
+        targets(builtin_mounts(Some(&config), &instance)),
+        vec![
+            "/tree",
+            "/var/cache/acbs/tarballs",
+            "/var/cache/apt/archives"
+        ]
+    );
+    let mut config = config;
+    config.local_repo = false;
+    instance.local_repo = None;
+    assert!(targets(builtin_mounts(Some(&config), &instance)).contains(&"/debs/".to_string()));
+}
@@ -7,17 +7,25 @@ use crate::{
     actions::get_branch_name,
     cli::GIT_TREE_URL,
     common::*,
-    config, error, info,
+    config::{self, OnboardingAnswers},
+    error, info,
     network::{download_git, pick_latest_rootfs},
     overlayfs::create_new_instance_fs,
     repo::{init_repo, refresh_repo},
     warn,
 };
 
-use super::{load_os, mount_fs};
+use super::{load_os, mount_fs, CancelToken};
 
-/// Show interactive onboarding guide, triggered by issuing `ciel new`
-pub fn onboarding(custom_tarball: Option<&String>, arch: Option<&str>) -> Result<()> {
+/// Show interactive onboarding guide, triggered by issuing `ciel new`.
+/// With `answers`, the questions are not asked, and `custom_tarball` and `arch` take precedence
+/// over the answers. The answers (given or not) are written to `save_answers`.
+pub fn onboarding(
+    custom_tarball: Option<&String>,
+    arch: Option<&str>,
+    answers: Option<OnboardingAnswers>,
+    save_answers: Option<&Path>,
+) -> Result<()> {
     ctrlc::set_handler(move || {
         let _ = Term::stderr().show_cursor();
         exit(1);
@@ -31,17 +39,44 @@ pub fn onboarding(custom_tarball: Option<&String>, arch: Option<&str>) -> Result
         info!("Please run `ciel farewell` to nuke it before running this command.");
         return Err(anyhow!("Unable to create a ciel workspace."));
     }
-    info!("Before continuing, I need to ask you a few questions:");
-    let real_arch = if let Some(arch) = arch {
-        arch
-    } else if custom_tarball.is_some() {
-        "custom"
-    } else {
-        ask_for_target_arch()?
+    let replay = answers.is_some();
+    let mut answers = match answers {
+        Some(answers) => {
+            info!("Using the saved answers to the questions.");
+            answers
+        }
+        None => {
+            info!("Before continuing, I need to ask you a few questions:");
+            OnboardingAnswers::new(config::CielConfig::default(), GIT_TREE_URL)
+        }
     };
-    let config = config::ask_for_config(None)?;
-    let mut init_instance: Option<String> = None;
-    if user_attended()
+    // the buildkit picked for the saved architecture does not apply to another one
+    if let Some(arch) = arch {
+        answers.arch = Some(arch.to_owned());
+        answers.rootfs = None;
+        answers.rootfs_sha256 = None;
+    }
+    if let Some(tarball) = custom_tarball {
+        answers.rootfs = Some(tarball.clone());
+        answers.rootfs_sha256 = None;
+    }
+    if answers.arch.is_none() && answers.rootfs.is_none() {
+        answers.arch = Some(ask_for_target_arch()?.to_owned());
+    }
+    let real_arch = answers.arch.clone().unwrap_or_else(|| "custom".to_owned());
+    if !replay {
+        answers.config = config::ask_for_config(None)?;
+    }
+    let config = answers.config.clone();
+    let mut init_instance = answers.instance.clone();
+    if replay {
+        if let Some(name) = &init_instance {
+            info!(
+                "`{}` will be created after initialization is finished.",
+                name
+            );
+        }
+    } else if user_attended()
         && Confirm::with_theme(&theme)
             .with_prompt("Do you want to add a new instance now?")
             .interact()?
@@ -58,39 +93,50 @@ pub fn onboarding(custom_tarball: Option<&String>, arch: Option<&str>) -> Result
         info!("Okay. You can always add a new instance later.");
     }
 
-    info!("Initializing workspace...");
-    ciel_init()?;
-    info!("Initializing container OS...");
-    let (rootfs_url, rootfs_sha256, use_tarball) = match custom_tarball {
+    answers.instance = init_instance.clone();
+
+    let (rootfs_url, rootfs_sha256, format) = match &answers.rootfs {
         Some(rootfs) => {
-            let use_tarball = !rootfs.ends_with(".squashfs");
-            info!(
-                "Using custom {} from {}",
-                if use_tarball { "tarball" } else { "squashfs" },
-                rootfs
-            );
-            (rootfs.clone(), None, use_tarball)
+            info!("Using custom rootfs from {}", rootfs);
+            (
+                rootfs.clone(),
+                answers.rootfs_sha256.clone(),
+                RootfsFormat::Auto,
+            )
         }
         None => {
             info!("Searching for latest AOSC OS buildkit release...");
-            auto_pick_rootfs(&theme, real_arch)?
+            auto_pick_rootfs(&theme, &real_arch)?
         }
     };
-    load_os(&rootfs_url, rootfs_sha256, use_tarball)?;
+    if let Some(path) = save_answers {
+        // the picked buildkit is recorded, so that the other workspaces get the same one
+        answers.rootfs = Some(rootfs_url.clone());
+        answers.rootfs_sha256 = rootfs_sha256.clone();
+        fs::write(path, answers.save()?)?;
+        info!("Answers saved to {}.", path.display());
+    }
+    info!("Initializing workspace...");
+    ciel_init()?;
+    info!("Initializing container OS...");
+    load_os(
+        &rootfs_url,
+        rootfs_sha256,
+        format,
+        false,
+        &CancelToken::new(),
+    )?;
     info!("Initializing ABBS tree...");
     if Path::new("TREE").is_dir() {
         warn!("TREE already exists, skipping this step...");
     } else {
         // if TREE is a file, then remove it
         fs::remove_file("TREE").ok();
-        download_git(GIT_TREE_URL, Path::new("TREE"))?;
+        download_git(&answers.tree, Path::new("TREE"))?;
     }
     config::apply_config(CIEL_DIST_DIR, &config)?;
     info!("Applying configurations...");
-    fs::write(
-        Path::new(CIEL_DATA_DIR).join("config.toml"),
-        config.save_config()?,
-    )?;
+    config::write_config(&config)?;
     info!("Configurations applied.");
     let cwd = std::env::current_dir()?;
     let mut output_dir_name = "OUTPUT".to_string();
@@ -123,7 +169,7 @@ pub fn onboarding(custom_tarball: Option<&String>, arch: Option<&str>) -> Result
 fn auto_pick_rootfs(
     theme: &dyn dialoguer::theme::Theme,
     arch: &str,
-) -> Result<(String, Option<String>, bool)> {
+) -> Result<(String, Option<String>, RootfsFormat)> {
     let root = pick_latest_rootfs(arch);
 
     if let Ok(rootfs) = root {
@@ -134,7 +180,7 @@ fn auto_pick_rootfs(
         Ok((
             format!("https://releases.aosc.io/{}", rootfs.path),
             Some(rootfs.sha256sum),
-            false,
+            RootfsFormat::Squashfs,
         ))
     } else {
         warn!(
@@ -144,8 +190,6 @@ fn auto_pick_rootfs(
             .with_prompt("Rootfs URL")
             .interact_text()?;
 
-        let use_tarball = !rootfs_url.ends_with(".squashfs");
-
-        Ok((rootfs_url, None, use_tarball))
+        Ok((rootfs_url, None, RootfsFormat::Auto))
     }
 }
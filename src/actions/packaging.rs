@@ -1,46 +1,283 @@
 use anyhow::{anyhow, Result};
 use console::style;
 use dialoguer::{theme::ColorfulTheme, Select};
-use nix::unistd::gethostname;
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::{BTreeMap, HashMap},
     fs::{self, File},
     io::{BufRead, BufReader, Write},
-    path::Path,
-    thread::{self, sleep},
-    time::{Duration, Instant},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use walkdir::WalkDir;
 
-use crate::{actions::OMA_UPDATE_SCRIPT, common::create_spinner, config, error, info, repo, warn};
+use crate::{
+    actions::OMA_UPDATE_SCRIPT, common::create_spinner, config, error, info, machine, repo, timing,
+    warn,
+};
 
 use super::{
-    container::{get_output_directory, mount_fs, rollback_container, run_in_container},
-    APT_UPDATE_SCRIPT,
+    cancel::{CancelToken, Cancelled},
+    container::{
+        build_count, get_output_directory, increase_build_count, instance_hostname, mount_fs,
+        rollback_container, run_in_container, run_in_container_capture, run_in_container_logged,
+        run_in_container_output, stop_container, terminate_on_cancel,
+    },
+    enforce_cache_limit,
+    failure::{classify_build_output, classify_update_output, BUILD_OUTPUT_TAIL_LINES},
+    is_lock_contention, log_tail, output_tail, APT_UPDATE_SCRIPT,
 };
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Saved in JSON, so that the fields added later can be left out of the older checkpoints
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct BuildCheckPoint {
     packages: Vec<String>,
     progress: usize,
     time_elapsed: usize,
     attempts: usize,
+    #[serde(default)]
+    topics: Vec<String>,
+    /// Date of the repository snapshot used by the build
+    #[serde(default)]
+    repo_snapshot: Option<String>,
+    /// Extra arguments passed to acbs-build
+    #[serde(default)]
+    acbs_args: Vec<String>,
+    /// Versions of the tools used by the previous attempt
+    #[serde(default)]
+    tooling: Option<Tooling>,
+    /// Whether the OS update before each package was skipped
+    #[serde(default)]
+    skip_update: bool,
+    /// Whether the build was running in an ephemeral instance
+    #[serde(default)]
+    ephemeral: bool,
+    /// Packages removed from the remaining list when resuming
+    #[serde(default)]
+    skipped: Vec<String>,
+    /// Niceness of the build commands
+    #[serde(default)]
+    nice: Option<i32>,
+    /// I/O scheduling class of the build commands
+    #[serde(default)]
+    io_class: Option<machine::IoClass>,
+}
+
+/// Checkpoints written by the older versions of ciel, in bincode
+#[derive(Deserialize, Serialize)]
+struct LegacyBuildCheckPoint {
+    packages: Vec<String>,
+    progress: usize,
+    time_elapsed: usize,
+    attempts: usize,
+}
+
+impl From<LegacyBuildCheckPoint> for BuildCheckPoint {
+    fn from(legacy: LegacyBuildCheckPoint) -> Self {
+        BuildCheckPoint {
+            packages: legacy.packages,
+            progress: legacy.progress,
+            time_elapsed: legacy.time_elapsed,
+            attempts: legacy.attempts,
+            ..Default::default()
+        }
+    }
+}
+
+/// Versions of the tools used by a build, "unknown" if they could not be found out
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Tooling {
+    ciel: String,
+    acbs: String,
+    autobuild: String,
+}
+
+impl std::fmt::Display for Tooling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ciel {}, acbs {}, autobuild {}",
+            self.ciel, self.acbs, self.autobuild
+        )
+    }
+}
+
+/// The first non-empty line of the `--version` output, if the command succeeded
+fn parse_tool_version(status: i32, output: &str) -> Option<String> {
+    if status != 0 {
+        return None;
+    }
+    output
+        .lines()
+        .map(str::trim)
+        .find(|x| !x.is_empty())
+        .map(str::to_owned)
+}
+
+impl Tooling {
+    /// Find out the versions of the tools in the instance, failures are not fatal
+    fn probe(instance: &str) -> Tooling {
+        let version = |tool: &str| {
+            run_in_container_output(instance, &[tool, "--version"])
+                .ok()
+                .and_then(|(status, output)| parse_tool_version(status, &output))
+                .unwrap_or_else(|| "unknown".to_owned())
+        };
+
+        Tooling {
+            ciel: env!("CARGO_PKG_VERSION").to_owned(),
+            acbs: version("acbs-build"),
+            autobuild: version("autobuild"),
+        }
+    }
+}
+
+/// Check if the package list entry refers to the specified package
+fn package_matches(entry: &str, package: &str) -> bool {
+    entry == package || entry.split_once('/').map(|x| x.1) == Some(package)
 }
 
-#[derive(Debug, Copy, Clone)]
+impl BuildCheckPoint {
+    pub fn is_ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
+    /// Find the position of the package in the remaining list,
+    /// returns an error if the package was already built or is not in the list
+    fn find_remaining(&self, package: &str) -> Result<usize> {
+        if let Some(pos) = self.packages[self.progress..]
+            .iter()
+            .position(|x| package_matches(x, package))
+        {
+            return Ok(self.progress + pos);
+        }
+        if self.packages[..self.progress]
+            .iter()
+            .any(|x| package_matches(x, package))
+        {
+            return Err(anyhow!("Package `{}` has already been built", package));
+        }
+
+        Err(anyhow!("Package `{}` is not in the checkpoint", package))
+    }
+
+    /// Remove the packages from the remaining list
+    pub fn skip_packages<S: AsRef<str>>(&mut self, packages: &[S]) -> Result<()> {
+        for package in packages {
+            let pos = self.find_remaining(package.as_ref())?;
+            self.skipped.push(self.packages.remove(pos));
+        }
+
+        Ok(())
+    }
+
+    /// Only keep the specified packages in the remaining list (in their original order)
+    pub fn retain_packages<S: AsRef<str>>(&mut self, packages: &[S]) -> Result<()> {
+        let mut keep = Vec::new();
+        for package in packages {
+            keep.push(self.find_remaining(package.as_ref())?);
+        }
+        let remaining = self.packages.split_off(self.progress);
+        for (i, package) in remaining.into_iter().enumerate() {
+            if keep.contains(&(self.progress + i)) {
+                self.packages.push(package);
+            } else {
+                self.skipped.push(package);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct BuildSettings {
     pub offline: bool,
     pub stage2: bool,
+    /// Topic repositories to enable during the build
+    pub topics: Vec<String>,
+    /// Build against the repository snapshot of the given date, overriding the workspace setting
+    pub repo_snapshot: Option<String>,
+    /// Extra arguments passed to acbs-build before the package list
+    pub acbs_args: Vec<String>,
+    /// Whether the build runs in an ephemeral instance
+    pub ephemeral: bool,
+    /// Record the installed package versions after each successful build
+    pub record_environment: bool,
+    /// Group the debs, the build log and the environment of each package in `by-package`
+    pub collect_artifacts: bool,
+    /// Skip the OS update before each package, the local repository is still refreshed
+    pub skip_update: bool,
+    /// Niceness of the build commands, e.g. 10 to give way to the interactive work
+    pub nice: Option<i32>,
+    /// I/O scheduling class of the build commands
+    pub io_class: Option<machine::IoClass>,
+    /// Called before building each package, for the front-end to show the progress
+    pub on_progress: Option<fn(&BuildProgress)>,
+    /// Called when the OS update before a package build failed and is about to be retried
+    pub on_update_retry: Option<fn(&UpdateRetry)>,
+    /// Stops the build at the next package or retry, leaving a checkpoint behind
+    pub cancel: CancelToken,
+}
+
+impl BuildSettings {
+    /// How the build commands are run in the container
+    fn exec_options(&self) -> machine::ExecOptions {
+        machine::ExecOptions {
+            offline: self.offline,
+            stage2: self.stage2,
+            nice: self.nice,
+            io_class: self.io_class,
+        }
+    }
+}
+
+/// A failed OS update that is going to be retried, reported through `BuildSettings::on_update_retry`
+#[derive(Debug)]
+pub struct UpdateRetry<'a> {
+    pub instance: &'a str,
+    /// The failed attempt, starting from 1
+    pub attempt: u32,
+    pub attempts: u32,
+    /// Time to wait before the next attempt
+    pub delay: Duration,
+    /// Tail of the output of the failed attempt
+    pub error: &'a str,
+    /// Whether the next attempt uses apt instead of oma
+    pub use_apt: bool,
+}
+
+/// Progress of a build, reported through `BuildSettings::on_progress`
+#[derive(Debug)]
+pub struct BuildProgress<'a> {
+    /// Index of the current package, starting from 1
+    pub index: usize,
+    pub total: usize,
+    pub package: &'a str,
+    pub instance: &'a str,
+    /// Hostname of the container
+    pub hostname: &'a str,
 }
 
 pub fn load_build_checkpoint<P: AsRef<Path>>(path: P) -> Result<BuildCheckPoint> {
-    let f = File::open(path)?;
+    let data = fs::read(path)?;
 
-    Ok(bincode::deserialize_from(f)?)
+    parse_build_checkpoint(&data)
+}
+
+fn parse_build_checkpoint(data: &[u8]) -> Result<BuildCheckPoint> {
+    match serde_json::from_slice(data) {
+        Ok(checkpoint) => Ok(checkpoint),
+        Err(e) => bincode::deserialize::<LegacyBuildCheckPoint>(data)
+            .map(BuildCheckPoint::from)
+            .map_err(|_| anyhow!("Invalid checkpoint: {}", e)),
+    }
 }
 
 fn dump_build_checkpoint(checkpoint: &BuildCheckPoint) -> Result<()> {
-    let save_state = bincode::serialize(checkpoint)?;
+    let save_state = serde_json::to_vec(checkpoint)?;
     let last_package = checkpoint
         .packages
         .get(checkpoint.progress)
@@ -102,6 +339,205 @@ fn read_package_list<P: AsRef<Path>>(filename: P, depth: usize) -> Result<Vec<St
     Ok(results)
 }
 
+/// Find the directory of the package in the tree by scanning the category directories,
+/// returns the path of the directory inside the container
+pub fn find_package_dir<P: AsRef<Path>>(tree: P, name: &str) -> Result<PathBuf> {
+    let mut categories = fs::read_dir(tree.as_ref())?
+        .filter_map(|x| x.ok())
+        .filter(|x| x.file_type().is_ok_and(|t| t.is_dir()))
+        .map(|x| x.file_name())
+        .filter(|x| !x.to_string_lossy().starts_with('.') && x != "groups")
+        .collect::<Vec<_>>();
+    categories.sort();
+    for category in categories {
+        if tree.as_ref().join(&category).join(name).is_dir() {
+            return Ok(Path::new("/tree").join(category).join(name));
+        }
+    }
+
+    Err(anyhow!("Package `{}` is not found in the tree", name))
+}
+
+/// Read the version of the package from its spec file in the tree
+fn read_package_version<P: AsRef<Path>>(tree: P, name: &str) -> Option<String> {
+    let dir = find_package_dir(tree.as_ref(), name).ok()?;
+    let spec = fs::read_to_string(
+        tree.as_ref()
+            .join(dir.strip_prefix("/tree").ok()?)
+            .join("spec"),
+    )
+    .ok()?;
+    let get_var = |var: &str| {
+        spec.lines()
+            .find_map(|line| line.strip_prefix(var)?.strip_prefix('='))
+            .map(|x| x.trim().trim_matches('"').to_owned())
+    };
+    let version = get_var("VER")?;
+
+    Some(match get_var("REL") {
+        Some(rel) => format!("{}-{}", version, rel),
+        None => version,
+    })
+}
+
+/// Record the versions of the packages installed in the container after building `package`
+fn record_build_environment(
+    instance: &str,
+    package: &str,
+    manifests_dir: &Path,
+) -> Result<PathBuf> {
+    let (status, output) = run_in_container_output(
+        instance,
+        &[
+            "dpkg-query",
+            "-W",
+            "-f",
+            "${Package}\t${Version}\t${Architecture}\n",
+        ],
+    )?;
+    if status != 0 {
+        return Err(anyhow!("dpkg-query exited with status {}", status));
+    }
+    let name = package.rsplit('/').next().unwrap_or(package);
+    let version = read_package_version("TREE", name).unwrap_or_else(|| "unknown".to_owned());
+    fs::create_dir_all(manifests_dir)?;
+    let path = manifests_dir.join(format!("{}-{}.env.txt", name, version));
+    fs::write(&path, output)?;
+
+    Ok(path)
+}
+
+/// The artifacts of a package, recorded in `by-package/report.json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ArtifactRecord {
+    /// Directory containing the artifacts, relative to `by-package`
+    directory: String,
+    /// Debs built from the package, relative to the output directory
+    debs: Vec<PathBuf>,
+    /// Date of the repository snapshot the package was built against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    repo_snapshot: Option<String>,
+    /// Versions of the tools used by the build, absent in the records of the older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tooling: Option<Tooling>,
+}
+
+/// Debs in the output directory and their modification times
+fn snapshot_debs(debs_dir: &Path) -> HashMap<PathBuf, Option<SystemTime>> {
+    repo::collect_all_packages(debs_dir)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|entry| {
+            let modified = entry.metadata().ok().and_then(|x| x.modified().ok());
+            (entry.into_path(), modified)
+        })
+        .collect()
+}
+
+/// Find the debs that are added or updated since the previous snapshot
+fn new_debs(
+    before: &HashMap<PathBuf, Option<SystemTime>>,
+    after: &HashMap<PathBuf, Option<SystemTime>>,
+) -> Vec<PathBuf> {
+    let mut debs = after
+        .iter()
+        .filter(|(path, modified)| before.get(*path) != Some(modified))
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<_>>();
+    debs.sort_unstable();
+
+    debs
+}
+
+/// Record the builder, the instance, the tree commit, the repository snapshot and the build time
+/// of the new debs, and refresh the local repository so that they appear in the index
+fn write_build_metadata(
+    root: &Path,
+    instance: &str,
+    debs: &[PathBuf],
+    repo_snapshot: Option<&str>,
+) -> Result<()> {
+    let builder = nix::unistd::gethostname()?
+        .into_string()
+        .map_err(|_| anyhow!("Hostname is not valid unicode"))?;
+    let commit = git2::Repository::open("TREE")
+        .and_then(|repo| Ok(repo.head()?.peel_to_commit()?.id().to_string()));
+    let date = OffsetDateTime::now_utc().format(&Rfc3339)?;
+    let mut fields = vec![("Built-By", builder.as_str()), ("Instance", instance)];
+    if let Ok(commit) = &commit {
+        fields.push(("Tree-Commit", commit));
+    }
+    if let Some(repo_snapshot) = repo_snapshot {
+        fields.push(("Repo-Snapshot", repo_snapshot));
+    }
+    fields.push(("Build-Date", &date));
+    for deb in debs {
+        repo::write_metadata(deb, &fields)?;
+    }
+    repo::refresh_repo(root)?;
+
+    Ok(())
+}
+
+/// Hard link the debs, the build environment and the build log into
+/// `by-package/<name>-<version>`, and record them in the report
+fn collect_artifacts(
+    root: &Path,
+    package: &str,
+    debs: &[PathBuf],
+    log: &Path,
+    environment: Option<&Path>,
+    repo_snapshot: Option<&str>,
+    tooling: &Tooling,
+) -> Result<PathBuf> {
+    let name = package.rsplit('/').next().unwrap_or(package);
+    let version = read_package_version("TREE", name).unwrap_or_else(|| "unknown".to_owned());
+    let directory = format!("{}-{}", name, version);
+    let by_package = root.join("by-package");
+    let dest = by_package.join(&directory);
+    fs::create_dir_all(&dest)?;
+    let mut files = debs.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+    files.extend(environment);
+    for file in files {
+        let link = dest.join(
+            file.file_name()
+                .ok_or_else(|| anyhow!("Invalid artifact path: {}", file.display()))?,
+        );
+        if link.exists() {
+            fs::remove_file(&link)?;
+        }
+        fs::hard_link(file, &link)?;
+    }
+    let build_log = dest.join("build.log");
+    if build_log.exists() {
+        fs::remove_file(&build_log)?;
+    }
+    fs::hard_link(log, &build_log)?;
+
+    let report_path = by_package.join("report.json");
+    let mut report: BTreeMap<String, ArtifactRecord> = match File::open(&report_path) {
+        Ok(f) => serde_json::from_reader(f)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let debs = debs
+        .iter()
+        .map(|x| x.strip_prefix(root).unwrap_or(x).to_path_buf())
+        .collect();
+    report.insert(
+        package.to_owned(),
+        ArtifactRecord {
+            directory,
+            debs,
+            repo_snapshot: repo_snapshot.map(str::to_owned),
+            tooling: Some(tooling.clone()),
+        },
+    );
+    serde_json::to_writer_pretty(File::create(report_path)?, &report)?;
+
+    Ok(dest)
+}
+
 /// Expand the packages list to an array of packages
 fn expand_package_list<S: AsRef<str>, I: IntoIterator<Item = S>>(packages: I) -> Vec<String> {
     let mut expanded = Vec::new();
@@ -151,61 +587,208 @@ fn package_build_inner<P: AsRef<Path>>(
     packages: &[String],
     instance: &str,
     root: P,
+    settings: &BuildSettings,
+    tooling: &Tooling,
 ) -> Result<(i32, usize)> {
+    let conf = config::read_config()?;
     let total = packages.len();
-    let hostname = gethostname().map_or_else(
-        |_| "unknown".to_string(),
-        |s| s.into_string().unwrap_or_else(|_| "unknown".to_string()),
-    );
+    let hostname = instance_hostname(instance)?;
+    let exec = settings.exec_options();
+    let rollback_after_package = config::InstanceConfig::load(instance)?
+        .rollback_policy
+        .after_package();
+    // the monitor needs the repository directory to exist
+    repo::ensure_repo(root.as_ref())?;
     let (tx, rx) = std::sync::mpsc::channel();
     let root_path = root.as_ref().to_path_buf();
     let refresh_monitor = thread::spawn(move || repo::start_monitor(&root_path, rx));
     let guard = RepoMonitorGuard::new(refresh_monitor, tx);
+    let _watch = terminate_on_cancel(instance, &settings.cancel)?;
     for (index, package) in packages.iter().enumerate() {
-        // set terminal title, \r is for hiding the message if the terminal does not support the sequence
-        eprint!(
-            "\x1b]0;ciel: [{}/{}] {} ({}@{})\x07\r",
+        // the status does not matter, `package_build` reports the cancellation
+        if settings.cancel.is_cancelled() {
+            return Ok((-1, index));
+        }
+        if let Some(on_progress) = settings.on_progress {
+            on_progress(&BuildProgress {
+                index: index + 1,
+                total,
+                package,
+                instance,
+                hostname: &hostname,
+            });
+        }
+        let cmd = acbs_command(&settings.acbs_args, &[package]);
+        info!(
+            "[{}/{}] Building {}: {}",
             index + 1,
             total,
             package,
-            instance,
-            hostname
+            cmd.join(" ")
         );
-        // hopefully the sequence gets flushed together with the `info!` below
-        info!("[{}/{}] Building {}...", index + 1, total, package);
         mount_fs(instance)?;
         info!("Refreshing local repository...");
         repo::init_repo(root.as_ref(), Path::new(instance))?;
+        config::apply_topics(instance, &conf, &settings.topics)?;
+        config::apply_repo_snapshot(instance, &conf, settings.repo_snapshot.as_deref())?;
         let mut status = -1;
         let mut oma = true;
-        for i in 1..=5 {
-            status = if oma {
-                run_in_container(instance, &["/bin/bash", "-ec", OMA_UPDATE_SCRIPT]).unwrap_or(-1)
+        let mut attempts = conf.update_attempts.max(1);
+        if settings.skip_update {
+            // only refresh the package lists, so that the new local packages are visible
+            info!("Skipping the OS update, refreshing the package lists only...");
+            let (index_status, output) =
+                run_in_container_capture(instance, &["apt-get", "update"], &exec)?;
+            if index_status != 0 {
+                warn!(
+                    "Failed to refresh the package lists: {}",
+                    output_tail(&output, 5)
+                );
+            }
+            (status, attempts) = (0, 0);
+        }
+        // the outputs of the failed attempts, to tell the cause of the failure
+        let mut outputs = Vec::new();
+        for attempt in 1..=attempts {
+            // each attempt is timed on its own, leaving out the wait before the next one
+            let update_span = timing::span("update-os", instance);
+            let output;
+            (status, output) = if oma {
+                run_in_container_capture(instance, &["/bin/bash", "-ec", OMA_UPDATE_SCRIPT], &exec)
             } else {
-                run_in_container(instance, &["/bin/bash", "-ec", APT_UPDATE_SCRIPT]).unwrap_or(-1)
-            };
+                run_in_container_capture(instance, &["/bin/bash", "-ec", APT_UPDATE_SCRIPT], &exec)
+            }
+            .unwrap_or_else(|e| (-1, format!("{:#}", e)));
+            drop(update_span);
+            if settings.cancel.is_cancelled() {
+                return Ok((-1, index));
+            }
             if status == 0 {
                 break;
-            } else {
-                let interval = 3u64.pow(i);
-                warn!(
-                    "Failed to update the OS, will retry in {} seconds ...",
-                    interval
-                );
+            }
+            outputs.push(output_tail(&output, BUILD_OUTPUT_TAIL_LINES));
+            if attempt == attempts {
+                break;
+            }
+            let error = output_tail(&output, 5);
+            let delay = conf.update_retry_interval(attempt);
+            warn!(
+                "Failed to update the OS (attempt {}/{}), will retry in {} seconds: {}",
+                attempt,
+                attempts,
+                delay.as_secs(),
+                error
+            );
+            // lock contention is transient, oma may well succeed next time
+            if oma && conf.update_fallback_apt && !is_lock_contention(&output) {
+                warn!("oma failed, falling back to apt");
                 oma = false;
-                sleep(Duration::from_secs(interval));
+            }
+            if let Some(on_update_retry) = settings.on_update_retry {
+                on_update_retry(&UpdateRetry {
+                    instance,
+                    attempt,
+                    attempts,
+                    delay,
+                    error: &error,
+                    use_apt: !oma,
+                });
+            }
+            if settings.cancel.sleep(delay).is_err() {
+                return Ok((-1, index));
             }
         }
         if status != 0 {
-            error!("Failed to update the OS before building packages");
+            let last = outputs
+                .last()
+                .map(|x| output_tail(x, 10))
+                .unwrap_or_default();
+            error!("Failed to update the OS before building packages: {}", last);
+            if let Some(failure) = classify_update_output(outputs.iter().map(|x| x.as_str())) {
+                error!("Probable cause ({}): {}", failure.kind, failure.excerpt);
+                info!("Hint: {}", failure.kind.hint());
+            }
             return Ok((status, index));
         }
-        let status = run_in_container(instance, &["/bin/acbs-build", "--", package])?;
+        let debs_dir = root.as_ref().join("debs");
+        let debs_before = snapshot_debs(&debs_dir);
+        let acbs_span = timing::span("acbs", package.as_str());
+        // written as the build goes, removed after collecting the artifacts
+        let log = tempfile::Builder::new()
+            .prefix(".build-")
+            .suffix(".log")
+            .tempfile_in(root.as_ref())?
+            .into_temp_path();
+        let status = run_in_container_logged(instance, &cmd, &log, &exec)?;
+        drop(acbs_span);
+        if settings.cancel.is_cancelled() {
+            return Ok((-1, index));
+        }
         if status != 0 {
             error!("Build failed with status: {}", status);
+            match log_tail(&log, BUILD_OUTPUT_TAIL_LINES) {
+                Ok(tail) => {
+                    if let Some(failure) = classify_build_output(&tail) {
+                        error!("Probable cause ({}): {}", failure.kind, failure.excerpt);
+                        info!("Hint: {}", failure.kind.hint());
+                    }
+                }
+                Err(e) => {
+                    warn!("Unable to read the build log: {}", e);
+                }
+            }
             return Ok((status, index));
         }
-        rollback_container(instance)?;
+        let mut environment = None;
+        if settings.record_environment || settings.collect_artifacts {
+            let manifests_dir = root.as_ref().join("../manifests");
+            match record_build_environment(instance, package, &manifests_dir) {
+                Ok(path) => {
+                    info!("Build environment recorded to {}", path.display());
+                    environment = Some(path);
+                }
+                Err(e) => {
+                    warn!("Unable to record the build environment: {:?}", e);
+                }
+            }
+        }
+        let debs = new_debs(&debs_before, &snapshot_debs(&debs_dir));
+        if !debs.is_empty() {
+            match write_build_metadata(
+                root.as_ref(),
+                instance,
+                &debs,
+                settings.repo_snapshot.as_deref(),
+            ) {
+                Ok(()) => {
+                    info!("Build metadata recorded for {} deb(s)", debs.len());
+                }
+                Err(e) => {
+                    warn!("Unable to record the build metadata: {:?}", e);
+                }
+            }
+        }
+        if settings.collect_artifacts {
+            match collect_artifacts(
+                root.as_ref(),
+                package,
+                &debs,
+                &log,
+                environment.as_deref(),
+                settings.repo_snapshot.as_deref(),
+                tooling,
+            ) {
+                Ok(path) => {
+                    info!("Artifacts collected to {}", path.display());
+                }
+                Err(e) => {
+                    warn!("Unable to collect the artifacts: {:?}", e);
+                }
+            }
+        }
+        if rollback_after_package {
+            rollback_container(instance)?;
+        }
     }
     drop(guard);
 
@@ -248,6 +831,15 @@ pub fn packages_stage_select<S: AsRef<str>, K: Clone + ExactSizeIterator<Item =
             progress: selection,
             time_elapsed: 0,
             attempts: 1,
+            topics: settings.topics.clone(),
+            repo_snapshot: settings.repo_snapshot.clone(),
+            acbs_args: settings.acbs_args.clone(),
+            tooling: None,
+            skip_update: settings.skip_update,
+            ephemeral: settings.ephemeral,
+            skipped: Vec::new(),
+            nice: settings.nice,
+            io_class: settings.io_class,
         }),
         settings,
     )
@@ -269,56 +861,160 @@ pub fn package_fetch<S: AsRef<str>>(instance: &str, packages: &[S]) -> Result<i3
 
     let mut cmd = vec!["/bin/acbs-build", "-g", "--"];
     cmd.extend(packages.iter().map(|p| p.as_ref()));
-    let status = run_in_container(instance, &cmd)?;
+    let status = run_in_container(instance, &cmd, &Default::default())?;
 
     Ok(status)
 }
 
+/// The acbs-build command line, the extra arguments go before the `--` separator
+fn acbs_command(acbs_args: &[String], packages: &[&str]) -> Vec<String> {
+    let mut cmd = vec!["/bin/acbs-build".to_string()];
+    cmd.extend(acbs_args.iter().cloned());
+    cmd.push("--".to_string());
+    cmd.extend(packages.iter().map(|x| x.to_string()));
+
+    cmd
+}
+
+/// Enforce the download cache limit after builds, failures are not fatal
+fn maintain_cache() {
+    if let Err(e) = enforce_cache_limit() {
+        warn!("Unable to trim the download cache: {:?}", e);
+    }
+}
+
 /// Build packages in the container
 pub fn package_build<S: AsRef<str>, K: Clone + ExactSizeIterator<Item = S>>(
     instance: &str,
     packages: K,
     state: Option<BuildCheckPoint>,
-    settings: BuildSettings,
+    mut settings: BuildSettings,
 ) -> Result<i32> {
     let conf = config::read_config();
     if conf.is_err() {
         return Err(anyhow!("Please configure this workspace first!"));
     }
     let conf = conf.unwrap();
+    settings.cancel.check()?;
     let mut attempts = 1usize;
+    let mut skipped = Vec::new();
+    let mut previous_tooling = None;
 
     let packages = if let Some(p) = state {
         attempts = p.attempts + 1;
+        if !p.skipped.is_empty() {
+            info!("Skipped packages: {}", p.skipped.join(", "));
+        }
+        skipped = p.skipped;
         info!(
             "Successfully restored from a checkpoint. Attempt #{} started.",
             attempts
         );
+        if settings.topics.is_empty() {
+            settings.topics = p.topics;
+        }
+        if settings.repo_snapshot.is_none() {
+            settings.repo_snapshot = p.repo_snapshot;
+        }
+        if settings.acbs_args.is_empty() {
+            settings.acbs_args = p.acbs_args;
+        }
+        settings.skip_update |= p.skip_update;
+        settings.nice = settings.nice.or(p.nice);
+        settings.io_class = settings.io_class.or(p.io_class);
+        previous_tooling = p.tooling;
         p.packages[p.progress..].to_owned()
     } else {
         expand_package_list(packages)
     };
 
-    if settings.offline || std::env::var("CIEL_OFFLINE").is_ok() {
+    if settings.offline {
         info!("Preparing offline mode. Fetching source packages first ...");
         package_fetch(instance, &packages)?;
-        std::env::set_var("CIEL_OFFLINE", "ON");
         // FIXME: does not work with current version of systemd
         info!("Running in offline mode. Network access disabled.");
     }
 
     if settings.stage2 {
-        std::env::set_var("CIEL_STAGE2", "ON");
         info!("Running in stage 2 mode. ACBS and autobuild3 may behave differently.");
     }
 
+    if settings.nice.is_some() || settings.io_class.is_some() {
+        info!(
+            "Running the builds with niceness {} and I/O class {}.",
+            settings.nice.unwrap_or(0),
+            settings.io_class.map_or("default", |x| x.name())
+        );
+    }
+
+    if !settings.topics.is_empty() {
+        info!("Enabled topics: {}", settings.topics.join(", "));
+    }
+
+    if settings.skip_update {
+        warn!("The OS will NOT be updated before building each package (--no-update-os).");
+        warn!(
+            "The dependencies in the instance may be stale, do not upload the resulting packages."
+        );
+    }
+
+    if settings.repo_snapshot.is_none() {
+        settings.repo_snapshot = conf.repo_snapshot.clone();
+    }
+    if let Some(repo_snapshot) = &settings.repo_snapshot {
+        config::check_repo_snapshot(repo_snapshot)?;
+        info!("Using the repository snapshot of {}", repo_snapshot);
+    }
+
     mount_fs(instance)?;
-    rollback_container(instance)?;
+    if machine::instance_mount_options(instance)?.is_some_and(|x| x.is_volatile()) {
+        warn!(
+            "{}: the filesystem is mounted in volatile mode, the changes may be lost on a crash or power loss.",
+            instance
+        );
+    }
+    let inst_config = config::InstanceConfig::load(instance)?;
+    let policy = inst_config.rollback_policy;
+    if policy.before_build(build_count(instance)) {
+        rollback_container(instance)?;
+    } else {
+        info!(
+            "{}: keeping the changes from previous builds (rollback policy: {})",
+            instance, policy
+        );
+    }
+    increase_build_count(instance)?;
+    let tooling = Tooling::probe(instance);
+    info!("Tooling: {}", tooling);
+    if let Some(previous) = previous_tooling.filter(|x| *x != tooling) {
+        warn!(
+            "The tools have changed since the previous attempt (was: {}), the packages may not be built consistently.",
+            previous
+        );
+    }
 
-    if !conf.local_repo {
-        let mut cmd = vec!["/bin/acbs-build".to_string(), "--".to_string()];
-        cmd.extend(packages);
-        let status = run_in_container(instance, &cmd)?;
+    // the local repository is neither refreshed nor used in the instance
+    if !inst_config.use_local_repo(&conf) {
+        if inst_config.local_repo == Some(false) {
+            warn!(
+                "{}: the local repository is disabled for this instance, the output directory is not mounted: the built packages are discarded when the instance is rolled back!",
+                instance
+            );
+        }
+        mount_fs(instance)?;
+        config::apply_topics(instance, &conf, &settings.topics)?;
+        config::apply_repo_snapshot(instance, &conf, settings.repo_snapshot.as_deref())?;
+        let packages = packages.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+        let cmd = acbs_command(&settings.acbs_args, &packages);
+        info!("Building {} packages: {}", packages.len(), cmd.join(" "));
+        let watch = terminate_on_cancel(instance, &settings.cancel)?;
+        let status = run_in_container(instance, &cmd, &settings.exec_options())?;
+        drop(watch);
+        maintain_cache();
+        if status != 0 && settings.cancel.is_cancelled() {
+            stop_container(instance)?;
+            return Err(Cancelled.into());
+        }
         return Ok(status);
     }
 
@@ -326,17 +1022,33 @@ pub fn package_build<S: AsRef<str>, K: Clone + ExactSizeIterator<Item = S>>(
     let root = std::env::current_dir()?.join(output_dir);
     let total = packages.len();
     let start = Instant::now();
-    let (exit_status, progress) = package_build_inner(&packages, instance, root)?;
+    let (exit_status, progress) =
+        package_build_inner(&packages, instance, root, &settings, &tooling)?;
+    maintain_cache();
     if exit_status != 0 {
+        let cancelled = settings.cancel.is_cancelled();
         let checkpoint = BuildCheckPoint {
             packages,
             progress,
             attempts,
             time_elapsed: 0,
+            topics: settings.topics,
+            repo_snapshot: settings.repo_snapshot,
+            acbs_args: settings.acbs_args,
+            tooling: Some(tooling),
+            skip_update: settings.skip_update,
+            ephemeral: settings.ephemeral,
+            skipped,
+            nice: settings.nice,
+            io_class: settings.io_class,
         };
         if std::env::var("CIEL_NO_CHECKPOINT").is_err() {
             dump_build_checkpoint(&checkpoint)?;
         }
+        if cancelled {
+            stop_container(instance)?;
+            return Err(Cancelled.into());
+        }
         return Ok(exit_status);
     }
     let duration = start.elapsed().as_secs();
@@ -350,6 +1062,62 @@ pub fn package_build<S: AsRef<str>, K: Clone + ExactSizeIterator<Item = S>>(
     Ok(0)
 }
 
+/// Outcome of building one shard of the packages, see `package_build_sharded`
+#[derive(Debug)]
+pub struct ShardReport {
+    pub instance: String,
+    pub packages: Vec<String>,
+    /// Exit status of the build, a failed shard leaves a checkpoint for its instance
+    pub status: Result<i32>,
+}
+
+/// Split the packages into at most `count` shards in a round-robin fashion,
+/// so that each shard keeps the relative order of the list
+fn shard_packages(packages: Vec<String>, count: usize) -> Vec<Vec<String>> {
+    let mut shards = vec![Vec::new(); count.min(packages.len())];
+    let len = shards.len();
+    for (i, package) in packages.into_iter().enumerate() {
+        shards[i % len].push(package);
+    }
+
+    shards
+}
+
+/// Build the packages in several instances at the same time (experimental).
+/// The packages must not depend on each other: each instance builds its own shard
+/// with its own checkpoint, and a failed shard does not stop the others.
+pub fn package_build_sharded<S: AsRef<str>, I: IntoIterator<Item = S>>(
+    instances: &[String],
+    packages: I,
+    settings: BuildSettings,
+) -> Vec<ShardReport> {
+    let shards = shard_packages(expand_package_list(packages), instances.len());
+    thread::scope(|s| {
+        let handles = instances
+            .iter()
+            .zip(shards)
+            .map(|(instance, shard)| {
+                let settings = settings.clone();
+                info!("{}: building {} packages", instance, shard.len());
+                let packages = shard.clone();
+                let handle =
+                    s.spawn(move || package_build(instance, packages.iter(), None, settings));
+                (instance, shard, handle)
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|(instance, packages, handle)| ShardReport {
+                instance: instance.to_owned(),
+                status: handle
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow!("the build thread panicked"))),
+                packages,
+            })
+            .collect()
+    })
+}
+
 /// Clean up output directories
 pub fn cleanup_outputs() -> Result<()> {
     let spinner = create_spinner("Removing output directories ...", 200);
@@ -376,3 +1144,181 @@ fn test_time_format() {
     let test_dur = 3661;
     assert_eq!(format_duration(test_dur), "01:01:01");
 }
+
+#[test]
+fn test_find_package_dir() {
+    let tree = tempfile::tempdir().unwrap();
+    fs::create_dir_all(tree.path().join("app-admin/foo/autobuild")).unwrap();
+    fs::create_dir_all(tree.path().join("groups/foo")).unwrap();
+    fs::create_dir_all(tree.path().join(".git/foo")).unwrap();
+    assert_eq!(
+        find_package_dir(tree.path(), "foo").unwrap(),
+        Path::new("/tree/app-admin/foo")
+    );
+    assert!(find_package_dir(tree.path(), "bar").is_err());
+}
+
+#[test]
+fn test_read_package_version() {
+    let tree = tempfile::tempdir().unwrap();
+    let dir = tree.path().join("app-admin/foo");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+        dir.join("spec"),
+        "VER=1.2.3\nREL=2\nSRCS=\"tbl::https://example.com\"\n",
+    )
+    .unwrap();
+    assert_eq!(
+        read_package_version(tree.path(), "foo").as_deref(),
+        Some("1.2.3-2")
+    );
+    fs::write(dir.join("spec"), "VER=\"1.2.3\"\n").unwrap();
+    assert_eq!(
+        read_package_version(tree.path(), "foo").as_deref(),
+        Some("1.2.3")
+    );
+    assert_eq!(read_package_version(tree.path(), "bar"), None);
+}
+
+#[test]
+fn test_checkpoint_modification() {
+    let mut checkpoint = BuildCheckPoint {
+        packages: ["a", "extra-x/b", "c", "d", "e"].map(String::from).to_vec(),
+        progress: 1,
+        time_elapsed: 0,
+        attempts: 1,
+        topics: Vec::new(),
+        repo_snapshot: None,
+        acbs_args: Vec::new(),
+        tooling: None,
+        skip_update: false,
+        ephemeral: false,
+        skipped: Vec::new(),
+        nice: Some(10),
+        io_class: Some(machine::IoClass::Idle),
+    };
+    assert!(checkpoint
+        .skip_packages(&["a"])
+        .unwrap_err()
+        .to_string()
+        .contains("already been built"));
+    assert!(checkpoint.skip_packages(&["f"]).is_err());
+    checkpoint.skip_packages(&["b"]).unwrap();
+    assert_eq!(checkpoint.packages, vec!["a", "c", "d", "e"]);
+    checkpoint.retain_packages(&["e", "c"]).unwrap();
+    assert_eq!(checkpoint.packages, vec!["a", "c", "e"]);
+    assert_eq!(checkpoint.skipped, vec!["extra-x/b", "d"]);
+    assert_eq!(checkpoint.progress, 1);
+    let saved = parse_build_checkpoint(&serde_json::to_vec(&checkpoint).unwrap()).unwrap();
+    assert_eq!(saved.packages, checkpoint.packages);
+    assert_eq!(saved.skipped, checkpoint.skipped);
+    assert_eq!(saved.nice, Some(10));
+    assert_eq!(saved.io_class, Some(machine::IoClass::Idle));
+}
+
+#[test]
+fn test_legacy_checkpoint() {
+    let legacy = bincode::serialize(&LegacyBuildCheckPoint {
+        packages: vec!["a".to_owned(), "b".to_owned()],
+        progress: 1,
+        time_elapsed: 42,
+        attempts: 2,
+    })
+    .unwrap();
+    let checkpoint = parse_build_checkpoint(&legacy).unwrap();
+    assert_eq!(checkpoint.packages, vec!["a", "b"]);
+    assert_eq!(checkpoint.progress, 1);
+    assert_eq!(checkpoint.time_elapsed, 42);
+    assert_eq!(checkpoint.attempts, 2);
+    assert!(checkpoint.topics.is_empty() && !checkpoint.ephemeral);
+    // only the fields known to the older versions
+    let checkpoint =
+        parse_build_checkpoint(br#"{"packages":["a"],"progress":0,"time_elapsed":0,"attempts":1}"#)
+            .unwrap();
+    assert_eq!(checkpoint.packages, vec!["a"]);
+    assert!(parse_build_checkpoint(b"junk").is_err());
+}
+
+#[test]
+fn test_collect_artifacts() {
+    let dir = tempfile::tempdir().unwrap();
+    let debs = dir.path().join("debs");
+    fs::create_dir_all(debs.join("a")).unwrap();
+    fs::write(debs.join("a/old.deb"), b"old").unwrap();
+    let before = snapshot_debs(&debs);
+    fs::write(debs.join("a/new.deb"), b"new").unwrap();
+    let added = new_debs(&before, &snapshot_debs(&debs));
+    assert_eq!(added, vec![debs.join("a/new.deb")]);
+    // recorded by an older version, without the tooling
+    fs::create_dir_all(dir.path().join("by-package")).unwrap();
+    fs::write(
+        dir.path().join("by-package/report.json"),
+        r#"{"old":{"directory":"old-1","debs":[]}}"#,
+    )
+    .unwrap();
+    let log = dir.path().join(".build.log");
+    fs::write(&log, b"log").unwrap();
+    let dest = collect_artifacts(
+        dir.path(),
+        "extra-x/foo",
+        &added,
+        &log,
+        None,
+        Some("2024-10-01"),
+        &Tooling {
+            ciel: "1.0.0".to_owned(),
+            acbs: "20240101".to_owned(),
+            autobuild: "unknown".to_owned(),
+        },
+    )
+    .unwrap();
+    assert_eq!(dest, dir.path().join("by-package/foo-unknown"));
+    assert_eq!(fs::read(dest.join("new.deb")).unwrap(), b"new");
+    assert_eq!(fs::read(dest.join("build.log")).unwrap(), b"log");
+    let report = fs::read_to_string(dir.path().join("by-package/report.json")).unwrap();
+    assert!(report.contains("\"extra-x/foo\""));
+    assert!(report.contains("debs/a/new.deb"));
+    assert!(report.contains("\"repo_snapshot\": \"2024-10-01\""));
+    assert!(report.contains("\"tooling\": {"));
+    assert!(report.contains("\"acbs\": \"20240101\""));
+    assert!(report.contains("\"autobuild\": \"unknown\""));
+    let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+    assert!(report["old"].get("tooling").is_none());
+}
+
+#[test]
+fn test_parse_tool_version() {
+    assert_eq!(
+        parse_tool_version(0, "\nacbs-build 20240101\nmore\n").as_deref(),
+        Some("acbs-build 20240101")
+    );
+    assert_eq!(parse_tool_version(127, "command not found"), None);
+    assert_eq!(parse_tool_version(0, ""), None);
+}
+
+#[test]
+fn test_shard_packages() {
+    let packages = ["a", "b", "c", "d", "e"].map(String::from).to_vec();
+    assert_eq!(
+        shard_packages(packages.clone(), 2),
+        vec![vec!["a", "c", "e"], vec!["b", "d"]]
+    );
+    // no empty shards when there are more instances than packages
+    assert_eq!(
+        shard_packages(packages[..2].to_vec(), 3),
+        vec![vec!["a"], vec!["b"]]
+    );
+    assert!(shard_packages(Vec::new(), 3).is_empty());
+}
+
+#[test]
+fn test_acbs_command() {
+    assert_eq!(
+        acbs_command(&[], &["foo"]),
+        vec!["/bin/acbs-build", "--", "foo"]
+    );
+    assert_eq!(
+        acbs_command(&["-e".to_string(), "--clear".to_string()], &["foo", "bar"]),
+        vec!["/bin/acbs-build", "-e", "--clear", "--", "foo", "bar"]
+    );
+}
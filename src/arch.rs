@@ -0,0 +1,99 @@
+//! Target architectures supported by ciel
+
+use std::env::consts::ARCH;
+
+pub const CIEL_MAINLINE_ARCHS: &[&str] = &[
+    "amd64",
+    "arm64",
+    "ppc64el",
+    "mips64r6el",
+    "riscv64",
+    "loongarch64",
+    "loongson3",
+];
+pub const CIEL_RETRO_ARCHS: &[&str] = &["armv4", "armv6hf", "armv7hf", "i486", "m68k", "powerpc"];
+/// Other names of the architectures (from the kernel, Debian or the toolchains),
+/// along with the names used by AOSC OS
+const ARCH_ALIASES: &[(&str, &str)] = &[
+    ("x86_64", "amd64"),
+    ("aarch64", "arm64"),
+    ("ppc64le", "ppc64el"),
+    ("loong64", "loongarch64"),
+    ("armv6", "armv6hf"),
+    ("armv7", "armv7hf"),
+    ("armhf", "armv7hf"),
+    ("i386", "i486"),
+    ("i686", "i486"),
+    ("x86", "i486"),
+    ("ppc", "powerpc"),
+];
+
+/// Return the AOSC OS name of the architecture, accepting the aliases as well
+pub fn normalize_arch_name(arch: &str) -> Option<&'static str> {
+    CIEL_MAINLINE_ARCHS
+        .iter()
+        .chain(CIEL_RETRO_ARCHS)
+        .find(|x| **x == arch)
+        .copied()
+        .or_else(|| {
+            ARCH_ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == arch)
+                .map(|(_, name)| *name)
+        })
+}
+
+/// Other names accepted for the architecture
+pub fn arch_aliases(arch: &str) -> Vec<&'static str> {
+    ARCH_ALIASES
+        .iter()
+        .filter(|(_, name)| *name == arch)
+        .map(|(alias, _)| *alias)
+        .collect()
+}
+
+/// AOSC OS specific architecture mapping table
+#[inline]
+pub fn get_host_arch_name() -> Option<&'static str> {
+    #[cfg(not(target_arch = "powerpc64"))]
+    match ARCH {
+        "x86_64" => Some("amd64"),
+        "x86" => Some("i486"),
+        "powerpc" => Some("powerpc"),
+        "aarch64" => Some("arm64"),
+        "mips64" => Some("loongson3"),
+        "riscv64" => Some("riscv64"),
+        "loongarch64" => Some("loongarch64"),
+        _ => None,
+    }
+
+    #[cfg(target_arch = "powerpc64")]
+    {
+        let mut endian: libc::c_int = -1;
+        let result = unsafe { libc::prctl(libc::PR_GET_ENDIAN, &mut endian as *mut libc::c_int) };
+        if result < 0 {
+            return None;
+        }
+        match endian {
+            libc::PR_ENDIAN_LITTLE | libc::PR_ENDIAN_PPC_LITTLE => Some("ppc64el"),
+            libc::PR_ENDIAN_BIG => Some("ppc64"),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_normalize_arch_name() {
+    assert_eq!(normalize_arch_name("amd64"), Some("amd64"));
+    assert_eq!(normalize_arch_name("loong64"), Some("loongarch64"));
+    assert_eq!(normalize_arch_name("armv7"), Some("armv7hf"));
+    assert_eq!(normalize_arch_name("x86_64"), Some("amd64"));
+    assert_eq!(normalize_arch_name("sparc64"), None);
+    assert_eq!(normalize_arch_name("AMD64"), None);
+    assert_eq!(arch_aliases("i486"), vec!["i386", "i686", "x86"]);
+    // every alias points to a supported architecture
+    for (alias, name) in ARCH_ALIASES {
+        assert!(!CIEL_MAINLINE_ARCHS.contains(alias) && !CIEL_RETRO_ARCHS.contains(alias));
+        assert!(CIEL_MAINLINE_ARCHS.contains(name) || CIEL_RETRO_ARCHS.contains(name));
+    }
+}
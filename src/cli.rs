@@ -3,6 +3,32 @@ use clap::{Arg, Command};
 use std::ffi::OsStr;
 
 pub const GIT_TREE_URL: &str = "https://github.com/AOSC-Dev/aosc-os-abbs.git";
+/// Options of `ciel config` changing the settings, which can't be used with `--get`/`--get-all`
+const CONFIG_SETTERS: &[&str] = &[
+    "g",
+    "add-dns",
+    "remove-dns",
+    "add-mount",
+    "remove-mount",
+    "force-no-rollback",
+    "trust-host-ca",
+    "host-resolv-conf",
+];
+/// Options of `ciel instconf` changing the settings, which can't be used with `--get`/`--get-all`
+const INSTCONF_SETTERS: &[&str] = &[
+    "network-zone",
+    "no-network-zone",
+    "network-isolation",
+    "volatile",
+    "local-repo",
+    "add-mount",
+    "remove-mount",
+    "rollback-policy",
+    "private-tmp",
+    "no-private-tmp",
+    "hostname",
+    "no-hostname",
+];
 
 /// List all the available plugins/helper scripts
 fn list_helpers() -> Result<Vec<String>> {
@@ -35,11 +61,27 @@ pub fn build_cli() -> Command {
         .num_args(1)
         .env("CIEL_INST")
         .action(clap::ArgAction::Set);
+    let chdir_arg = Arg::new("CHDIR")
+        .long("chdir")
+        .num_args(1)
+        .value_parser(clap::value_parser!(std::path::PathBuf))
+        .help("Working directory inside the container");
+    let pkg_arg = Arg::new("PKG")
+        .long("pkg")
+        .num_args(1)
+        .conflicts_with("CHDIR")
+        .help("Start in the directory of the specified package in the tree");
+    let no_start_arg = Arg::new("NO_START")
+        .long("no-start")
+        .action(clap::ArgAction::SetTrue)
+        .help("Fail instead of starting the instance if it is not running");
     Command::new("ciel")
         .version(env!("CARGO_PKG_VERSION"))
         .about("CIEL! is a nspawn container manager")
         .allow_external_subcommands(true)
-        .subcommand(Command::new("version").about("Display the version of CIEL!"))
+        .subcommand(Command::new("version")
+            .arg(Arg::new("workspace").long("workspace").action(clap::ArgAction::SetTrue).help("Also show which versions of CIEL! created and last used the workspace"))
+            .about("Display the version of CIEL!"))
         .subcommand(Command::new("init")
             .arg(Arg::new("upgrade").long("upgrade").action(clap::ArgAction::SetTrue).help("Upgrade Ciel workspace from an older version"))
             .about("Initialize the work directory"))
@@ -47,11 +89,18 @@ pub fn build_cli() -> Command {
             Command::new("load-os")
                 .arg(Arg::new("url").help("URL or path to the tarball"))
                 .arg(Arg::new("arch").short('a').long("arch").help("Specify the target architecture for fetching OS tarball"))
+                .arg(Arg::new("progress-json").long("progress-json").action(clap::ArgAction::SetTrue).value_parser(clap::builder::FalseyValueParser::new()).env("CIEL_PROGRESS_JSON").help("Print the progress and the messages as newline-delimited JSON records on stderr, instead of progress bars"))
+                .arg(Arg::new("list-arches").long("list-arches").action(clap::ArgAction::SetTrue).exclusive(true).help("List the supported target architectures, their groups and other accepted names"))
+                .arg(Arg::new("allow-devices").long("allow-devices").action(clap::ArgAction::SetTrue).value_parser(clap::builder::FalseyValueParser::new()).env("CIEL_ALLOW_DEVICES").help("Allow device nodes other than the common ones in /dev in the OS tarball"))
+                .arg(Arg::new("update").long("update").action(clap::ArgAction::SetTrue).help("Skip loading if the OS is unchanged, and keep the current OS until the new one is extracted"))
+                .arg(Arg::new("format").long("format").value_parser(["auto", "tar", "squashfs", "oci"]).default_value("auto").help("Format of the OS archive (oci also accepts Docker image archives)"))
                 .about("Unpack OS tarball or fetch the latest BuildKit from the repository"),
         )
         .subcommand(
             Command::new("update-os")
                 .arg(Arg::new("force_use_apt").long("force-use-apt").help("Use apt to update-os").action(clap::ArgAction::SetTrue))
+                .arg(Arg::new("stage").long("stage").action(clap::ArgAction::SetTrue).requires("INSTANCE").help("Update in the specified instance only, without committing the changes"))
+                .arg(instance_arg.clone().help("Instance to stage the update in"))
                 .about("Update the OS in the container")
         )
         .subcommand(
@@ -69,13 +118,23 @@ pub fn build_cli() -> Command {
             Command::new("new")
             .arg(Arg::new("tarball").num_args(1).long("from-tarball").help("Create a new workspace from the specified tarball"))
             .arg(Arg::new("arch").num_args(1).short('a').long("arch").help("Create a new workspace for specified architecture"))
+            .arg(Arg::new("answers").num_args(1).long("answers").value_name("FILE").help("Set up the workspace with the answers saved by --save-answers, without asking"))
+            .arg(Arg::new("save-answers").num_args(1).long("save-answers").value_name("FILE").help("Save the answers to the questions, to set up other workspaces the same way"))
             .about("Create a new CIEL workspace")
         )
         .subcommand(
             Command::new("list")
                 .alias("ls")
+                .arg(Arg::new("sort").long("sort").value_parser(["name", "created"]).default_value("name").help("Sort the instances by name or by creation time"))
+                .arg(Arg::new("verbose").short('v').long("verbose").action(clap::ArgAction::SetTrue).help("Also show the uptime of the containers and the mount options of the filesystems"))
                 .about("List all the instances under the specified working directory"),
         )
+        .subcommand(
+            Command::new("status")
+                .arg(instance_arg.clone().help("Instance to inspect"))
+                .arg(Arg::new("units").long("units").action(clap::ArgAction::SetTrue).help("Also show the system state and the failed units in the container"))
+                .about("Show the status of an instance"),
+        )
         .subcommand(
             Command::new("add")
                 .arg(Arg::new("INSTANCE").required(true))
@@ -84,13 +143,20 @@ pub fn build_cli() -> Command {
         .subcommand(
             Command::new("del")
                 .alias("rm")
-                .arg(Arg::new("INSTANCE").required(true))
-                .about("Remove an instance"),
+                .arg(Arg::new("INSTANCE").num_args(1..).required_unless_present("all"))
+                .arg(Arg::new("all").short('a').long("all").action(clap::ArgAction::SetTrue).conflicts_with("INSTANCE").help("Remove all the instances"))
+                .arg(Arg::new("force").short('f').long("force").action(clap::ArgAction::SetTrue).help("Do not ask for confirmation when removing several instances (required when not running interactively)"))
+                .arg(Arg::new("force-broken").long("force-broken").action(clap::ArgAction::SetTrue).help("Best-effort removal of instances whose filesystem can't be unmounted, moving what is left to the trash"))
+                .about("Remove one or more instances"),
         )
         .subcommand(
             Command::new("shell")
                 .alias("sh")
                 .arg(instance_arg.clone().help("Instance to be used"))
+                .arg(chdir_arg.clone())
+                .arg(pkg_arg.clone())
+                .arg(no_start_arg.clone())
+                .arg(Arg::new("ATTACH").long("attach").action(clap::ArgAction::SetTrue).conflicts_with("NO_START").help("Attach to the running container without taking the instance lock, e.g. during a build. The changes to the filesystem land in the same upper layer as the build."))
                 .arg(Arg::new("COMMANDS").required(false).num_args(1..))
                 .about("Start an interactive shell"),
         )
@@ -98,46 +164,118 @@ pub fn build_cli() -> Command {
             Command::new("run")
                 .alias("exec")
                 .arg(instance_arg.clone().help("Instance to run command in"))
+                .arg(chdir_arg.clone())
+                .arg(pkg_arg.clone())
+                .arg(no_start_arg.clone())
                 .arg(Arg::new("COMMANDS").required(true).num_args(1..))
                 .about("Lower-level version of 'shell', without login environment, without sourcing ~/.bash_profile"),
         )
+        .subcommand(
+            Command::new("try")
+                .arg(Arg::new("network-zone").long("network-zone").num_args(1).help("Put the temporary instance into the specified private network zone"))
+                .arg(Arg::new("network-isolation").long("network-isolation").action(clap::ArgAction::SetTrue).help("Disable network access through the host"))
+                .arg(Arg::new("mount").long("mount").value_name("SOURCE:TARGET").action(clap::ArgAction::Append).help("Add a bind mount for the temporary instance"))
+                .arg(Arg::new("no-local-repo").long("no-local-repo").action(clap::ArgAction::SetTrue).help("Do not use the local repository"))
+                .arg(Arg::new("keep-on-failure").long("keep-on-failure").action(clap::ArgAction::SetTrue).help("Keep the temporary instance if the command fails"))
+                .arg(Arg::new("COMMANDS").required(true).num_args(1..).last(true))
+                .about("Run a command in a temporary instance, which is removed afterwards"),
+        )
         .subcommand(
             Command::new("config")
                 .arg(instance_arg.clone().help("Instance to be configured"))
                 .arg(Arg::new("g").short('g').action(clap::ArgAction::SetTrue).conflicts_with("INSTANCE").help("Configure base system instead of an instance"))
+                .arg(Arg::new("add-dns").long("add-dns").value_parser(clap::value_parser!(std::net::IpAddr)).action(clap::ArgAction::Append).conflicts_with("INSTANCE").help("Add a DNS server for the containers"))
+                .arg(Arg::new("remove-dns").long("remove-dns").value_parser(clap::value_parser!(std::net::IpAddr)).action(clap::ArgAction::Append).conflicts_with("INSTANCE").help("Remove a DNS server for the containers"))
+                .arg(Arg::new("add-mount").long("add-mount").value_name("SOURCE:TARGET").action(clap::ArgAction::Append).conflicts_with("INSTANCE").help("Add a bind mount for all the instances"))
+                .arg(Arg::new("remove-mount").long("remove-mount").value_name("TARGET").action(clap::ArgAction::Append).conflicts_with("INSTANCE").help("Remove a bind mount for all the instances"))
+                .arg(Arg::new("force-no-rollback").long("force-no-rollback").action(clap::ArgAction::SetTrue).conflicts_with("INSTANCE").help("Apply the configuration to the mounted instances right away, instead of shutting them down"))
+                .arg(Arg::new("trust-host-ca").long("trust-host-ca").num_args(1).value_parser(clap::value_parser!(bool)).conflicts_with("INSTANCE").help("Share the CA certificates of the host with the containers, read-only (true or false)"))
+                .arg(Arg::new("host-resolv-conf").long("host-resolv-conf").num_args(1).value_parser(clap::value_parser!(bool)).conflicts_with("INSTANCE").help("Use the DNS configuration of the host in the containers (true or false)"))
+                .arg(Arg::new("get").long("get").num_args(1).value_name("KEY").conflicts_with("INSTANCE").conflicts_with_all(CONFIG_SETTERS).help("Print the value of a setting of the workspace (use dots for the nested values)"))
+                .arg(Arg::new("get-all").long("get-all").alias("show").action(clap::ArgAction::SetTrue).conflicts_with_all(["INSTANCE", "get"]).conflicts_with_all(CONFIG_SETTERS).help("Print all the settings of the workspace"))
                 .about("Configure system and toolchain for building interactively"),
         )
+        .subcommand(
+            Command::new("instconf")
+                .arg(instance_arg.clone().required(true).help("Instance to be configured"))
+                .arg(Arg::new("network-zone").long("network-zone").num_args(1).help("Put the instance into the specified private network zone"))
+                .arg(Arg::new("no-network-zone").long("no-network-zone").action(clap::ArgAction::SetTrue).conflicts_with("network-zone").help("Remove the instance from its network zone"))
+                .arg(Arg::new("network-isolation").long("network-isolation").num_args(1).value_parser(clap::value_parser!(bool)).help("Disable network access through the host (true or false)"))
+                .arg(Arg::new("volatile").long("volatile").num_args(1).value_parser(clap::value_parser!(bool)).help("Mount the filesystem of the instance in volatile mode, overriding the workspace setting (true or false)"))
+                .arg(Arg::new("local-repo").long("local-repo").num_args(1).value_parser(clap::value_parser!(bool)).help("Use the local repository in the instance, if enabled for the workspace (true or false)"))
+                .arg(Arg::new("add-mount").long("add-mount").value_name("SOURCE:TARGET").action(clap::ArgAction::Append).help("Add a bind mount for the instance"))
+                .arg(Arg::new("rollback-policy").long("rollback-policy").num_args(1).value_name("POLICY").help("When `ciel build` rolls the instance back: never, per-build, per-package, or after the specified number of builds"))
+                .arg(Arg::new("private-tmp").long("private-tmp").num_args(1).value_name("MiB").value_parser(clap::value_parser!(u64).range(1..)).help("Mount a private tmpfs of the specified size (in MiB) on /tmp of the container"))
+                .arg(Arg::new("no-private-tmp").long("no-private-tmp").action(clap::ArgAction::SetTrue).conflicts_with("private-tmp").help("Keep /tmp in the filesystem of the instance (default)"))
+                .arg(Arg::new("hostname").long("hostname").num_args(1).help("Set the hostname of the container (defaults to the namespace name of the instance)"))
+                .arg(Arg::new("no-hostname").long("no-hostname").action(clap::ArgAction::SetTrue).conflicts_with("hostname").help("Use the default hostname for the container"))
+                .arg(Arg::new("remove-mount").long("remove-mount").value_name("TARGET").action(clap::ArgAction::Append).help("Remove a bind mount from the instance"))
+                .arg(Arg::new("get").long("get").num_args(1).value_name("KEY").conflicts_with_all(INSTCONF_SETTERS).help("Print the value of a setting of the instance (use dots for the nested values)"))
+                .arg(Arg::new("get-all").long("get-all").alias("show").action(clap::ArgAction::SetTrue).conflicts_with("get").conflicts_with_all(INSTCONF_SETTERS).help("Print all the settings of the instance (the default without any option)"))
+                .about("Show or change the settings of an instance"),
+        )
         .subcommand(
             Command::new("commit")
                 .arg(instance_arg.clone().help("Instance to be committed"))
+                .arg(Arg::new("path").long("path").action(clap::ArgAction::Append).help("Only commit the changes under the specified path (can be specified multiple times)"))
+                .arg(Arg::new("force").long("force").action(clap::ArgAction::SetTrue).help("Commit even if a package operation was interrupted in the instance"))
+                .arg(Arg::new("no-default-excludes").long("no-default-excludes").action(clap::ArgAction::SetTrue).help("Also commit the contents of volatile paths like /tmp and /run"))
+                .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).conflicts_with("force").help("Only show a summary of what would be changed in the shared underlying OS"))
                 .about("Commit changes onto the shared underlying OS"),
         )
         .subcommand(
             Command::new("doctor")
-                .about("Diagnose problems (hopefully)"),
+                .arg(Arg::new("json").long("json").action(clap::ArgAction::SetTrue).help("Print the results in JSON format"))
+                .arg(Arg::new("fix").long("fix").action(clap::ArgAction::SetTrue).help("Recreate the missing layers of the damaged instances"))
+                .about("Diagnose problems (hopefully), exits with 1 on warnings and 2 on failures"),
         )
         .subcommand(
             Command::new("build")
                 .arg(Arg::new("FETCH").short('g').action(clap::ArgAction::SetTrue).help("Fetch source packages only"))
-                .arg(Arg::new("OFFLINE").short('x').long("offline").action(clap::ArgAction::SetTrue).env("CIEL_OFFLINE").help("Disable network in the container during the build"))
+                .arg(Arg::new("OFFLINE").short('x').long("offline").action(clap::ArgAction::SetTrue).value_parser(clap::builder::FalseyValueParser::new()).env("CIEL_OFFLINE").help("Disable network in the container during the build"))
                 .arg(instance_arg.clone().help("Instance to build in"))
-                .arg(Arg::new("STAGE2").long("stage2").short('2').action(clap::ArgAction::SetTrue).env("CIEL_STAGE2").help("Use stage 2 mode instead of the regular build mode"))
+                .arg(Arg::new("INSTANCES").long("instances").value_delimiter(',').action(clap::ArgAction::Append).value_name("INSTANCES").requires("PACKAGES").conflicts_with_all(["CONTINUE", "FETCH", "SELECT", "ALWAYS_DISCARD"]).help("Experimental: split the independent packages across the instances (comma-separated) and build them at the same time"))
+                .arg(Arg::new("STAGE2").long("stage2").short('2').action(clap::ArgAction::SetTrue).value_parser(clap::builder::FalseyValueParser::new()).env("CIEL_STAGE2").help("Use stage 2 mode instead of the regular build mode"))
                 .arg(Arg::new("CONTINUE").conflicts_with("SELECT").short('c').long("resume").alias("continue").num_args(1).help("Continue from a Ciel checkpoint"))
+                .arg(Arg::new("SKIP").long("skip").num_args(1).action(clap::ArgAction::Append).requires("CONTINUE").help("Skip the specified package when resuming from a checkpoint"))
+                .arg(Arg::new("ONLY").long("only").num_args(1..).requires("CONTINUE").conflicts_with("SKIP").help("Only build the specified packages when resuming from a checkpoint"))
                 .arg(Arg::new("SELECT").num_args(0..=1).long("stage-select").help("Select the starting point for a build"))
+                .arg(Arg::new("ALWAYS_DISCARD").long("always-discard").action(clap::ArgAction::SetTrue).help("Build in an ephemeral instance when no instance is specified, and destroy it if the build fails"))
+                .arg(Arg::new("KEEP_EPHEMERAL").long("keep-ephemeral").action(clap::ArgAction::SetTrue).requires("ALWAYS_DISCARD").help("Keep the ephemeral instance after a successful build"))
+                .arg(Arg::new("NO_UPDATE_OS").long("no-update-os").action(clap::ArgAction::SetTrue).help("Do not update the OS before building each package, the dependencies may be stale"))
+                .arg(Arg::new("NICE").long("nice").num_args(1).allow_negative_numbers(true).value_parser(clap::value_parser!(i32).range(-20..=19)).help("Run the build commands with the specified niceness (-20 to 19)"))
+                .arg(Arg::new("IO_CLASS").long("io-class").num_args(1).value_parser(["realtime", "best-effort", "idle"]).help("Run the build commands with the specified I/O scheduling class"))
+                .arg(Arg::new("TIMINGS").long("timings").action(clap::ArgAction::SetTrue).help("Print how long each phase of the build took at the end"))
+                .arg(Arg::new("RECORD_ENV").long("record-env").action(clap::ArgAction::SetTrue).help("Record the installed package versions after each successful build"))
+                .arg(Arg::new("NOTIFY").long("notify").num_args(1).help("How to notify about the build progress: all, title, bell, none, or command:<COMMAND> to run a command when the build finishes"))
+                .arg(Arg::new("COLLECT_ARTIFACTS").long("collect-artifacts").action(clap::ArgAction::SetTrue).help("Group the debs, the build log and the environment of each package in the by-package directory"))
+                .arg(Arg::new("ACBS_ARGS").long("acbs-arg").action(clap::ArgAction::Append).num_args(1).allow_hyphen_values(true).value_name("ARG").help("Pass an extra argument to acbs-build, e.g. --acbs-arg=-e (can be repeated)"))
+                .arg(Arg::new("REPO_SNAPSHOT").long("repo-snapshot").num_args(1).value_name("DATE").help("Build against the repository snapshot of the specified date (YYYY-MM-DD)"))
+                .arg(Arg::new("TOPICS").long("with-topics").value_delimiter(',').action(clap::ArgAction::Append).help("Enable the specified topic repositories during the build (comma-separated)"))
                 .arg(Arg::new("PACKAGES").conflicts_with("CONTINUE").num_args(1..))
                 .about("Build the packages using the specified instance"),
         )
         .subcommand(
             Command::new("rollback")
                 .arg(instance_arg.clone().help("Instance to be rolled back"))
+                .arg(Arg::new("PRESERVE").long("preserve").num_args(1).action(clap::ArgAction::Append).value_parser(clap::value_parser!(std::path::PathBuf)).help("Keep the changes under the specified path in the instance directory"))
                 .about("Rollback all or specified instance"),
         )
         .subcommand(
             Command::new("down")
                 .alias("umount")
                 .arg(instance_arg.clone().help("Instance to be un-mounted"))
+                .arg(Arg::new("kill-holders").long("kill-holders").action(clap::ArgAction::SetTrue).help("Terminate the host processes keeping the filesystem busy"))
+                .arg(Arg::new("idle-for").long("idle-for").num_args(1).value_name("DURATION").conflicts_with("INSTANCE").help("Only shut down the containers running for longer than this (e.g. 24h), skipping the ones in use"))
                 .about("Shutdown and unmount all or one instance"),
         )
+        .subcommand(
+            Command::new("boot")
+                .arg(instance_arg.clone().help("Instance to be booted"))
+                .arg(Arg::new("all").short('a').long("all").action(clap::ArgAction::SetTrue).help("Boot all the instances"))
+                .arg(Arg::new("jobs").short('j').long("jobs").num_args(1).value_parser(clap::value_parser!(usize)).default_value("4").help("Number of instances to boot at the same time"))
+                .about("Boot all or specified instance"),
+        )
         .subcommand(
             Command::new("stop")
                 .arg(instance_arg.clone().help("Instance to be stopped"))
@@ -145,25 +283,54 @@ pub fn build_cli() -> Command {
         )
         .subcommand(
             Command::new("mount")
-                .arg(instance_arg.help("Instance to be mounted"))
+                .arg(instance_arg.clone().help("Instance to be mounted"))
                 .about("Mount all or specified instance"),
         )
         .subcommand(
             Command::new("farewell")
                 .alias("harakiri")
+                .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).help("Only show what would be removed and what would be kept"))
                 .about("Remove everything related to CIEL!"),
         )
         .subcommand(
             Command::new("repo")
                 .arg_required_else_help(true)
-                .subcommands(vec![Command::new("refresh").about("Refresh the repository"), Command::new("init").arg(Arg::new("INSTANCE").required(true)).about("Initialize the repository"), Command::new("deinit").about("Uninitialize the repository")])
+                .subcommands(vec![
+                    Command::new("refresh").about("Refresh the repository").after_help("Unreadable debs are left out of the index and listed, the exit code is 2 in that case."),
+                    Command::new("init").arg(instance_arg.clone().required(true).help("Instance to enable the local repository in")).about("Initialize the repository"),
+                    Command::new("deinit").arg(instance_arg.required(true).help("Instance to disable the local repository in")).about("Uninitialize the repository"),
+                    Command::new("verify")
+                        .arg(Arg::new("json").long("json").action(clap::ArgAction::SetTrue).help("Print the result in JSON format"))
+                        .arg(Arg::new("repair").long("repair").action(clap::ArgAction::SetTrue).help("Refresh the index if any discrepancy is found"))
+                        .about("Check the packages in the repository against the index"),
+                    Command::new("import")
+                        .arg(Arg::new("PATHS").num_args(1..).required(true).help("Debs, or directories containing debs, to import"))
+                        .arg(Arg::new("move").long("move").action(clap::ArgAction::SetTrue).help("Move the debs instead of copying them"))
+                        .arg(Arg::new("hardlink").long("hardlink").action(clap::ArgAction::SetTrue).conflicts_with("move").help("Hard link the debs instead of copying them"))
+                        .about("Import debs built elsewhere into the repository"),
+                    Command::new("migrate-output")
+                        .arg(Arg::new("hardlink").long("hardlink").action(clap::ArgAction::SetTrue).help("Hard link the debs instead of moving them"))
+                        .about("Move the debs into the output directory after toggling branch-exclusive output"),
+                    Command::new("serve")
+                        .alias("export")
+                        .arg(Arg::new("bind").long("bind").num_args(1).default_value("0.0.0.0:8000").help("Address and port to listen on"))
+                        .arg(Arg::new("token").long("token").num_args(1).env("CIEL_REPO_TOKEN").help("Only answer the requests under /<TOKEN>/"))
+                        .about("Serve the repository read-only over HTTP for other machines"),
+                ])
                 .alias("localrepo")
                 .about("Local repository operations")
         )
         .subcommand(
             Command::new("clean")
+                .arg(Arg::new("apt-cache").long("apt-cache").action(clap::ArgAction::SetTrue).conflicts_with("trash").help("Only clean the package download cache (packages in use are kept)"))
+                .arg(Arg::new("trash").long("trash").action(clap::ArgAction::SetTrue).help("Only remove the instances left in the trash by `del --force-broken`"))
+                .arg(Arg::new("over-limit").long("over-limit").action(clap::ArgAction::SetTrue).requires("apt-cache").help("Only remove the least recently used packages until the cache is under the configured limit"))
                 .about("Clean all the output directories and source cache directories")
         )
+        .subcommand(
+            Command::new("space")
+                .about("Show the disk space used by the caches and the outputs")
+        )
         .subcommands({
             let plugins = list_helpers();
             if let Ok(plugins) = plugins {
@@ -190,6 +357,42 @@ pub fn build_cli() -> Command {
                     .long("batch")
                     .action(clap::ArgAction::SetTrue)
                     .help("Batch mode, no input required"),
+                Arg::new("color")
+                    .long("color")
+                    .value_parser(["auto", "always", "never"])
+                    .default_value("auto")
+                    .help("When to use colors and other escape sequences in the output"),
+                Arg::new("ignore-version")
+                    .long("ignore-version")
+                    .action(clap::ArgAction::SetTrue)
+                    .value_parser(clap::builder::FalseyValueParser::new())
+                    .env("CIEL_IGNORE_VERSION")
+                    .help("Load configuration written by a newer version of CIEL! anyway"),
+                Arg::new("strict-config")
+                    .long("strict-config")
+                    .action(clap::ArgAction::SetTrue)
+                    .value_parser(clap::builder::FalseyValueParser::new())
+                    .env("CIEL_STRICT_CONFIG")
+                    .help("Refuse configuration files with unknown keys"),
+                Arg::new("auto-upgrade")
+                    .long("auto-upgrade")
+                    .action(clap::ArgAction::SetTrue)
+                    .value_parser(clap::builder::FalseyValueParser::new())
+                    .env("CIEL_AUTO_UPGRADE")
+                    .help("Upgrade the workspace if it is in an older format, instead of refusing to use it"),
+                Arg::new("log-timings")
+                    .long("log-timings")
+                    .action(clap::ArgAction::SetTrue)
+                    .value_parser(clap::builder::FalseyValueParser::new())
+                    .env("CIEL_LOG_TIMINGS")
+                    .help("Log how long the slow operations (mount, commit, build, ...) take"),
+                Arg::new("quiet")
+                    .short('q')
+                    .long("quiet")
+                    .action(clap::ArgAction::SetTrue)
+                    .value_parser(clap::builder::FalseyValueParser::new())
+                    .env("CIEL_QUIET")
+                    .help("Do not draw progress bars, print the progress now and then instead"),
             ]
         )
 }
@@ -1,34 +1,38 @@
+use crate::arch::{get_host_arch_name, CIEL_MAINLINE_ARCHS, CIEL_RETRO_ARCHS};
+use crate::{info, warn};
 use anyhow::{anyhow, Result};
-use console::user_attended;
+use console::{style, user_attended, Term};
 use dialoguer::{theme::ColorfulTheme, FuzzySelect};
-use indicatif::ProgressBar;
+use indicatif::{HumanBytes, HumanDuration, ProgressBar};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::env::consts::ARCH;
+use std::cell::RefCell;
 use std::fs::{self, File};
 use std::os::unix::prelude::MetadataExt;
 use std::sync::LazyLock;
 use std::{
-    io::{Read, Write},
-    path::{Path, PathBuf},
-    time::Duration,
+    io::{BufRead, BufReader, Read, Write},
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
 };
-use unsquashfs_wrapper::Unsquashfs;
-
-pub const CIEL_MAINLINE_ARCHS: &[&str] = &[
-    "amd64",
-    "arm64",
-    "ppc64el",
-    "mips64r6el",
-    "riscv64",
-    "loongarch64",
-    "loongson3",
-];
-pub const CIEL_RETRO_ARCHS: &[&str] = &["armv4", "armv6hf", "armv7hf", "i486", "m68k", "powerpc"];
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
 pub const CURRENT_CIEL_VERSION: usize = 3;
 const CURRENT_CIEL_VERSION_STR: &str = "3";
 pub const CIEL_DIST_DIR: &str = ".ciel/container/dist";
 pub const CIEL_INST_DIR: &str = ".ciel/container/instances";
 pub const CIEL_DATA_DIR: &str = ".ciel/data";
+/// Where the instances that could not be removed are kept until they can be
+pub const CIEL_TRASH_DIR: &str = ".ciel/trash";
+/// Present while the workspace configuration is yet to be applied to the base system,
+/// which can't be modified while instances are mounted on it
+pub const CIEL_PENDING_CONFIG_FILE: &str = ".ciel/data/config.pending";
+const CIEL_ARCH_FILE: &str = ".ciel/data/arch";
+const CIEL_META_FILE: &str = ".ciel/data/meta.toml";
+const CIEL_ROOTFS_CHECKSUM_FILE: &str = ".ciel/data/rootfs.sha256";
+const CIEL_DIST_NEW_DIR: &str = ".ciel/container/dist.new";
+const CIEL_DIST_OLD_DIR: &str = ".ciel/container/dist.old";
 const SKELETON_DIRS: &[&str] = &[CIEL_DIST_DIR, CIEL_INST_DIR, CIEL_DATA_DIR];
 
 static SPINNER_STYLE: LazyLock<indicatif::ProgressStyle> = LazyLock::new(|| {
@@ -49,6 +53,125 @@ macro_rules! make_progress_bar {
     };
 }
 
+/// Interval of the progress lines printed in place of the progress bars
+const PROGRESS_LINE_INTERVAL: Duration = Duration::from_secs(30);
+/// How often a JSON progress record is printed with `--progress-json`
+const PROGRESS_JSON_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a progress bar is tracking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressPhase {
+    Download,
+    Verify,
+    Extract,
+}
+
+impl ProgressPhase {
+    /// Name of the phase in the JSON progress records
+    fn name(self) -> &'static str {
+        match self {
+            ProgressPhase::Download => "download",
+            ProgressPhase::Verify => "verify",
+            ProgressPhase::Extract => "extract",
+        }
+    }
+
+    /// Prefix of the plain progress lines
+    fn label(self) -> &'static str {
+        match self {
+            ProgressPhase::Download => "Downloading",
+            ProgressPhase::Verify => "Verifying checksum ...",
+            ProgressPhase::Extract => "Extracting rootfs ...",
+        }
+    }
+}
+
+/// Progress bars are only drawn on an interactive terminal and without `--quiet`
+fn progress_bars_enabled() -> bool {
+    !env_flag("CIEL_QUIET") && Term::stderr().is_term()
+}
+
+/// Whether the progress is reported as newline-delimited JSON records on stderr,
+/// instead of progress bars or plain lines
+pub fn progress_json_enabled() -> bool {
+    env_flag("CIEL_PROGRESS_JSON")
+}
+
+/// Create a progress bar for processing `total` bytes, using the template made by
+/// `make_progress_bar!`. When the bar can't be drawn, a plain line with the progress
+/// is printed now and then instead (or a JSON record with `--progress-json`)
+pub fn create_progress_bar(total: u64, template: &str, phase: ProgressPhase) -> ProgressBar {
+    let json = progress_json_enabled();
+    if !json && progress_bars_enabled() {
+        let progress_bar = ProgressBar::new(total);
+        progress_bar.set_style(
+            indicatif::ProgressStyle::default_bar()
+                .template(template)
+                .unwrap(),
+        );
+        progress_bar.set_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(5));
+        return progress_bar;
+    }
+    let progress_bar = ProgressBar::hidden();
+    progress_bar.set_length(total);
+    let interval = if json {
+        eprintln!("{}", progress_record(phase, &progress_bar, false));
+        PROGRESS_JSON_INTERVAL
+    } else {
+        PROGRESS_LINE_INTERVAL
+    };
+    let weak = progress_bar.downgrade();
+    std::thread::spawn(move || {
+        let mut last_report = Instant::now();
+        // stops when the bar is finished or dropped
+        while let Some(progress_bar) = weak.upgrade().filter(|x| !x.is_finished()) {
+            if last_report.elapsed() >= interval {
+                if json {
+                    eprintln!("{}", progress_record(phase, &progress_bar, false));
+                } else {
+                    eprintln!("{}", progress_line(phase.label(), &progress_bar));
+                }
+                last_report = Instant::now();
+            }
+            drop(progress_bar);
+            std::thread::sleep(Duration::from_millis(200));
+        }
+    });
+
+    progress_bar
+}
+
+/// Clear the progress bar, the final progress is reported with `--progress-json`
+pub fn finish_progress_bar(progress_bar: &ProgressBar, phase: ProgressPhase) {
+    progress_bar.finish_and_clear();
+    if progress_json_enabled() {
+        eprintln!("{}", progress_record(phase, progress_bar, true));
+    }
+}
+
+/// A JSON progress record, the total is null if unknown (e.g. no Content-Length)
+fn progress_record(phase: ProgressPhase, progress_bar: &ProgressBar, finished: bool) -> String {
+    serde_json::json!({
+        "type": "progress",
+        "phase": phase.name(),
+        "bytes": progress_bar.position(),
+        "total": progress_bar.length().filter(|x| *x > 0),
+        "finished": finished,
+    })
+    .to_string()
+}
+
+fn progress_line(label: &str, progress_bar: &ProgressBar) -> String {
+    format!(
+        "{} {}/{} ({}/s, eta {})",
+        label,
+        HumanBytes(progress_bar.position()),
+        HumanBytes(progress_bar.length().unwrap_or_default()),
+        HumanBytes(progress_bar.per_sec() as u64),
+        HumanDuration(progress_bar.eta())
+    )
+}
+
 #[inline]
 pub fn create_spinner(msg: &'static str, tick_rate: u64) -> indicatif::ProgressBar {
     let spinner = indicatif::ProgressBar::new_spinner().with_style(SPINNER_STYLE.clone());
@@ -58,87 +181,427 @@ pub fn create_spinner(msg: &'static str, tick_rate: u64) -> indicatif::ProgressB
     spinner
 }
 
-#[inline]
-pub fn check_arch_name(arch: &str) -> bool {
-    CIEL_MAINLINE_ARCHS.contains(&arch) || CIEL_RETRO_ARCHS.contains(&arch)
+/// Size of the buffer used for hashing, small reads are much slower on large files
+const HASH_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+thread_local! {
+    /// Hashing buffer, reused across the checksums calculated on the same thread
+    static HASH_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
 }
 
-/// AOSC OS specific architecture mapping table
-#[inline]
-pub fn get_host_arch_name() -> Option<&'static str> {
-    #[cfg(not(target_arch = "powerpc64"))]
-    match ARCH {
-        "x86_64" => Some("amd64"),
-        "x86" => Some("i486"),
-        "powerpc" => Some("powerpc"),
-        "aarch64" => Some("arm64"),
-        "mips64" => Some("loongson3"),
-        "riscv64" => Some("riscv64"),
-        "loongarch64" => Some("loongarch64"),
-        _ => None,
-    }
-
-    #[cfg(target_arch = "powerpc64")]
-    {
-        let mut endian: libc::c_int = -1;
-        let result = unsafe { libc::prctl(libc::PR_GET_ENDIAN, &mut endian as *mut libc::c_int) };
-        if result < 0 {
-            return None;
-        }
-        match endian {
-            libc::PR_ENDIAN_LITTLE | libc::PR_ENDIAN_PPC_LITTLE => Some("ppc64el"),
-            libc::PR_ENDIAN_BIG => Some("ppc64"),
+/// Calculate the Sha256 checksum of the given stream, reporting the bytes hashed to `progress`
+fn sha256sum_with_progress<R: Read>(mut reader: R, progress: &ProgressBar) -> Result<String> {
+    let mut hasher = Sha256::new();
+    HASH_BUFFER.with_borrow_mut(|buf| -> Result<()> {
+        buf.resize(HASH_BUFFER_SIZE, 0);
+        loop {
+            let len = match reader.read(buf) {
+                Ok(0) => return Ok(()),
+                Ok(len) => len,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            hasher.update(&buf[..len]);
+            progress.inc(len as u64);
+        }
+    })?;
+
+    Ok(faster_hex::hex_string(&hasher.finalize()))
+}
+
+/// Calculate the Sha256 checksum of the given stream
+pub fn sha256sum<R: Read>(reader: R) -> Result<String> {
+    sha256sum_with_progress(reader, &ProgressBar::hidden())
+}
+
+/// Calculate the Sha256 checksum of the given file, showing the progress
+pub fn sha256sum_file(path: &Path) -> Result<String> {
+    let file = File::open(path)?;
+    let progress_bar = create_progress_bar(
+        file.metadata()?.len(),
+        make_progress_bar!("Verifying checksum ..."),
+        ProgressPhase::Verify,
+    );
+    let checksum = sha256sum_with_progress(file, &progress_bar);
+    finish_progress_bar(&progress_bar, ProgressPhase::Verify);
+
+    checksum
+}
+
+/// A reader that calculates the Sha256 checksum of everything read through it
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Read the rest of the stream (archive readers may stop before the end),
+    /// and return the checksum of the whole stream
+    pub fn finish(mut self) -> Result<String> {
+        std::io::copy(&mut self, &mut std::io::sink())?;
+
+        Ok(format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+
+        Ok(len)
+    }
+}
+
+/// Errors from downloading and loading the OS rootfs, use `downcast_ref` on the returned
+/// error to tell the transient failures from the ones that retrying won't fix
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RootfsError {
+    /// Unable to fetch the file, e.g. due to a network problem or an error from the server
+    DownloadFailed {
+        url: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The server refused the request (e.g. the file is not found) or sent an unusable response
+    DownloadRejected {
+        url: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The file is not the expected one
+    ChecksumMismatch { expected: String, actual: String },
+    /// The format of the rootfs is unknown or could not be detected
+    UnsupportedRootfsFormat(String),
+}
+
+impl RootfsError {
+    /// Whether trying again later may succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, RootfsError::DownloadFailed { .. })
+    }
+}
+
+impl std::fmt::Display for RootfsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RootfsError::DownloadFailed { url, source }
+            | RootfsError::DownloadRejected { url, source } => {
+                write!(f, "Failed to download {}: {}", url, source)
+            }
+            RootfsError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Checksum mismatch: expected {} but got {}",
+                expected, actual
+            ),
+            RootfsError::UnsupportedRootfsFormat(format) => {
+                write!(f, "Unsupported rootfs format: {}", format)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RootfsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RootfsError::DownloadFailed { source, .. }
+            | RootfsError::DownloadRejected { source, .. } => Some(source.as_ref()),
             _ => None,
         }
     }
 }
 
-/// Calculate the Sha256 checksum of the given stream
-pub fn sha256sum<R: Read>(mut reader: R) -> Result<String> {
-    let mut hasher = Sha256::new();
-    std::io::copy(&mut reader, &mut hasher)?;
+/// Compare the checksum with the expected one
+fn check_checksum(expected: &str, checksum: &str) -> Result<()> {
+    if expected != checksum {
+        return Err(RootfsError::ChecksumMismatch {
+            expected: expected.to_owned(),
+            actual: checksum.to_owned(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Device nodes that are expected in a rootfs archive
+const ALLOWED_DEVICE_NODES: &[&str] = &[
+    "dev/null",
+    "dev/zero",
+    "dev/full",
+    "dev/random",
+    "dev/urandom",
+    "dev/tty",
+    "dev/console",
+    "dev/ptmx",
+];
+
+/// Whether device nodes outside of the whitelist are allowed in the rootfs archive
+fn allow_device_nodes() -> bool {
+    env_flag("CIEL_ALLOW_DEVICES")
+}
 
-    Ok(format!("{:x}", hasher.finalize()))
+/// Check that the archive entry stays inside the destination directory,
+/// and that it is not an unexpected device node
+pub(crate) fn check_archive_entry(path: &Path, is_device: bool, allow_devices: bool) -> Result<()> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => (),
+            _ => {
+                return Err(anyhow!(
+                    "Archive entry `{}` escapes the destination directory",
+                    path.display()
+                ))
+            }
+        }
+    }
+    if is_device
+        && !allow_devices
+        && !ALLOWED_DEVICE_NODES
+            .iter()
+            .any(|x| normalized == Path::new(x))
+    {
+        return Err(anyhow!(
+            "Archive entry `{}` is an unexpected device node (use `--allow-devices` to allow it)",
+            path.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Unpack the tar stream entry by entry, refusing entries that are not safe to extract
+fn unpack_tar<R: Read>(reader: R, path: &Path, allow_devices: bool) -> Result<()> {
+    let mut tar_processor = tar::Archive::new(reader);
+    tar_processor.set_unpack_xattrs(true);
+    tar_processor.set_preserve_permissions(true);
+    for entry in tar_processor.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        let is_device = entry_type.is_block_special() || entry_type.is_character_special();
+        check_archive_entry(&entry.path()?, is_device, allow_devices)?;
+        if entry_type.is_hard_link() {
+            if let Some(target) = entry.link_name()? {
+                check_archive_entry(&target, false, allow_devices)?;
+            }
+        }
+        entry.unpack_in(path)?;
+    }
+
+    Ok(())
 }
 
 /// Extract the given .tar.xz stream and preserve all the file attributes
 pub fn extract_tar_xz<R: Read>(reader: R, path: &Path) -> Result<()> {
     let decompress = xz2::read::XzDecoder::new(reader);
-    let mut tar_processor = tar::Archive::new(decompress);
-    tar_processor.set_unpack_xattrs(true);
-    tar_processor.set_preserve_permissions(true);
-    tar_processor.unpack(path)?;
+
+    unpack_tar(decompress, path, allow_device_nodes())
+}
+
+/// Parse a line of `unsquashfs -lls` output, returns the entry path and whether it is a device node
+fn parse_unsquashfs_listing(line: &str) -> Option<(&str, bool)> {
+    let is_device = line.starts_with('b') || line.starts_with('c');
+    let (_, path) = line.split_once(" squashfs-root")?;
+    let path = path.strip_prefix('/').unwrap_or(path);
+    let path = if line.starts_with('l') {
+        path.split_once(" -> ").map_or(path, |x| x.0)
+    } else {
+        path
+    };
+
+    Some((path, is_device))
+}
+
+/// Check all the entries in the squashfs before extracting it
+fn check_squashfs(unsquashfs: &Path, path: &Path, allow_devices: bool) -> Result<()> {
+    let output = Command::new(unsquashfs)
+        .arg("-lls")
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "unsquashfs failed to list the image ({}):\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some((entry, is_device)) = parse_unsquashfs_listing(line) {
+            check_archive_entry(Path::new(entry), is_device, allow_devices)?;
+        }
+    }
 
     Ok(())
 }
 
-/// Extract the given .squashfs
-pub fn extract_squashfs(path: &Path, dist_dir: &Path, pb: &ProgressBar, total: u64) -> Result<()> {
-    let unsquashfs = Unsquashfs::default();
+/// Parse a progress line printed by `unsquashfs -percentage`
+fn parse_unsquashfs_progress(line: &str) -> Option<u64> {
+    let percentage = line.trim().trim_end_matches('%').parse::<u64>().ok()?;
+
+    Some(percentage.min(100))
+}
 
-    unsquashfs.extract(path, dist_dir, None, move |c| {
-        pb.set_position(total * c as u64 / 100);
+/// Extract the given .squashfs using `unsquashfs`
+pub fn extract_squashfs(path: &Path, dist_dir: &Path, pb: &ProgressBar, total: u64) -> Result<()> {
+    let unsquashfs = which::which("unsquashfs").map_err(|_| {
+        anyhow!("Required program `unsquashfs` is not found, please install squashfs-tools.")
     })?;
+    check_squashfs(&unsquashfs, path, allow_device_nodes())?;
+    // ownership is always preserved since we are running as root
+    let mut child = Command::new(unsquashfs)
+        .args(["-f", "-xattrs", "-percentage", "-d"])
+        .arg(dist_dir)
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stderr = child.stderr.take().unwrap();
+    // drain stderr in the background so that unsquashfs won't block on a full pipe
+    let stderr_reader = std::thread::spawn(move || {
+        let mut output = String::new();
+        stderr.read_to_string(&mut output).ok();
+        output
+    });
+    for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+        let line = line?;
+        if let Some(percentage) = line.rsplit('\r').find_map(parse_unsquashfs_progress) {
+            pb.set_position(total * percentage / 100);
+        }
+    }
+    let status = child.wait()?;
+    let stderr = stderr_reader.join().unwrap_or_default();
+    if !status.success() {
+        return Err(anyhow!(
+            "unsquashfs failed ({}):\n{}",
+            status,
+            stderr.trim()
+        ));
+    }
 
     Ok(())
 }
 
-pub fn extract_system_rootfs(path: &Path, total: u64, use_tarball: bool) -> Result<()> {
-    let f = File::open(path)?;
-    let progress_bar = indicatif::ProgressBar::new(total);
+/// Format of the OS rootfs archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootfsFormat {
+    /// Detect the format from the content of the file
+    Auto,
+    /// A .tar.xz tarball
+    Tarball,
+    Squashfs,
+    /// An OCI or Docker image archive
+    Oci,
+}
 
-    progress_bar.set_style(
-        indicatif::ProgressStyle::default_bar()
-            .template(make_progress_bar!("Extracting rootfs ..."))
-            .unwrap(),
-    );
+impl RootfsFormat {
+    /// Parse the format name given by the user (`auto`, `tar`, `squashfs` or `oci`)
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "auto" => Ok(RootfsFormat::Auto),
+            "tar" => Ok(RootfsFormat::Tarball),
+            "squashfs" => Ok(RootfsFormat::Squashfs),
+            "oci" => Ok(RootfsFormat::Oci),
+            _ => Err(RootfsError::UnsupportedRootfsFormat(name.to_owned()).into()),
+        }
+    }
 
-    progress_bar.set_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(5));
+    /// Resolve `Auto` to the actual format of the file
+    pub fn resolve(self, path: &Path) -> Result<Self> {
+        if self != RootfsFormat::Auto {
+            return Ok(self);
+        }
+        let mut magic = [0u8; 6];
+        let len = File::open(path)?.read(&mut magic)?;
+        let magic = &magic[..len];
+        if magic.starts_with(b"hsqs") {
+            Ok(RootfsFormat::Squashfs)
+        } else if magic.starts_with(b"\xfd7zXZ\0") {
+            Ok(RootfsFormat::Tarball)
+        } else if crate::oci::is_image_archive(path).unwrap_or(false) {
+            Ok(RootfsFormat::Oci)
+        } else {
+            Err(RootfsError::UnsupportedRootfsFormat(format!(
+                "unable to detect the format of {}, please specify it using `--format`",
+                path.display()
+            ))
+            .into())
+        }
+    }
+}
+
+/// Extract the rootfs as the base system, the checksum of the archive is verified
+/// if `sha256` is given and the partially extracted system is removed on mismatch
+pub fn extract_system_rootfs(
+    path: &Path,
+    total: u64,
+    format: RootfsFormat,
+    sha256: Option<&str>,
+) -> Result<()> {
+    extract_system_rootfs_to(path, total, format, sha256, Path::new(CIEL_DIST_DIR))
+}
+
+/// Extract the rootfs into a temporary directory first, and then swap it with the current one,
+/// so that the current rootfs is kept intact if the extraction fails
+pub fn replace_system_rootfs(
+    path: &Path,
+    total: u64,
+    format: RootfsFormat,
+    sha256: Option<&str>,
+) -> Result<()> {
+    let new_dir = Path::new(CIEL_DIST_NEW_DIR);
+    let old_dir = Path::new(CIEL_DIST_OLD_DIR);
+    // clean up the remnants from an interrupted replacement
+    for dir in [new_dir, old_dir] {
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+    }
+    fs::create_dir_all(new_dir)?;
+    if let Err(e) = extract_system_rootfs_to(path, total, format, sha256, new_dir) {
+        fs::remove_dir_all(new_dir).ok();
+        return Err(e);
+    }
+    fs::rename(CIEL_DIST_DIR, old_dir)?;
+    fs::rename(new_dir, CIEL_DIST_DIR)?;
+    fs::remove_dir_all(old_dir)?;
+
+    Ok(())
+}
+
+fn extract_system_rootfs_to(
+    path: &Path,
+    total: u64,
+    format: RootfsFormat,
+    sha256: Option<&str>,
+    dist_dir: &Path,
+) -> Result<()> {
+    let format = format.resolve(path)?;
+    if let (Some(sha256), RootfsFormat::Squashfs) = (sha256, format) {
+        // unsquashfs reads the file by itself, so the checksum has to be verified beforehand
+        info!("Verifying tarball checksum...");
+        check_checksum(sha256, &sha256sum_file(path)?)?;
+        info!("Checksum verified.");
+    }
+    let mut reader = HashingReader::new(File::open(path)?);
+    let progress_bar = create_progress_bar(
+        total,
+        make_progress_bar!("Extracting rootfs ..."),
+        ProgressPhase::Extract,
+    );
 
-    let dist_dir = PathBuf::from(CIEL_DIST_DIR);
     if dist_dir.exists() {
-        fs::remove_dir_all(&dist_dir).ok();
-        fs::create_dir_all(&dist_dir)?;
+        fs::remove_dir_all(dist_dir).ok();
+        fs::create_dir_all(dist_dir)?;
     }
 
     // detect if we are running in systemd-nspawn
@@ -151,27 +614,215 @@ pub fn extract_system_rootfs(path: &Path, total: u64, use_tarball: bool) -> Resu
         }
     }
 
-    let res = if use_tarball {
-        extract_tar_xz(progress_bar.wrap_read(f), &dist_dir)
-    } else {
-        extract_squashfs(path, &dist_dir, &progress_bar, total)
+    let res = match format {
+        RootfsFormat::Squashfs => extract_squashfs(path, dist_dir, &progress_bar, total),
+        RootfsFormat::Oci => crate::oci::extract_image_archive(
+            progress_bar.wrap_read(&mut reader),
+            dist_dir,
+            allow_device_nodes(),
+        ),
+        _ => extract_tar_xz(progress_bar.wrap_read(&mut reader), dist_dir),
     };
 
     if !in_systemd_nspawn {
         res?
     }
 
-    progress_bar.finish_and_clear();
+    finish_progress_bar(&progress_bar, ProgressPhase::Extract);
+
+    // the checksum is calculated while extracting
+    if let (Some(sha256), RootfsFormat::Tarball | RootfsFormat::Oci) = (sha256, format) {
+        let checksum = reader.finish()?;
+        if let Err(e) = check_checksum(sha256, &checksum) {
+            fs::remove_dir_all(dist_dir)?;
+            fs::create_dir_all(dist_dir)?;
+            return Err(e);
+        }
+        info!("Checksum verified.");
+    }
+
+    // record the architecture of the base system for later use
+    if let Some(arch) = detect_rootfs_arch(dist_dir) {
+        fs::write(CIEL_ARCH_FILE, arch)?;
+    } else {
+        fs::remove_file(CIEL_ARCH_FILE).ok();
+    }
+
+    Ok(())
+}
+
+/// Detect the architecture of the rootfs by looking at the dpkg database
+fn detect_rootfs_arch(rootfs: &Path) -> Option<String> {
+    let status = fs::read_to_string(rootfs.join("var/lib/dpkg/status")).ok()?;
+    let stanza = status
+        .split("\n\n")
+        .find(|x| x.lines().any(|line| line == "Package: dpkg"))?;
+
+    stanza
+        .lines()
+        .find_map(|line| line.strip_prefix("Architecture: "))
+        .map(|x| x.trim().to_owned())
+}
+
+/// Get the architecture of the base system recorded when the OS was loaded,
+/// the base systems loaded by older versions are inspected once and the result is recorded
+pub fn get_rootfs_arch() -> Option<String> {
+    rootfs_arch_in(Path::new(CIEL_ARCH_FILE), Path::new(CIEL_DIST_DIR))
+}
+
+fn rootfs_arch_in(arch_file: &Path, dist_dir: &Path) -> Option<String> {
+    if let Ok(arch) = fs::read_to_string(arch_file) {
+        return Some(arch.trim().to_owned());
+    }
+    let arch = detect_rootfs_arch(dist_dir)?;
+    if let Err(e) = fs::write(arch_file, &arch) {
+        warn!(
+            "Unable to record the architecture of the base system in {}: {}",
+            arch_file.display(),
+            e
+        );
+    }
+
+    Some(arch)
+}
+
+/// Get the checksum of the rootfs tarball that is currently loaded
+pub fn get_rootfs_checksum() -> Option<String> {
+    fs::read_to_string(CIEL_ROOTFS_CHECKSUM_FILE)
+        .ok()
+        .map(|x| x.trim().to_owned())
+}
+
+/// Record the checksum of the rootfs tarball that has been loaded (`None` if unknown)
+pub fn set_rootfs_checksum(checksum: Option<&str>) -> Result<()> {
+    match checksum {
+        Some(checksum) => fs::write(CIEL_ROOTFS_CHECKSUM_FILE, checksum)?,
+        None => {
+            fs::remove_file(CIEL_ROOTFS_CHECKSUM_FILE).ok();
+        }
+    }
 
     Ok(())
 }
 
+/// Which versions of ciel created and last used the workspace, and when
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkspaceMeta {
+    /// Unknown for the workspaces created before the metadata was recorded
+    pub created_by: Option<String>,
+    pub created_at: Option<String>,
+    pub last_opened_by: String,
+    pub last_opened_at: String,
+}
+
+impl WorkspaceMeta {
+    /// Update the metadata for opening the workspace at `now`,
+    /// the metadata of older workspaces is backfilled without the creation info
+    fn opened(previous: Option<WorkspaceMeta>, now: &str) -> WorkspaceMeta {
+        let (created_by, created_at) = previous
+            .map(|x| (x.created_by, x.created_at))
+            .unwrap_or_default();
+
+        WorkspaceMeta {
+            created_by,
+            created_at,
+            last_opened_by: env!("CARGO_PKG_VERSION").to_owned(),
+            last_opened_at: now.to_owned(),
+        }
+    }
+}
+
+impl std::fmt::Display for WorkspaceMeta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.created_by, &self.created_at) {
+            (Some(created_by), Some(created_at)) => {
+                writeln!(f, "Created by: ciel {} at {}", created_by, created_at)?
+            }
+            _ => writeln!(f, "Created by: unknown (older than the workspace metadata)")?,
+        }
+        write!(
+            f,
+            "Last opened by: ciel {} at {}",
+            self.last_opened_by, self.last_opened_at
+        )
+    }
+}
+
+/// Read the workspace metadata, `None` if it has not been recorded yet
+pub fn read_workspace_meta() -> Result<Option<WorkspaceMeta>> {
+    read_workspace_meta_at(Path::new(CIEL_META_FILE))
+}
+
+fn read_workspace_meta_at(path: &Path) -> Result<Option<WorkspaceMeta>> {
+    match fs::read_to_string(path) {
+        Ok(data) => Ok(Some(toml::from_str(&data)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replace the metadata file at once, an interrupted write never leaves a truncated file
+fn write_workspace_meta_at(path: &Path, meta: &WorkspaceMeta) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(toml::to_string(meta)?.as_bytes())?;
+    file.persist(path)?;
+
+    Ok(())
+}
+
+/// Record that the workspace is opened by this version of ciel
+pub fn touch_workspace_meta() -> Result<()> {
+    let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+    touch_workspace_meta_at(Path::new(CIEL_META_FILE), &now)
+}
+
+fn touch_workspace_meta_at(path: &Path, now: &str) -> Result<()> {
+    let previous = match read_workspace_meta_at(path) {
+        Ok(previous) => previous,
+        Err(e) => {
+            // keep the broken file, so that the creation info can still be recovered by hand
+            warn!(
+                "Unable to read the workspace metadata, leaving it unchanged: {:#}",
+                e
+            );
+            return Ok(());
+        }
+    };
+
+    write_workspace_meta_at(path, &WorkspaceMeta::opened(previous, now))
+}
+
+/// Return the format version of the workspace, as written in `.ciel/version`
+pub fn workspace_format_version() -> Result<String> {
+    Ok(fs::read_to_string(".ciel/version")?.trim().to_owned())
+}
+
 pub fn ciel_init() -> Result<()> {
     for dir in SKELETON_DIRS {
         fs::create_dir_all(dir)?;
     }
     let mut f = File::create(".ciel/version")?;
     f.write_all(CURRENT_CIEL_VERSION_STR.as_bytes())?;
+    // keep the creation info when upgrading
+    let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+    let path = Path::new(CIEL_META_FILE);
+    match read_workspace_meta_at(path) {
+        Ok(previous) => {
+            let mut meta = WorkspaceMeta::opened(previous, &now);
+            if meta.created_by.is_none() {
+                meta.created_by = Some(meta.last_opened_by.clone());
+                meta.created_at = Some(now);
+            }
+            write_workspace_meta_at(path, &meta)?;
+        }
+        Err(e) => {
+            warn!(
+                "Unable to read the workspace metadata, leaving it unchanged: {:#}",
+                e
+            );
+        }
+    }
 
     Ok(())
 }
@@ -200,6 +851,38 @@ pub fn is_instance_exists(instance: &str) -> bool {
     Path::new(CIEL_INST_DIR).join(instance).is_dir()
 }
 
+/// The workspace is in an older format, which is only upgraded on request,
+/// since older versions of ciel can not use the upgraded workspace
+#[derive(Debug)]
+pub struct WorkspaceNeedsUpgrade(pub usize);
+
+impl std::fmt::Display for WorkspaceNeedsUpgrade {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "This workspace is in format version {} and needs to be upgraded to version {}. \
+             Older versions of ciel can not use the workspace once it is upgraded. \
+             Please save your work, then run `ciel init --upgrade` (or pass `--auto-upgrade`).",
+            self.0, CURRENT_CIEL_VERSION
+        )
+    }
+}
+
+impl std::error::Error for WorkspaceNeedsUpgrade {}
+
+/// Return `WorkspaceNeedsUpgrade` if the workspace is in an older format
+pub fn check_workspace_version() -> Result<()> {
+    let version = workspace_format_version()?;
+    let version = version
+        .parse::<usize>()
+        .map_err(|_| anyhow!("Invalid workspace format version: {}", version))?;
+    if version < CURRENT_CIEL_VERSION {
+        return Err(WorkspaceNeedsUpgrade(version).into());
+    }
+
+    Ok(())
+}
+
 pub fn is_legacy_workspace() -> Result<bool> {
     let mut f = fs::File::open(".ciel/version")?;
     // TODO: use a more robust check
@@ -209,6 +892,49 @@ pub fn is_legacy_workspace() -> Result<bool> {
     Ok(buf[0] < CURRENT_CIEL_VERSION_STR.as_bytes()[0])
 }
 
+/// Attach the instance name to the errors from the filesystem and container layers
+pub trait InstanceContext<T> {
+    /// Prefix the error message with the instance name, e.g. `test: unmount overlay at ...`
+    fn with_instance(self, instance: &str) -> Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> InstanceContext<T> for std::result::Result<T, E> {
+    fn with_instance(self, instance: &str) -> Result<T> {
+        self.map_err(|e| e.into().context(instance.to_owned()))
+    }
+}
+
+/// The workspace is on a read-only filesystem, e.g. a btrfs snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadOnlyWorkspace;
+
+impl std::fmt::Display for ReadOnlyWorkspace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The workspace is on a read-only filesystem, only commands like `list`, `status` and `space` work here"
+        )
+    }
+}
+
+impl std::error::Error for ReadOnlyWorkspace {}
+
+/// Turn the errors caused by writing to a read-only filesystem into `ReadOnlyWorkspace`,
+/// the original error is kept as the cause
+pub fn explain_read_only(err: anyhow::Error) -> anyhow::Error {
+    let read_only = err.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.raw_os_error() == Some(libc::EROFS))
+            || cause.downcast_ref::<nix::errno::Errno>() == Some(&nix::errno::Errno::EROFS)
+    });
+    if read_only && err.downcast_ref::<ReadOnlyWorkspace>().is_none() {
+        return err.context(ReadOnlyWorkspace);
+    }
+
+    err
+}
+
 pub fn ask_for_target_arch() -> Result<&'static str> {
     // Collect all supported architectures
     let host_arch = get_host_arch_name();
@@ -239,3 +965,349 @@ pub fn ask_for_target_arch() -> Result<&'static str> {
 
     Ok(all_archs[chosen_index])
 }
+
+/// Parse a duration given by the user, e.g. `90s`, `30m`, `24h`, `7d` or `1d12h`.
+/// A number without a unit is in seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let invalid = || anyhow!("Invalid duration `{}`, expected e.g. 30m, 24h or 7d", s);
+    if s.is_empty() {
+        return Err(invalid());
+    }
+    if let Ok(seconds) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+    let mut total = 0u64;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let value = rest[..end].parse::<u64>().map_err(|_| invalid())?;
+        let unit = match rest[end..].chars().next() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 3600,
+            Some('d') => 86400,
+            Some('w') => 7 * 86400,
+            _ => return Err(invalid()),
+        };
+        total = value
+            .checked_mul(unit)
+            .and_then(|x| x.checked_add(total))
+            .ok_or_else(invalid)?;
+        rest = &rest[end + 1..];
+    }
+
+    Ok(Duration::from_secs(total))
+}
+
+/// Whether the flag is enabled in the environment, the values are interpreted like clap does
+/// for the flags read from the environment: unset, empty, `0`, `false`, `no`, `off`, ... are false
+pub fn env_flag(name: &str) -> bool {
+    std::env::var_os(name).is_some_and(|x| parse_env_flag(&x.to_string_lossy()))
+}
+
+fn parse_env_flag(value: &str) -> bool {
+    !value.is_empty()
+        && !["n", "no", "f", "false", "off", "0"]
+            .iter()
+            .any(|x| value.eq_ignore_ascii_case(x))
+}
+
+#[test]
+fn test_parse_env_flag() {
+    for value in ["1", "ON", "yes", "true", "anything"] {
+        assert!(parse_env_flag(value));
+    }
+    for value in ["", "0", "false", "False", "no", "OFF", "n", "f"] {
+        assert!(!parse_env_flag(value));
+    }
+}
+
+#[test]
+fn test_detect_rootfs_arch() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("var/lib/dpkg")).unwrap();
+    fs::write(
+        dir.path().join("var/lib/dpkg/status"),
+        "Package: aosc-aaa\nArchitecture: all\n\nPackage: dpkg\nStatus: install ok installed\nArchitecture: loongarch64\n\n",
+    )
+    .unwrap();
+    assert_eq!(
+        detect_rootfs_arch(dir.path()).as_deref(),
+        Some("loongarch64")
+    );
+    // not recorded by older versions, detected and recorded on first use
+    let arch_file = dir.path().join("arch");
+    assert_eq!(
+        rootfs_arch_in(&arch_file, dir.path()).as_deref(),
+        Some("loongarch64")
+    );
+    assert_eq!(fs::read_to_string(&arch_file).unwrap(), "loongarch64");
+    fs::write(&arch_file, "amd64\n").unwrap();
+    assert_eq!(
+        rootfs_arch_in(&arch_file, dir.path()).as_deref(),
+        Some("amd64")
+    );
+    assert!(rootfs_arch_in(&arch_file.with_extension("x"), &dir.path().join("x")).is_none());
+}
+
+#[test]
+fn test_workspace_meta() {
+    let meta = WorkspaceMeta::opened(None, "2024-10-01T00:00:00Z");
+    assert_eq!(meta.created_by, None);
+    assert_eq!(meta.last_opened_by, env!("CARGO_PKG_VERSION"));
+    let created = WorkspaceMeta {
+        created_by: Some("3.0.0".to_owned()),
+        created_at: Some("2023-01-01T00:00:00Z".to_owned()),
+        last_opened_by: "3.0.0".to_owned(),
+        last_opened_at: "2023-01-01T00:00:00Z".to_owned(),
+    };
+    let data = toml::to_string(&created).unwrap();
+    assert!(data.contains("created_by = \"3.0.0\""));
+    let meta = WorkspaceMeta::opened(toml::from_str(&data).unwrap(), "2024-10-01T00:00:00Z");
+    assert_eq!(meta.created_by.as_deref(), Some("3.0.0"));
+    assert_eq!(meta.created_at, created.created_at);
+    assert_eq!(meta.last_opened_at, "2024-10-01T00:00:00Z");
+    assert!(meta
+        .to_string()
+        .starts_with("Created by: ciel 3.0.0 at 2023-01-01T00:00:00Z\n"));
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("meta.toml");
+    fs::write(&path, &data).unwrap();
+    touch_workspace_meta_at(&path, "2024-10-01T00:00:00Z").unwrap();
+    let meta = read_workspace_meta_at(&path).unwrap().unwrap();
+    assert_eq!(meta.created_at, created.created_at);
+    assert_eq!(meta.last_opened_at, "2024-10-01T00:00:00Z");
+    // a broken file is left alone instead of losing the creation info
+    fs::write(&path, "created_by = ").unwrap();
+    touch_workspace_meta_at(&path, "2024-10-02T00:00:00Z").unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "created_by = ");
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn test_progress_line() {
+    let progress_bar = ProgressBar::hidden();
+    progress_bar.set_length(4096);
+    progress_bar.set_position(1024);
+    assert!(
+        progress_line("Downloading", &progress_bar).starts_with("Downloading 1.00 KiB/4.00 KiB (")
+    );
+}
+
+#[test]
+fn test_progress_record() {
+    let progress_bar = ProgressBar::hidden();
+    progress_bar.set_length(4096);
+    progress_bar.set_position(1024);
+    let record: serde_json::Value = serde_json::from_str(&progress_record(
+        ProgressPhase::Download,
+        &progress_bar,
+        false,
+    ))
+    .unwrap();
+    assert_eq!(
+        record,
+        serde_json::json!({"type": "progress", "phase": "download", "bytes": 1024, "total": 4096, "finished": false})
+    );
+    progress_bar.set_length(0);
+    let record = progress_record(ProgressPhase::Extract, &progress_bar, true);
+    assert!(record.contains(r#""total":null"#));
+    assert!(record.contains(r#""phase":"extract""#));
+}
+
+#[test]
+fn test_parse_unsquashfs_progress() {
+    assert_eq!(parse_unsquashfs_progress("42"), Some(42));
+    assert_eq!(parse_unsquashfs_progress(" 100%\n"), Some(100));
+    assert_eq!(parse_unsquashfs_progress("120"), Some(100));
+    assert_eq!(parse_unsquashfs_progress("created 1234 files"), None);
+}
+
+#[cfg(test)]
+fn craft_tar_entry(name: &[u8], entry_type: tar::EntryType, link: Option<&[u8]>) -> Vec<u8> {
+    let mut header = tar::Header::new_old();
+    header.as_old_mut().name[..name.len()].copy_from_slice(name);
+    if let Some(link) = link {
+        header.as_old_mut().linkname[..link.len()].copy_from_slice(link);
+    }
+    header.set_entry_type(entry_type);
+    header.set_mode(0o644);
+    header.set_size(0);
+    header.set_cksum();
+    let mut data = header.as_bytes().to_vec();
+    data.extend([0u8; 1024]);
+
+    data
+}
+
+/// Path, entry type, link target and the expected offending path
+#[cfg(test)]
+type UnsafeEntryCase<'a> = (&'a [u8], tar::EntryType, Option<&'a [u8]>, &'a str);
+
+#[test]
+fn test_unpack_tar_rejects_unsafe_entries() {
+    let dir = tempfile::tempdir().unwrap();
+    let cases: &[UnsafeEntryCase] = &[
+        (b"../evil", tar::EntryType::Regular, None, "../evil"),
+        (b"/etc/evil", tar::EntryType::Regular, None, "/etc/evil"),
+        (
+            b"etc/./../../evil",
+            tar::EntryType::Regular,
+            None,
+            "etc/./../../evil",
+        ),
+        (
+            b"etc/link",
+            tar::EntryType::Link,
+            Some(b"../../etc/shadow"),
+            "../../etc/shadow",
+        ),
+        (b"dev/sda", tar::EntryType::Block, None, "dev/sda"),
+    ];
+    for (name, entry_type, link, offending) in cases {
+        let archive = craft_tar_entry(name, *entry_type, *link);
+        let err = unpack_tar(archive.as_slice(), dir.path(), false).unwrap_err();
+        assert!(err.to_string().contains(offending), "{}", err);
+    }
+    assert!(check_archive_entry(Path::new("./dev/null"), true, false).is_ok());
+    assert!(check_archive_entry(Path::new("dev/sda"), true, true).is_ok());
+    let archive = craft_tar_entry(b"./etc/hostname", tar::EntryType::Regular, None);
+    unpack_tar(archive.as_slice(), dir.path(), false).unwrap();
+    assert!(dir.path().join("etc/hostname").is_file());
+}
+
+#[test]
+fn test_parse_unsquashfs_listing() {
+    assert_eq!(
+        parse_unsquashfs_listing(
+            "crw-rw-rw- root/root             1,  3 2024-01-01 00:00 squashfs-root/dev/null"
+        ),
+        Some(("dev/null", true))
+    );
+    assert_eq!(
+        parse_unsquashfs_listing(
+            "lrwxrwxrwx root/root                 7 2024-01-01 00:00 squashfs-root/bin -> usr/bin"
+        ),
+        Some(("bin", false))
+    );
+    assert_eq!(
+        parse_unsquashfs_listing(
+            "drwxr-xr-x root/root                61 2024-01-01 00:00 squashfs-root"
+        ),
+        Some(("", false))
+    );
+    assert_eq!(
+        parse_unsquashfs_listing("Parallel unsquashfs: Using 8 processors"),
+        None
+    );
+}
+
+#[test]
+fn test_instance_context() {
+    let err = std::fs::read_dir("/nonexistent")
+        .map_err(|e| anyhow!(e).context("read /nonexistent"))
+        .with_instance("test")
+        .unwrap_err();
+    assert_eq!(
+        format!("{:#}", err),
+        "test: read /nonexistent: No such file or directory (os error 2)"
+    );
+}
+
+#[test]
+fn test_hashing_reader() {
+    let data = b"An-An was born a rabbit";
+    let mut reader = HashingReader::new(&data[..]);
+    let mut buf = [0u8; 5];
+    reader.read_exact(&mut buf).unwrap();
+    // the rest of the stream is hashed too
+    assert_eq!(reader.finish().unwrap(), sha256sum(&data[..]).unwrap());
+    // larger than the hashing buffer
+    let data = vec![0x5au8; HASH_BUFFER_SIZE * 2 + 3];
+    let mut reader = HashingReader::new(&data[..]);
+    reader.read_exact(&mut [0u8; 7]).unwrap();
+    assert_eq!(reader.finish().unwrap(), sha256sum(&data[..]).unwrap());
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("data"), &data).unwrap();
+    assert_eq!(
+        sha256sum_file(&dir.path().join("data")).unwrap(),
+        sha256sum(&data[..]).unwrap()
+    );
+    assert!(check_checksum("abc", "abd").is_err());
+}
+
+#[test]
+fn test_rootfs_errors() {
+    let err = check_checksum("abc", "abd").unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(RootfsError::ChecksumMismatch { expected, actual }) if expected == "abc" && actual == "abd"
+    ));
+    assert!(!err.downcast_ref::<RootfsError>().unwrap().is_retryable());
+    let err = RootfsFormat::from_name("zip").unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(RootfsError::UnsupportedRootfsFormat(x)) if x == "zip"
+    ));
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("rootfs"), b"not an archive").unwrap();
+    let err = RootfsFormat::Auto
+        .resolve(&dir.path().join("rootfs"))
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(RootfsError::UnsupportedRootfsFormat(_))
+    ));
+    // nothing listens on port 1
+    let err = crate::network::download_file("http://127.0.0.1:1/rootfs.squashfs").unwrap_err();
+    let err = err.downcast_ref::<RootfsError>().unwrap();
+    assert!(err.is_retryable());
+    assert!(std::error::Error::source(err).is_some());
+    assert!(err
+        .to_string()
+        .starts_with("Failed to download http://127.0.0.1:1/rootfs.squashfs: "));
+}
+
+#[test]
+fn test_explain_read_only() {
+    let err = explain_read_only(
+        anyhow::Error::from(std::io::Error::from_raw_os_error(libc::EROFS)).context("create lock"),
+    );
+    assert_eq!(
+        err.downcast_ref::<ReadOnlyWorkspace>(),
+        Some(&ReadOnlyWorkspace)
+    );
+    assert!(format!("{:#}", err).contains("create lock"));
+    let err = explain_read_only(anyhow!(nix::errno::Errno::EROFS));
+    assert!(err.downcast_ref::<ReadOnlyWorkspace>().is_some());
+    let err = explain_read_only(anyhow::Error::from(std::io::Error::from_raw_os_error(
+        libc::ENOENT,
+    )));
+    assert!(err.downcast_ref::<ReadOnlyWorkspace>().is_none());
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+    assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(1800));
+    assert_eq!(parse_duration("24h").unwrap(), Duration::from_secs(86400));
+    assert_eq!(
+        parse_duration("1d12h").unwrap(),
+        Duration::from_secs(129600)
+    );
+    assert_eq!(parse_duration("2w").unwrap(), Duration::from_secs(1209600));
+    for invalid in [
+        "",
+        "h",
+        "24x",
+        "1.5h",
+        "-1h",
+        "24h3",
+        "99999999999999999999w",
+    ] {
+        assert!(parse_duration(invalid).is_err(), "{}", invalid);
+    }
+}
@@ -1,26 +1,251 @@
 //! This module contains configuration files related APIs
 
-use crate::common::CURRENT_CIEL_VERSION;
-use crate::{get_host_arch_name, info};
+use crate::arch::{get_host_arch_name, normalize_arch_name};
+use crate::common::{env_flag, get_rootfs_arch, CIEL_INST_DIR, CURRENT_CIEL_VERSION};
+use crate::{info, warn};
 use anyhow::{anyhow, Result};
 use console::{style, user_attended};
 use dialoguer::{theme::ColorfulTheme, Confirm, Editor, Input};
 use serde::{Deserialize, Serialize};
-use std::{ffi::OsString, path::Path};
 use std::{
-    fs,
+    ffi::OsString,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use std::{
+    fmt, fs,
     io::{Read, Write},
 };
 
 const DEFAULT_CONFIG_LOCATION: &str = ".ciel/data/config.toml";
+const INSTANCE_CONFIG_FILE: &str = "config.toml";
 const DEFAULT_APT_SOURCE: &str = "deb https://repo.aosc.io/debs/ stable main";
 const DEFAULT_AB4_CONFIG_FILE: &str = "ab4cfg.sh";
 const DEFAULT_AB4_CONFIG_LOCATION: &str = "etc/autobuild/ab4cfg.sh";
 const DEFAULT_APT_LIST_LOCATION: &str = "etc/apt/sources.list";
+const DEFAULT_EXTRA_LIST_LOCATION: &str = "etc/apt/sources.list.d/ciel-extra.list";
+const DEFAULT_TOPICS_LIST_LOCATION: &str = "etc/apt/sources.list.d/ciel-topics.list";
+const DEFAULT_TOPICS_PIN_LOCATION: &str = "etc/apt/preferences.d/ciel-topics";
+const DEFAULT_SNAPSHOT_PIN_LOCATION: &str = "etc/apt/preferences.d/ciel-snapshot";
+const DEFAULT_SNAPSHOT_APT_CONF_LOCATION: &str = "etc/apt/apt.conf.d/50ciel-snapshot";
+const DEFAULT_APT_MIRROR: &str = "https://repo.aosc.io/debs/";
 const DEFAULT_RESOLV_LOCATION: &str = "etc/systemd/resolved.conf";
+const DEFAULT_RESOLV_CONF_LOCATION: &str = "etc/resolv.conf";
 const DEFAULT_ACBS_CONFIG: &str = "etc/acbs/forest.conf";
+/// Longest wait between the attempts of the OS update
+const MAX_UPDATE_RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Version of the onboarding answers format
+const ONBOARDING_ANSWERS_VERSION: usize = 1;
+/// Keys accepted in the onboarding answers
+const ONBOARDING_ANSWERS_KEYS: &[&str] = &[
+    "version",
+    "arch",
+    "rootfs",
+    "rootfs-sha256",
+    "tree",
+    "instance",
+    "config",
+];
+
+/// Errors from loading the configuration files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The configuration was written by a newer version of ciel
+    UnsupportedVersion { found: usize, supported: usize },
+    /// Unrecognized keys (and the closest valid keys) in the configuration, in strict mode
+    UnknownKeys(Vec<(String, Option<&'static str>)>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "Configuration version {} is not supported (supported: up to {}), please upgrade ciel or use --ignore-version",
+                found, supported
+            ),
+            ConfigError::UnknownKeys(keys) => {
+                f.write_str("Unknown configuration keys: ")?;
+                for (i, (key, suggestion)) in keys.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "`{}`", key)?;
+                    if let Some(suggestion) = suggestion {
+                        write!(f, " (did you mean `{}`?)", suggestion)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Only the version field of the configuration files, parsed before the rest
+#[derive(Deserialize)]
+struct ConfigVersion {
+    #[serde(default)]
+    version: usize,
+}
+
+/// Refuse to load configuration written by a newer version of ciel,
+/// unless `CIEL_IGNORE_VERSION` is enabled
+fn check_config_version(data: &str) -> Result<()> {
+    let found = toml::from_str::<ConfigVersion>(data)?.version;
+    if found <= CURRENT_CIEL_VERSION {
+        return Ok(());
+    }
+    if env_flag("CIEL_IGNORE_VERSION") {
+        warn!(
+            "Loading configuration version {} with an older ciel, some settings may be ignored.",
+            found
+        );
+        return Ok(());
+    }
+
+    Err(ConfigError::UnsupportedVersion {
+        found,
+        supported: CURRENT_CIEL_VERSION,
+    }
+    .into())
+}
+
+/// Number of single-character edits needed to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                prev.min(row[j]).min(current) + 1
+            };
+            prev = current;
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Find the top-level keys that are not in `known`, along with the closest known key
+fn find_unknown_keys(
+    data: &str,
+    known: &[&'static str],
+) -> Result<Vec<(String, Option<&'static str>)>> {
+    let table = toml::from_str::<toml::Table>(data)?;
+    let unknown = table
+        .keys()
+        .filter(|key| !known.contains(&key.as_str()))
+        .map(|key| {
+            let suggestion = known
+                .iter()
+                .map(|x| (edit_distance(key, x), *x))
+                .filter(|(distance, _)| *distance <= 3.max(key.len() / 3))
+                .min()
+                .map(|(_, x)| x);
+            (key.to_owned(), suggestion)
+        })
+        .collect();
+
+    Ok(unknown)
+}
+
+/// Warn about the unrecognized keys in the configuration,
+/// they are refused if `CIEL_STRICT_CONFIG` is enabled
+fn lint_config_keys(data: &str, known: &[&'static str]) -> Result<()> {
+    let unknown = find_unknown_keys(data, known)?;
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    if env_flag("CIEL_STRICT_CONFIG") {
+        return Err(ConfigError::UnknownKeys(unknown).into());
+    }
+    for (key, suggestion) in unknown {
+        match suggestion {
+            Some(suggestion) => {
+                warn!(
+                    "Unknown configuration key `{}` is ignored, did you mean `{}`?",
+                    key, suggestion
+                );
+            }
+            None => {
+                warn!("Unknown configuration key `{}` is ignored.", key);
+            }
+        }
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
+    Ok(())
+}
+
+/// An extra bind mount from the host into the containers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BindMount {
+    /// Path on the host, relative paths are resolved from the workspace root
+    pub source: PathBuf,
+    /// Absolute path in the container
+    pub target: String,
+}
+
+impl FromStr for BindMount {
+    type Err = anyhow::Error;
+
+    /// Parse the mount from the `SOURCE:TARGET` form
+    fn from_str(s: &str) -> Result<Self> {
+        let (source, target) = s
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("Invalid bind mount `{}`, expected SOURCE:TARGET", s))?;
+        if source.is_empty() || target.is_empty() {
+            return Err(anyhow!(
+                "Invalid bind mount `{}`, expected SOURCE:TARGET",
+                s
+            ));
+        }
+
+        Ok(BindMount {
+            source: PathBuf::from(source),
+            target: target.to_string(),
+        })
+    }
+}
+
+/// An extra APT repository, optionally restricted to some architectures
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ExtraRepo {
+    Plain(String),
+    Restricted { line: String, arch: Vec<String> },
+}
+
+impl ExtraRepo {
+    pub fn line(&self) -> &str {
+        match self {
+            ExtraRepo::Plain(line) => line,
+            ExtraRepo::Restricted { line, .. } => line,
+        }
+    }
+
+    /// Whether this repository should be used for the given rootfs architecture
+    /// (restricted repositories are skipped when the architecture is unknown)
+    pub fn applies_to(&self, arch: Option<&str>) -> bool {
+        match self {
+            ExtraRepo::Plain(_) => true,
+            ExtraRepo::Restricted { arch: archs, .. } => {
+                arch.is_some_and(|arch| archs.iter().any(|x| x == arch))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CielConfig {
     version: usize,
     maintainer: String,
@@ -36,6 +261,71 @@ pub struct CielConfig {
     pub volatile_mount: bool,
     #[serde(default = "CielConfig::default_force_use_apt")]
     pub force_use_apt: bool,
+    /// DNS servers used by systemd-resolved in the containers
+    #[serde(rename = "dns-servers", default)]
+    pub dns_servers: Vec<IpAddr>,
+    /// Whether to keep using the fallback DNS servers shipped with systemd-resolved
+    #[serde(rename = "fallback-dns", default = "CielConfig::default_true")]
+    pub fallback_dns: bool,
+    /// Bind-mount the CA bundle of the host (/etc/ssl/certs/ca-certificates.crt) read-only into
+    /// the containers, e.g. for a TLS-intercepting proxy with a corporate CA. The containers then
+    /// trust whatever the host trusts, and the packages updating the bundle (like ca-certificates)
+    /// can not be installed or updated while it is enabled.
+    #[serde(rename = "trust-host-ca", default)]
+    pub trust_host_ca: bool,
+    /// Use the /etc/resolv.conf of the host in the containers, taking precedence over
+    /// `dns-servers`. The DNS queries from the containers go wherever the host sends its own.
+    #[serde(rename = "host-resolv-conf", default)]
+    pub host_resolv_conf: bool,
+    /// Extra APT repositories, optionally restricted to some architectures
+    #[serde(rename = "extra-apt-repos", default)]
+    pub extra_apt_repos: Vec<ExtraRepo>,
+    /// Paths to keep when rolling back instances with `ciel rollback`
+    #[serde(rename = "preserve-paths", default)]
+    pub preserve_paths: Vec<PathBuf>,
+    /// Extra bind mounts for all the instances
+    #[serde(rename = "extra-bind-mounts", default)]
+    pub extra_bind_mounts: Vec<BindMount>,
+    /// Size limit of the package download cache (in MiB)
+    #[serde(rename = "cache-limit", default)]
+    pub cache_limit: Option<u64>,
+    /// Extra paths whose contents are deleted instead of being committed
+    #[serde(rename = "commit-excludes", default)]
+    pub commit_excludes: Vec<PathBuf>,
+    /// How to notify about the build progress: `all`, `title`, `bell`, `none`,
+    /// or `command:<COMMAND>` to run a command when the build finishes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notifications: Option<String>,
+    /// How many times the OS update before each package build is attempted
+    #[serde(
+        rename = "update-attempts",
+        default = "CielConfig::default_update_attempts"
+    )]
+    pub update_attempts: u32,
+    /// Seconds to wait before the first retry of the OS update, tripled for each further retry
+    /// (up to 5 minutes)
+    #[serde(
+        rename = "update-retry-delay",
+        default = "CielConfig::default_update_retry_delay"
+    )]
+    pub update_retry_delay: u64,
+    /// Whether to fall back to apt when oma fails to update the OS
+    #[serde(rename = "update-fallback-apt", default = "CielConfig::default_true")]
+    pub update_fallback_apt: bool,
+    /// Build against the repository snapshot of the given date (YYYY-MM-DD)
+    #[serde(
+        rename = "repo-snapshot",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub repo_snapshot: Option<String>,
+    /// Unix datagram socket to publish the build and instance events to
+    #[serde(
+        rename = "event-socket",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub event_socket: Option<PathBuf>,
 }
 
 impl CielConfig {
@@ -43,13 +333,47 @@ impl CielConfig {
         cfg!(target_arch = "riscv64")
     }
 
+    const fn default_true() -> bool {
+        true
+    }
+
+    const fn default_update_attempts() -> u32 {
+        5
+    }
+
+    const fn default_update_retry_delay() -> u64 {
+        3
+    }
+
+    /// Time to wait after the given failed attempt (starting from 1) of the OS update
+    pub fn update_retry_interval(&self, attempt: u32) -> Duration {
+        Duration::from_secs(
+            self.update_retry_delay
+                .saturating_mul(3u64.saturating_pow(attempt.saturating_sub(1))),
+        )
+        .min(MAX_UPDATE_RETRY_INTERVAL)
+    }
+
     pub fn save_config(&self) -> Result<String> {
         Ok(toml::to_string(self)?)
     }
 
     pub fn load_config(data: &str) -> Result<CielConfig> {
+        check_config_version(data)?;
+        lint_config_keys(data, struct_fields::<CielConfig>())?;
+
         Ok(toml::from_str(data)?)
     }
+
+    /// Return the mirror URL used by the first `deb` entry in the sources list
+    pub fn apt_mirror(&self) -> &str {
+        self.apt_sources
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("deb "))
+            .filter_map(|line| line.split_whitespace().find(|x| x.contains("://")))
+            .next()
+            .unwrap_or(DEFAULT_APT_MIRROR)
+    }
 }
 
 impl Default for CielConfig {
@@ -65,6 +389,21 @@ impl Default for CielConfig {
             sep_mount: true,
             volatile_mount: false,
             force_use_apt: false,
+            dns_servers: Vec::new(),
+            fallback_dns: true,
+            trust_host_ca: false,
+            host_resolv_conf: false,
+            extra_apt_repos: Vec::new(),
+            preserve_paths: Vec::new(),
+            extra_bind_mounts: Vec::new(),
+            cache_limit: None,
+            commit_excludes: Vec::new(),
+            notifications: None,
+            update_attempts: CielConfig::default_update_attempts(),
+            update_retry_delay: CielConfig::default_update_retry_delay(),
+            update_fallback_apt: true,
+            repo_snapshot: None,
+            event_socket: None,
         }
     }
 }
@@ -216,6 +555,393 @@ pub fn read_config() -> Result<CielConfig> {
     CielConfig::load_config(&data)
 }
 
+/// Read the workspace configuration when the defaults are good enough,
+/// a configuration that exists but can not be read is reported instead of being ignored
+pub fn read_config_or_warn() -> Option<CielConfig> {
+    match read_config() {
+        Ok(config) => Some(config),
+        Err(e)
+            if e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) =>
+        {
+            None
+        }
+        Err(e) => {
+            warn!(
+                "Unable to read the workspace configuration, default settings are used: {:#}",
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Saves the configuration file to the current workspace
+pub fn write_config(config: &CielConfig) -> Result<()> {
+    crate::actions::check_nspawn_options(&config.extra_options)?;
+    fs::write(DEFAULT_CONFIG_LOCATION, config.save_config()?)?;
+
+    Ok(())
+}
+
+/// Convert the configuration written by an older workspace version, keeping all the values
+/// it sets and taking the defaults for the settings introduced since then
+pub fn migrate_config_data(data: &str) -> Result<CielConfig> {
+    lint_config_keys(data, struct_fields::<CielConfig>())?;
+    let legacy = toml::from_str::<toml::Table>(data)?;
+    let mut table = toml::Table::try_from(CielConfig::default())?;
+    for (key, value) in legacy {
+        if key != "version" {
+            table.insert(key, value);
+        }
+    }
+
+    Ok(table.try_into()?)
+}
+
+/// Migrate the workspace configuration to the current version, falling back to
+/// the default configuration only if the old one is missing or broken.
+/// A broken configuration is kept in a backup file next to it.
+pub fn migrate_config() -> Result<CielConfig> {
+    let config = match fs::read_to_string(DEFAULT_CONFIG_LOCATION) {
+        Ok(data) => match migrate_config_data(&data) {
+            Ok(config) => config,
+            Err(e) => {
+                let backup = backup_path(Path::new(DEFAULT_CONFIG_LOCATION));
+                fs::rename(DEFAULT_CONFIG_LOCATION, &backup).map_err(|err| {
+                    anyhow!(
+                        "Unable to parse the old configuration ({}), and unable to back it up: {}",
+                        e,
+                        err
+                    )
+                })?;
+                warn!(
+                    "Unable to parse the old configuration, using the defaults: {}",
+                    e
+                );
+                warn!(
+                    "The old configuration has been moved to {}, copy your settings from there.",
+                    backup.display()
+                );
+                CielConfig::default()
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!("No configuration found in the old workspace, using the defaults.");
+            CielConfig::default()
+        }
+        Err(e) => return Err(e.into()),
+    };
+    write_config(&config)?;
+
+    Ok(config)
+}
+
+/// A path for the backup of the given file (`<file>.bak`, then `<file>.bak.1`, ...)
+/// that does not exist yet
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    let mut candidate = PathBuf::from(&backup);
+    let mut index = 0;
+    while fs::symlink_metadata(&candidate).is_ok() {
+        index += 1;
+        let mut numbered = backup.clone();
+        numbered.push(format!(".{}", index));
+        candidate = PathBuf::from(numbered);
+    }
+
+    candidate
+}
+
+/// Per-instance settings, stored in the instance directory
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstanceConfig {
+    #[serde(default = "InstanceConfig::default_version")]
+    version: usize,
+    /// Put the instance into a private network shared with the other instances in the same zone
+    #[serde(rename = "network-zone", default)]
+    pub network_zone: Option<String>,
+    /// Do not give the instance network access through the host
+    #[serde(rename = "network-isolation", default)]
+    pub network_isolation: bool,
+    /// Extra bind mounts for this instance, overriding the workspace ones with the same target
+    #[serde(rename = "bind-mounts", default, skip_serializing_if = "Vec::is_empty")]
+    pub bind_mounts: Vec<BindMount>,
+    /// Set to false to not use the local repository in this instance,
+    /// even if it is enabled for the workspace
+    #[serde(
+        rename = "local-repo",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub local_repo: Option<bool>,
+    /// Mount the filesystem in volatile mode, overriding the workspace setting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volatile: Option<bool>,
+    /// Hostname of the container, defaults to the namespace name of the instance
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// When the instance is rolled back automatically by `ciel build`
+    #[serde(
+        rename = "rollback-policy",
+        default,
+        skip_serializing_if = "RollbackPolicy::is_default"
+    )]
+    pub rollback_policy: RollbackPolicy,
+    /// Size (in MiB) of the private tmpfs mounted on /tmp of the container,
+    /// otherwise /tmp is kept in the upper layer of the instance
+    #[serde(
+        rename = "private-tmp",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub private_tmp: Option<usize>,
+}
+
+impl Default for InstanceConfig {
+    fn default() -> Self {
+        InstanceConfig {
+            version: CURRENT_CIEL_VERSION,
+            network_zone: None,
+            network_isolation: false,
+            bind_mounts: Vec::new(),
+            local_repo: None,
+            volatile: None,
+            hostname: None,
+            rollback_policy: RollbackPolicy::default(),
+            private_tmp: None,
+        }
+    }
+}
+
+impl InstanceConfig {
+    const fn default_version() -> usize {
+        CURRENT_CIEL_VERSION
+    }
+
+    fn path(instance: &str) -> PathBuf {
+        Path::new(CIEL_INST_DIR)
+            .join(instance)
+            .join(INSTANCE_CONFIG_FILE)
+    }
+
+    /// Read the configuration of the instance, returns the defaults if it has not been configured
+    pub fn load(instance: &str) -> Result<InstanceConfig> {
+        match fs::read_to_string(Self::path(instance)) {
+            Ok(data) => {
+                check_config_version(&data)?;
+                lint_config_keys(&data, struct_fields::<InstanceConfig>())?;
+
+                Ok(toml::from_str(&data)?)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(InstanceConfig::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Read the configuration of the instance, falling back to the defaults if it is corrupted,
+    /// so that the instance can still be mounted, committed or rolled back
+    pub fn load_or_default(instance: &str) -> InstanceConfig {
+        match Self::load(instance) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!(
+                    "{}: unable to load the instance configuration, default settings are used: {:#}",
+                    instance, e
+                );
+                warn!(
+                    "Please fix or remove {} to restore the settings of the instance.",
+                    Self::path(instance).display()
+                );
+                InstanceConfig::default()
+            }
+        }
+    }
+
+    pub fn save(&self, instance: &str) -> Result<()> {
+        fs::write(Self::path(instance), toml::to_string(self)?)?;
+
+        Ok(())
+    }
+
+    /// Whether the local repository is used, it has to be enabled for both the workspace
+    /// and the instance
+    pub fn use_local_repo(&self, workspace: &CielConfig) -> bool {
+        workspace.local_repo && self.local_repo.unwrap_or(true)
+    }
+
+    /// Whether the filesystem is mounted in volatile mode
+    pub fn is_volatile(&self, workspace: &CielConfig) -> bool {
+        self.volatile.unwrap_or(workspace.volatile_mount)
+    }
+
+    /// Hostname of the container, `ns_name` is the namespace name of the instance
+    pub fn hostname(&self, ns_name: &str) -> String {
+        self.hostname.clone().unwrap_or_else(|| ns_name.to_owned())
+    }
+
+    /// Extra systemd-nspawn options for the instance
+    pub fn nspawn_options(&self) -> Vec<String> {
+        let mut options = Vec::new();
+        if self.network_isolation {
+            options.push("--private-network".to_string());
+        }
+        // a zone implies a private network, host connectivity is provided by systemd-networkd
+        if let Some(zone) = &self.network_zone {
+            options.push(format!("--network-zone={}", zone));
+        }
+        if let Some(size) = self.private_tmp {
+            options.push(format!("--tmpfs=/tmp:mode=1777,size={}M", size));
+        }
+
+        options
+    }
+}
+
+/// When an instance is rolled back automatically by `ciel build`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RollbackPolicy {
+    /// Never roll back automatically
+    Never,
+    /// Roll back before each build, the packages of a build share the instance
+    PerBuild,
+    /// Roll back before each build and after each successfully built package
+    #[default]
+    PerPackage,
+    /// Roll back before a build once the given number of builds have run since the last rollback
+    AfterNBuilds(u32),
+}
+
+impl RollbackPolicy {
+    fn is_default(&self) -> bool {
+        *self == RollbackPolicy::default()
+    }
+
+    /// Whether to roll back before a build, `builds` is the number of builds since the last rollback
+    pub fn before_build(&self, builds: u32) -> bool {
+        match self {
+            RollbackPolicy::Never => false,
+            RollbackPolicy::PerBuild | RollbackPolicy::PerPackage => true,
+            RollbackPolicy::AfterNBuilds(n) => builds >= *n,
+        }
+    }
+
+    /// Whether to roll back after each successfully built package
+    pub fn after_package(&self) -> bool {
+        *self == RollbackPolicy::PerPackage
+    }
+}
+
+impl fmt::Display for RollbackPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RollbackPolicy::Never => write!(f, "never"),
+            RollbackPolicy::PerBuild => write!(f, "per-build"),
+            RollbackPolicy::PerPackage => write!(f, "per-package"),
+            RollbackPolicy::AfterNBuilds(n) => write!(f, "after {} builds", n),
+        }
+    }
+}
+
+impl FromStr for RollbackPolicy {
+    type Err = anyhow::Error;
+
+    /// Parse `never`, `per-build`, `per-package` or a number of builds
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "never" => Ok(RollbackPolicy::Never),
+            "per-build" => Ok(RollbackPolicy::PerBuild),
+            "per-package" => Ok(RollbackPolicy::PerPackage),
+            _ => match s.parse::<u32>() {
+                Ok(n) if n > 0 => Ok(RollbackPolicy::AfterNBuilds(n)),
+                _ => Err(anyhow!(
+                    "Invalid rollback policy `{}`, expected never, per-build, per-package or a number of builds",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Check if the name can be used as a hostname (RFC 1123)
+pub fn check_hostname(name: &str) -> Result<()> {
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if name.len() > 64 || !name.split('.').all(valid_label) {
+        return Err(anyhow!(
+            "Invalid hostname `{}`: must be at most 64 characters of dot-separated letters, digits or `-`",
+            name
+        ));
+    }
+
+    Ok(())
+}
+
+/// Replace the `127.0.1.1` entry of the hosts file with one for the given hostname
+pub fn hosts_with_entry(hosts: &str, hostname: &str) -> String {
+    let mut result = hosts
+        .lines()
+        .filter(|line| line.split_whitespace().next() != Some("127.0.1.1"))
+        .fold(String::new(), |mut acc, line| {
+            acc.push_str(line);
+            acc.push('\n');
+            acc
+        });
+    result.push_str(&format!("127.0.1.1\t{}\n", hostname));
+
+    result
+}
+
+/// Check if the name can be used as a network zone (the interface name is `vz-<name>`)
+pub fn check_network_zone_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.len() > 12
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(anyhow!(
+            "Invalid network zone name `{}`: must be 1 to 12 letters, digits, `-` or `_`",
+            name
+        ));
+    }
+
+    Ok(())
+}
+
+/// Generate the systemd-resolved configuration, returns `None` if the defaults should be used
+fn generate_resolved_conf(config: &CielConfig) -> Option<String> {
+    let mut content = String::from("[Resolve]\n");
+    if !config.dnssec {
+        content.push_str("DNSSEC=no\n");
+    }
+    if !config.dns_servers.is_empty() {
+        let servers = config
+            .dns_servers
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        content.push_str(&format!("DNS={}\n", servers.join(" ")));
+    }
+    if !config.fallback_dns {
+        // an empty value disables the built-in fallback servers
+        content.push_str("FallbackDNS=\n");
+    }
+
+    if content.lines().count() > 1 {
+        Some(content)
+    } else {
+        None
+    }
+}
+
 /// Applies the given configuration (th configuration itself will not be saved to the disk)
 pub fn apply_config<P: AsRef<Path>>(root: P, config: &CielConfig) -> Result<()> {
     // write maintainer information
@@ -240,13 +966,46 @@ pub fn apply_config<P: AsRef<Path>>(root: P, config: &CielConfig) -> Result<()>
         let mut f = std::fs::File::create(apt_list_path)?;
         f.write_all(config.apt_sources.as_bytes())?;
     }
-    // write DNSSEC configuration
-    if !config.dnssec {
+    // write extra repositories applicable to the architecture of the base system
+    let arch = get_rootfs_arch();
+    let extra_repos = config
+        .extra_apt_repos
+        .iter()
+        .filter(|x| x.applies_to(arch.as_deref()))
+        .map(|x| format!("{}\n", x.line()))
+        .collect::<String>();
+    let extra_list_path = rootfs.join(DEFAULT_EXTRA_LIST_LOCATION);
+    if extra_repos.is_empty() {
+        fs::remove_file(extra_list_path).ok();
+    } else {
+        create_parent_dir(&extra_list_path)?;
+        fs::write(extra_list_path, extra_repos)?;
+    }
+    // write DNSSEC and DNS servers configuration
+    if let Some(resolved_conf) = generate_resolved_conf(config) {
         let mut resolv_path = rootfs.to_owned();
         resolv_path.push(DEFAULT_RESOLV_LOCATION);
         create_parent_dir(&resolv_path)?;
         let mut f = std::fs::File::create(resolv_path)?;
-        f.write_all(b"[Resolve]\nDNSSEC=no\n")?;
+        f.write_all(resolved_conf.as_bytes())?;
+    }
+    // systemd-resolved is not used in the container, write a static resolv.conf instead
+    if !config.dns_servers.is_empty()
+        && config
+            .extra_options
+            .iter()
+            .any(|x| x == "--resolv-conf=off")
+    {
+        let resolv_conf_path = rootfs.join(DEFAULT_RESOLV_CONF_LOCATION);
+        create_parent_dir(&resolv_conf_path)?;
+        // this is usually a symlink to the stub resolver configuration
+        fs::remove_file(&resolv_conf_path).ok();
+        let servers = config
+            .dns_servers
+            .iter()
+            .map(|x| format!("nameserver {}\n", x))
+            .collect::<String>();
+        fs::write(resolv_conf_path, servers)?;
     }
     // write acbs configuration
     let mut acbs_path = rootfs.to_owned();
@@ -258,6 +1017,376 @@ pub fn apply_config<P: AsRef<Path>>(root: P, config: &CielConfig) -> Result<()>
     Ok(())
 }
 
+/// Answers to the questions of `ciel new`, saved with `--save-answers` to set up
+/// other workspaces the same way with `--answers`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OnboardingAnswers {
+    version: usize,
+    /// Target architecture, used to pick the latest buildkit if `rootfs` is not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    /// URL or path of the OS rootfs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rootfs: Option<String>,
+    #[serde(
+        rename = "rootfs-sha256",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub rootfs_sha256: Option<String>,
+    /// URL of the ABBS tree
+    pub tree: String,
+    /// Instance to create after the initialization
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    /// The workspace configuration
+    pub config: CielConfig,
+}
+
+impl OnboardingAnswers {
+    pub fn new(config: CielConfig, tree: &str) -> Self {
+        OnboardingAnswers {
+            version: ONBOARDING_ANSWERS_VERSION,
+            arch: None,
+            rootfs: None,
+            rootfs_sha256: None,
+            tree: tree.to_owned(),
+            instance: None,
+            config,
+        }
+    }
+
+    /// Parse and validate the answers, the workspace configuration is checked
+    /// the same way as `.ciel/data/config.toml`
+    pub fn load(data: &str) -> Result<Self> {
+        let found = toml::from_str::<ConfigVersion>(data)?.version;
+        if found > ONBOARDING_ANSWERS_VERSION {
+            return Err(anyhow!(
+                "Onboarding answers version {} is not supported (supported: up to {}), please upgrade ciel",
+                found,
+                ONBOARDING_ANSWERS_VERSION
+            ));
+        }
+        lint_config_keys(data, ONBOARDING_ANSWERS_KEYS)?;
+        let mut table = toml::from_str::<toml::Table>(data)?;
+        let config = table
+            .remove("config")
+            .ok_or_else(|| anyhow!("Onboarding answers have no workspace configuration"))?;
+        let config = CielConfig::load_config(&toml::to_string(&config)?)?;
+        validate_maintainer(&config.maintainer)
+            .map_err(|e| anyhow!("Invalid maintainer `{}`: {}", config.maintainer, e))?;
+        crate::actions::check_nspawn_options(&config.extra_options)?;
+        let mut answers = toml::from_str::<OnboardingAnswers>(data)?;
+        answers.config = config;
+        if let Some(arch) = &answers.arch {
+            let name = normalize_arch_name(arch)
+                .ok_or_else(|| anyhow!("Unknown target architecture `{}`", arch))?;
+            answers.arch = Some(name.to_owned());
+        }
+
+        Ok(answers)
+    }
+
+    pub fn save(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+}
+
+/// Whether the rootfs (usually the config layer of an instance) has its own configuration
+/// written by `apply_config`, instead of inheriting the one from the base system
+pub fn has_own_config<P: AsRef<Path>>(root: P) -> bool {
+    root.as_ref().join(DEFAULT_AB4_CONFIG_LOCATION).is_file()
+}
+
+/// Settings changed between `old` and `new` that can't be applied to a running container
+/// by rewriting its files, along with whether a remount (`ciel down`) is needed
+/// instead of just a restart (`ciel stop`)
+pub fn settings_needing_restart(old: &CielConfig, new: &CielConfig) -> Vec<(&'static str, bool)> {
+    let changes = [
+        // the overlay mount options
+        (
+            "volatile-mount",
+            old.volatile_mount != new.volatile_mount,
+            true,
+        ),
+        // the bind mounts and the systemd-nspawn options
+        ("local-repo", old.local_repo != new.local_repo, false),
+        (
+            "local-sources",
+            old.local_sources != new.local_sources,
+            false,
+        ),
+        (
+            "branch-exclusive-output",
+            old.sep_mount != new.sep_mount,
+            false,
+        ),
+        (
+            "extra-bind-mounts",
+            old.extra_bind_mounts != new.extra_bind_mounts,
+            false,
+        ),
+        (
+            "nspawn-extra-options",
+            old.extra_options != new.extra_options,
+            false,
+        ),
+        (
+            "trust-host-ca",
+            old.trust_host_ca != new.trust_host_ca,
+            false,
+        ),
+        (
+            "host-resolv-conf",
+            old.host_resolv_conf != new.host_resolv_conf,
+            false,
+        ),
+        // systemd-resolved only reads its configuration when started
+        ("dnssec", old.dnssec != new.dnssec, false),
+        ("dns-servers", old.dns_servers != new.dns_servers, false),
+        ("fallback-dns", old.fallback_dns != new.fallback_dns, false),
+    ];
+
+    changes
+        .into_iter()
+        .filter(|x| x.1)
+        .map(|(key, _, remount)| (key, remount))
+        .collect()
+}
+
+/// Collects the field names of a struct from its `Deserialize` implementation
+struct StructFields(&'static [&'static str]);
+
+impl<'de> serde::Deserializer<'de> for &mut StructFields {
+    type Error = serde::de::value::Error;
+
+    fn deserialize_any<V: serde::de::Visitor<'de>>(
+        self,
+        _visitor: V,
+    ) -> std::result::Result<V::Value, Self::Error> {
+        Err(serde::de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: serde::de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> std::result::Result<V::Value, Self::Error> {
+        self.0 = fields;
+        // only the field names are needed, stop here
+        Err(serde::de::Error::custom("fields collected"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
+
+/// Keys accepted by the configuration struct, taken from its serde model
+fn struct_fields<T: serde::de::DeserializeOwned>() -> &'static [&'static str] {
+    let mut fields = StructFields(&[]);
+    T::deserialize(&mut fields).ok();
+
+    fields.0
+}
+
+/// Compare the keys, dashes and underscores are interchangeable
+fn same_key(a: &str, b: &str) -> bool {
+    a.replace('_', "-") == b.replace('_', "-")
+}
+
+/// Look up a setting of the configuration by its key, using dots for the nested values
+/// (e.g. `extra-bind-mounts.0.target`). Returns `None` if the setting is known but unset,
+/// and an error for the unknown keys.
+pub fn config_value<T: Serialize + serde::de::DeserializeOwned>(
+    config: &T,
+    key: &str,
+) -> Result<Option<toml::Value>> {
+    let mut value = toml::Value::try_from(config)?;
+    for (depth, part) in key.split('.').enumerate() {
+        value = match value {
+            toml::Value::Table(mut table) => {
+                let found = table.keys().find(|k| same_key(k, part)).cloned();
+                match found.and_then(|k| table.remove(&k)) {
+                    Some(x) => x,
+                    // unset optional settings are not serialized
+                    None if depth == 0
+                        && struct_fields::<T>().iter().any(|x| same_key(x, part)) =>
+                    {
+                        return Ok(None)
+                    }
+                    None => {
+                        let suggestion = struct_fields::<T>()
+                            .iter()
+                            .filter(|_| depth == 0)
+                            .map(|x| (edit_distance(part, x), *x))
+                            .filter(|(distance, _)| *distance <= 3.max(part.len() / 3))
+                            .min()
+                            .map(|(_, x)| format!(", did you mean `{}`?", x))
+                            .unwrap_or_default();
+                        return Err(anyhow!("Unknown configuration key `{}`{}", key, suggestion));
+                    }
+                }
+            }
+            toml::Value::Array(mut array) => match part.parse::<usize>() {
+                Ok(i) if i < array.len() => array.swap_remove(i),
+                _ => return Err(anyhow!("Unknown configuration key `{}`", key)),
+            },
+            _ => return Err(anyhow!("Unknown configuration key `{}`", key)),
+        };
+    }
+
+    Ok(Some(value))
+}
+
+/// Format a value from `config_value` for printing, the strings are not quoted
+pub fn format_config_value(value: &toml::Value) -> Result<String> {
+    Ok(match value {
+        toml::Value::String(x) => x.to_owned(),
+        toml::Value::Table(x) => toml::to_string(x)?.trim_end().to_owned(),
+        x => x.to_string(),
+    })
+}
+
+/// Add the topic repositories to the given rootfs (and prefer the packages from them).
+/// This is meant to be applied to the upper layer of an instance, so that a rollback removes them.
+pub fn apply_topics<P: AsRef<Path>>(root: P, config: &CielConfig, topics: &[String]) -> Result<()> {
+    let rootfs = root.as_ref();
+    let list_path = rootfs.join(DEFAULT_TOPICS_LIST_LOCATION);
+    let pin_path = rootfs.join(DEFAULT_TOPICS_PIN_LOCATION);
+    if topics.is_empty() {
+        fs::remove_file(list_path).ok();
+        fs::remove_file(pin_path).ok();
+        return Ok(());
+    }
+    let mirror = config.apt_mirror();
+    let mut sources = String::new();
+    let mut pins = String::new();
+    for topic in topics {
+        sources.push_str(&format!("deb {} {} main\n", mirror, topic));
+        pins.push_str(&format!(
+            "Package: *\nPin: release a={}\nPin-Priority: 910\n\n",
+            topic
+        ));
+    }
+    create_parent_dir(&list_path)?;
+    fs::write(list_path, sources)?;
+    create_parent_dir(&pin_path)?;
+    fs::write(pin_path, pins)?;
+
+    Ok(())
+}
+
+/// Check if the date can be used as a repository snapshot (YYYY-MM-DD)
+pub fn check_repo_snapshot(date: &str) -> Result<()> {
+    let valid = date.len() == 10
+        && date.char_indices().all(|(i, c)| match i {
+            4 | 7 => c == '-',
+            _ => c.is_ascii_digit(),
+        });
+    if !valid {
+        return Err(anyhow!(
+            "Invalid repository snapshot `{}`, expected a date like 2024-10-01",
+            date
+        ));
+    }
+
+    Ok(())
+}
+
+/// Point the entries of the AOSC mirror in the sources list at the snapshot of the given date,
+/// e.g. `https://repo.aosc.io/debs/` becomes `https://repo.aosc.io/snapshots/2024-10-01/`.
+/// The other repositories (including the local one) are left alone.
+pub fn snapshot_sources(sources: &str, mirror: &str, date: &str) -> String {
+    let mirror = mirror.trim_end_matches('/');
+    let mut result = String::new();
+    for line in sources.lines() {
+        let rewritten = line.trim().strip_prefix("deb ").map(|rest| {
+            let parts = rest
+                .split_whitespace()
+                .map(|part| {
+                    if part.trim_end_matches('/') != mirror {
+                        return part.to_owned();
+                    }
+                    match mirror.strip_suffix("/debs") {
+                        Some(base) => format!("{}/snapshots/{}/", base, date),
+                        None => part.to_owned(),
+                    }
+                })
+                .collect::<Vec<_>>();
+            format!("deb {}", parts.join(" "))
+        });
+        result.push_str(rewritten.as_deref().unwrap_or(line));
+        result.push('\n');
+    }
+
+    result
+}
+
+/// Use the repository snapshot of the given date in the given rootfs, or stop using it.
+/// The snapshot is preferred over the newer packages in the rootfs (but not over the
+/// local repository), and the expired release files of old snapshots are accepted.
+/// Like the topics, this is meant to be applied to the upper layer of an instance.
+pub fn apply_repo_snapshot<P: AsRef<Path>>(
+    root: P,
+    config: &CielConfig,
+    date: Option<&str>,
+) -> Result<()> {
+    let rootfs = root.as_ref();
+    let list_path = rootfs.join(DEFAULT_APT_LIST_LOCATION);
+    let pin_path = rootfs.join(DEFAULT_SNAPSHOT_PIN_LOCATION);
+    let apt_conf_path = rootfs.join(DEFAULT_SNAPSHOT_APT_CONF_LOCATION);
+    let Some(date) = date else {
+        // restore the sources list if a snapshot was used before
+        if fs::remove_file(pin_path).is_ok() && !config.apt_sources.is_empty() {
+            fs::write(list_path, &config.apt_sources)?;
+        }
+        fs::remove_file(apt_conf_path).ok();
+        return Ok(());
+    };
+    check_repo_snapshot(date)?;
+    let sources = snapshot_sources(&config.apt_sources, config.apt_mirror(), date);
+    let hosts = snapshot_hosts(&sources);
+    if hosts.is_empty() {
+        return Err(anyhow!(
+            "The mirror {} does not serve repository snapshots",
+            config.apt_mirror()
+        ));
+    }
+    // the local repository (with an empty origin) keeps the freshly built packages first
+    let mut pins = "Package: *\nPin: origin \"\"\nPin-Priority: 1002\n\n".to_string();
+    for host in hosts {
+        pins.push_str(&format!(
+            "Package: *\nPin: origin \"{}\"\nPin-Priority: 1001\n\n",
+            host
+        ));
+    }
+    create_parent_dir(&list_path)?;
+    fs::write(list_path, sources)?;
+    create_parent_dir(&pin_path)?;
+    fs::write(pin_path, pins)?;
+    create_parent_dir(&apt_conf_path)?;
+    fs::write(apt_conf_path, "Acquire::Check-Valid-Until \"false\";\n")?;
+
+    Ok(())
+}
+
+/// Hosts serving the snapshots in the sources list
+fn snapshot_hosts(sources: &str) -> Vec<&str> {
+    let mut hosts = sources
+        .split_whitespace()
+        .filter(|x| x.contains("/snapshots/"))
+        .filter_map(|x| x.split_once("://")?.1.split('/').next())
+        .collect::<Vec<_>>();
+    hosts.sort_unstable();
+    hosts.dedup();
+
+    hosts
+}
+
 #[test]
 fn test_validate_maintainer() {
     assert_eq!(
@@ -269,3 +1398,381 @@ fn test_validate_maintainer() {
         Err("Invalid format.".to_owned())
     );
 }
+
+#[test]
+fn test_apt_mirror() {
+    let mut config = CielConfig::default();
+    assert_eq!(config.apt_mirror(), "https://repo.aosc.io/debs/");
+    config.apt_sources =
+        "# comment\ndeb [trusted=yes] https://mirrors.example.org/anthon/debs stable main"
+            .to_owned();
+    assert_eq!(
+        config.apt_mirror(),
+        "https://mirrors.example.org/anthon/debs"
+    );
+}
+
+#[test]
+fn test_settings_needing_restart() {
+    let old = CielConfig::default();
+    let mut new = old.clone();
+    new.maintainer = "Someone <someone@example.com>".to_owned();
+    new.apt_sources = "deb https://mirrors.example.com/anthon/debs/ stable main".to_owned();
+    assert!(settings_needing_restart(&old, &new).is_empty());
+    new.volatile_mount = true;
+    new.dns_servers = vec!["1.1.1.1".parse().unwrap()];
+    assert_eq!(
+        settings_needing_restart(&old, &new),
+        vec![("volatile-mount", true), ("dns-servers", false)]
+    );
+}
+
+#[test]
+fn test_resolved_conf() {
+    let mut config = CielConfig::default();
+    assert_eq!(
+        generate_resolved_conf(&config).as_deref(),
+        Some("[Resolve]\nDNSSEC=no\n")
+    );
+    config.dnssec = true;
+    assert_eq!(generate_resolved_conf(&config), None);
+    config.dns_servers = vec!["10.0.0.1".parse().unwrap(), "fd00::1".parse().unwrap()];
+    config.fallback_dns = false;
+    assert_eq!(
+        generate_resolved_conf(&config).as_deref(),
+        Some("[Resolve]\nDNS=10.0.0.1 fd00::1\nFallbackDNS=\n")
+    );
+}
+
+#[test]
+fn test_extra_repos() {
+    let config = CielConfig {
+        extra_apt_repos: vec![
+            ExtraRepo::Plain("deb https://repo.aosc.io/debs/ stable main".to_owned()),
+            ExtraRepo::Restricted {
+                line: "deb https://repo.aosc.io/debs-retro/ stable main".to_owned(),
+                arch: vec!["loongson2f".to_owned(), "powerpc".to_owned()],
+            },
+        ],
+        ..Default::default()
+    };
+    let saved = config.save_config().unwrap();
+    let loaded = CielConfig::load_config(&saved).unwrap();
+    assert_eq!(loaded.extra_apt_repos, config.extra_apt_repos);
+    let mut data = CielConfig::default()
+        .save_config()
+        .unwrap()
+        .lines()
+        .filter(|x| !x.starts_with("extra-apt-repos"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    data.push_str(
+        "\nextra-apt-repos = [\"deb a b c\", { line = \"deb d e f\", arch = [\"powerpc\"] }]\n",
+    );
+    let loaded = CielConfig::load_config(&data).unwrap();
+    assert!(loaded.extra_apt_repos[0].applies_to(None));
+    assert!(!loaded.extra_apt_repos[1].applies_to(None));
+    assert!(!loaded.extra_apt_repos[1].applies_to(Some("amd64")));
+    assert!(loaded.extra_apt_repos[1].applies_to(Some("powerpc")));
+}
+
+#[test]
+fn test_instance_config() {
+    let mut config = InstanceConfig {
+        network_zone: Some("testnet".to_owned()),
+        ..Default::default()
+    };
+    let data = toml::to_string(&config).unwrap();
+    assert_eq!(
+        data.trim(),
+        "version = 3\nnetwork-zone = \"testnet\"\nnetwork-isolation = false"
+    );
+    assert_eq!(toml::from_str::<InstanceConfig>(&data).unwrap(), config);
+    assert_eq!(
+        toml::from_str::<InstanceConfig>("").unwrap(),
+        InstanceConfig::default()
+    );
+    assert_eq!(config.nspawn_options(), vec!["--network-zone=testnet"]);
+    let tmp_config = toml::from_str::<InstanceConfig>("private-tmp = 512").unwrap();
+    assert_eq!(tmp_config.private_tmp, Some(512));
+    assert_eq!(
+        tmp_config.nspawn_options(),
+        vec!["--tmpfs=/tmp:mode=1777,size=512M"]
+    );
+    assert!(check_network_zone_name("testnet").is_ok());
+    assert!(check_network_zone_name("a-very-long-zone").is_err());
+    assert!(check_network_zone_name("test net").is_err());
+    config
+        .bind_mounts
+        .push("/srv/distfiles:/distfiles".parse().unwrap());
+    let data = toml::to_string(&config).unwrap();
+    assert_eq!(toml::from_str::<InstanceConfig>(&data).unwrap(), config);
+    assert_eq!(
+        config.bind_mounts[0].source,
+        PathBuf::from("/srv/distfiles")
+    );
+    assert!("/srv/distfiles".parse::<BindMount>().is_err());
+    assert!(":/distfiles".parse::<BindMount>().is_err());
+}
+
+#[test]
+fn test_config_version() {
+    let data = CielConfig::default().save_config().unwrap();
+    assert!(check_config_version(&data).is_ok());
+    assert!(check_config_version("network-zone = \"a\"").is_ok());
+    let err = check_config_version("version = 4").unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ConfigError>(),
+        Some(&ConfigError::UnsupportedVersion {
+            found: 4,
+            supported: CURRENT_CIEL_VERSION
+        })
+    );
+}
+
+#[test]
+fn test_unknown_keys() {
+    let config = CielConfig {
+        cache_limit: Some(1024),
+        notifications: Some("none".to_owned()),
+        ..Default::default()
+    };
+    let data = config.save_config().unwrap();
+    assert!(find_unknown_keys(&data, struct_fields::<CielConfig>())
+        .unwrap()
+        .is_empty());
+    let instance = InstanceConfig {
+        network_zone: Some("a".to_owned()),
+        local_repo: Some(false),
+        volatile: Some(true),
+        bind_mounts: vec!["/a:/b".parse().unwrap()],
+        ..Default::default()
+    };
+    let data = toml::to_string(&instance).unwrap();
+    assert!(find_unknown_keys(&data, struct_fields::<InstanceConfig>())
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        find_unknown_keys(
+            "extra-apt-repo = []\nfoo = 1",
+            struct_fields::<CielConfig>()
+        )
+        .unwrap(),
+        vec![
+            ("extra-apt-repo".to_owned(), Some("extra-apt-repos")),
+            ("foo".to_owned(), None)
+        ]
+    );
+    let keys = struct_fields::<InstanceConfig>();
+    assert!(keys.contains(&"rollback-policy") && !keys.contains(&"rollback_policy"));
+    assert_eq!(
+        find_unknown_keys("rollback-polcy = 'never'", keys).unwrap(),
+        vec![("rollback-polcy".to_owned(), Some("rollback-policy"))]
+    );
+    // the keys are only accepted in their own spelling
+    assert_eq!(
+        find_unknown_keys("local-repo = false", struct_fields::<CielConfig>()).unwrap(),
+        vec![("local-repo".to_owned(), Some("local_repo"))]
+    );
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    let err = ConfigError::UnknownKeys(vec![("foo".to_owned(), Some("bar"))]);
+    assert_eq!(
+        err.to_string(),
+        "Unknown configuration keys: `foo` (did you mean `bar`?)"
+    );
+}
+
+#[test]
+fn test_hostname() {
+    let mut config = InstanceConfig::default();
+    assert_eq!(config.hostname("main-1a2b"), "main-1a2b");
+    config.hostname = Some("builder".to_owned());
+    assert_eq!(config.hostname("main-1a2b"), "builder");
+    assert!(check_hostname("builder-1.example.org").is_ok());
+    assert!(check_hostname("-builder").is_err());
+    assert!(check_hostname("build_er").is_err());
+    assert!(check_hostname("").is_err());
+    assert_eq!(
+        hosts_with_entry("127.0.0.1\tlocalhost\n127.0.1.1 old\n", "builder"),
+        "127.0.0.1\tlocalhost\n127.0.1.1\tbuilder\n"
+    );
+    assert_eq!(hosts_with_entry("", "builder"), "127.0.1.1\tbuilder\n");
+}
+
+#[test]
+fn test_update_retry_interval() {
+    let mut config = CielConfig::default();
+    assert_eq!(config.update_retry_interval(1), Duration::from_secs(3));
+    assert_eq!(config.update_retry_interval(3), Duration::from_secs(27));
+    config.update_retry_delay = 10;
+    assert_eq!(config.update_retry_interval(2), Duration::from_secs(30));
+    assert_eq!(config.update_retry_interval(5), Duration::from_secs(300));
+    assert_eq!(config.update_retry_interval(100), Duration::from_secs(300));
+    config.update_retry_delay = u64::MAX;
+    assert_eq!(config.update_retry_interval(1), Duration::from_secs(300));
+}
+
+#[test]
+fn test_rollback_policy() {
+    assert_eq!(
+        "never".parse::<RollbackPolicy>().unwrap(),
+        RollbackPolicy::Never
+    );
+    assert_eq!(
+        "3".parse::<RollbackPolicy>().unwrap(),
+        RollbackPolicy::AfterNBuilds(3)
+    );
+    assert!("0".parse::<RollbackPolicy>().is_err());
+    assert!("sometimes".parse::<RollbackPolicy>().is_err());
+    assert!(RollbackPolicy::PerBuild.before_build(0));
+    assert!(!RollbackPolicy::PerBuild.after_package());
+    assert!(!RollbackPolicy::AfterNBuilds(3).before_build(2));
+    assert!(RollbackPolicy::AfterNBuilds(3).before_build(3));
+    let config = InstanceConfig {
+        rollback_policy: RollbackPolicy::AfterNBuilds(3),
+        ..Default::default()
+    };
+    let data = toml::to_string(&config).unwrap();
+    let config: InstanceConfig = toml::from_str(&data).unwrap();
+    assert_eq!(config.rollback_policy, RollbackPolicy::AfterNBuilds(3));
+    let config: InstanceConfig = toml::from_str("rollback-policy = \"per-build\"").unwrap();
+    assert_eq!(config.rollback_policy, RollbackPolicy::PerBuild);
+}
+
+#[test]
+fn test_repo_snapshot() {
+    assert!(check_repo_snapshot("2024-10-01").is_ok());
+    assert!(check_repo_snapshot("2024-1-01").is_err());
+    assert!(check_repo_snapshot("../../etc").is_err());
+    let sources = "deb https://repo.aosc.io/debs/ stable main\n# comment\ndeb https://example.org/debs stable main\ndeb [trusted=yes] file:///debs/ /\n";
+    let rewritten = snapshot_sources(sources, "https://repo.aosc.io/debs/", "2024-10-01");
+    assert_eq!(
+        rewritten,
+        "deb https://repo.aosc.io/snapshots/2024-10-01/ stable main\n# comment\ndeb https://example.org/debs stable main\ndeb [trusted=yes] file:///debs/ /\n"
+    );
+    assert_eq!(snapshot_hosts(&rewritten), vec!["repo.aosc.io"]);
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = CielConfig::default();
+    apply_repo_snapshot(dir.path(), &config, Some("2024-10-01")).unwrap();
+    assert!(
+        fs::read_to_string(dir.path().join(DEFAULT_APT_LIST_LOCATION))
+            .unwrap()
+            .contains("/snapshots/2024-10-01/")
+    );
+    let pins = fs::read_to_string(dir.path().join(DEFAULT_SNAPSHOT_PIN_LOCATION)).unwrap();
+    assert!(pins.contains("Pin: origin \"\"\nPin-Priority: 1002"));
+    assert!(pins.contains("Pin: origin \"repo.aosc.io\"\nPin-Priority: 1001"));
+    apply_repo_snapshot(dir.path(), &config, None).unwrap();
+    assert_eq!(
+        fs::read_to_string(dir.path().join(DEFAULT_APT_LIST_LOCATION)).unwrap(),
+        DEFAULT_APT_SOURCE
+    );
+    assert!(!dir.path().join(DEFAULT_SNAPSHOT_PIN_LOCATION).exists());
+    assert!(!dir.path().join(DEFAULT_SNAPSHOT_APT_CONF_LOCATION).exists());
+}
+
+#[test]
+fn test_migrate_config() {
+    let data = fs::read_to_string(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/legacy-workspace/config.toml"),
+    )
+    .unwrap();
+    let config = migrate_config_data(&data).unwrap();
+    assert_eq!(config.version, CURRENT_CIEL_VERSION);
+    assert_eq!(config.maintainer, "Old Maintainer <old@example.com>");
+    assert!(config.dnssec);
+    assert_eq!(
+        config.apt_sources,
+        "deb https://mirrors.example.com/anthon/debs/ stable main"
+    );
+    assert!(!config.local_repo);
+    assert!(config.local_sources);
+    assert_eq!(config.extra_options, vec!["--private-network".to_string()]);
+    assert!(!config.sep_mount);
+    assert!(config.volatile_mount);
+    // settings introduced after version 2 take their defaults
+    assert_eq!(config.update_attempts, 5);
+    assert!(config.fallback_dns);
+    assert!(config.extra_apt_repos.is_empty());
+
+    assert!(migrate_config_data("maintainer = [").is_err());
+}
+
+#[test]
+fn test_backup_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    assert_eq!(backup_path(&path), dir.path().join("config.toml.bak"));
+    fs::write(dir.path().join("config.toml.bak"), "").unwrap();
+    assert_eq!(backup_path(&path), dir.path().join("config.toml.bak.1"));
+}
+
+#[test]
+fn test_config_value() {
+    let mut config = InstanceConfig {
+        network_zone: Some("testnet".to_owned()),
+        ..Default::default()
+    };
+    config
+        .bind_mounts
+        .push("/srv/distfiles:/distfiles".parse().unwrap());
+    let get = |key| config_value(&config, key).map(|x| x.map(|x| format_config_value(&x).unwrap()));
+    assert_eq!(get("network-zone").unwrap().as_deref(), Some("testnet"));
+    assert_eq!(get("network_isolation").unwrap().as_deref(), Some("false"));
+    assert_eq!(
+        get("bind-mounts.0.target").unwrap().as_deref(),
+        Some("/distfiles")
+    );
+    // known but unset
+    assert_eq!(get("private-tmp").unwrap(), None);
+    assert!(get("bind-mounts.1").is_err());
+    assert!(get("network-zone.name").is_err());
+    let err = get("hostnme").unwrap_err().to_string();
+    assert!(err.contains("did you mean `hostname`?"));
+    let workspace = CielConfig::default();
+    assert_eq!(
+        config_value(&workspace, "force-use-apt").unwrap(),
+        Some(toml::Value::Boolean(workspace.force_use_apt))
+    );
+    assert_eq!(config_value(&workspace, "event-socket").unwrap(), None);
+}
+
+#[test]
+fn test_onboarding_answers() {
+    let config = CielConfig {
+        maintainer: "Tester <tester@aosc.io>".to_owned(),
+        dns_servers: vec!["192.0.2.1".parse().unwrap()],
+        ..Default::default()
+    };
+    let mut answers = OnboardingAnswers::new(config, "https://example.com/tree.git");
+    answers.arch = Some("x86_64".to_owned());
+    answers.instance = Some("main".to_owned());
+    let data = answers.save().unwrap();
+    assert!(data.starts_with("version = 1\n"));
+    let loaded = OnboardingAnswers::load(&data).unwrap();
+    // the aliases are resolved
+    assert_eq!(loaded.arch.as_deref(), Some("amd64"));
+    assert_eq!(loaded.instance.as_deref(), Some("main"));
+    assert_eq!(loaded.tree, "https://example.com/tree.git");
+    assert_eq!(
+        loaded.config.save_config().unwrap(),
+        answers.config.save_config().unwrap()
+    );
+    // same checks as the workspace configuration
+    let invalid = [
+        data.replace("version = 1\n", "version = 2\n"),
+        data.replace("x86_64", "sparc64"),
+        data.replace("Tester <tester@aosc.io>", "tester"),
+        data.replace(
+            "nspawn-extra-options = []",
+            "nspawn-extra-options = [\"--machine=x\"]",
+        ),
+        data.replace("[config]\nversion = 3\n", "[config]\nversion = 99\n"),
+        data.split("[config]").next().unwrap().to_owned(),
+    ];
+    for invalid in invalid {
+        assert_ne!(invalid, data);
+        assert!(OnboardingAnswers::load(&invalid).is_err(), "{}", invalid);
+    }
+}
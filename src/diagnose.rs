@@ -1,33 +1,167 @@
 use anyhow::{anyhow, bail, Result};
 use console::style;
 use fs3::statvfs;
-use indicatif::HumanBytes;
+use indicatif::{HumanBytes, HumanDuration};
+use serde::Serialize;
 use std::env;
 use std::sync::mpsc::channel;
-use std::{fs::File, io::BufRead, time::Duration};
-use std::{
-    io::{BufReader, Write},
-    thread,
-};
+use std::{fs::File, path::Path, time::Duration};
+use std::{io::Write, thread};
 use tempfile::tempfile_in;
 use which::which;
 use zbus::blocking::Connection;
 use zbus::proxy;
 
-use crate::error;
+use crate::actions::{resolve_ca_bundle, HOST_CA_BUNDLE};
+use crate::arch::get_host_arch_name;
+use crate::common::{get_rootfs_arch, read_workspace_meta, workspace_format_version};
+use crate::config::{read_config, InstanceConfig};
+use crate::machine::{
+    instance_integrity, is_networkd_running, list_instances_simple, repair_instance,
+};
+use crate::network::server_time;
+use crate::overlayfs::{load_overlayfs_support, test_overlay_usability};
+use crate::{error, info, warn};
 
 const TEST_TEXT: &[u8] = b"An-An was born a rabbit, but found herself a girl with bunny ears and tails when she woke up one day. She couldn't seem to remember why.";
-const TEST_PROGRAMS: &[&str] = &["systemd-nspawn", "systemd-run", "unsquashfs"];
-const TEST_CASES: &[&dyn Fn() -> Result<String>] = &[
-    &test_sd_bus,
-    &test_io_simple,
-    &test_required_binaries,
-    &test_fs_support,
-    &test_vm_container,
-    &test_disk_io,
-    &test_disk_space,
-    &test_editor,
+/// Clock skew (in seconds) against the repository that is tolerated by apt: a Release file
+/// dated further in the future than `Acquire::Max-FutureTime` (10 seconds by default,
+/// see apt.conf(5)) is rejected as not valid yet
+const CLOCK_SKEW_TOLERANCE: i64 = 10;
+/// Required programs and the packages providing them on
+/// Debian/Ubuntu, Fedora, Arch Linux and AOSC OS respectively
+const TEST_PROGRAMS: &[(&str, [&str; 4])] = &[
+    (
+        "systemd-nspawn",
+        [
+            "systemd-container",
+            "systemd-container",
+            "systemd",
+            "systemd",
+        ],
+    ),
+    ("systemd-run", ["systemd", "systemd", "systemd", "systemd"]),
+    (
+        "unsquashfs",
+        [
+            "squashfs-tools",
+            "squashfs-tools",
+            "squashfs-tools",
+            "squashfs-tools",
+        ],
+    ),
 ];
+const DISTROS: [&str; 4] = ["Debian/Ubuntu", "Fedora", "Arch Linux", "AOSC OS"];
+/// Diagnostic tests and their IDs, the IDs are part of the JSON output and must not change
+const TEST_CASES: &[(&str, &dyn Fn() -> Result<String>)] = &[
+    ("workspace", &test_workspace),
+    ("sd-bus", &test_sd_bus),
+    ("io-simple", &test_io_simple),
+    ("required-binaries", &test_required_binaries),
+    ("optional-binaries", &test_optional_binaries),
+    ("fs-support", &test_fs_support),
+    ("cgroup-v2", &test_cgroup_v2),
+    ("binfmt", &test_binfmt),
+    ("network-zone", &test_network_zone),
+    ("host-trust", &test_host_trust),
+    ("clock", &test_clock),
+    ("instance-integrity", &test_instance_integrity),
+    ("vm-container", &test_vm_container),
+    ("disk-io", &test_disk_io),
+    ("disk-space", &test_disk_space),
+    ("editor", &test_editor),
+];
+
+/// Outcome of a diagnostic test
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Ok,
+    /// The test could not run, e.g. outside of a workspace
+    Skipped,
+    Warning,
+    Failure,
+}
+
+impl Severity {
+    /// Exit code of `ciel doctor`: 0 if everything is fine, 1 for warnings and 2 for failures
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Severity::Ok | Severity::Skipped => 0,
+            Severity::Warning => 1,
+            Severity::Failure => 2,
+        }
+    }
+}
+
+/// Result of a diagnostic test
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    id: &'static str,
+    severity: Severity,
+    message: String,
+}
+
+impl CheckResult {
+    fn new(id: &'static str, result: Result<String>) -> Self {
+        let (severity, message) = match result {
+            Ok(msg) => match msg.strip_prefix('!') {
+                Some(msg) => (Severity::Warning, msg.to_owned()),
+                None => (Severity::Ok, msg),
+            },
+            Err(err) if err.downcast_ref::<Skipped>().is_some() => {
+                (Severity::Skipped, err.to_string())
+            }
+            Err(err) => (Severity::Failure, err.to_string()),
+        };
+
+        CheckResult {
+            id,
+            severity,
+            message,
+        }
+    }
+
+    fn print(&self) {
+        match self.severity {
+            Severity::Ok => println!(
+                "{} {}",
+                style("✓").green(),
+                style(&self.message).green().bold()
+            ),
+            Severity::Skipped => println!("{} {}", style("-").dim(), style(&self.message).dim()),
+            Severity::Warning => println!(
+                "{} {}",
+                style("!").yellow(),
+                style(&self.message).yellow().bold()
+            ),
+            Severity::Failure => {
+                println!("{} {}", style("x").red(), style(&self.message).red().bold())
+            }
+        }
+    }
+}
+
+/// A test that can't run in the current environment
+#[derive(Debug)]
+struct Skipped(&'static str);
+
+impl std::fmt::Display for Skipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for Skipped {}
+
+/// Fail with `Skipped` when not running in a workspace
+fn require_workspace(test: &'static str) -> Result<()> {
+    if !Path::new(".ciel").is_dir() {
+        return Err(Skipped(test).into());
+    }
+
+    Ok(())
+}
 
 #[proxy(
     interface = "org.freedesktop.systemd1.Manager",
@@ -44,6 +178,17 @@ trait Systemd1Manager {
     fn virtualization(&self) -> zbus::Result<String>;
 }
 
+fn test_workspace() -> Result<String> {
+    require_workspace("Not in a workspace, skipped checking the workspace")?;
+    let version = workspace_format_version()?;
+    let meta = match read_workspace_meta()? {
+        Some(meta) => meta.to_string().replace('\n', ", "),
+        None => "metadata not recorded yet".to_owned(),
+    };
+
+    Ok(format!("Workspace format {} ({})", version, meta))
+}
+
 fn test_sd_bus() -> Result<String> {
     let conn = Connection::system()?;
     let proxy = Systemd1ManagerProxyBlocking::new(&conn)?;
@@ -59,26 +204,42 @@ fn test_io_simple() -> Result<String> {
     Ok("Basic I/O operations seem to be working".to_string())
 }
 
+fn install_hint(packages: &[&str; 4]) -> String {
+    DISTROS
+        .iter()
+        .zip(packages)
+        .map(|(distro, package)| format!("`{}` on {}", package, distro))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 fn test_required_binaries() -> Result<String> {
-    for binary in TEST_PROGRAMS {
+    for (binary, packages) in TEST_PROGRAMS {
         if which(binary).is_err() {
-            return Err(anyhow!("Required program `{}` is not found", binary));
+            return Err(anyhow!(
+                "Required program `{}` is not found, please install {}",
+                binary,
+                install_hint(packages)
+            ));
         }
     }
     Ok("Required binaries are correctly installed".to_string())
 }
 
+fn test_optional_binaries() -> Result<String> {
+    if which("machinectl").is_err() {
+        return Ok(format!(
+            "!Optional program `machinectl` is not found, you may install {}",
+            install_hint(&TEST_PROGRAMS[0].1)
+        ));
+    }
+    Ok("Optional binaries are correctly installed".to_string())
+}
+
 fn test_fs_support() -> Result<String> {
-    let f = File::open("/proc/filesystems")?;
-    let reader = BufReader::new(f);
-    for line in reader.lines() {
-        let line = line?;
-        let mut fs_type = line.splitn(2, '\t');
-        if let Some(fs_type) = fs_type.nth(1) {
-            if fs_type == "overlay" {
-                return Ok("Filesystem support seems to be sufficient".to_string());
-            }
-        }
+    load_overlayfs_support()?;
+    if test_overlay_usability().is_ok() {
+        return Ok("Filesystem support seems to be sufficient".to_string());
     }
 
     Err(anyhow!(
@@ -86,6 +247,170 @@ fn test_fs_support() -> Result<String> {
     ))
 }
 
+fn test_cgroup_v2() -> Result<String> {
+    if Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+        return Ok("cgroup v2 is available".to_string());
+    }
+
+    Err(anyhow!(
+        "cgroup v2 is not available, please boot with `systemd.unified_cgroup_hierarchy=1`"
+    ))
+}
+
+/// Map the AOSC OS architecture names to the names used by QEMU user emulation
+fn qemu_arch_name(arch: &str) -> Option<&'static str> {
+    match arch {
+        "amd64" => Some("x86_64"),
+        "arm64" => Some("aarch64"),
+        "ppc64el" => Some("ppc64le"),
+        "ppc64" => Some("ppc64"),
+        "mips64r6el" | "loongson3" => Some("mips64el"),
+        "riscv64" => Some("riscv64"),
+        "loongarch64" => Some("loongarch64"),
+        "armv4" | "armv6hf" | "armv7hf" => Some("arm"),
+        "i486" => Some("i386"),
+        "m68k" => Some("m68k"),
+        "powerpc" => Some("ppc"),
+        _ => None,
+    }
+}
+
+fn test_binfmt() -> Result<String> {
+    require_workspace("Not in a workspace, skipped checking binfmt emulation")?;
+    let rootfs_arch = match get_rootfs_arch() {
+        Some(arch) if Some(arch.as_str()) != get_host_arch_name() => arch,
+        _ => return Ok("No foreign architecture emulation is needed".to_string()),
+    };
+    let qemu_arch = qemu_arch_name(&rootfs_arch)
+        .ok_or_else(|| anyhow!("Unknown rootfs architecture `{}`", rootfs_arch))?;
+    if Path::new("/proc/sys/fs/binfmt_misc")
+        .join(format!("qemu-{}", qemu_arch))
+        .exists()
+    {
+        return Ok(format!(
+            "binfmt emulation for {} is registered",
+            rootfs_arch
+        ));
+    }
+
+    Err(anyhow!(
+        "binfmt emulation for {} is not registered, please install {}",
+        rootfs_arch,
+        install_hint(&[
+            "qemu-user-static binfmt-support",
+            "qemu-user-static",
+            "qemu-user-static-binfmt",
+            "qemu-user-static"
+        ])
+    ))
+}
+
+fn test_network_zone() -> Result<String> {
+    require_workspace("Not in a workspace, skipped checking network zones")?;
+    let instances = list_instances_simple().unwrap_or_default();
+    let uses_zone = instances.iter().any(|instance| {
+        InstanceConfig::load(instance).is_ok_and(|config| config.network_zone.is_some())
+    });
+    if !uses_zone {
+        return Ok("No network zones are used".to_string());
+    }
+    if is_networkd_running()? {
+        return Ok("systemd-networkd is running for network zones".to_string());
+    }
+
+    Err(anyhow!(
+        "Network zones are used but systemd-networkd is not running, try `systemctl enable --now systemd-networkd`"
+    ))
+}
+
+fn test_host_trust() -> Result<String> {
+    require_workspace("Not in a workspace, skipped checking the host resources")?;
+    let config = read_config().map_err(|_| {
+        Skipped("The workspace is not configured, skipped checking the host resources")
+    })?;
+    let shared = [
+        (config.trust_host_ca, "CA certificates (trust-host-ca)"),
+        (
+            config.host_resolv_conf,
+            "DNS configuration (host-resolv-conf)",
+        ),
+    ]
+    .iter()
+    .filter(|x| x.0)
+    .map(|x| x.1)
+    .collect::<Vec<_>>();
+    if shared.is_empty() {
+        return Ok("Containers use their own CA certificates and DNS configuration".to_string());
+    }
+    if config.trust_host_ca {
+        resolve_ca_bundle(Path::new(HOST_CA_BUNDLE))?;
+    }
+
+    Ok(format!(
+        "Containers share the {} of the host",
+        shared.join(" and ")
+    ))
+}
+
+fn test_clock() -> Result<String> {
+    let mirror = read_config().unwrap_or_default().apt_mirror().to_owned();
+    let server = server_time(&mirror)
+        .map_err(|_| Skipped("Unable to reach the repository, skipped checking the clock"))?;
+    let skew = (server - time::OffsetDateTime::now_utc()).whole_seconds();
+    let behind = HumanDuration(Duration::from_secs(skew.unsigned_abs()));
+    if skew > CLOCK_SKEW_TOLERANCE {
+        bail!("The clock is {} behind {}, apt will reject the repository as not valid yet. Please sync the clock, e.g. `timedatectl set-ntp true`", behind, mirror);
+    }
+    if skew < -CLOCK_SKEW_TOLERANCE {
+        return Ok(format!("!The clock is {} ahead of {}, the timestamps of the builds and the local repository will be wrong", behind, mirror));
+    }
+
+    Ok(format!("The clock is in sync with {}", mirror))
+}
+
+fn test_instance_integrity() -> Result<String> {
+    require_workspace("Not in a workspace, skipped checking the instances")?;
+    let mut problems = Vec::new();
+    for instance in list_instances_simple()? {
+        for problem in instance_integrity(&instance)? {
+            problems.push(format!("{}: {}", instance, problem));
+        }
+    }
+    if problems.is_empty() {
+        return Ok("Instance directories are intact".to_string());
+    }
+
+    Err(anyhow!(
+        "Some instances are damaged, try `ciel doctor --fix`: {}",
+        problems.join("; ")
+    ))
+}
+
+/// Recreate the missing layers of the damaged instances, before checking them again
+fn fix_instances() -> Result<()> {
+    if !Path::new(".ciel").is_dir() {
+        return Ok(());
+    }
+    for instance in list_instances_simple()? {
+        if instance_integrity(&instance)?.is_empty() {
+            continue;
+        }
+        match repair_instance(&instance) {
+            Ok(()) => {
+                info!("{}: missing layers recreated.", instance);
+            }
+            Err(e) => {
+                warn!(
+                    "{}: unable to recreate the missing layers: {:#}",
+                    instance, e
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
 fn test_vm_container() -> Result<String> {
     let conn = Connection::system()?;
     let proxy = Systemd1ManagerProxyBlocking::new(&conn)?;
@@ -148,41 +473,74 @@ fn test_editor() -> Result<String> {
     }
 }
 
-/// Carry out the diagnostic tests
-pub fn run_diagnose() -> Result<()> {
-    let mut lines = vec![];
-    let mut has_error = false;
-    for test in TEST_CASES {
-        match test() {
-            Ok(msg) => {
-                if msg.starts_with('!') {
-                    lines.push(format!(
-                        "{} {}",
-                        style("!").yellow(),
-                        style(msg.strip_prefix('!').unwrap()).yellow().bold()
-                    ));
-                    continue;
-                }
-                lines.push(format!(
-                    "{} {}",
-                    style("✓").green(),
-                    style(msg).green().bold()
-                ))
-            }
-            Err(err) => {
-                has_error = true;
-                lines.push(format!("{} {}", style("x").red(), style(err).red().bold()));
-                break;
-            }
-        }
+/// Carry out the diagnostic tests, returns the exit code for the worst result.
+/// With `json`, the results are printed as a JSON array.
+/// With `fix`, the damaged instances are repaired before the tests
+pub fn run_diagnose(json: bool, fix: bool) -> Result<i32> {
+    if fix {
+        fix_instances()?;
     }
+    let results = TEST_CASES
+        .iter()
+        .map(|(id, test)| CheckResult::new(id, test()))
+        .collect::<Vec<_>>();
+    let worst = results
+        .iter()
+        .map(|x| x.severity)
+        .max()
+        .unwrap_or(Severity::Ok);
 
-    for line in lines {
-        println!("{}", line);
-    }
-    if has_error {
-        return Err(anyhow!("Test error detected"));
+    if json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    } else {
+        for result in results.iter() {
+            result.print();
+        }
+        if worst == Severity::Failure {
+            error!("Test error detected");
+        }
     }
 
-    Ok(())
+    Ok(worst.exit_code())
+}
+
+#[test]
+fn test_install_hint() {
+    assert_eq!(
+        install_hint(&TEST_PROGRAMS[2].1),
+        "`squashfs-tools` on Debian/Ubuntu, `squashfs-tools` on Fedora, `squashfs-tools` on Arch Linux, `squashfs-tools` on AOSC OS"
+    );
+    assert_eq!(qemu_arch_name("loongson3"), Some("mips64el"));
+}
+
+#[test]
+fn test_check_result() {
+    let result = CheckResult::new("a", Ok("fine".to_string()));
+    assert_eq!(result.severity, Severity::Ok);
+    let result = CheckResult::new("b", Ok("!meh".to_string()));
+    assert_eq!(result.severity, Severity::Warning);
+    assert_eq!(result.message, "meh");
+    let result = CheckResult::new("c", Err(Skipped("no workspace").into()));
+    assert_eq!(result.severity, Severity::Skipped);
+    assert_eq!(
+        serde_json::to_string(&result).unwrap(),
+        r#"{"id":"c","severity":"skipped","message":"no workspace"}"#
+    );
+    let result = CheckResult::new("d", Err(anyhow!("broken")));
+    assert_eq!(result.severity, Severity::Failure);
+    assert_eq!(Severity::Skipped.exit_code(), 0);
+    assert_eq!(
+        [Severity::Warning, Severity::Ok]
+            .iter()
+            .max()
+            .unwrap()
+            .exit_code(),
+        1
+    );
+    assert_eq!(Severity::Failure.exit_code(), 2);
+    // the IDs are unique
+    let mut ids = TEST_CASES.iter().map(|x| x.0).collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids.dedup();
+    assert_eq!(ids.len(), TEST_CASES.len());
 }
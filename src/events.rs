@@ -0,0 +1,117 @@
+//! Build and instance events, published as JSON datagrams to a Unix socket
+//! (`CIEL_EVENT_SOCKET` or the `event-socket` setting) for desktop notifiers and the like
+
+use console::style;
+use serde::Serialize;
+use std::{io, os::unix::net::UnixDatagram, path::Path, sync::OnceLock};
+
+use crate::{machine::ContainerState, warn};
+
+/// An event published to the socket, tagged with its kind in the `event` field
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    PackageStarted {
+        instance: &'a str,
+        package: &'a str,
+        index: usize,
+        total: usize,
+    },
+    UpdateRetry {
+        instance: &'a str,
+        attempt: u32,
+        attempts: u32,
+        delay: u64,
+    },
+    BuildFinished {
+        success: bool,
+    },
+    StateChanged {
+        instance: &'a str,
+        state: ContainerState,
+    },
+}
+
+struct EventPublisher {
+    socket: UnixDatagram,
+}
+
+impl EventPublisher {
+    fn connect(path: &Path) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        // a listener that falls behind must never block ciel
+        socket.set_nonblocking(true)?;
+
+        Ok(EventPublisher { socket })
+    }
+
+    fn publish(&self, event: &Event) {
+        if let Ok(data) = serde_json::to_vec(event) {
+            self.socket.send(&data).ok();
+        }
+    }
+}
+
+static PUBLISHER: OnceLock<EventPublisher> = OnceLock::new();
+
+/// Start publishing the events to the socket at `path`, can only be set up once
+pub fn init_events(path: &Path) {
+    match EventPublisher::connect(path) {
+        Ok(publisher) => {
+            PUBLISHER.set(publisher).ok();
+        }
+        Err(e) => {
+            warn!(
+                "Unable to connect to the event socket {}, no events are published: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Publish the event, if enabled. Failures are ignored
+pub fn publish(event: &Event) {
+    if let Some(publisher) = PUBLISHER.get() {
+        publisher.publish(event);
+    }
+}
+
+/// Publish the state changes of the instances
+pub fn publish_state(instance: &str, state: ContainerState) {
+    publish(&Event::StateChanged { instance, state });
+}
+
+#[test]
+fn test_event_publisher() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events.sock");
+    let listener = UnixDatagram::bind(&path).unwrap();
+    let publisher = EventPublisher::connect(&path).unwrap();
+    publisher.publish(&Event::PackageStarted {
+        instance: "main",
+        package: "extra-x/foo",
+        index: 1,
+        total: 2,
+    });
+    publisher.publish(&Event::StateChanged {
+        instance: "main",
+        state: ContainerState::Running,
+    });
+    let mut buf = [0u8; 1024];
+    let len = listener.recv(&mut buf).unwrap();
+    assert_eq!(
+        std::str::from_utf8(&buf[..len]).unwrap(),
+        r#"{"event":"package-started","instance":"main","package":"extra-x/foo","index":1,"total":2}"#
+    );
+    let len = listener.recv(&mut buf).unwrap();
+    assert_eq!(
+        std::str::from_utf8(&buf[..len]).unwrap(),
+        r#"{"event":"state-changed","instance":"main","state":"running"}"#
+    );
+    // the listener going away does not affect the publisher
+    drop(listener);
+    publisher.publish(&Event::BuildFinished { success: true });
+    assert!(EventPublisher::connect(&dir.path().join("missing.sock")).is_err());
+}
@@ -0,0 +1,180 @@
+//! Workspace-level advisory locks
+
+use anyhow::{anyhow, Result};
+use fs3::FileExt;
+use std::{
+    fs::File,
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::common::{explain_read_only, CIEL_INST_DIR};
+
+const WORKSPACE_LOCK_FILE: &str = ".ciel/.lock";
+const INSTANCE_LOCK_FILE: &str = ".lock";
+
+/// Information about the process holding a lock
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockInfo {
+    pub pid: u32,
+    pub operation: String,
+}
+
+impl LockInfo {
+    fn parse(data: &str) -> Option<LockInfo> {
+        let (pid, operation) = data.trim().split_once(' ')?;
+
+        Some(LockInfo {
+            pid: pid.parse().ok()?,
+            operation: operation.to_owned(),
+        })
+    }
+}
+
+/// The lock is released when the guard is dropped
+pub struct LockGuard {
+    inner: File,
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        FileExt::unlock(&self.inner).ok();
+    }
+}
+
+fn read_lock_info(f: &mut File) -> Option<LockInfo> {
+    let mut data = String::new();
+    f.rewind().ok()?;
+    f.read_to_string(&mut data).ok()?;
+
+    LockInfo::parse(&data)
+}
+
+fn lock_file(path: &Path, target: &str, operation: &str, exclusive: bool) -> Result<LockGuard> {
+    let mut f = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| explain_read_only(e.into()))?;
+    // use the fs3 methods explicitly, newer Rust versions have similarly named methods on `File`
+    let result = if exclusive {
+        FileExt::try_lock_exclusive(&f)
+    } else {
+        FileExt::try_lock_shared(&f)
+    };
+    if let Err(e) = result {
+        if e.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(e.into());
+        }
+        return Err(match read_lock_info(&mut f) {
+            Some(info) => anyhow!(
+                "{} is being used by another ciel process: `{}` (PID {}). Please wait for it to finish.",
+                target,
+                info.operation,
+                info.pid
+            ),
+            None => anyhow!("{} is being used by another ciel process.", target),
+        });
+    }
+    // record who is holding the lock
+    f.set_len(0)?;
+    f.rewind()?;
+    write!(f, "{} {}", std::process::id(), operation)?;
+
+    Ok(LockGuard { inner: f })
+}
+
+/// Return the holder of an exclusive lock on the file, without disturbing the holder
+fn lock_holder(path: &Path) -> Result<Option<LockInfo>> {
+    let mut f = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if let Err(e) = FileExt::try_lock_shared(&f) {
+        if e.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(e.into());
+        }
+        return Ok(Some(read_lock_info(&mut f).unwrap_or(LockInfo {
+            pid: 0,
+            operation: "unknown".to_owned(),
+        })));
+    }
+    FileExt::unlock(&f)?;
+
+    Ok(None)
+}
+
+fn instance_lock_path(instance: &str) -> Result<PathBuf> {
+    let inst_dir = Path::new(CIEL_INST_DIR).join(instance);
+    if !inst_dir.is_dir() {
+        return Err(anyhow!("Instance `{}` does not exist.", instance));
+    }
+
+    Ok(inst_dir.join(INSTANCE_LOCK_FILE))
+}
+
+/// Take the workspace lock for operations that can run alongside each other (e.g. builds)
+pub fn lock_workspace_shared(operation: &str) -> Result<LockGuard> {
+    lock_file(
+        Path::new(WORKSPACE_LOCK_FILE),
+        "Workspace",
+        operation,
+        false,
+    )
+}
+
+/// Take the workspace lock for destructive operations (e.g. farewell, commit)
+pub fn lock_workspace_exclusive(operation: &str) -> Result<LockGuard> {
+    lock_file(Path::new(WORKSPACE_LOCK_FILE), "Workspace", operation, true)
+}
+
+/// Take the instance lock for operations that can share the instance (e.g. shell)
+pub fn lock_instance_shared(instance: &str, operation: &str) -> Result<LockGuard> {
+    let target = format!("Instance `{}`", instance);
+    lock_file(&instance_lock_path(instance)?, &target, operation, false)
+}
+
+/// Take the instance lock for operations that modify the instance (e.g. build, rollback)
+pub fn lock_instance_exclusive(instance: &str, operation: &str) -> Result<LockGuard> {
+    let target = format!("Instance `{}`", instance);
+    lock_file(&instance_lock_path(instance)?, &target, operation, true)
+}
+
+/// Return the process holding the instance exclusively, without taking the lock
+pub fn instance_lock_holder(instance: &str) -> Result<Option<LockInfo>> {
+    lock_holder(
+        &Path::new(CIEL_INST_DIR)
+            .join(instance)
+            .join(INSTANCE_LOCK_FILE),
+    )
+}
+
+#[test]
+fn test_lock_conflict() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(".lock");
+    let shared = lock_file(&path, "Workspace", "build", false).unwrap();
+    let shared2 = lock_file(&path, "Workspace", "shell", false).unwrap();
+    let err = lock_file(&path, "Workspace", "farewell", true)
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("`shell`"));
+    assert_eq!(lock_holder(&path).unwrap(), None);
+    drop(shared);
+    drop(shared2);
+    let _exclusive = lock_file(&path, "Workspace", "farewell", true).unwrap();
+    assert_eq!(
+        lock_holder(&path).unwrap().map(|x| x.operation),
+        Some("farewell".to_owned())
+    );
+    assert_eq!(
+        LockInfo::parse(&std::fs::read_to_string(&path).unwrap()),
+        Some(LockInfo {
+            pid: std::process::id(),
+            operation: "farewell".to_owned()
+        })
+    );
+}
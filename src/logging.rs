@@ -1,32 +1,226 @@
 #[macro_export]
 macro_rules! info {
     ($($arg:tt)+) => {
-        eprint!("{} ", style("info:").cyan().bold());
-        eprintln!($($arg)+);
+        if $crate::common::progress_json_enabled() {
+            eprintln!("{}", $crate::logging::log_record("info", &format!($($arg)+)));
+        } else {
+            eprint!("{} ", style("info:").cyan().bold().for_stderr());
+            eprintln!($($arg)+);
+        }
     };
 }
 
 #[macro_export]
 macro_rules! warn {
     ($($arg:tt)+) => {
-        eprint!("{} ", style("warning:").yellow().bold());
-        eprintln!($($arg)+);
+        if $crate::common::progress_json_enabled() {
+            eprintln!("{}", $crate::logging::log_record("warning", &format!($($arg)+)));
+        } else {
+            eprint!("{} ", style("warning:").yellow().bold().for_stderr());
+            eprintln!($($arg)+);
+        }
     };
 }
 
 #[macro_export]
 macro_rules! error {
     ($($arg:tt)+) => {
-        eprint!("{} ", style("error:").red().bold());
-        eprintln!($($arg)+);
+        if $crate::common::progress_json_enabled() {
+            eprintln!("{}", $crate::logging::log_record("error", &format!($($arg)+)));
+        } else {
+            eprint!("{} ", style("error:").red().bold().for_stderr());
+            eprintln!($($arg)+);
+        }
     };
 }
 
+use console::{style, Term};
+use std::{process::Command, sync::OnceLock};
+
+/// A JSON log record for `--progress-json`, printed among the progress records
+pub fn log_record(level: &str, message: &str) -> String {
+    serde_json::json!({
+        "type": "log",
+        "level": level,
+        "message": console::strip_ansi_codes(message),
+    })
+    .to_string()
+}
+
+/// Decide whether to use colors from the `--color` option and the environment,
+/// following the `NO_COLOR` and `CLICOLOR_FORCE` conventions
+fn should_use_colors(choice: &str, no_color: Option<&str>, force: Option<&str>) -> Option<bool> {
+    match choice {
+        "always" => Some(true),
+        "never" => Some(false),
+        _ if no_color.is_some_and(|x| !x.is_empty()) => Some(false),
+        _ if force.is_some_and(|x| !x.is_empty() && x != "0") => Some(true),
+        // leave it to the terminal detection
+        _ => None,
+    }
+}
+
+/// Apply the color choice (`auto`, `always` or `never`) to all the output
+pub fn init_colors(choice: &str) {
+    let no_color = std::env::var("NO_COLOR").ok();
+    let force = std::env::var("CLICOLOR_FORCE").ok();
+    if let Some(enabled) = should_use_colors(choice, no_color.as_deref(), force.as_deref()) {
+        console::set_colors_enabled(enabled);
+        console::set_colors_enabled_stderr(enabled);
+    }
+}
+
+/// Whether escape sequences (colors, terminal title, bell) may be written to stderr
 #[inline]
-pub fn color_bool(pred: bool) -> &'static str {
+pub fn escapes_enabled() -> bool {
+    console::colors_enabled_stderr()
+}
+
+/// How the user is notified about the build progress
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Notifications {
+    /// Set the terminal title and ring the bell
+    #[default]
+    All,
+    /// Only set the terminal title
+    Title,
+    /// Only ring the bell when the build finishes
+    Bell,
+    None,
+    /// Run the command (with `sh -c`) when the build finishes,
+    /// the message is passed in the `CIEL_NOTIFICATION` environment variable
+    Command(String),
+}
+
+impl std::str::FromStr for Notifications {
+    type Err = anyhow::Error;
+
+    /// A command is given as `command:<COMMAND>`, so that a misspelled keyword is not run
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "all" => Ok(Notifications::All),
+            "title" => Ok(Notifications::Title),
+            "bell" => Ok(Notifications::Bell),
+            "none" => Ok(Notifications::None),
+            _ => match s.strip_prefix("command:") {
+                Some(command) if !command.trim().is_empty() => {
+                    Ok(Notifications::Command(command.to_string()))
+                }
+                _ => Err(anyhow::anyhow!(
+                    "Invalid notification setting `{}`, expected all, title, bell, none or command:<COMMAND>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+static NOTIFICATIONS: OnceLock<Notifications> = OnceLock::new();
+
+/// Set how the user is notified, can only be set once
+pub fn init_notifications(notifications: Notifications) {
+    NOTIFICATIONS.set(notifications).ok();
+}
+
+/// Terminal control sequences are only written to an interactive terminal
+#[inline]
+fn terminal_escapes_enabled() -> bool {
+    escapes_enabled() && Term::stderr().is_term()
+}
+
+/// Set the terminal title, if enabled and stderr is a terminal
+pub fn set_terminal_title(title: &str) {
+    let notifications = NOTIFICATIONS.get_or_init(Default::default);
+    if matches!(notifications, Notifications::All | Notifications::Title)
+        && terminal_escapes_enabled()
+    {
+        // \r is for hiding the message if the terminal does not support the sequence
+        eprint!("\x1b]0;{}\x07\r", title);
+    }
+}
+
+/// Tell the user that a long-running operation has finished,
+/// by ringing the bell or running the notification command
+pub fn notify_finished(message: &str) {
+    match NOTIFICATIONS.get_or_init(Default::default) {
+        Notifications::All | Notifications::Bell => {
+            if terminal_escapes_enabled() {
+                eprintln!("\x07");
+            }
+        }
+        Notifications::Command(command) => {
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .env("CIEL_NOTIFICATION", message)
+                .status();
+            match status {
+                Ok(status) if status.success() => (),
+                Ok(status) => {
+                    warn!("Notification command failed: {}", status);
+                }
+                Err(e) => {
+                    warn!("Unable to run the notification command: {}", e);
+                }
+            }
+        }
+        Notifications::Title | Notifications::None => (),
+    }
+}
+
+#[inline]
+pub fn color_bool(pred: bool) -> String {
     if pred {
-        "\x1b[1m\x1b[32mYes\x1b[0m"
+        style("Yes").bold().green().for_stderr().to_string()
     } else {
-        "\x1b[34mNo\x1b[0m"
+        style("No").blue().for_stderr().to_string()
     }
 }
+
+/// A dimmed dash for absent values in tables
+#[inline]
+pub fn dim_dash() -> String {
+    style("-").dim().for_stderr().to_string()
+}
+
+#[test]
+fn test_notifications() {
+    assert_eq!(
+        "title".parse::<Notifications>().unwrap(),
+        Notifications::Title
+    );
+    assert_eq!(
+        "none".parse::<Notifications>().unwrap(),
+        Notifications::None
+    );
+    assert_eq!(
+        "command:notify-send ciel".parse::<Notifications>().unwrap(),
+        Notifications::Command("notify-send ciel".to_string())
+    );
+    assert!("tilte".parse::<Notifications>().is_err());
+    assert!("notify-send ciel".parse::<Notifications>().is_err());
+    assert!("command:".parse::<Notifications>().is_err());
+}
+
+#[test]
+fn test_should_use_colors() {
+    assert_eq!(should_use_colors("always", Some("1"), None), Some(true));
+    assert_eq!(should_use_colors("never", None, Some("1")), Some(false));
+    assert_eq!(should_use_colors("auto", Some("1"), Some("1")), Some(false));
+    assert_eq!(should_use_colors("auto", Some(""), Some("1")), Some(true));
+    assert_eq!(should_use_colors("auto", None, Some("0")), None);
+    assert_eq!(should_use_colors("auto", None, None), None);
+}
+
+#[test]
+fn test_log_record() {
+    let record: serde_json::Value = serde_json::from_str(&log_record(
+        "warning",
+        &format!("{} is busy", style("alpine").cyan()),
+    ))
+    .unwrap();
+    assert_eq!(
+        record,
+        serde_json::json!({"type": "log", "level": "warning", "message": "alpine is busy"})
+    );
+}
@@ -3,23 +3,40 @@
 use crate::common::{is_legacy_workspace, CIEL_INST_DIR};
 use crate::dbus_machine1::ManagerProxyBlocking;
 use crate::dbus_machine1_machine::MachineProxyBlocking;
-use crate::overlayfs::is_mounted;
+use crate::overlayfs::{is_mounted, list_mountpoints, MountOptions, INSTANCE_CREATED_FILE};
+use crate::{config, lock, timing};
 use crate::{info, overlayfs::LayerManager, warn};
 use adler32::adler32;
 use anyhow::{anyhow, Result};
 use console::style;
-use libc::{c_char, ftok, waitpid, WNOHANG};
+use libc::{c_char, ftok};
 use libsystemd_sys::bus::{sd_bus_flush_close_unref, sd_bus_open_system_machine};
+use serde::{Deserialize, Serialize};
 use std::{
-    ffi::{CString, OsStr},
+    collections::{HashMap, VecDeque},
+    ffi::{CString, OsStr, OsString},
     mem::MaybeUninit,
     process::Command,
+    sync::Mutex,
+};
+use std::{
+    fmt, fs,
+    time::{Duration, SystemTime},
+};
+use std::{
+    io::{BufRead, BufReader, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    process::Stdio,
+    thread::sleep,
 };
-use std::{fs, time::Duration};
 use std::{os::unix::ffi::OsStrExt, process::Child};
-use std::{path::Path, process::Stdio, thread::sleep};
-use zbus::blocking::Connection;
+use zbus::{blocking::Connection, zvariant::OwnedObjectPath};
 
+const BIND_MOUNT_RETRIES: usize = 5;
+/// The systemd-nspawn processes spawned by ciel, along with the name of their container
+static CONTAINER_PROCESSES: Mutex<Vec<(String, Child)>> = Mutex::new(Vec::new());
+/// How long to wait for systemctl in the container, it hangs if dbus.service has failed
+const SYSTEMCTL_TIMEOUT: Duration = Duration::from_secs(10);
 const DEFAULT_NSPAWN_OPTIONS: &[&str] = &[
     "-qb",
     "--capability=CAP_IPC_LOCK",
@@ -29,13 +46,64 @@ const DEFAULT_NSPAWN_OPTIONS: &[&str] = &[
 /// Instance status information
 #[derive(Debug)]
 pub struct CielInstance {
-    name: String,
+    pub name: String,
     // namespace name (in the form of `$name-$id`)
     pub ns_name: String,
     pub mounted: bool,
     running: bool,
     pub started: bool,
     booted: Option<bool>,
+    /// When the container was started, as registered with machined
+    booted_at: Option<SystemTime>,
+}
+
+/// Overall state of an instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContainerState {
+    /// The filesystem is not mounted
+    Down,
+    /// The filesystem is mounted, but the container is not started
+    Mounted,
+    /// The container is registered, but not running yet
+    Starting,
+    /// The container is up and running
+    Running,
+}
+
+impl fmt::Display for ContainerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ContainerState::Down => "down",
+            ContainerState::Mounted => "mounted but not started",
+            ContainerState::Starting => "starting",
+            ContainerState::Running => "running",
+        })
+    }
+}
+
+impl CielInstance {
+    /// How long the container has been running, `None` if it is not started
+    pub fn uptime(&self) -> Option<Duration> {
+        SystemTime::now().duration_since(self.booted_at?).ok()
+    }
+
+    /// When the container was started, `None` if it is not started
+    pub fn booted_at(&self) -> Option<SystemTime> {
+        self.booted_at
+    }
+
+    pub fn state(&self) -> ContainerState {
+        if self.running {
+            ContainerState::Running
+        } else if self.started {
+            ContainerState::Starting
+        } else if self.mounted {
+            ContainerState::Mounted
+        } else {
+            ContainerState::Down
+        }
+    }
 }
 
 /// Used for getting the instance name from Ciel 1/2
@@ -119,25 +187,170 @@ fn wait_for_container(child: &mut Child, ns_name: &str, retry: usize) -> Result<
     Err(anyhow!("Timeout waiting for container {}", ns_name))
 }
 
+/// machined only accepts UTF-8 paths over D-Bus, refuse other paths instead of mangling them
+fn bind_mount_source(path: &Path) -> Result<&str> {
+    path.to_str().ok_or_else(|| {
+        anyhow!(
+            "Unable to bind-mount {:?}: systemd-machined does not support non-UTF-8 paths",
+            path
+        )
+    })
+}
+
 /// Setting up cross-namespace bind-mounts for the container using systemd
-fn setup_bind_mounts(ns_name: &str, mounts: &[(String, &str)]) -> Result<()> {
+fn setup_bind_mounts(ns_name: &str, mounts: &[(String, String)]) -> Result<()> {
     let conn = Connection::system()?;
     let proxy = ManagerProxyBlocking::new(&conn)?;
     for mount in mounts {
         fs::create_dir_all(&mount.0)?;
         let source_path = fs::canonicalize(&mount.0)?;
-        proxy.bind_mount_machine(
-            ns_name,
-            &source_path.to_string_lossy(),
-            mount.1,
-            false,
-            true,
-        )?;
+        let source = bind_mount_source(&source_path)?;
+        // machined may report the mount point as busy right after the container has booted
+        let mut retry = 0;
+        while let Err(e) = proxy.bind_mount_machine(ns_name, source, &mount.1, false, true) {
+            retry += 1;
+            if retry >= BIND_MOUNT_RETRIES {
+                return Err(anyhow!(
+                    "Failed to bind-mount {} to {} in the container: {}",
+                    source,
+                    mount.1,
+                    e
+                ));
+            }
+            sleep(Duration::from_secs(1));
+        }
     }
 
     Ok(())
 }
 
+/// Check if systemd-networkd is running on the host (needed for network zones)
+pub fn is_networkd_running() -> Result<bool> {
+    let conn = Connection::system()?;
+    let proxy = zbus::blocking::fdo::DBusProxy::new(&conn)?;
+
+    Ok(proxy.name_has_owner("org.freedesktop.network1".try_into()?)?)
+}
+
+/// CAP_SYS_ADMIN, needed for mounting the instance filesystems
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// A requirement for running containers that is not met
+#[derive(Debug, PartialEq, Eq)]
+pub enum MissingPrivilege {
+    /// Mounting is not permitted
+    CapSysAdmin,
+    /// Running in a user namespace, root is not the real root
+    UserNamespace,
+    /// The cgroup hierarchy (the given path) is not writable,
+    /// so systemd-nspawn can't create the scopes
+    ReadOnlyCgroup(PathBuf),
+    /// systemd-machined is not reachable on the system bus
+    Machined(String),
+}
+
+impl fmt::Display for MissingPrivilege {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MissingPrivilege::CapSysAdmin => {
+                write!(
+                    f,
+                    "CAP_SYS_ADMIN is not effective, mounting is not permitted"
+                )
+            }
+            MissingPrivilege::UserNamespace => write!(
+                f,
+                "running in a user namespace, the root user is not privileged on the host"
+            ),
+            MissingPrivilege::ReadOnlyCgroup(path) => {
+                write!(f, "{} is not writable", path.display())
+            }
+            MissingPrivilege::Machined(e) => write!(f, "systemd-machined is not reachable: {}", e),
+        }
+    }
+}
+
+/// All the requirements for running containers that are not met
+#[derive(Debug)]
+pub struct PrivilegeError(pub Vec<MissingPrivilege>);
+
+impl fmt::Display for PrivilegeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "This environment can not run ciel containers:")?;
+        for missing in self.0.iter() {
+            writeln!(f, "  - {}", missing)?;
+        }
+        write!(
+            f,
+            "If ciel is running in a container, it has to be started with `--privileged`, \
+             with /sys/fs/cgroup mounted read-write, on a host running systemd as PID 1."
+        )
+    }
+}
+
+impl std::error::Error for PrivilegeError {}
+
+/// Whether the effective capabilities in `/proc/self/status` include CAP_SYS_ADMIN
+fn has_cap_sys_admin(status: &str) -> bool {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .is_some_and(|caps| caps & (1 << CAP_SYS_ADMIN) != 0)
+}
+
+/// Whether the `/proc/self/uid_map` maps a partial range, i.e. in a user namespace
+fn is_user_namespace(uid_map: &str) -> bool {
+    let ranges = uid_map
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    ranges != [["0", "0", "4294967295"]]
+}
+
+/// The cgroup hierarchy systemd-nspawn creates the scopes in: the unified hierarchy,
+/// or the systemd one with cgroup v1 (where the root is usually a read-only tmpfs)
+fn cgroup_hierarchy(root: &Path) -> PathBuf {
+    if root.join("cgroup.controllers").exists() {
+        return root.to_owned();
+    }
+    let unified = root.join("unified");
+    if unified.join("cgroup.controllers").exists() {
+        return unified;
+    }
+
+    root.join("systemd")
+}
+
+/// Probe for everything needed to mount the instances and, if `machined` is set, to run the
+/// containers, so that one clear error is reported instead of a failure halfway through
+pub fn check_privileges(machined: bool) -> Result<()> {
+    let mut missing = Vec::new();
+    if !fs::read_to_string("/proc/self/status").is_ok_and(|x| has_cap_sys_admin(&x)) {
+        missing.push(MissingPrivilege::CapSysAdmin);
+    }
+    if fs::read_to_string("/proc/self/uid_map").is_ok_and(|x| is_user_namespace(&x)) {
+        missing.push(MissingPrivilege::UserNamespace);
+    }
+    if machined {
+        let cgroup = cgroup_hierarchy(Path::new("/sys/fs/cgroup"));
+        if nix::unistd::access(&cgroup, nix::unistd::AccessFlags::W_OK).is_err() {
+            missing.push(MissingPrivilege::ReadOnlyCgroup(cgroup));
+        }
+        if let Err(e) =
+            Connection::system().and_then(|conn| ManagerProxyBlocking::new(&conn)?.list_machines())
+        {
+            missing.push(MissingPrivilege::Machined(e.to_string()));
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    Err(PrivilegeError(missing).into())
+}
+
 /// Get the container name (ns_name) of the instance
 pub fn get_container_ns_name<P: AsRef<Path>>(path: P, legacy: bool) -> Result<String> {
     let current_dir = std::env::current_dir()?;
@@ -147,52 +360,382 @@ pub fn get_container_ns_name<P: AsRef<Path>>(path: P, legacy: bool) -> Result<St
         warn!("Please make sure to save your work before upgrading.");
         return legacy_container_name(&path);
     }
+    // the same instance must have the same name however the workspace is reached
+    let path = path.canonicalize().unwrap_or(path);
 
     new_container_name(&path)
 }
 
+/// The directories the workspace may have been reached through
+fn working_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Ok(dir) = std::env::current_dir() {
+        dirs.push(dir);
+    }
+    // the logical working directory of the shell, symlinks are not resolved in it
+    if let Some(dir) = std::env::var_os("PWD") {
+        dirs.push(dir.into());
+    }
+
+    dirs
+}
+
+/// Other names the container of the instance may have been started with from the given
+/// working directories, e.g. by older versions of ciel when the workspace was reached
+/// through a symlink
+fn alternative_ns_names(dirs: &[PathBuf], path: &Path, ns_name: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    for dir in dirs {
+        if let Ok(name) = new_container_name(&dir.join(path)) {
+            if name != ns_name && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+
+    names
+}
+
+/// Return the name the container of the instance is registered under,
+/// a container started under an alternative name is adopted
+fn adopt_ns_name(
+    dirs: &[PathBuf],
+    name: &str,
+    ns_name: &str,
+    is_registered: impl Fn(&str) -> bool,
+) -> String {
+    if is_registered(ns_name) {
+        return ns_name.to_owned();
+    }
+    for alt_name in alternative_ns_names(dirs, Path::new(name), ns_name) {
+        if is_registered(&alt_name) {
+            warn!(
+                "{}: adopting the container running as {} (expected {})",
+                name, alt_name, ns_name
+            );
+            return alt_name;
+        }
+    }
+
+    ns_name.to_owned()
+}
+
+/// Get the container name of the instance, taking over a running container
+/// registered under an alternative name of the same instance
+pub fn resolve_container_ns_name(name: &str, legacy: bool) -> Result<String> {
+    let ns_name = get_container_ns_name(name, legacy)?;
+    if legacy {
+        return Ok(ns_name);
+    }
+    let machines = match Connection::system()
+        .and_then(|conn| ManagerProxyBlocking::new(&conn)?.list_machines())
+    {
+        Ok(machines) => machines,
+        // without machined, the instance can't be running anyway
+        Err(_) => return Ok(ns_name),
+    };
+
+    Ok(adopt_ns_name(&working_dirs(), name, &ns_name, |x| {
+        machines.iter().any(|m| m.0 == x)
+    }))
+}
+
+/// Build the systemd-nspawn arguments, the rootfs path is passed through without conversion
+fn nspawn_args(path: &Path, ns_name: &str, extra_options: &[String]) -> Vec<OsString> {
+    let mut args = DEFAULT_NSPAWN_OPTIONS
+        .iter()
+        .map(OsString::from)
+        .collect::<Vec<_>>();
+    args.extend(extra_options.iter().map(OsString::from));
+    args.push("-D".into());
+    args.push(path.into());
+    args.extend(["-M", ns_name, "--"].map(OsString::from));
+
+    args
+}
+
 /// Spawn a new container using nspawn
 pub fn spawn_container<P: AsRef<Path>>(
     ns_name: &str,
     path: P,
     extra_options: &[String],
-    mounts: &[(String, &str)],
+    mounts: &[(String, String)],
 ) -> Result<()> {
-    let path = path
-        .as_ref()
-        .to_str()
-        .ok_or_else(|| anyhow!("Path contains invalid Unicode characters."))?;
     let mut child = Command::new("systemd-nspawn")
-        .args(DEFAULT_NSPAWN_OPTIONS)
-        .args(extra_options)
-        .args(["-D", path, "-M", ns_name, "--"])
+        .args(nspawn_args(path.as_ref(), ns_name, extra_options))
         .env("SYSTEMD_NSPAWN_TMPFS_TMP", "0")
         .stdout(Stdio::null())
         .stderr(Stdio::null())
-        .spawn()?;
+        .spawn()
+        .map_err(|e| anyhow!("spawn systemd-nspawn: {}", e))?;
 
     info!("{}: waiting for container to start...", ns_name);
     wait_for_container(&mut child, ns_name, 10)?;
+    CONTAINER_PROCESSES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push((ns_name.to_owned(), child));
     info!("{}: setting up mounts...", ns_name);
     if let Err(e) = setup_bind_mounts(ns_name, mounts) {
-        warn!("Failed to setup bind mounts: {:?}", e);
+        // do not leave a half-configured container running
+        warn!(
+            "{}: stopping the container due to mount failures...",
+            ns_name
+        );
+        terminate_container_by_name(ns_name).ok();
+        clean_child_process(ns_name);
+        return Err(e);
     }
 
     Ok(())
 }
 
-/// Execute a command in the container
-pub fn execute_container_command<S: AsRef<OsStr>>(ns_name: &str, args: &[S]) -> Result<i32> {
+/// I/O scheduling class of the commands run in the containers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+impl IoClass {
+    /// Name of the class in the `IOSchedulingClass=` property of systemd
+    pub fn name(&self) -> &'static str {
+        match self {
+            IoClass::Realtime => "realtime",
+            IoClass::BestEffort => "best-effort",
+            IoClass::Idle => "idle",
+        }
+    }
+}
+
+impl std::str::FromStr for IoClass {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "realtime" => Ok(IoClass::Realtime),
+            "best-effort" => Ok(IoClass::BestEffort),
+            "idle" => Ok(IoClass::Idle),
+            _ => Err(anyhow!(
+                "Invalid I/O class `{}`, expected realtime, best-effort or idle",
+                s
+            )),
+        }
+    }
+}
+
+/// How the commands are run in the container, e.g. for a build
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExecOptions {
+    /// Boot the container without network access, if it has to be started for the command
+    pub offline: bool,
+    /// Run autobuild in stage 2 mode
+    pub stage2: bool,
+    /// Niceness of the commands, e.g. 10 to run the builds in the background
+    pub nice: Option<i32>,
+    /// I/O scheduling class of the commands
+    pub io_class: Option<IoClass>,
+}
+
+#[inline]
+fn get_exec_options(options: &ExecOptions) -> Vec<String> {
     let mut extra_options = vec!["--setenv=HOME=/root".to_string()];
-    if std::env::var("CIEL_STAGE2").is_ok() {
+    if options.stage2 || crate::common::env_flag("CIEL_STAGE2") {
         extra_options.push("--setenv=ABSTAGE2=1".to_string());
     }
+    if let Some(nice) = options.nice {
+        extra_options.push(format!("--nice={}", nice));
+    }
+    if let Some(io_class) = options.io_class {
+        extra_options.push(format!("--property=IOSchedulingClass={}", io_class.name()));
+    }
+
+    extra_options
+}
+
+/// Whether to allocate a pseudo terminal for the commands, only possible when ciel itself
+/// is started from a terminal (e.g. not from cron or CI). The output may still be redirected.
+fn use_pty() -> bool {
+    std::io::stdin().is_terminal()
+}
+
+/// Forward the output stream to `sink` line by line, and return the last `lines` lines that have been read
+fn tee_output<R: Read, W: Write>(reader: R, mut sink: W, lines: usize) -> Vec<u8> {
+    let mut reader = BufReader::new(reader);
+    let mut captured = VecDeque::new();
+    let mut line = Vec::new();
+    while let Ok(n) = reader.read_until(b'\n', &mut line) {
+        if n == 0 {
+            break;
+        }
+        sink.write_all(&line).ok();
+        if lines == 0 {
+            line.clear();
+            continue;
+        }
+        if captured.len() >= lines {
+            captured.pop_front();
+        }
+        captured.push_back(std::mem::take(&mut line));
+    }
+
+    captured.into_iter().flatten().collect()
+}
+
+/// Writes to the terminal and to the log file at once
+struct Tee<A: Write, B: Write>(A, B);
+
+impl<A: Write, B: Write> Write for Tee<A, B> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // the log file must not miss anything, the terminal may have gone away
+        self.0.write_all(buf).ok();
+        self.1.write_all(buf)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush().ok();
+        self.1.flush()
+    }
+}
+
+/// Quote the argument for the POSIX shell, if needed
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        return arg.to_owned();
+    }
+
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Execute a command in the container
+pub fn execute_container_command<S: AsRef<OsStr>>(ns_name: &str, args: &[S]) -> Result<i32> {
+    execute_container_command_in(ns_name, args, None, &ExecOptions::default())
+}
+
+/// Execute a command in the container, optionally in the specified working directory
+pub fn execute_container_command_in<S: AsRef<OsStr>>(
+    ns_name: &str,
+    args: &[S],
+    workdir: Option<&Path>,
+    options: &ExecOptions,
+) -> Result<i32> {
+    let workdir = workdir.map(|workdir| {
+        let mut option = OsString::from("--working-directory=");
+        option.push(workdir);
+        option
+    });
     // TODO: maybe replace with systemd API cross-namespace call?
     let exit_code = Command::new("systemd-run")
         .env("SYSTEMD_ADJUST_TERMINAL_TITLE", "0")
-        .args(extra_options)
-        .args(["-M", ns_name, "-qt", "--"])
+        .args(get_exec_options(options))
+        .args(workdir)
+        .args(["-M", ns_name, if use_pty() { "-qt" } else { "-qP" }, "--"])
+        .args(args)
+        .spawn()?
+        .wait()?
+        .code()
+        .unwrap_or(127);
+
+    Ok(exit_code)
+}
+
+/// Execute a command in the container and capture its output,
+/// the output is still shown to the user as it arrives.
+/// Returns the exit code and the captured output (stdout and stderr).
+pub fn execute_container_command_capture<S: AsRef<OsStr>>(
+    ns_name: &str,
+    args: &[S],
+    options: &ExecOptions,
+) -> Result<(i32, String)> {
+    execute_container_command_tail(ns_name, args, usize::MAX, options)
+}
+
+/// Same as [execute_container_command_capture], but only the last `lines` lines
+/// of the output are kept
+pub fn execute_container_command_tail<S: AsRef<OsStr>>(
+    ns_name: &str,
+    args: &[S],
+    lines: usize,
+    options: &ExecOptions,
+) -> Result<(i32, String)> {
+    let (exit_code, output) =
+        execute_container_command_piped(ns_name, args, options, std::io::stdout(), lines)?;
+
+    Ok((exit_code, String::from_utf8_lossy(&output).into_owned()))
+}
+
+/// Execute a command in the container with stdout and stderr sent to the same pipe, so that
+/// the output stays in order. The output is forwarded to `sink`, and its last `lines` lines
+/// are returned along with the exit code.
+fn execute_container_command_piped<S: AsRef<OsStr>, W: Write>(
+    ns_name: &str,
+    args: &[S],
+    options: &ExecOptions,
+    sink: W,
+    lines: usize,
+) -> Result<(i32, Vec<u8>)> {
+    let (reader, writer) = std::io::pipe()?;
+    // the write ends held by the command are closed at the end of the statement,
+    // so that the reader sees the end of the output once the child exits
+    let mut child = Command::new("systemd-run")
+        .env("SYSTEMD_ADJUST_TERMINAL_TITLE", "0")
+        .args(get_exec_options(options))
+        .args(["-M", ns_name, "-qP", "--"])
         .args(args)
+        .stdout(writer.try_clone()?)
+        .stderr(writer)
+        .spawn()?;
+    let output = tee_output(reader, sink, lines);
+    let exit_code = child.wait()?.code().unwrap_or(127);
+
+    Ok((exit_code, output))
+}
+
+/// Execute a command in the container, writing its output to `log` as well as it arrives.
+/// If attended, the command keeps a pseudo terminal by running under script(1),
+/// and the log contains what was shown in the terminal.
+pub fn execute_container_command_logged<S: AsRef<OsStr>>(
+    ns_name: &str,
+    args: &[S],
+    log: &Path,
+    options: &ExecOptions,
+) -> Result<i32> {
+    if !use_pty() {
+        let file = fs::File::create(log)?;
+        let (exit_code, _) = execute_container_command_piped(
+            ns_name,
+            args,
+            options,
+            Tee(std::io::stdout(), file),
+            0,
+        )?;
+        return Ok(exit_code);
+    }
+    let mut command = vec!["systemd-run".to_owned()];
+    command.extend(get_exec_options(options).iter().map(|x| shell_quote(x)));
+    command.extend(["-M", ns_name, "-qt", "--"].map(shell_quote));
+    for arg in args {
+        let arg = arg.as_ref();
+        let arg = arg.to_str().ok_or_else(|| {
+            anyhow!(
+                "Unable to run {:?}: non-UTF-8 arguments are not supported",
+                arg
+            )
+        })?;
+        command.push(shell_quote(arg));
+    }
+    let exit_code = Command::new("script")
+        .env("SYSTEMD_ADJUST_TERMINAL_TITLE", "0")
+        // the command line is quoted for the POSIX shell
+        .env("SHELL", "/bin/sh")
+        .args(["--quiet", "--return", "--flush", "--command"])
+        .arg(command.join(" "))
+        .arg(log)
         .spawn()?
         .wait()?
         .code()
@@ -201,10 +744,146 @@ pub fn execute_container_command<S: AsRef<OsStr>>(ns_name: &str, args: &[S]) ->
     Ok(exit_code)
 }
 
-/// Reap all the exited child processes
-pub(crate) fn clean_child_process() {
-    let mut status = 0;
-    unsafe { waitpid(-1, &mut status, WNOHANG) };
+/// Execute a command in the container and return its exit code and standard output,
+/// without showing the output to the user
+pub fn execute_container_command_output<S: AsRef<OsStr>>(
+    ns_name: &str,
+    args: &[S],
+) -> Result<(i32, String)> {
+    let output = Command::new("systemd-run")
+        .env("SYSTEMD_ADJUST_TERMINAL_TITLE", "0")
+        .args(get_exec_options(&ExecOptions::default()))
+        .args(["-M", ns_name, "-qP", "--"])
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()?;
+    let exit_code = output.status.code().unwrap_or(127);
+
+    Ok((
+        exit_code,
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+    ))
+}
+
+/// Run systemctl in the container and return its exit code and standard output,
+/// giving up after `SYSTEMCTL_TIMEOUT`
+fn execute_systemctl(ns_name: &str, args: &[&str]) -> Result<(i32, String)> {
+    let mut child = Command::new("systemd-run")
+        .env("SYSTEMD_ADJUST_TERMINAL_TITLE", "0")
+        .args(["-M", ns_name, "-qP", "--", "systemctl"])
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("Unable to capture stdout"))?;
+    let reader = std::thread::spawn(move || {
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).ok();
+        output
+    });
+    let deadline = std::time::Instant::now() + SYSTEMCTL_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if std::time::Instant::now() >= deadline {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(anyhow!(
+                "systemctl did not respond within {} seconds, the container may be unresponsive",
+                SYSTEMCTL_TIMEOUT.as_secs()
+            ));
+        }
+        sleep(Duration::from_millis(100));
+    };
+    let output = reader.join().unwrap_or_default();
+
+    Ok((status.code().unwrap_or(127), output))
+}
+
+/// Names of the failed units in the container
+pub fn failed_units(ns_name: &str) -> Result<Vec<String>> {
+    let (code, output) = execute_systemctl(
+        ns_name,
+        &["--failed", "--plain", "--no-legend", "--no-pager"],
+    )?;
+    if code != 0 {
+        return Err(anyhow!("systemctl exited with code {}", code));
+    }
+
+    Ok(parse_failed_units(&output))
+}
+
+fn parse_failed_units(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|unit| unit.to_owned())
+        .collect()
+}
+
+/// State of the system in the container as reported by systemd, e.g. "running" or "degraded"
+pub fn system_state(ns_name: &str) -> Result<String> {
+    // exits with a non-zero code unless the state is "running"
+    let (_, output) = execute_systemctl(ns_name, &["is-system-running"])?;
+
+    Ok(output.trim().to_owned())
+}
+
+/// Show the failed units the first time a machine is seen in the degraded state,
+/// a failed dbus.service usually makes the commands in the container hang
+fn report_degraded(ns_name: &str) {
+    static REPORTED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    {
+        let mut reported = REPORTED.lock().unwrap_or_else(|e| e.into_inner());
+        if reported.iter().any(|x| x == ns_name) {
+            return;
+        }
+        reported.push(ns_name.to_owned());
+    }
+    // systemctl gives up after `SYSTEMCTL_TIMEOUT` if the bus in the container does not respond
+    match failed_units(ns_name) {
+        Ok(units) if !units.is_empty() => {
+            warn!(
+                "{}: container is degraded, failed units: {}",
+                ns_name,
+                units.join(", ")
+            );
+            if units.iter().any(|x| x == "dbus.service") {
+                warn!(
+                    "{}: dbus.service has failed, commands in the container may hang.",
+                    ns_name
+                );
+            }
+        }
+        Ok(_) => {
+            warn!("{}: container is degraded.", ns_name);
+        }
+        Err(e) => {
+            warn!(
+                "{}: container is degraded, unable to list the failed units: {:#}",
+                ns_name, e
+            );
+            warn!(
+                "{}: dbus.service may be unavailable, commands in the container may hang.",
+                ns_name
+            );
+        }
+    }
+}
+
+/// Reap the systemd-nspawn process of the container if it has exited. Only the processes
+/// spawned for this container are waited for, the other child processes are left to their owners
+pub(crate) fn clean_child_process(ns_name: &str) {
+    let mut processes = CONTAINER_PROCESSES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    processes.retain_mut(|(name, child)| name != ns_name || matches!(child.try_wait(), Ok(None)));
 }
 
 fn kill_container(proxy: &MachineProxyBlocking) -> Result<()> {
@@ -295,23 +974,62 @@ fn terminate_container(
 pub fn terminate_container_by_name(ns_name: &str) -> Result<()> {
     let conn = Connection::system()?;
     let proxy = ManagerProxyBlocking::new(&conn)?;
-    let path = proxy.get_machine(ns_name)?;
+    let path = proxy
+        .get_machine(ns_name)
+        .map_err(|e| anyhow!("look up machine {}: {}", ns_name, e))?;
     let machine_proxy = MachineProxyBlocking::builder(&conn).path(&path)?.build()?;
 
     terminate_container(&proxy, &machine_proxy, ns_name)
 }
 
+/// Make machined forget about the container, killing whatever is left of it
+pub fn unregister_container(ns_name: &str) -> Result<()> {
+    let conn = Connection::system()?;
+    let proxy = ManagerProxyBlocking::new(&conn)?;
+    if proxy.terminate_machine(ns_name).is_ok() {
+        return Ok(());
+    }
+    proxy
+        .unregister_machine(ns_name)
+        .map_err(|e| anyhow!("unregister machine {}: {}", ns_name, e))
+}
+
 /// Mount the filesystem layers using the specified layer manager and the instance name
 pub fn mount_layers(manager: &mut dyn LayerManager, name: &str) -> Result<()> {
     let target = std::env::current_dir()?.join(name);
     if !manager.is_mounted(&target)? {
-        fs::create_dir_all(&target)?;
+        fs::create_dir_all(&target)
+            .map_err(|e| anyhow!("create mount point {}: {}", target.display(), e))?;
+        let _span = timing::span("mount", name);
         manager.mount(&target)?;
     }
 
     Ok(())
 }
 
+/// Effective mount options of the filesystem of the instance, `None` if it is not mounted
+pub fn instance_mount_options(name: &str) -> Result<Option<MountOptions>> {
+    let target = std::env::current_dir()?.join(name);
+
+    crate::overlayfs::get_overlayfs_manager(name)?.mount_options(&target)
+}
+
+/// Describe the problems with the files of the instance, e.g. after copying the workspace
+/// without some of the files or empty directories
+pub fn instance_integrity(name: &str) -> Result<Vec<String>> {
+    let mut problems = crate::overlayfs::get_overlayfs_manager(name)?.integrity_problems();
+    if let Err(e) = config::InstanceConfig::load(name) {
+        problems.push(format!("invalid instance configuration: {:#}", e));
+    }
+
+    Ok(problems)
+}
+
+/// Recreate the missing layer directories of the instance
+pub fn repair_instance(name: &str) -> Result<()> {
+    crate::overlayfs::get_overlayfs_manager(name)?.repair()
+}
+
 /// Get the information of the container specified
 pub fn inspect_instance(name: &str, ns_name: &str) -> Result<CielInstance> {
     let full_path = std::env::current_dir()?.join(name);
@@ -329,18 +1047,14 @@ pub fn inspect_instance(name: &str, ns_name: &str) -> Result<CielInstance> {
                     running: false,
                     mounted,
                     booted: None,
+                    booted_at: None,
                 });
             }
         }
         // For all other errors, just return the original error object
         return Err(anyhow!("{}", e));
     }
-    let path = path?;
-    let proxy = MachineProxyBlocking::builder(&conn).path(&path)?.build()?;
-    let state = proxy.state()?;
-    // Sometimes the system in the container is misconfigured, so we also accept "degraded" status as "running"
-    let running = state == "running" || state == "degraded";
-    let booted = is_booted(&proxy)?;
+    let (running, booted, booted_at) = inspect_machine(&conn, path?)?;
 
     Ok(CielInstance {
         name: name.to_owned(),
@@ -349,64 +1063,235 @@ pub fn inspect_instance(name: &str, ns_name: &str) -> Result<CielInstance> {
         running,
         mounted,
         booted: Some(booted),
+        booted_at,
     })
 }
 
-/// List all the instances under the current directory
-pub fn list_instances() -> Result<Vec<CielInstance>> {
-    let legacy = is_legacy_workspace()?;
-    let mut instances: Vec<CielInstance> = Vec::new();
-    for entry in (fs::read_dir(CIEL_INST_DIR)?).flatten() {
-        if entry.file_type().map(|e| e.is_dir())? {
-            instances.push(inspect_instance(
-                &entry.file_name().to_string_lossy(),
-                &get_container_ns_name(entry.file_name(), legacy)?,
-            )?);
+/// Convert the timestamps of machined (microseconds since the epoch, 0 if unknown)
+fn machine_timestamp(usec: u64) -> Option<SystemTime> {
+    (usec > 0).then(|| SystemTime::UNIX_EPOCH + Duration::from_micros(usec))
+}
+
+/// Query whether the registered machine is running and booted, and when it was started
+fn inspect_machine(
+    conn: &Connection,
+    path: OwnedObjectPath,
+) -> Result<(bool, bool, Option<SystemTime>)> {
+    let proxy = MachineProxyBlocking::builder(conn).path(path)?.build()?;
+    let state = proxy.state()?;
+    // Sometimes the system in the container is misconfigured, so we also accept "degraded" status as "running"
+    let running = state == "running" || state == "degraded";
+    let booted = is_booted(&proxy)?;
+    if state == "degraded" {
+        report_degraded(&proxy.name()?);
+    }
+    let booted_at = machine_timestamp(proxy.timestamp()?);
+
+    Ok((running, booted, booted_at))
+}
+
+/// Get the information of all the specified instances (pairs of the name and the ns_name),
+/// using a single query for the registered machines and a single parse of the mountinfo
+pub fn inspect_instances(instances: &[(String, String)]) -> Result<Vec<CielInstance>> {
+    let current_dir = std::env::current_dir()?;
+    let mountpoints = list_mountpoints(OsStr::new("overlay"))?;
+    let conn = Connection::system()?;
+    let proxy = ManagerProxyBlocking::new(&conn)?;
+    let mut machines = proxy
+        .list_machines()?
+        .into_iter()
+        .map(|(name, _, _, path)| (name, path))
+        .collect::<HashMap<_, _>>();
+    let mut result = Vec::with_capacity(instances.len());
+    let dirs = working_dirs();
+    for (name, ns_name) in instances {
+        let ns_name = &adopt_ns_name(&dirs, name, ns_name, |x| machines.contains_key(x));
+        let mounted = mountpoints.contains(&current_dir.join(name));
+        // only the registered machines need to be queried
+        let (started, running, booted, booted_at) = match machines.remove(ns_name) {
+            Some(path) => match inspect_machine(&conn, path) {
+                Ok((running, booted, booted_at)) => (true, running, Some(booted), booted_at),
+                // the machine may have stopped in the meantime, query it again individually
+                Err(_) => {
+                    result.push(inspect_instance(name, ns_name)?);
+                    continue;
+                }
+            },
+            None => (false, false, None, None),
+        };
+        result.push(CielInstance {
+            name: name.to_owned(),
+            ns_name: ns_name.to_owned(),
+            started,
+            running,
+            mounted,
+            booted,
+            booted_at,
+        });
+    }
+
+    Ok(result)
+}
+
+/// Order in which the instances are listed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstanceOrder {
+    Name,
+    Created,
+}
+
+impl InstanceOrder {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "name" => Ok(InstanceOrder::Name),
+            "created" => Ok(InstanceOrder::Created),
+            _ => Err(anyhow!("Unknown sort order: {}", name)),
         }
     }
+}
+
+/// When the instance was created, in nanoseconds since the Unix epoch. The instances created
+/// before the time was recorded fall back to the birth time of the directory, if available.
+fn instance_created_at(inst_dir: &Path) -> Option<u128> {
+    if let Some(created) = fs::read_to_string(inst_dir.join(INSTANCE_CREATED_FILE))
+        .ok()
+        .and_then(|x| x.trim().parse().ok())
+    {
+        return Some(created);
+    }
+    let created = fs::metadata(inst_dir).and_then(|m| m.created()).ok()?;
 
-    Ok(instances)
+    Some(
+        created
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()?
+            .as_nanos(),
+    )
 }
 
-/// List all the instances under the current directory, returns only instance names
-pub fn list_instances_simple() -> Result<Vec<String>> {
-    let mut instances: Vec<String> = Vec::new();
-    for entry in (fs::read_dir(CIEL_INST_DIR)?).flatten() {
+/// Collect the names of all the instances in the given directory, in the specified order.
+/// The instances with an unknown creation time are listed last.
+fn collect_instance_names(inst_dir: &Path, order: InstanceOrder) -> Result<Vec<String>> {
+    let mut instances = Vec::new();
+    for entry in (fs::read_dir(inst_dir)?).flatten() {
         if entry.file_type().map(|e| e.is_dir())? {
-            instances.push(entry.file_name().to_string_lossy().to_string());
+            let created = match order {
+                InstanceOrder::Name => None,
+                InstanceOrder::Created => instance_created_at(&entry.path()),
+            };
+            instances.push((
+                created.is_none(),
+                created,
+                entry.file_name().to_string_lossy().to_string(),
+            ));
         }
     }
+    instances.sort_unstable();
+
+    Ok(instances.into_iter().map(|x| x.2).collect())
+}
+
+/// List all the instances under the current directory
+pub fn list_instances(order: InstanceOrder) -> Result<Vec<CielInstance>> {
+    let legacy = is_legacy_workspace()?;
+    let mut instances = Vec::new();
+    for name in collect_instance_names(Path::new(CIEL_INST_DIR), order)? {
+        let ns_name = get_container_ns_name(&name, legacy)?;
+        instances.push((name, ns_name));
+    }
 
-    Ok(instances)
+    inspect_instances(&instances)
 }
 
-/// Print all the instances under the current directory
-pub fn print_instances() -> Result<()> {
-    use crate::logging::color_bool;
-    use std::io::Write;
+/// List all the instances under the current directory, returns only instance names (sorted by name)
+pub fn list_instances_simple() -> Result<Vec<String>> {
+    collect_instance_names(Path::new(CIEL_INST_DIR), InstanceOrder::Name)
+}
+
+/// Print all the instances under the current directory as a table, `verbose` also shows
+/// the uptime of the containers and the mount options of the filesystems.
+/// Listing never takes any lock, so it does not block on running operations.
+pub fn print_instances(order: InstanceOrder, verbose: bool) -> Result<()> {
+    use crate::logging::{color_bool, dim_dash};
+    use indicatif::HumanDuration;
     use tabwriter::TabWriter;
 
-    let instances = list_instances()?;
+    let instances = list_instances(order)?;
     let mut formatter = TabWriter::new(std::io::stderr());
-    writeln!(&mut formatter, "NAME\tMOUNTED\tRUNNING\tBOOTED")?;
+    write!(
+        &mut formatter,
+        "NAME\tMOUNTED\tRUNNING\tBOOTED\tLOCKED\tINTEGRITY\tHOSTNAME"
+    )?;
+    writeln!(
+        &mut formatter,
+        "{}",
+        if verbose {
+            "\tUPTIME\tMOUNT OPTIONS"
+        } else {
+            ""
+        }
+    )?;
+    let mut damaged = Vec::new();
+    let mut unreadable = Vec::new();
     for instance in instances {
+        let locked = match lock::instance_lock_holder(&instance.name)? {
+            Some(holder) => format!("{} (PID {})", holder.operation, holder.pid),
+            None => dim_dash(),
+        };
         let mounted = color_bool(instance.mounted);
         let running = color_bool(instance.running);
         let booted = {
             if let Some(booted) = instance.booted {
                 color_bool(booted)
             } else {
-                // dim
-                "\x1b[2m-\x1b[0m"
+                dim_dash()
             }
         };
-        writeln!(
+        let problems = instance_integrity(&instance.name)?;
+        let integrity = if problems.is_empty() {
+            style("OK").green().for_stderr().to_string()
+        } else {
+            style("Damaged").bold().red().for_stderr().to_string()
+        };
+        // the broken instance configuration is listed below the table
+        let hostname = config::InstanceConfig::load(&instance.name)
+            .unwrap_or_default()
+            .hostname(&instance.ns_name);
+        write!(
             &mut formatter,
-            "{}\t{}\t{}\t{}",
-            instance.name, mounted, running, booted
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            instance.name, mounted, running, booted, locked, integrity, hostname
         )?;
+        if verbose {
+            let uptime = instance
+                .uptime()
+                .map_or_else(dim_dash, |x| HumanDuration(x).to_string());
+            let options = match instance_mount_options(&instance.name) {
+                Ok(Some(options)) if options.is_volatile() => {
+                    style(options).yellow().for_stderr().to_string()
+                }
+                Ok(Some(options)) => options.to_string(),
+                Ok(None) => dim_dash(),
+                Err(e) => {
+                    unreadable.push((instance.name.clone(), e));
+                    dim_dash()
+                }
+            };
+            write!(&mut formatter, "\t{}\t{}", uptime, options)?;
+        }
+        writeln!(&mut formatter)?;
+        damaged.extend(problems.into_iter().map(|x| (instance.name.clone(), x)));
     }
     formatter.flush()?;
+    for (name, e) in unreadable.iter() {
+        warn!("{}: unable to read the mount options: {:#}", name, e);
+    }
+    for (name, problem) in damaged.iter() {
+        warn!("{}: {}", name, problem);
+    }
+    if !damaged.is_empty() {
+        info!("Run `ciel doctor --fix` to recreate the missing layers.");
+    }
 
     Ok(())
 }
@@ -416,14 +1301,205 @@ fn test_inspect_instance() {
     println!("{:#?}", inspect_instance("alpine", "alpine"));
 }
 
+#[test]
+fn test_container_state() {
+    let instance = |mounted, started, running| CielInstance {
+        name: "test".to_owned(),
+        ns_name: "test".to_owned(),
+        mounted,
+        running,
+        started,
+        booted: None,
+        booted_at: None,
+    };
+    assert_eq!(instance(false, false, false).state(), ContainerState::Down);
+    assert_eq!(
+        instance(true, false, false).state(),
+        ContainerState::Mounted
+    );
+    assert_eq!(
+        instance(true, true, false).state(),
+        ContainerState::Starting
+    );
+    assert_eq!(instance(true, true, true).state(), ContainerState::Running);
+    assert_eq!(instance(true, true, true).uptime(), None);
+}
+
+#[test]
+fn test_uptime() {
+    assert_eq!(machine_timestamp(0), None);
+    assert_eq!(
+        machine_timestamp(1_700_000_000_000_000),
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+    );
+    let booted_at = SystemTime::now() - Duration::from_secs(7200);
+    let instance = CielInstance {
+        name: "test".to_owned(),
+        ns_name: "test".to_owned(),
+        mounted: true,
+        running: true,
+        started: true,
+        booted: Some(true),
+        booted_at: Some(booted_at),
+    };
+    let uptime = instance.uptime().unwrap();
+    assert!(uptime >= Duration::from_secs(7200) && uptime < Duration::from_secs(7300));
+    // the clock may have been set back since the container started
+    let instance = CielInstance {
+        booted_at: Some(SystemTime::now() + Duration::from_secs(60)),
+        ..instance
+    };
+    assert_eq!(instance.uptime(), None);
+}
+
+#[test]
+fn test_instance_order() {
+    use crate::overlayfs::create_new_instance_fs;
+
+    let dir = tempfile::tempdir().unwrap();
+    for (name, created) in [("b", "300"), ("c", "100"), ("a", "200")] {
+        create_new_instance_fs(dir.path(), Path::new(name)).unwrap();
+        fs::write(dir.path().join(name).join(INSTANCE_CREATED_FILE), created).unwrap();
+    }
+    fs::write(dir.path().join("not-an-instance"), b"").unwrap();
+    assert_eq!(
+        collect_instance_names(dir.path(), InstanceOrder::Name).unwrap(),
+        vec!["a", "b", "c"]
+    );
+    assert_eq!(
+        collect_instance_names(dir.path(), InstanceOrder::Created).unwrap(),
+        vec!["c", "a", "b"]
+    );
+    // recorded when the instance is created, later instances come later
+    create_new_instance_fs(dir.path(), Path::new("0")).unwrap();
+    assert!(instance_created_at(&dir.path().join("0")).unwrap() > 300);
+    assert_eq!(
+        collect_instance_names(dir.path(), InstanceOrder::Created).unwrap(),
+        vec!["c", "a", "b", "0"]
+    );
+}
+
 #[test]
 fn test_container_name() {
     assert_eq!(
         get_container_ns_name(Path::new("/tmp/"), false).unwrap(),
-        "tmp-51601b0".to_string()
+        "tmp-3660181".to_string()
+    );
+    let dir = tempfile::tempdir().unwrap();
+    let real = dir.path().join("real/main");
+    fs::create_dir_all(&real).unwrap();
+    std::os::unix::fs::symlink(dir.path().join("real"), dir.path().join("link")).unwrap();
+    let ns_name = get_container_ns_name(&real, false).unwrap();
+    assert_eq!(
+        get_container_ns_name(dir.path().join("link/main"), false).unwrap(),
+        ns_name
+    );
+    // a container started through the symlink is adopted
+    let link_name = new_container_name(&dir.path().join("link/main")).unwrap();
+    assert_ne!(link_name, ns_name);
+    let dirs = [dir.path().join("real"), dir.path().join("link")];
+    assert_eq!(
+        alternative_ns_names(&dirs, Path::new("main"), &ns_name),
+        vec![link_name.clone()]
     );
+    assert_eq!(
+        adopt_ns_name(&dirs, "main", &ns_name, |x| x == link_name),
+        link_name
+    );
+    assert_eq!(
+        adopt_ns_name(&dirs, "main", &ns_name, |x| x == ns_name),
+        ns_name
+    );
+    assert_eq!(adopt_ns_name(&dirs, "main", &ns_name, |_| false), ns_name);
     println!(
         "{:#?}",
         get_container_ns_name(Path::new("/tmp/"), true).unwrap()
     );
 }
+
+#[test]
+fn test_unusual_workspace_paths() {
+    let path = Path::new(OsStr::from_bytes(
+        b"/home/user/AOSC builds/\xe5\xb7\xa5\xe4\xbd\x9c/main",
+    ));
+    let args = nspawn_args(path, "main-1", &["--bind=/a b".to_string()]);
+    let pos = args.iter().position(|x| x == "-D").unwrap();
+    assert_eq!(args[pos + 1], path.as_os_str());
+    assert!(args.contains(&OsString::from("--bind=/a b")));
+    assert!(new_container_name(path).unwrap().starts_with("main-"));
+    assert_eq!(
+        bind_mount_source(path).unwrap(),
+        "/home/user/AOSC builds/工作/main"
+    );
+
+    let path = Path::new(OsStr::from_bytes(b"/home/user/AOSC builds/\xff/main"));
+    let args = nspawn_args(path, "main-1", &[]);
+    assert!(args
+        .iter()
+        .any(|x| x.as_bytes() == path.as_os_str().as_bytes()));
+    assert!(bind_mount_source(path).is_err());
+}
+
+#[test]
+fn test_parse_failed_units() {
+    let output = "systemd-firstboot.service loaded failed failed First Boot Wizard\n\
+                  dbus.service loaded failed failed D-Bus System Message Bus\n";
+    assert_eq!(
+        parse_failed_units(output),
+        vec!["systemd-firstboot.service", "dbus.service"]
+    );
+    assert!(parse_failed_units("").is_empty());
+}
+
+#[test]
+fn test_privilege_probes() {
+    let status = "Name:\tciel\nCapPrm:\t000001ffffffffff\nCapEff:\t000001ffffffffff\n";
+    assert!(has_cap_sys_admin(status));
+    let status = "Name:\tciel\nCapEff:\t00000000a80425fb\n";
+    assert!(!has_cap_sys_admin(status));
+    assert!(!has_cap_sys_admin("Name:\tciel\n"));
+    assert!(!is_user_namespace("         0          0 4294967295\n"));
+    assert!(is_user_namespace("         0     100000      65536\n"));
+    let err = PrivilegeError(vec![
+        MissingPrivilege::CapSysAdmin,
+        MissingPrivilege::ReadOnlyCgroup(PathBuf::from("/sys/fs/cgroup")),
+    ])
+    .to_string();
+    assert!(err.contains("  - CAP_SYS_ADMIN is not effective"));
+    assert!(err.contains("  - /sys/fs/cgroup is not writable"));
+    assert!(err.contains("--privileged"));
+
+    let dir = tempfile::tempdir().unwrap();
+    assert_eq!(cgroup_hierarchy(dir.path()), dir.path().join("systemd"));
+    fs::create_dir(dir.path().join("unified")).unwrap();
+    fs::write(dir.path().join("unified/cgroup.controllers"), "").unwrap();
+    assert_eq!(cgroup_hierarchy(dir.path()), dir.path().join("unified"));
+    fs::write(dir.path().join("cgroup.controllers"), "cpu memory\n").unwrap();
+    assert_eq!(cgroup_hierarchy(dir.path()), dir.path());
+}
+
+#[test]
+fn test_io_class() {
+    for class in [IoClass::Realtime, IoClass::BestEffort, IoClass::Idle] {
+        assert_eq!(class.name().parse::<IoClass>().unwrap(), class);
+    }
+    assert!("background".parse::<IoClass>().is_err());
+}
+
+#[test]
+fn test_shell_quote() {
+    assert_eq!(shell_quote("/bin/acbs-build"), "/bin/acbs-build");
+    assert_eq!(shell_quote("--setenv=HOME=/root"), "--setenv=HOME=/root");
+    assert_eq!(shell_quote(""), "''");
+    assert_eq!(shell_quote("a b"), "'a b'");
+    assert_eq!(shell_quote("it's $HOME"), r"'it'\''s $HOME'");
+}
+
+#[test]
+fn test_tee_output() {
+    let mut log = Vec::new();
+    let tail = tee_output(&b"a\nb\nc\n"[..], Tee(std::io::sink(), &mut log), 2);
+    assert_eq!(tail, b"b\nc\n");
+    assert_eq!(log, b"a\nb\nc\n");
+    assert!(tee_output(&b"a\nb\n"[..], std::io::sink(), 0).is_empty());
+}
@@ -1,32 +1,62 @@
 mod actions;
+mod arch;
 mod cli;
 mod common;
 mod config;
 mod dbus_machine1;
 mod dbus_machine1_machine;
 mod diagnose;
+mod events;
+mod lock;
 mod logging;
 mod machine;
 mod network;
+mod oci;
 mod overlayfs;
 mod repo;
+mod timing;
 
 use anyhow::{anyhow, bail, Context, Result};
 use clap::ArgMatches;
 use config::read_config;
 use console::{style, user_attended};
 use dotenvy::dotenv;
+use std::net::IpAddr;
 use std::process;
-use std::{path::Path, process::Command};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
 
 use crate::actions::BuildSettings;
+use crate::arch::{
+    arch_aliases, get_host_arch_name, normalize_arch_name, CIEL_MAINLINE_ARCHS, CIEL_RETRO_ARCHS,
+};
 use crate::common::*;
+use crate::machine::InstanceOrder;
+
+/// Exit code of `repo refresh` when some files were left out of the index,
+/// the index is still refreshed in that case
+const REFRESH_SKIPPED_EXIT_CODE: i32 = 2;
 
 macro_rules! print_error {
     ($input:block) => {
         if let Err(e) = $input {
-            error!("{:?}", e);
-            process::exit(1);
+            let cancelled = e.is::<actions::Cancelled>();
+            let retryable = e
+                .downcast_ref::<common::RootfsError>()
+                .is_some_and(|x| x.is_retryable());
+            // print the error with its context on one line, e.g. "test: unmount overlay at ...: ..."
+            error!("{:#}", common::explain_read_only(e));
+            process::exit(if cancelled {
+                // same as being killed by SIGINT
+                130
+            } else if retryable {
+                // EX_TEMPFAIL, worth trying again later
+                75
+            } else {
+                1
+            });
         }
     };
 }
@@ -41,6 +71,32 @@ macro_rules! one_or_all_instance {
     }};
 }
 
+/// Print the supported target architectures, for `ciel load-os --list-arches`
+fn print_arches() {
+    let groups = [
+        ("mainline", CIEL_MAINLINE_ARCHS),
+        ("retro", CIEL_RETRO_ARCHS),
+    ];
+    for (group, archs) in groups {
+        for arch in archs.iter() {
+            let aliases = arch_aliases(arch);
+            if aliases.is_empty() {
+                println!("{}\t{}", arch, group);
+            } else {
+                println!("{}\t{}\t{}", arch, group, aliases.join(","));
+            }
+        }
+    }
+}
+
+/// Get the AOSC OS name of the target architecture, exits if it is not supported
+fn get_target_arch(arch: &str) -> &'static str {
+    match normalize_arch_name(arch) {
+        Some(arch) => arch,
+        None => unsupported_target_architecture(arch),
+    }
+}
+
 fn unsupported_target_architecture(arch: &str) -> ! {
     error!("Unknown target architecture {}", arch);
     info!("Supported target architectures:");
@@ -49,6 +105,7 @@ fn unsupported_target_architecture(arch: &str) -> ! {
         CIEL_MAINLINE_ARCHS.join("\n\t"),
         CIEL_RETRO_ARCHS.join("\n\t")
     );
+    info!("See `ciel load-os --list-arches` for the other accepted names.");
     info!("If you do want to load an OS unsupported by Ciel, specify a tarball to initialize this workspace.");
     process::exit(1);
 }
@@ -70,6 +127,24 @@ fn get_instance_option(args: &ArgMatches) -> Result<String> {
     Ok(option_instance.expect("Internal error").to_string())
 }
 
+/// Get the working directory inside the container from `--chdir` or `--pkg`
+fn get_workdir_option(args: &ArgMatches) -> Result<Option<PathBuf>> {
+    if let Some(package) = args.get_one::<String>("PKG") {
+        return Ok(Some(actions::find_package_dir("TREE", package)?));
+    }
+
+    Ok(args.get_one::<PathBuf>("CHDIR").cloned())
+}
+
+/// Get the instance to stage `ciel update-os` in, None to update the base system
+fn get_update_os_instance(args: &ArgMatches) -> Result<Option<String>> {
+    if !args.get_flag("stage") {
+        return Ok(None);
+    }
+
+    get_instance_option(args).map(Some)
+}
+
 #[inline]
 fn is_root() -> bool {
     nix::unistd::geteuid().is_root()
@@ -98,6 +173,209 @@ fn update_tree(path: &Path, branch: Option<&String>, rebase_from: Option<&String
     Ok(())
 }
 
+/// Apply `--add-mount` and `--remove-mount` to the list of bind mounts
+fn update_bind_mounts<'a, I: Iterator<Item = &'a String>>(
+    mounts: &mut Vec<config::BindMount>,
+    add: Option<I>,
+    remove: Option<I>,
+) -> Result<()> {
+    if let Some(targets) = remove {
+        let targets = targets.map(Path::new).collect::<Vec<_>>();
+        mounts.retain(|x| !targets.contains(&Path::new(&x.target)));
+    }
+    for mount in add.into_iter().flatten() {
+        let mount = mount.parse::<config::BindMount>()?;
+        actions::check_bind_mount(&mount)?;
+        mounts.retain(|x| Path::new(&x.target) != Path::new(&mount.target));
+        mounts.push(mount);
+    }
+
+    Ok(())
+}
+
+/// Show the build progress in the terminal title
+fn show_build_progress(progress: &actions::BuildProgress) {
+    logging::set_terminal_title(&format!(
+        "ciel: [{}/{}] {} ({}@{})",
+        progress.index, progress.total, progress.package, progress.instance, progress.hostname
+    ));
+    events::publish(&events::Event::PackageStarted {
+        instance: progress.instance,
+        package: progress.package,
+        index: progress.index,
+        total: progress.total,
+    });
+}
+
+/// Show the pending retry of the OS update in the terminal title
+fn show_update_retry(retry: &actions::UpdateRetry) {
+    logging::set_terminal_title(&format!(
+        "ciel: OS update failed ({}), retry {}/{} with {} in {}s ({})",
+        retry.error.lines().last().unwrap_or("unknown error"),
+        retry.attempt + 1,
+        retry.attempts,
+        if retry.use_apt { "apt" } else { "oma" },
+        retry.delay.as_secs(),
+        retry.instance
+    ));
+    events::publish(&events::Event::UpdateRetry {
+        instance: retry.instance,
+        attempt: retry.attempt,
+        attempts: retry.attempts,
+        delay: retry.delay.as_secs(),
+    });
+}
+
+/// Ring the bell or run the notification command after a build
+fn notify_build_finished(status: &Result<i32>) {
+    logging::notify_finished(match status {
+        Ok(0) => "Build finished",
+        _ => "Build failed",
+    });
+    events::publish(&events::Event::BuildFinished {
+        success: matches!(status, Ok(0)),
+    });
+}
+
+/// Cancel the build or the update in an orderly manner on the first Ctrl-C,
+/// exit right away on the second one
+fn cancel_on_interrupt() -> Result<actions::CancelToken> {
+    let cancel = actions::CancelToken::new();
+    let handle = cancel.clone();
+    ctrlc::set_handler(move || {
+        if handle.is_cancelled() {
+            process::exit(130);
+        }
+        warn!("Interrupted, cancelling (press Ctrl-C again to exit right away) ...");
+        handle.cancel();
+    })?;
+
+    Ok(cancel)
+}
+
+/// Get the build settings from the options of `ciel build`
+fn get_build_settings(
+    args: &ArgMatches,
+    ephemeral: bool,
+    cancel: actions::CancelToken,
+) -> BuildSettings {
+    BuildSettings {
+        offline: args.get_flag("OFFLINE"),
+        stage2: args.get_flag("STAGE2"),
+        topics: args
+            .get_many::<String>("TOPICS")
+            .map(|topics| topics.cloned().collect())
+            .unwrap_or_default(),
+        repo_snapshot: args.get_one::<String>("REPO_SNAPSHOT").cloned(),
+        acbs_args: args
+            .get_many::<String>("ACBS_ARGS")
+            .map(|x| x.cloned().collect())
+            .unwrap_or_default(),
+        ephemeral,
+        record_environment: args.get_flag("RECORD_ENV"),
+        collect_artifacts: args.get_flag("COLLECT_ARTIFACTS"),
+        skip_update: args.get_flag("NO_UPDATE_OS"),
+        nice: args.get_one::<i32>("NICE").copied(),
+        io_class: args
+            .get_one::<String>("IO_CLASS")
+            .and_then(|x| x.parse().ok()),
+        on_progress: Some(show_build_progress),
+        on_update_retry: Some(show_update_retry),
+        cancel,
+    }
+}
+
+/// Build the packages across several instances, see `actions::package_build_sharded`
+fn build_sharded(instances: &[String], args: &ArgMatches) -> Result<i32> {
+    // not a clap conflict, so that an instance picked through `CIEL_INST` is simply ignored
+    if args.value_source("INSTANCE") == Some(clap::parser::ValueSource::CommandLine) {
+        bail!("`-i` can't be used with `--instances`, list all the instances in `--instances`.");
+    }
+    if let Some(duplicate) = instances
+        .iter()
+        .enumerate()
+        .find(|(i, x)| instances[..*i].contains(x))
+    {
+        bail!("Instance `{}` is specified more than once.", duplicate.1);
+    }
+    let _locks = instances
+        .iter()
+        .map(|x| lock::lock_instance_exclusive(x, "build"))
+        .collect::<Result<Vec<_>>>()?;
+    let settings = get_build_settings(args, false, cancel_on_interrupt()?);
+    warn!("Building across multiple instances is experimental, the packages must not depend on each other.");
+    let packages = args.get_many::<String>("PACKAGES").unwrap();
+    let reports = actions::package_build_sharded(instances, packages, settings);
+    eprintln!("{}", style("BUILD REPORT").bold());
+    let mut status = 0;
+    for report in reports {
+        match report.status {
+            Ok(0) => {
+                info!(
+                    "{}: {} packages built: {}",
+                    report.instance,
+                    report.packages.len(),
+                    report.packages.join(" ")
+                );
+            }
+            Ok(code) => {
+                error!(
+                    "{}: build failed with status {}, resume it with `ciel build -i {} -c <checkpoint>`",
+                    report.instance, code, report.instance
+                );
+                status = code;
+            }
+            Err(e) => {
+                error!("{}: {:#}", report.instance, e);
+                status = 1;
+            }
+        }
+    }
+
+    Ok(status)
+}
+
+fn print_verify_report(report: &repo::VerifyReport) {
+    for deb in report.mismatched.iter() {
+        error!("Checksum mismatch: {}", deb);
+    }
+    for deb in report.missing.iter() {
+        error!("Missing from the disk: {}", deb);
+    }
+    for deb in report.unindexed.iter() {
+        warn!("Not in the index: {}", deb);
+    }
+    info!(
+        "Checked {} packages, {} problem(s) found.",
+        report.checked,
+        report.mismatched.len() + report.missing.len() + report.unindexed.len()
+    );
+}
+
+/// Print the value of the setting, nothing if it is unset
+fn print_config_value<T: serde::Serialize + serde::de::DeserializeOwned>(
+    config: &T,
+    key: &str,
+) -> Result<()> {
+    if let Some(value) = config::config_value(config, key)? {
+        println!("{}", config::format_config_value(&value)?);
+    }
+
+    Ok(())
+}
+
+fn print_import_report(report: &repo::ImportReport) {
+    for (path, reason) in report.failed.iter() {
+        error!("Unable to import {}: {}", path.display(), reason);
+    }
+    info!(
+        "Imported {} package(s), skipped {} already present, {} failed.",
+        report.imported.len(),
+        report.skipped.len(),
+        report.failed.len()
+    );
+}
+
 fn main() -> Result<()> {
     // set umask to 022 to ensure correct permissions on rootfs
     unsafe {
@@ -114,15 +392,39 @@ fn main() -> Result<()> {
         println!("Please run me as root!");
         process::exit(1);
     }
+    logging::init_colors(args.get_one::<String>("color").unwrap());
+    if args.get_flag("ignore-version") {
+        std::env::set_var("CIEL_IGNORE_VERSION", "1");
+    }
+    if args.get_flag("strict-config") {
+        std::env::set_var("CIEL_STRICT_CONFIG", "1");
+    }
+    if args.get_flag("quiet") {
+        std::env::set_var("CIEL_QUIET", "1");
+    }
+    if args.get_flag("log-timings") {
+        std::env::set_var("CIEL_LOG_TIMINGS", "1");
+    }
     let mut directory = Path::new(args.get_one::<String>("C").unwrap()).to_path_buf();
     let host_arch = get_host_arch_name();
     // Switch to the target directory
     std::env::set_current_dir(&directory).unwrap();
     // get subcommands from command line parser
     let subcmd = args.subcommand();
+    // listing the architectures does not need a workspace
+    if let Some(("load-os", args)) = subcmd {
+        if args.get_flag("list-arches") {
+            print_arches();
+            return Ok(());
+        }
+    }
     // check if the workspace exists, except when the command is `init` or `new`
     match subcmd {
-        Some(("init", _)) | Some(("new", _)) | Some(("version", _)) => (),
+        Some(("init", _)) | Some(("new", _)) => (),
+        Some(("version", args)) if !args.get_flag("workspace") => (),
+        // the checks needing a workspace are skipped
+        Some(("doctor", _))
+            if !Path::new("./.ciel").is_dir() && common::find_ciel_dir(".").is_err() => {}
         _ if !Path::new("./.ciel").is_dir() => {
             if directory == Path::new(".") {
                 directory =
@@ -139,27 +441,75 @@ fn main() -> Result<()> {
         }
         _ => (),
     }
+    // older workspaces are only upgraded on request, without writing anything to them before
+    if !matches!(
+        subcmd,
+        Some(("init" | "new" | "version" | "doctor" | "farewell", _))
+    ) {
+        match common::check_workspace_version() {
+            Err(e) if e.is::<common::WorkspaceNeedsUpgrade>() && args.get_flag("auto-upgrade") => {
+                warn!("{}", e);
+                print_error!({ actions::upgrade_workspace() });
+            }
+            result => print_error!({ result }),
+        }
+    }
     // list instances if no command is specified
     if subcmd.is_none() {
-        machine::print_instances()?;
+        machine::print_instances(InstanceOrder::Name, false)?;
         return Ok(());
     }
     let subcmd = subcmd.unwrap();
+    // the diagnostic commands show when the workspace was last opened, so don't update it
+    if !matches!(subcmd.0, "init" | "new" | "version" | "doctor") {
+        common::touch_workspace_meta().ok();
+    }
+    let event_socket = std::env::var_os("CIEL_EVENT_SOCKET")
+        .map(PathBuf::from)
+        .or_else(|| config::read_config_or_warn().and_then(|x| x.event_socket));
+    if let Some(event_socket) = event_socket {
+        events::init_events(&event_socket);
+        actions::set_state_observer(events::publish_state);
+    }
+    // fail early with a clear error if the environment can't mount or run containers
+    match subcmd.0 {
+        // previewing a commit only reads the upper layer
+        "commit" if subcmd.1.get_flag("dry-run") => (),
+        "mount" | "commit" | "rollback" => {
+            print_error!({ machine::check_privileges(false) });
+        }
+        "new" | "update-os" | "shell" | "run" | "try" | "build" | "down" | "boot" | "stop" => {
+            print_error!({ machine::check_privileges(true) });
+        }
+        _ => (),
+    }
     // Switch table
     match subcmd {
-        ("farewell", _) => {
+        ("farewell", args) => {
+            if args.get_flag("dry-run") {
+                print_error!({
+                    actions::destroy_plan(&directory).and_then(|x| actions::print_destroy_plan(&x))
+                });
+                return Ok(());
+            }
+            let _lock = lock::lock_workspace_exclusive("farewell")?;
             actions::farewell(&directory).unwrap();
         }
         ("init", args) => {
             if args.get_flag("upgrade") {
-                info!("Upgrading workspace...");
-                info!("First, shutting down all the instances...");
-                print_error!({ actions::for_each_instance(&actions::container_down) });
+                print_error!({ actions::upgrade_workspace() });
             } else {
                 warn!("Please do not use this command manually ...");
                 warn!("... try `ciel new` instead.");
+                print_error!({ common::ciel_init() });
+            }
+            // retry removing the instances left behind by `del --force-broken`
+            if Path::new(common::CIEL_TRASH_DIR).is_dir() {
+                let _lock = lock::lock_workspace_exclusive("init")?;
+                if let Err(e) = actions::empty_trash() {
+                    warn!("Unable to empty the trash: {:#}", e);
+                }
             }
-            print_error!({ common::ciel_init() });
             info!("Initialized working directory at {}", directory.display());
         }
         ("load-tree", args) => {
@@ -172,12 +522,21 @@ fn main() -> Result<()> {
             print_error!({ update_tree(tree, args.get_one("branch"), args.get_one("rebase")) });
         }
         ("load-os", args) => {
+            let _lock = lock::lock_workspace_exclusive("load-os")?;
             let url = args.get_one::<String>("url");
+            let update = args.get_flag("update");
+            if args.get_flag("allow-devices") {
+                std::env::set_var("CIEL_ALLOW_DEVICES", "1");
+            }
+            if args.get_flag("progress-json") {
+                std::env::set_var("CIEL_PROGRESS_JSON", "1");
+            }
+            let format = RootfsFormat::from_name(args.get_one::<String>("format").unwrap())?;
+            let cancel = cancel_on_interrupt()?;
             if let Some(url) = url {
-                let use_tarball = !url.ends_with(".squashfs");
                 // load from network using specified url
                 if url.starts_with("https://") || url.starts_with("http://") {
-                    print_error!({ actions::load_os(url, None, use_tarball) });
+                    print_error!({ actions::load_os(url, None, format, update, &cancel) });
                     return Ok(());
                 }
                 // load from file
@@ -186,19 +545,21 @@ fn main() -> Result<()> {
                     error!("{:?} is not a file", url);
                     process::exit(1);
                 }
+                if update {
+                    print_error!({ actions::load_os(url, None, format, true, &cancel) });
+                    return Ok(());
+                }
                 print_error!({
-                    common::extract_system_rootfs(tarball, tarball.metadata()?.len(), use_tarball)
+                    common::extract_system_rootfs(tarball, tarball.metadata()?.len(), format, None)
                 });
+                print_error!({ common::set_rootfs_checksum(None) });
 
                 return Ok(());
             }
             // load from network using auto picked url
             let specified_arch = args.get_one::<String>("arch");
             let arch = if let Some(specified_arch) = specified_arch {
-                if !check_arch_name(specified_arch.as_str()) {
-                    unsupported_target_architecture(specified_arch.as_str());
-                }
-                specified_arch
+                get_target_arch(specified_arch)
             } else if !user_attended() {
                 host_arch
                     .ok_or_else(|| anyhow!("Ciel does not support this CPU architecture."))
@@ -219,7 +580,9 @@ fn main() -> Result<()> {
                 actions::load_os(
                     &format!("https://releases.aosc.io/{}", rootfs.path),
                     Some(rootfs.sha256sum),
-                    false,
+                    RootfsFormat::Squashfs,
+                    update,
+                    &cancel,
                 )
             });
         }
@@ -230,148 +593,667 @@ fn main() -> Result<()> {
                 args.get_flag("force_use_apt") || read_config().is_ok_and(|x| x.force_use_apt)
             };
 
-            print_error!({ actions::update_os(force_use_apt,) });
+            let cancel = cancel_on_interrupt()?;
+            if let Some(instance) = get_update_os_instance(args)? {
+                let _lock = lock::lock_workspace_shared("update-os")?;
+                let _instance_lock = lock::lock_instance_exclusive(&instance, "update-os")?;
+                print_error!({
+                    actions::update_os(
+                        force_use_apt,
+                        actions::UpdateTarget::Staged(&instance),
+                        &cancel,
+                    )
+                });
+                return Ok(());
+            }
+            let _lock = lock::lock_workspace_exclusive("update-os")?;
+            print_error!({
+                actions::update_os(force_use_apt, actions::UpdateTarget::Base, &cancel)
+            });
         }
         ("config", args) => {
+            let add_dns = args.get_many::<IpAddr>("add-dns");
+            let remove_dns = args.get_many::<IpAddr>("remove-dns");
+            let add_mount = args.get_many::<String>("add-mount");
+            let remove_mount = args.get_many::<String>("remove-mount");
+            let trust_host_ca = args.get_one::<bool>("trust-host-ca");
+            let host_resolv_conf = args.get_one::<bool>("host-resolv-conf");
+            let live = args.get_flag("force-no-rollback");
+            if let Some(key) = args.get_one::<String>("get") {
+                print_config_value(&config::read_config()?, key)?;
+                return Ok(());
+            }
+            if args.get_flag("get-all") {
+                print!("{}", config::read_config()?.save_config()?);
+                return Ok(());
+            }
+            if add_dns.is_some()
+                || remove_dns.is_some()
+                || add_mount.is_some()
+                || remove_mount.is_some()
+                || trust_host_ca.is_some()
+                || host_resolv_conf.is_some()
+            {
+                print_error!({
+                    actions::update_config(
+                        |c| {
+                            if let Some(trust_host_ca) = trust_host_ca {
+                                c.trust_host_ca = *trust_host_ca;
+                            }
+                            if let Some(host_resolv_conf) = host_resolv_conf {
+                                c.host_resolv_conf = *host_resolv_conf;
+                            }
+                            if let Some(servers) = remove_dns {
+                                let servers = servers.collect::<Vec<_>>();
+                                c.dns_servers.retain(|x| !servers.contains(&x));
+                            }
+                            for server in add_dns.into_iter().flatten() {
+                                if !c.dns_servers.contains(server) {
+                                    c.dns_servers.push(*server);
+                                }
+                            }
+                            update_bind_mounts(&mut c.extra_bind_mounts, add_mount, remove_mount)
+                        },
+                        live,
+                    )
+                });
+                return Ok(());
+            }
             if args.get_flag("g") {
-                print_error!({ actions::config_os(None) });
+                print_error!({ actions::config_os(None, live) });
                 return Ok(());
             }
+            if live {
+                bail!("--force-no-rollback only applies to the workspace configuration, use it with -g or a workspace setting");
+            }
             let instance = get_instance_option(args)?;
-            print_error!({ actions::config_os(Some(&instance)) });
+            print_error!({ actions::config_os(Some(&instance), false) });
+        }
+        ("instconf", args) => {
+            let instance = get_instance_option(args)?;
+            if !Path::new(CIEL_INST_DIR).join(&instance).is_dir() {
+                bail!("Instance `{}` does not exist.", instance);
+            }
+            let mut config = config::InstanceConfig::load(&instance)?;
+            if let Some(key) = args.get_one::<String>("get") {
+                print_config_value(&config, key)?;
+                return Ok(());
+            }
+            let zone = args.get_one::<String>("network-zone");
+            let isolation = args.get_one::<bool>("network-isolation");
+            let local_repo = args.get_one::<bool>("local-repo");
+            let volatile = args.get_one::<bool>("volatile");
+            let hostname = args.get_one::<String>("hostname");
+            let rollback_policy = args.get_one::<String>("rollback-policy");
+            let private_tmp = args.get_one::<u64>("private-tmp");
+            let add_mount = args.get_many::<String>("add-mount");
+            let remove_mount = args.get_many::<String>("remove-mount");
+            if zone.is_none()
+                && isolation.is_none()
+                && local_repo.is_none()
+                && volatile.is_none()
+                && hostname.is_none()
+                && rollback_policy.is_none()
+                && private_tmp.is_none()
+                && !args.get_flag("no-private-tmp")
+                && !args.get_flag("no-hostname")
+                && add_mount.is_none()
+                && remove_mount.is_none()
+                && !args.get_flag("no-network-zone")
+                || args.get_flag("get-all")
+            {
+                print!("{}", toml::to_string(&config)?);
+                return Ok(());
+            }
+            if let Some(zone) = zone {
+                config::check_network_zone_name(zone)?;
+                config.network_zone = Some(zone.to_owned());
+            }
+            if args.get_flag("no-network-zone") {
+                config.network_zone = None;
+            }
+            if let Some(isolation) = isolation {
+                config.network_isolation = *isolation;
+            }
+            if let Some(local_repo) = local_repo {
+                config.local_repo = Some(*local_repo);
+            }
+            if let Some(volatile) = volatile {
+                config.volatile = Some(*volatile);
+            }
+            if let Some(hostname) = hostname {
+                config::check_hostname(hostname)?;
+                config.hostname = Some(hostname.to_owned());
+            }
+            if args.get_flag("no-hostname") {
+                config.hostname = None;
+            }
+            if let Some(policy) = rollback_policy {
+                config.rollback_policy = policy.parse()?;
+            }
+            if let Some(size) = private_tmp {
+                config.private_tmp = Some(*size as usize);
+            }
+            if args.get_flag("no-private-tmp") {
+                config.private_tmp = None;
+            }
+            update_bind_mounts(&mut config.bind_mounts, add_mount, remove_mount)?;
+            config.save(&instance)?;
+            info!("{}: configuration saved.", instance);
+            warn!(
+                "Please stop {} for the new configuration to take effect!",
+                instance
+            );
         }
         ("mount", args) => {
             print_error!({ one_or_all_instance!(args, &actions::mount_fs) });
         }
         ("new", args) => {
-            let arch = args.get_one::<String>("arch").map(|val| {
-                if !check_arch_name(val) {
-                    unsupported_target_architecture(val.as_str());
-                }
-                val.as_str()
-            });
+            let arch = args
+                .get_one::<String>("arch")
+                .map(|val| get_target_arch(val));
             let tarball = args.get_one::<String>("tarball");
-            if let Err(e) = actions::onboarding(tarball, arch) {
-                error!("{}", e);
+            let answers = args
+                .get_one::<String>("answers")
+                .map(|path| -> Result<_> {
+                    let data = std::fs::read_to_string(path)?;
+                    config::OnboardingAnswers::load(&data)
+                        .with_context(|| format!("Invalid onboarding answers in {}", path))
+                })
+                .transpose();
+            let save_answers = args.get_one::<String>("save-answers").map(Path::new);
+            if let Err(e) = answers
+                .and_then(|answers| actions::onboarding(tarball, arch, answers, save_answers))
+            {
+                error!("{:#}", e);
                 process::exit(1);
             }
         }
         ("run", args) => {
+            let _lock = lock::lock_workspace_shared("run")?;
             let instance = get_instance_option(args)?;
+            let _instance_lock = lock::lock_instance_shared(&instance, "run")?;
+            let workdir = get_workdir_option(args)?;
+            let autostart = !args.get_flag("NO_START");
             let args = args.get_many::<String>("COMMANDS").unwrap();
-            let status =
-                actions::run_in_container(&instance, &args.into_iter().collect::<Vec<_>>())?;
+            let status = actions::run_in_container_at(
+                &instance,
+                &args.into_iter().collect::<Vec<_>>(),
+                workdir.as_deref(),
+                autostart,
+            )?;
+            process::exit(status);
+        }
+        ("try", args) => {
+            let _lock = lock::lock_workspace_shared("try")?;
+            let mut config = config::InstanceConfig::default();
+            if let Some(zone) = args.get_one::<String>("network-zone") {
+                config::check_network_zone_name(zone)?;
+                config.network_zone = Some(zone.to_owned());
+            }
+            config.network_isolation = args.get_flag("network-isolation");
+            if args.get_flag("no-local-repo") {
+                config.local_repo = Some(false);
+            }
+            update_bind_mounts(
+                &mut config.bind_mounts,
+                args.get_many::<String>("mount"),
+                None,
+            )?;
+            let commands = args.get_many::<String>("COMMANDS").unwrap();
+            let status = actions::run_ephemeral(
+                &config,
+                &commands.collect::<Vec<_>>(),
+                args.get_flag("keep-on-failure"),
+            )?;
             process::exit(status);
         }
         ("shell", args) => {
+            let _lock = lock::lock_workspace_shared("shell")?;
             let instance = get_instance_option(args)?;
-            if let Some(cmd) = args.get_many::<String>("COMMANDS") {
-                let command = cmd
-                    .into_iter()
-                    .fold(String::with_capacity(1024), |acc, x| acc + " " + x);
-                let status = actions::run_in_container(&instance, &["/bin/bash", "-ec", &command])?;
+            let workdir = get_workdir_option(args)?;
+            let command = args.get_many::<String>("COMMANDS").map(|cmd| {
+                cmd.into_iter()
+                    .fold(String::with_capacity(1024), |acc, x| acc + " " + x)
+            });
+            if args.get_flag("ATTACH") {
+                let view = actions::InstanceView::open(&instance)?;
+                let status = match &command {
+                    Some(command) => {
+                        view.attach(&["/bin/bash", "-ec", command.as_str()], workdir.as_deref())
+                    }
+                    None => view.attach(&["/bin/bash"], workdir.as_deref()),
+                };
+                process::exit(status?);
+            }
+            let _instance_lock = lock::lock_instance_shared(&instance, "shell")?;
+            let autostart = !args.get_flag("NO_START");
+            if let Some(command) = command {
+                let status = actions::run_in_container_at(
+                    &instance,
+                    &["/bin/bash", "-ec", &command],
+                    workdir.as_deref(),
+                    autostart,
+                )?;
                 process::exit(status);
             }
-            let status = actions::run_in_container(&instance, &["/bin/bash"])?;
+            let status = actions::run_in_container_at(
+                &instance,
+                &["/bin/bash"],
+                workdir.as_deref(),
+                autostart,
+            )?;
             process::exit(status);
         }
+        ("boot", args) => {
+            let _lock = lock::lock_workspace_shared("boot")?;
+            let instances = if args.get_flag("all") {
+                machine::list_instances_simple()?
+            } else {
+                vec![get_instance_option(args)?]
+            };
+            let jobs = *args.get_one::<usize>("jobs").unwrap();
+            let results = match actions::boot_instances(&instances, jobs) {
+                Ok(results) => results,
+                Err(e) => {
+                    error!("{:#}", e);
+                    process::exit(1);
+                }
+            };
+            let mut failed = false;
+            for (instance, result) in results {
+                match result {
+                    Ok(()) => {
+                        info!("{}: instance booted.", instance);
+                    }
+                    Err(e) => {
+                        error!("{}: {:#}", instance, e);
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                process::exit(1);
+            }
+        }
         ("stop", args) => {
             let instance = get_instance_option(args)?;
             print_error!({ actions::stop_container(&instance) });
         }
         ("down", args) => {
-            print_error!({ one_or_all_instance!(args, &actions::container_down) });
+            let busy = if args.get_flag("kill-holders") {
+                actions::BusyMount::KillHolders
+            } else {
+                actions::BusyMount::Refuse
+            };
+            if let Some(idle_for) = args.get_one::<String>("idle-for") {
+                print_error!({
+                    common::parse_duration(idle_for)
+                        .and_then(|x| actions::down_idle_instances(x, busy))
+                });
+                return Ok(());
+            }
+            print_error!({
+                one_or_all_instance!(args, &|instance: &str| {
+                    actions::container_down_with(instance, busy)
+                })
+            });
         }
         ("commit", args) => {
             let instance = get_instance_option(args)?;
-            print_error!({ actions::commit_container(&instance) });
+            let paths = args
+                .get_many::<String>("path")
+                .map(|paths| paths.map(PathBuf::from).collect::<Vec<_>>())
+                .unwrap_or_default();
+            if args.get_flag("dry-run") {
+                print_error!({
+                    actions::print_commit_preview(
+                        &instance,
+                        &paths,
+                        !args.get_flag("no-default-excludes"),
+                    )
+                });
+                return Ok(());
+            }
+            let _lock = lock::lock_workspace_exclusive("commit")?;
+            let _instance_lock = lock::lock_instance_exclusive(&instance, "commit")?;
+            print_error!({
+                actions::commit_container_paths(
+                    &instance,
+                    &paths,
+                    args.get_flag("force"),
+                    !args.get_flag("no-default-excludes"),
+                )
+            });
         }
         ("rollback", args) => {
-            print_error!({ one_or_all_instance!(args, &actions::rollback_container) });
+            let mut paths = args
+                .get_many::<PathBuf>("PRESERVE")
+                .map(|x| x.cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            if let Ok(config) = read_config() {
+                paths.extend(config.preserve_paths);
+            }
+            print_error!({
+                one_or_all_instance!(args, &|instance: &str| {
+                    let _lock = lock::lock_instance_exclusive(instance, "rollback")?;
+                    actions::rollback_container_preserving(instance, &paths)
+                })
+            });
         }
         ("del", args) => {
-            let instance = args.get_one::<String>("INSTANCE").unwrap();
-            print_error!({ actions::remove_instance(instance) });
+            let instances = if args.get_flag("all") {
+                machine::list_instances_simple()?
+            } else {
+                args.get_many::<String>("INSTANCE")
+                    .unwrap()
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            let broken = args.get_flag("force-broken");
+            if let [instance] = instances.as_slice() {
+                if !args.get_flag("all") {
+                    print_error!({
+                        lock::lock_instance_exclusive(instance, "del").and_then(|_lock| {
+                            if broken {
+                                actions::remove_broken_instance(instance)
+                            } else {
+                                actions::remove_instance(instance)
+                            }
+                        })
+                    });
+                    return Ok(());
+                }
+            }
+            match actions::remove_instances(&instances, args.get_flag("force"), broken) {
+                Ok(true) => (),
+                Ok(false) => process::exit(1),
+                Err(e) => {
+                    error!("{:#}", e);
+                    process::exit(1);
+                }
+            }
         }
         ("add", args) => {
             let instance = args.get_one::<String>("INSTANCE").unwrap();
             print_error!({ actions::add_instance(instance) });
         }
         ("build", args) => {
-            let instance = get_instance_option(args)?;
-            let settings = BuildSettings {
-                offline: args.get_flag("OFFLINE"),
-                stage2: args.get_flag("STAGE2"),
+            let _lock = lock::lock_workspace_shared("build")?;
+            if let Some(instances) = args.get_many::<String>("INSTANCES") {
+                let instances = instances.cloned().collect::<Vec<_>>();
+                let status = build_sharded(&instances, args);
+                notify_build_finished(&status);
+                if args.get_flag("TIMINGS") {
+                    timing::print_summary();
+                }
+                process::exit(status?);
+            }
+            let state = match args.get_one::<String>("CONTINUE") {
+                Some(cont) => {
+                    let mut state = actions::load_build_checkpoint(cont)?;
+                    if let Some(skip) = args.get_many::<String>("SKIP") {
+                        state.skip_packages(&skip.collect::<Vec<_>>())?;
+                    }
+                    if let Some(only) = args.get_many::<String>("ONLY") {
+                        state.retain_packages(&only.collect::<Vec<_>>())?;
+                    }
+                    Some(state)
+                }
+                None => None,
             };
-            let mut state = None;
-            if let Some(cont) = args.get_one::<String>("CONTINUE") {
-                state = Some(actions::load_build_checkpoint(cont)?);
-                let empty: Vec<&str> = Vec::new();
-                let status = actions::package_build(&instance, empty.into_iter(), state, settings)?;
-                println!("\x07"); // bell character
-                process::exit(status);
+            // builds that were running in an ephemeral instance are resumed in a new one
+            let ephemeral = if !args.contains_id("INSTANCE")
+                && (args.get_flag("ALWAYS_DISCARD")
+                    || state.as_ref().is_some_and(|x| x.is_ephemeral()))
+            {
+                let instance = actions::EphemeralInstance::new("build")?;
+                info!("Building in ephemeral instance {} ...", instance.name());
+                Some(instance)
+            } else {
+                None
+            };
+            let instance = match &ephemeral {
+                Some(ephemeral) => ephemeral.name().to_owned(),
+                None => get_instance_option(args)?,
+            };
+            let _instance_lock = lock::lock_instance_exclusive(&instance, "build")?;
+            let settings = get_build_settings(args, ephemeral.is_some(), cancel_on_interrupt()?);
+            let notifications = args
+                .get_one::<String>("NOTIFY")
+                .cloned()
+                .or_else(|| config::read_config_or_warn().and_then(|x| x.notifications));
+            if let Some(notifications) = notifications {
+                logging::init_notifications(notifications.parse()?);
             }
-            let packages = args.get_many::<String>("PACKAGES");
-            if packages.is_none() {
+            let status = if state.is_some() {
+                let empty: Vec<&str> = Vec::new();
+                let status = actions::package_build(&instance, empty.into_iter(), state, settings);
+                notify_build_finished(&status);
+                status
+            } else if let Some(packages) = args.get_many::<String>("PACKAGES") {
+                if args.contains_id("SELECT") {
+                    let start_package = args.get_one::<String>("SELECT");
+                    actions::packages_stage_select(&instance, packages, settings, start_package)
+                } else if args.get_flag("FETCH") {
+                    let packages = packages.into_iter().collect::<Vec<_>>();
+                    actions::package_fetch(&instance, &packages)
+                } else {
+                    let status = actions::package_build(&instance, packages, state, settings);
+                    notify_build_finished(&status);
+                    status
+                }
+            } else {
                 error!("Please specify a list of packages to build!");
-                process::exit(1);
-            }
-            let packages = packages.unwrap();
-            if args.contains_id("SELECT") {
-                let start_package = args.get_one::<String>("SELECT");
-                let status =
-                    actions::packages_stage_select(&instance, packages, settings, start_package)?;
-                process::exit(status);
+                Ok(1)
+            };
+            if args.get_flag("TIMINGS") {
+                timing::print_summary();
             }
-            if args.get_flag("FETCH") {
-                let packages = packages.into_iter().collect::<Vec<_>>();
-                let status = actions::package_fetch(&instance, &packages)?;
-                process::exit(status);
+            // the ephemeral instance is destroyed when the guard is dropped on error
+            let status = status?;
+            if let Some(ephemeral) = ephemeral {
+                if status == 0 && args.get_flag("KEEP_EPHEMERAL") {
+                    info!("Keeping ephemeral instance {}.", ephemeral.keep());
+                } else {
+                    ephemeral.discard()?;
+                }
             }
-            let status = actions::package_build(&instance, packages, state, settings)?;
-            println!("\x07"); // bell character
             process::exit(status);
         }
         ("", _) => {
-            machine::print_instances()?;
+            machine::print_instances(InstanceOrder::Name, false)?;
+        }
+        ("list", args) => {
+            let order = InstanceOrder::from_name(args.get_one::<String>("sort").unwrap())?;
+            machine::print_instances(order, args.get_flag("verbose"))?;
         }
-        ("list", _) => {
-            machine::print_instances()?;
+        ("status", args) => {
+            let instance = get_instance_option(args)?;
+            print_error!({ actions::print_instance_status(&instance, args.get_flag("units")) });
         }
-        ("doctor", _) => {
-            print_error!({ diagnose::run_diagnose() });
+        ("doctor", args) => {
+            let code = diagnose::run_diagnose(args.get_flag("json"), args.get_flag("fix"))?;
+            if code != 0 {
+                process::exit(code);
+            }
         }
         ("repo", args) => match args.subcommand() {
             Some(("refresh", _)) => {
                 info!("Refreshing repository...");
-                print_error!({
-                    repo::refresh_repo(&std::env::current_dir().unwrap().join(get_output_dir()))
-                });
-                info!("Repository has been refreshed.");
+                let root = std::env::current_dir()?.join(get_output_dir());
+                let report = repo::refresh_repo(&root)?;
+                info!(
+                    "Repository has been refreshed with {} packages.",
+                    report.indexed
+                );
+                if !report.skipped.is_empty() {
+                    warn!("{} files were left out of the index:", report.skipped.len());
+                    for (path, reason) in &report.skipped {
+                        eprintln!("  {}: {}", path.display(), reason);
+                    }
+                    process::exit(REFRESH_SKIPPED_EXIT_CODE);
+                }
+            }
+            Some(("verify", args)) => {
+                let root = std::env::current_dir()?.join(get_output_dir());
+                let report = repo::verify_repo(&root)?;
+                if args.get_flag("json") {
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    print_verify_report(&report);
+                }
+                if report.is_ok() {
+                    return Ok(());
+                }
+                if args.get_flag("repair") {
+                    info!("Refreshing repository...");
+                    print_error!({ repo::refresh_repo(&root) });
+                    if !report.mismatched.is_empty() {
+                        warn!("The index now matches the mismatched packages, but their content may be corrupted. Consider rebuilding them.");
+                    }
+                }
+                process::exit(1);
+            }
+            Some(("import", args)) => {
+                let root = std::env::current_dir()?.join(get_output_dir());
+                let paths = args
+                    .get_many::<String>("PATHS")
+                    .unwrap()
+                    .collect::<Vec<_>>();
+                let mode = if args.get_flag("move") {
+                    repo::ImportMode::Move
+                } else if args.get_flag("hardlink") {
+                    repo::ImportMode::Hardlink
+                } else {
+                    repo::ImportMode::Copy
+                };
+                let report = repo::import_debs(&root, &paths, mode)?;
+                print_import_report(&report);
+                if !report.failed.is_empty() {
+                    process::exit(1);
+                }
+            }
+            Some(("migrate-output", args)) => {
+                let sep_mount = config::read_config()?.sep_mount;
+                let cwd = std::env::current_dir()?;
+                let from = cwd.join(actions::get_output_directory(!sep_mount));
+                let to = cwd.join(actions::get_output_directory(sep_mount));
+                let mode = if args.get_flag("hardlink") {
+                    repo::ImportMode::Hardlink
+                } else {
+                    repo::ImportMode::Move
+                };
+                info!(
+                    "Migrating the packages from {} to {} ...",
+                    from.display(),
+                    to.display()
+                );
+                let report = repo::migrate_output(&from, &to, mode)?;
+                for deb in report.imported.iter() {
+                    println!("{}", deb);
+                }
+                print_import_report(&report);
+                if !report.failed.is_empty() {
+                    process::exit(1);
+                }
             }
             Some(("init", args)) => {
                 info!("Initializing repository...");
                 let instance = get_instance_option(args)?;
                 let cwd = std::env::current_dir().unwrap();
+                let mut inst_config = config::InstanceConfig::load(&instance)?;
+                let was_enabled = inst_config.local_repo.unwrap_or(true);
+                // the sources list is edited in the mounted instance, so that a running
+                // container picks up the change without restarting
                 print_error!({ actions::mount_fs(&instance) });
-                print_error!({ repo::init_repo(&cwd.join(get_output_dir()), &cwd.join(instance)) });
+                print_error!({
+                    repo::init_repo(&cwd.join(get_output_dir()), &cwd.join(&instance))
+                });
+                inst_config.local_repo = Some(true);
+                inst_config.save(&instance)?;
                 info!("Repository has been initialized and refreshed.");
+                if !was_enabled {
+                    warn!(
+                        "The output directory is only mounted into the instance on its next boot."
+                    );
+                }
             }
             Some(("deinit", args)) => {
                 info!("Disabling local repository...");
                 let instance = get_instance_option(args)?;
                 let cwd = std::env::current_dir().unwrap();
+                let mut inst_config = config::InstanceConfig::load(&instance)?;
                 print_error!({ actions::mount_fs(&instance) });
-                print_error!({ repo::deinit_repo(&cwd.join(instance)) });
+                print_error!({ repo::deinit_repo(&cwd.join(&instance)) });
+                inst_config.local_repo = Some(false);
+                inst_config.save(&instance)?;
                 info!("Repository has been disabled.");
             }
+            Some(("serve", args)) => {
+                let root = std::env::current_dir()?.join(get_output_dir());
+                let listener = std::net::TcpListener::bind(args.get_one::<String>("bind").unwrap())
+                    .context("Unable to listen on the specified address")?;
+                let addr = listener.local_addr()?;
+                let host = if addr.ip().is_unspecified() {
+                    nix::unistd::gethostname()?.to_string_lossy().into_owned()
+                } else {
+                    addr.ip().to_string()
+                };
+                let token = args.get_one::<String>("token");
+                let prefix = token.map(|x| format!("{}/", x)).unwrap_or_default();
+                info!(
+                    "Serving {} at {}, press Ctrl-C to stop.",
+                    root.join("debs").display(),
+                    addr
+                );
+                info!("Add this line to the other workspaces to use the packages:");
+                println!(
+                    "deb [trusted=yes] http://{}:{}/{} /",
+                    host,
+                    addr.port(),
+                    prefix
+                );
+                let (tx, rx) = std::sync::mpsc::channel();
+                ctrlc::set_handler(move || {
+                    tx.send(()).ok();
+                })?;
+                print_error!({ repo::serve_repo(listener, &root, token.map(|x| x.as_str()), rx) });
+                info!("Repository server stopped.");
+            }
             _ => unreachable!(),
         },
-        ("clean", _) => {
-            print_error!({ actions::cleanup_outputs() });
+        ("clean", args) => {
+            if args.get_flag("trash") {
+                let _lock = lock::lock_workspace_exclusive("clean")?;
+                let (removed, left) = actions::empty_trash()?;
+                info!("Removed {} entries from the trash.", removed);
+                if left > 0 {
+                    warn!(
+                        "{} entries are still in use and can't be removed yet.",
+                        left
+                    );
+                }
+            } else if args.get_flag("over-limit") {
+                print_error!({ actions::enforce_cache_limit() });
+            } else if args.get_flag("apt-cache") {
+                print_error!({ actions::clean_apt_cache() });
+            } else {
+                print_error!({ actions::cleanup_outputs() });
+            }
         }
-        ("version", _) => {
+        ("space", _) => {
+            print_error!({ actions::print_space_usage() });
+        }
+        ("version", args) => {
             println!("{}", version_string);
+            if args.get_flag("workspace") {
+                println!("Workspace format: {}", common::workspace_format_version()?);
+                match common::read_workspace_meta()? {
+                    Some(meta) => println!("{}", meta),
+                    None => println!("Workspace metadata: not recorded yet"),
+                }
+            }
         }
         // catch all other conditions
         (_, options) => {
@@ -400,3 +1282,19 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_update_os_instance() {
+    let parse = |args: &[&str]| {
+        let matches = cli::build_cli().try_get_matches_from(args)?;
+        let (_, args) = matches.subcommand().unwrap();
+        get_update_os_instance(args)
+    };
+    assert_eq!(parse(&["ciel", "update-os"]).unwrap(), None);
+    assert_eq!(parse(&["ciel", "update-os", "-i", "alpha"]).unwrap(), None);
+    assert_eq!(
+        parse(&["ciel", "update-os", "--stage", "-i", "alpha"]).unwrap(),
+        Some("alpha".to_string())
+    );
+    assert!(parse(&["ciel", "update-os", "--stage"]).is_err());
+}
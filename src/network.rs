@@ -1,10 +1,20 @@
-use crate::make_progress_bar;
+use crate::{
+    actions::CancelToken,
+    common::{create_progress_bar, finish_progress_bar, ProgressPhase, RootfsError},
+    make_progress_bar,
+};
 use anyhow::{anyhow, Result};
 use fs3::FileExt;
-use reqwest::blocking::{Client, Response};
+use reqwest::{
+    blocking::{Client, Response},
+    StatusCode,
+};
 use serde::Deserialize;
-use std::path::Path;
 use std::sync::LazyLock;
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
 use std::{
     sync::{
         atomic::{AtomicUsize, Ordering},
@@ -13,8 +23,15 @@ use std::{
     thread::{self, sleep},
     time::Duration,
 };
+use time::{
+    format_description::FormatItem, macros::format_description, OffsetDateTime, PrimitiveDateTime,
+};
 
 const MANIFEST_URL: &str = "https://releases.aosc.io/manifest/recipe.json";
+/// Format of the `Date` header in the HTTP responses, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+const HTTP_DATE: &[FormatItem] = format_description!(
+    "[weekday repr:short], [day] [month repr:short] [year] [hour repr:24]:[minute]:[second] GMT"
+);
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct RootFs {
@@ -43,15 +60,73 @@ static GIT_PROGRESS: LazyLock<indicatif::ProgressStyle> = LazyLock::new(|| {
         .unwrap()
 });
 
-/// Download a file from the web
+/// Download a file from the web, failing with `RootfsError::DownloadFailed`
+/// if the server can not be reached or is unavailable for now,
+/// and with `RootfsError::DownloadRejected` if it refuses to send the file
 pub fn download_file(url: &str) -> Result<Response> {
-    let client = Client::new().get(url).send()?;
+    Client::new()
+        .get(url)
+        .send()
+        .and_then(|x| x.error_for_status())
+        .map_err(|e| download_error(url, e))
+}
+
+/// Whether the request may succeed if tried again later: the connection problems,
+/// the server errors and the responses asking to slow down (408 and 429)
+fn is_transient(error: &reqwest::Error) -> bool {
+    match error.status() {
+        Some(status) => {
+            status.is_server_error()
+                || status == StatusCode::REQUEST_TIMEOUT
+                || status == StatusCode::TOO_MANY_REQUESTS
+        }
+        None => error.is_connect() || error.is_timeout() || error.is_request() || error.is_body(),
+    }
+}
+
+fn download_error(url: &str, error: reqwest::Error) -> anyhow::Error {
+    if is_transient(&error) {
+        return download_failed(url, error);
+    }
+    RootfsError::DownloadRejected {
+        url: url.to_owned(),
+        source: error.into(),
+    }
+    .into()
+}
 
-    Ok(client)
+fn download_failed<E: Into<Box<dyn std::error::Error + Send + Sync>>>(
+    url: &str,
+    source: E,
+) -> anyhow::Error {
+    RootfsError::DownloadFailed {
+        url: url.to_owned(),
+        source: source.into(),
+    }
+    .into()
+}
+
+fn parse_http_date(date: &str) -> Result<OffsetDateTime> {
+    Ok(PrimitiveDateTime::parse(date, &HTTP_DATE)?.assume_utc())
 }
 
-/// Download a file with progress indicator
-pub fn download_file_progress(url: &str, file: &str) -> Result<u64> {
+/// Current time of the server, according to the `Date` header of its response
+pub fn server_time(url: &str) -> Result<OffsetDateTime> {
+    let resp = Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?
+        .head(url)
+        .send()?;
+    let date = resp
+        .headers()
+        .get(reqwest::header::DATE)
+        .ok_or_else(|| anyhow!("the server did not send its time"))?;
+
+    parse_http_date(date.to_str()?)
+}
+
+/// Download a file with progress indicator, the partial file is removed if `cancel` is triggered
+pub fn download_file_progress(url: &str, file: &str, cancel: &CancelToken) -> Result<u64> {
     let mut output = std::fs::File::create(file)?;
     let resp = download_file(url)?;
     let mut total: u64 = 0;
@@ -63,24 +138,38 @@ pub fn download_file_progress(url: &str, file: &str) -> Result<u64> {
         // fails early when there is insufficient disk space available
         output.allocate(total)?;
     }
-    let progress_bar = indicatif::ProgressBar::new(total);
-    progress_bar.set_style(
-        indicatif::ProgressStyle::default_bar()
-            .template(make_progress_bar!("{bytes}/{total_bytes}"))
-            .unwrap(),
+    let progress_bar = create_progress_bar(
+        total,
+        make_progress_bar!("{bytes}/{total_bytes}"),
+        ProgressPhase::Download,
     );
-    progress_bar.set_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(5));
     let mut reader = progress_bar.wrap_read(resp);
-    std::io::copy(&mut reader, &mut output)?;
-    progress_bar.finish_and_clear();
+    // tell the connection problems apart from the failures writing the file
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        if cancel.is_cancelled() {
+            progress_bar.abandon();
+            std::fs::remove_file(file).ok();
+            cancel.check()?;
+        }
+        let len = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(download_failed(url, e)),
+        };
+        output.write_all(&buffer[..len])?;
+    }
+    finish_progress_bar(&progress_bar, ProgressPhase::Download);
 
     Ok(total)
 }
 
 /// Pick the latest buildkit rootfs according to the recipe
 pub fn pick_latest_rootfs(arch: &str) -> Result<RootFs> {
-    let resp = Client::new().get(MANIFEST_URL).send()?;
-    let recipe: Recipe = resp.json()?;
+    let recipe: Recipe = download_file(MANIFEST_URL)?
+        .json()
+        .map_err(|e| download_error(MANIFEST_URL, e))?;
     let buildkit = recipe
         .variants
         .into_iter()
@@ -251,3 +340,53 @@ pub fn git_switch_branch(
     // returns whether a stash was made
     Ok(is_tree_dirty)
 }
+
+#[test]
+fn test_parse_http_date() {
+    let date = parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+    assert_eq!(date.unix_timestamp(), 784111777);
+    assert!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT").is_err());
+}
+
+#[test]
+fn test_download_errors() {
+    // a server answering a single request with the given status and body
+    let serve = |status: &'static str, body: &'static str| {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/manifest.json", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // the request is small enough to arrive in one read
+            let _request = stream.read(&mut [0u8; 4096]).unwrap_or(0);
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .ok();
+        });
+        url
+    };
+    let retryable = |err: anyhow::Error| err.downcast_ref::<RootfsError>().unwrap().is_retryable();
+    for (status, expected) in [
+        ("404 Not Found", false),
+        ("403 Forbidden", false),
+        ("408 Request Timeout", true),
+        ("429 Too Many Requests", true),
+        ("503 Service Unavailable", true),
+    ] {
+        let err = download_file(&serve(status, "")).unwrap_err();
+        assert_eq!(retryable(err), expected, "{}", status);
+    }
+    // an invalid manifest won't get any better by downloading it again
+    let url = serve("200 OK", "not a manifest");
+    let err = download_file(&url)
+        .unwrap()
+        .json::<Recipe>()
+        .map_err(|e| download_error(&url, e))
+        .err()
+        .unwrap();
+    assert!(!retryable(err));
+}
@@ -0,0 +1,342 @@
+//! Loading the rootfs from OCI (oci-archive) and Docker (docker-archive) image archives
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{BufRead, BufReader, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{common::check_archive_entry, info, warn};
+use console::style;
+
+const OCI_LAYOUT_FILE: &str = "oci-layout";
+const OCI_INDEX_FILE: &str = "index.json";
+const DOCKER_MANIFEST_FILE: &str = "manifest.json";
+const OCI_INDEX_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OciDescriptor {
+    #[serde(default)]
+    media_type: Option<String>,
+    digest: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OciIndex {
+    manifests: Vec<OciDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OciManifest {
+    layers: Vec<OciDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    layers: Vec<String>,
+}
+
+/// Check if the (uncompressed) tar archive is an OCI or Docker image archive
+pub fn is_image_archive(path: &Path) -> Result<bool> {
+    let mut archive = tar::Archive::new(File::open(path)?);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = entry.path()?;
+        let path = path.strip_prefix("./").unwrap_or(&path);
+        if path == Path::new(OCI_LAYOUT_FILE) || path == Path::new(DOCKER_MANIFEST_FILE) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Resolve the path of the blob in the OCI image layout
+fn blob_path(image: &Path, digest: &str) -> Result<PathBuf> {
+    let (algorithm, hash) = digest
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Invalid digest in the image: {}", digest))?;
+    let is_safe = |x: &str| !x.is_empty() && x.chars().all(|c| c.is_ascii_alphanumeric());
+    if !is_safe(algorithm) || !is_safe(hash) {
+        return Err(anyhow!("Invalid digest in the image: {}", digest));
+    }
+
+    Ok(image.join("blobs").join(algorithm).join(hash))
+}
+
+/// Find the layers (from the bottom to the top) of the image in the unpacked OCI image layout
+fn oci_layers(image: &Path) -> Result<Vec<PathBuf>> {
+    let mut index: OciIndex = serde_json::from_reader(File::open(image.join(OCI_INDEX_FILE))?)?;
+    loop {
+        if index.manifests.len() > 1 {
+            warn!("The image contains multiple manifests, using the first one.");
+        }
+        let descriptor = index
+            .manifests
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("The image does not contain any manifest"))?;
+        let blob = File::open(blob_path(image, &descriptor.digest)?)?;
+        // follow nested (e.g. multi-platform) indexes
+        if descriptor
+            .media_type
+            .is_some_and(|x| OCI_INDEX_MEDIA_TYPES.contains(&x.as_str()))
+        {
+            index = serde_json::from_reader(blob)?;
+            continue;
+        }
+        let manifest: OciManifest = serde_json::from_reader(blob)?;
+        return manifest
+            .layers
+            .iter()
+            .map(|x| blob_path(image, &x.digest))
+            .collect();
+    }
+}
+
+/// Find the layers (from the bottom to the top) of the image in the unpacked Docker image archive
+fn docker_layers(image: &Path) -> Result<Vec<PathBuf>> {
+    let manifests: Vec<DockerManifest> =
+        serde_json::from_reader(File::open(image.join(DOCKER_MANIFEST_FILE))?)?;
+    if manifests.len() > 1 {
+        warn!("The archive contains multiple images, using the first one.");
+    }
+    let manifest = manifests
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("The archive does not contain any image"))?;
+    manifest
+        .layers
+        .iter()
+        .map(|layer| {
+            check_archive_entry(Path::new(layer), false, false)?;
+            Ok(image.join(layer))
+        })
+        .collect()
+}
+
+/// Open the layer, decompressing it if needed
+fn open_layer(path: &Path) -> Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let magic = reader.fill_buf()?;
+    let layer: Box<dyn Read> = if magic.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::read::GzDecoder::new(reader))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(zstd::stream::read::Decoder::with_buffer(reader)?)
+    } else {
+        Box::new(reader)
+    };
+
+    Ok(layer)
+}
+
+/// Remove the file or the directory (recursively), if it exists
+fn remove_path(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(())
+}
+
+/// Normalize the entry path (without the leading `./`)
+fn normalize_entry_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|x| matches!(x, Component::Normal(_)))
+        .collect()
+}
+
+/// Resolve the directory in the rootfs, refusing to follow symlinks out of the rootfs,
+/// returns `None` if it does not exist
+fn resolve_dir(rootfs: &Path, dir: &Path) -> Result<Option<PathBuf>> {
+    let resolved = match fs::canonicalize(rootfs.join(dir)) {
+        Ok(resolved) => resolved,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if !resolved.starts_with(fs::canonicalize(rootfs)?) {
+        return Err(anyhow!(
+            "Layer entry `{}` escapes the destination directory",
+            dir.display()
+        ));
+    }
+
+    Ok(Some(resolved))
+}
+
+/// Apply a layer on top of the rootfs, processing the whiteout files
+fn apply_layer<R: Read>(reader: R, rootfs: &Path, allow_devices: bool) -> Result<()> {
+    let mut layer = tar::Archive::new(reader);
+    layer.set_unpack_xattrs(true);
+    layer.set_preserve_permissions(true);
+    layer.set_preserve_ownerships(true);
+    // entries from this layer must survive the opaque whiteouts in the same layer
+    let mut added = HashSet::new();
+    for entry in layer.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        let is_device = entry_type.is_block_special() || entry_type.is_character_special();
+        let path = entry.path()?.into_owned();
+        check_archive_entry(&path, is_device, allow_devices)?;
+        if entry_type.is_hard_link() {
+            if let Some(target) = entry.link_name()? {
+                check_archive_entry(&target, false, allow_devices)?;
+            }
+        }
+        let path = normalize_entry_path(&path);
+        let name = path
+            .file_name()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let parent_dir = resolve_dir(rootfs, parent)?;
+        if name == OPAQUE_WHITEOUT {
+            // hide everything from the lower layers in this directory
+            if let Some(parent_dir) = parent_dir {
+                for child in fs::read_dir(parent_dir)? {
+                    let child = child?;
+                    if !added.contains(&parent.join(child.file_name())) {
+                        remove_path(&child.path())?;
+                    }
+                }
+            }
+            continue;
+        }
+        if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            if let Some(parent_dir) = parent_dir {
+                remove_path(&parent_dir.join(hidden))?;
+            }
+            continue;
+        }
+        // replace the file from the lower layers, directories are merged
+        if let Some(parent_dir) = parent_dir {
+            let dest = parent_dir.join(&name);
+            if let Ok(metadata) = fs::symlink_metadata(&dest) {
+                if !(metadata.is_dir() && entry_type.is_dir()) {
+                    remove_path(&dest)?;
+                }
+            }
+        }
+        entry.unpack_in(rootfs)?;
+        added.insert(path);
+    }
+
+    Ok(())
+}
+
+/// Extract the OCI or Docker image archive and merge its layers into the rootfs
+pub fn extract_image_archive<R: Read>(reader: R, rootfs: &Path, allow_devices: bool) -> Result<()> {
+    // unpack the archive next to the rootfs, /tmp may not have enough space
+    let image = tempfile::tempdir_in(rootfs.parent().unwrap_or(Path::new(".")))?;
+    tar::Archive::new(reader).unpack(image.path())?;
+    let layers = if image.path().join(OCI_LAYOUT_FILE).is_file() {
+        oci_layers(image.path())?
+    } else {
+        docker_layers(image.path())?
+    };
+    let total = layers.len();
+    for (i, layer) in layers.iter().enumerate() {
+        info!("Applying layer {}/{} ...", i + 1, total);
+        apply_layer(open_layer(layer)?, rootfs, allow_devices)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+fn craft_layer(entries: &[(&str, Option<&[u8]>)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, content) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o755);
+        header.set_uid(nix::unistd::getuid().as_raw().into());
+        header.set_gid(nix::unistd::getgid().as_raw().into());
+        match content {
+            Some(content) => {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(content.len() as u64);
+                builder.append_data(&mut header, path, *content).unwrap();
+            }
+            None => {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_size(0);
+                builder.append_data(&mut header, path, &[][..]).unwrap();
+            }
+        }
+    }
+
+    builder.into_inner().unwrap()
+}
+
+#[test]
+fn test_apply_layer() {
+    let dir = tempfile::tempdir().unwrap();
+    let rootfs = dir.path();
+    let base = craft_layer(&[
+        ("etc", None),
+        ("etc/a", Some(b"a")),
+        ("etc/b", Some(b"b")),
+        ("opt", None),
+        ("opt/x", Some(b"x")),
+    ]);
+    apply_layer(&base[..], rootfs, false).unwrap();
+    let upper = craft_layer(&[
+        ("etc/.wh.a", Some(b"")),
+        ("etc/b", Some(b"new")),
+        ("opt", None),
+        ("opt/y", Some(b"y")),
+        ("opt/.wh..wh..opq", Some(b"")),
+    ]);
+    apply_layer(&upper[..], rootfs, false).unwrap();
+    assert!(!rootfs.join("etc/a").exists());
+    assert!(!rootfs.join("etc/.wh.a").exists());
+    assert_eq!(fs::read(rootfs.join("etc/b")).unwrap(), b"new");
+    assert!(!rootfs.join("opt/x").exists());
+    assert_eq!(fs::read(rootfs.join("opt/y")).unwrap(), b"y");
+}
+
+#[test]
+fn test_apply_layer_symlink_escape() {
+    let dir = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    fs::write(outside.path().join("secret"), b"secret").unwrap();
+    let rootfs = dir.path();
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    header.set_uid(nix::unistd::getuid().as_raw().into());
+    header.set_gid(nix::unistd::getgid().as_raw().into());
+    builder
+        .append_link(&mut header, "etc", outside.path())
+        .unwrap();
+    let base = builder.into_inner().unwrap();
+    apply_layer(&base[..], rootfs, false).unwrap();
+    let upper = craft_layer(&[("etc/.wh.secret", Some(b""))]);
+    assert!(apply_layer(&upper[..], rootfs, false).is_err());
+    assert!(outside.path().join("secret").exists());
+}
+
+#[test]
+fn test_blob_path() {
+    let image = Path::new("/image");
+    assert_eq!(
+        blob_path(image, "sha256:0123abcd").unwrap(),
+        Path::new("/image/blobs/sha256/0123abcd")
+    );
+    assert!(blob_path(image, "sha256:../../etc").is_err());
+    assert!(blob_path(image, "0123abcd").is_err());
+}
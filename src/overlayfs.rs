@@ -1,15 +1,25 @@
-use crate::common;
+use crate::{common, timing, warn};
 use anyhow::{anyhow, bail, Context, Result};
+use console::style;
 use libmount::{mountinfo::Parser, Overlay};
+use nix::errno::Errno;
 use nix::mount::{umount2, MntFlags};
+use nix::sys::stat::{mknod, utimensat, Mode, SFlag, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{lseek, Whence};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
+use std::io::{self, Seek, SeekFrom};
+use std::os::fd::AsRawFd;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{
     ffi::OsStr,
     io::{BufRead, BufReader},
+    time::SystemTime,
 };
 
 pub trait LayerManager {
@@ -31,19 +41,41 @@ pub trait LayerManager {
     fn mount(&mut self, to: &Path) -> Result<()>;
     /// Return if the filesystem is mounted
     fn is_mounted(&self, target: &Path) -> Result<bool>;
+    /// Return the effective options of the filesystem mounted at the given path,
+    /// `None` if it is not mounted
+    fn mount_options(&self, target: &Path) -> Result<Option<MountOptions>>;
     /// Rollback the filesystem to the distribution state
     fn rollback(&mut self) -> Result<()>;
-    /// Commit the current state of the instance filesystem to the distribution state
-    fn commit(&mut self) -> Result<()>;
-    /// Un-mount the filesystem
+    /// Rollback the filesystem, but first move the changes under the given paths to `dest`
+    fn rollback_preserving(&mut self, paths: &[PathBuf], dest: &Path) -> Result<()>;
+    /// Commit the current state of the instance filesystem to the distribution state,
+    /// returns the number of entries dropped because of the commit exclusion list
+    fn commit(&mut self) -> Result<usize>;
+    /// Commit only the changes under the given paths to the distribution state,
+    /// the rest of the changes are left untouched in the instance filesystem
+    fn commit_paths(&mut self, paths: &[PathBuf]) -> Result<usize>;
+    /// Summarize what committing would change in the distribution state, nothing is modified.
+    /// Only the changes under the given paths are considered, unless no path is given
+    fn commit_preview(&self, paths: &[PathBuf]) -> Result<CommitSummary>;
+    /// Un-mount the filesystem, returns `MountBusy` if host processes are still using it
     fn unmount(&mut self, target: &Path) -> Result<()>;
+    /// Detach the filesystem lazily, it is freed once the processes using it are gone
+    fn detach(&mut self, target: &Path) -> Result<()>;
     /// Return the directory where the configuration layer is located
     /// You may temporary mount this directory if your backend does not expose this directory directly
     fn get_config_layer(&mut self) -> Result<PathBuf>;
     /// Return the directory where the base layer is located
     fn get_base_layer(&mut self) -> Result<PathBuf>;
+    /// Return the directory where the uncommitted changes are located
+    fn get_upper_layer(&mut self) -> Result<PathBuf>;
     /// Set the volatile state of the instance filesystem
     fn set_volatile(&mut self, volatile: bool) -> Result<()>;
+    /// Set the paths whose contents are deleted instead of being committed
+    fn set_commit_excludes(&mut self, excludes: &[PathBuf]);
+    /// Describe the problems with the layers of the instance, e.g. missing directories
+    fn integrity_problems(&self) -> Vec<String>;
+    /// Recreate the missing layer directories, the contents of the lost layers are not recovered
+    fn repair(&mut self) -> Result<()>;
     /// Destroy the filesystem of the current instance
     fn destroy(&mut self) -> Result<()>;
 }
@@ -55,17 +87,180 @@ struct OverlayFS {
     upper: PathBuf,
     work: PathBuf,
     volatile: bool,
+    /// Paths (relative to the root of the filesystem) whose contents are never committed
+    excludes: Vec<PathBuf>,
 }
 
+/// Refusal to remove a directory holding the base system layer
+#[derive(Debug)]
+pub struct ProtectedLayer(pub PathBuf);
+
+impl std::fmt::Display for ProtectedLayer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Refusing to remove {}, it holds the base system layer",
+            self.0.display()
+        )
+    }
+}
+
+impl std::error::Error for ProtectedLayer {}
+
+/// A process keeping a mount busy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountHolder {
+    pub pid: i32,
+    pub comm: String,
+}
+
+/// The filesystem could not be unmounted because processes are still using it
+#[derive(Debug)]
+pub struct MountBusy {
+    pub target: PathBuf,
+    pub holders: Vec<MountHolder>,
+}
+
+impl std::fmt::Display for MountBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let holders = self
+            .holders
+            .iter()
+            .map(|x| format!("{} ({})", x.pid, x.comm))
+            .collect::<Vec<_>>();
+        write!(
+            f,
+            "{} is busy, still used by: {}. Close them or use `--kill-holders` to terminate them.",
+            self.target.display(),
+            holders.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for MountBusy {}
+
+impl MountBusy {
+    /// Ask the processes holding the mount to terminate
+    pub fn terminate_holders(&self) {
+        for holder in self.holders.iter() {
+            // the process may have exited in the meantime, nothing to do then
+            unsafe {
+                libc::kill(holder.pid, libc::SIGTERM);
+            }
+        }
+    }
+}
+
+/// Find the processes whose working directory, root directory or open files
+/// are under `target`, by scanning the given procfs
+fn find_mount_holders_in(proc: &Path, target: &Path) -> Vec<MountHolder> {
+    let mut holders = Vec::new();
+    let entries = match fs::read_dir(proc) {
+        Ok(entries) => entries,
+        Err(_) => return holders,
+    };
+    for entry in entries.flatten() {
+        let pid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|x| x.parse::<i32>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        let dir = entry.path();
+        let fds = fs::read_dir(dir.join("fd"))
+            .map(|x| x.flatten().map(|x| x.path()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let uses_target = [dir.join("cwd"), dir.join("root")]
+            .into_iter()
+            .chain(fds)
+            .any(|link| fs::read_link(link).is_ok_and(|x| x.starts_with(target)));
+        if uses_target {
+            let comm = fs::read_to_string(dir.join("comm")).unwrap_or_default();
+            holders.push(MountHolder {
+                pid,
+                comm: comm.trim().to_owned(),
+            });
+        }
+    }
+    holders.sort_unstable_by_key(|x| x.pid);
+
+    holders
+}
+
+/// Options naming the layer directories, which are not reported in `MountOptions`
+const OVERLAY_LAYER_OPTIONS: &[&str] = &["lowerdir", "upperdir", "workdir", "datadir"];
+
+/// Effective options of a mounted overlay filesystem as reported by the kernel,
+/// e.g. `volatile`, `userxattr` or `index=on`, without the layer directories
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MountOptions(Vec<String>);
+
+impl MountOptions {
+    /// Parse the superblock options in the mountinfo entry
+    fn parse(super_options: &str) -> Self {
+        let mut options = Vec::new();
+        let mut escaped = false;
+        for option in super_options.split(',') {
+            // the commas in the layer paths are escaped
+            let continued = escaped;
+            escaped = option.ends_with('\\');
+            if continued
+                || option.is_empty()
+                || OVERLAY_LAYER_OPTIONS
+                    .iter()
+                    .any(|x| option.split(['=', '+']).next() == Some(*x))
+            {
+                continue;
+            }
+            options.push(option.to_owned());
+        }
+
+        MountOptions(options)
+    }
+
+    /// Whether the changes are not synced to the disk, and may be lost on a crash
+    pub fn is_volatile(&self) -> bool {
+        self.0.iter().any(|x| x == "volatile")
+    }
+}
+
+impl std::fmt::Display for MountOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0.join(","))
+    }
+}
+
+/// Volatile paths whose contents are not committed by default
+pub const DEFAULT_COMMIT_EXCLUDES: &[&str] = &[
+    "/tmp",
+    "/run",
+    "/var/tmp",
+    "/var/cache/apt/archives/partial",
+];
+
+/// File in the instance directory recording when the instance was created,
+/// in nanoseconds since the Unix epoch
+pub const INSTANCE_CREATED_FILE: &str = "created";
+
 /// Create a new overlay filesystem on the host system
-pub fn create_new_instance_fs<P: AsRef<Path>>(inst_path: P, inst_name: P) -> Result<()> {
-    let inst = inst_path.as_ref().join(inst_name.as_ref());
-    fs::create_dir_all(inst)?;
-    Ok(())
+pub fn create_new_instance_fs<P: AsRef<Path>>(inst_path: P, inst_name: P) -> std::io::Result<()> {
+    fs::create_dir_all(inst_path.as_ref())?;
+    let inst_dir = inst_path.as_ref().join(inst_name.as_ref());
+    // fails if the instance already exists, never reuse an existing directory
+    fs::create_dir(&inst_dir)?;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    fs::write(
+        inst_dir.join(INSTANCE_CREATED_FILE),
+        now.as_nanos().to_string(),
+    )
 }
 
 /// OverlayFS operations
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 enum Diff {
     Symlink(PathBuf),
     OverrideDir(PathBuf),
@@ -74,11 +269,150 @@ enum Diff {
     ModifiedDir(PathBuf),  // Modify permission only
     WhiteoutFile(PathBuf), // Dir or File
     File(PathBuf),         // Simple modified or new file
+    Excluded(PathBuf),     // Under an excluded path, to be deleted
+}
+
+/// Kinds of changes to the base layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+    Excluded,
+}
+
+impl std::fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChangeKind::Added => write!(f, "added"),
+            ChangeKind::Modified => write!(f, "modified"),
+            ChangeKind::Deleted => write!(f, "deleted"),
+            ChangeKind::Excluded => write!(f, "excluded"),
+        }
+    }
+}
+
+/// Number of files and their total size
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChangeStats {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl ChangeStats {
+    fn add(&mut self, other: ChangeStats) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+    }
+}
+
+/// What a commit would change in the base layer
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CommitSummary {
+    pub by_kind: BTreeMap<ChangeKind, ChangeStats>,
+    /// Keyed by the top-level directory, e.g. `/usr`
+    pub by_directory: BTreeMap<PathBuf, ChangeStats>,
+}
+
+impl CommitSummary {
+    fn record(&mut self, kind: ChangeKind, path: &Path, stats: ChangeStats) {
+        self.by_kind.entry(kind).or_default().add(stats);
+        let top = Path::new("/").join(
+            path.components()
+                .next()
+                .map_or("".as_ref(), |x| x.as_os_str()),
+        );
+        self.by_directory.entry(top).or_default().add(stats);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_kind.is_empty()
+    }
+}
+
+/// Number of files in the given tree and their total size, directories are not counted
+fn tree_stats(path: &Path) -> ChangeStats {
+    let mut stats = ChangeStats::default();
+    for entry in walkdir::WalkDir::new(path).into_iter().flatten() {
+        if let Ok(meta) = entry.metadata() {
+            if !meta.is_dir() {
+                stats.files += 1;
+                stats.bytes += meta.len();
+            }
+        }
+    }
+
+    stats
 }
 
 impl OverlayFS {
+    /// Remove a directory of the instance, unless it is or contains the base layer
+    fn remove_layer_dir(&self, dir: &Path) -> Result<()> {
+        let base = self
+            .base
+            .canonicalize()
+            .unwrap_or_else(|_| self.base.clone());
+        let target = dir.canonicalize().unwrap_or_else(|_| dir.to_owned());
+        if base.starts_with(&target) {
+            return Err(ProtectedLayer(dir.to_owned()).into());
+        }
+        fs::remove_dir_all(dir)?;
+
+        Ok(())
+    }
+
+    /// Create the missing layer directories of the instance
+    fn create_layers(&self) -> Result<()> {
+        fs::create_dir_all(&self.work)?;
+        fs::create_dir_all(&self.upper)?;
+        fs::create_dir_all(&self.lower)?;
+
+        Ok(())
+    }
+
+    /// Sum up the changes in the upper layer by kind and by top-level directory
+    fn summarize_diff(&self, mods: &[Diff]) -> CommitSummary {
+        let mut summary = CommitSummary::default();
+        for m in mods {
+            match m {
+                Diff::Symlink(path) | Diff::File(path) => {
+                    let kind = if fs::symlink_metadata(self.base.join(path)).is_ok() {
+                        ChangeKind::Modified
+                    } else {
+                        ChangeKind::Added
+                    };
+                    summary.record(kind, path, tree_stats(&self.upper.join(path)));
+                }
+                Diff::OverrideDir(path) => {
+                    summary.record(ChangeKind::Deleted, path, tree_stats(&self.base.join(path)));
+                    summary.record(ChangeKind::Added, path, tree_stats(&self.upper.join(path)));
+                }
+                Diff::RenamedDir(from, to) => {
+                    let stats = tree_stats(&self.base.join(from));
+                    summary.record(ChangeKind::Deleted, from, stats);
+                    summary.record(ChangeKind::Added, to, stats);
+                }
+                Diff::WhiteoutFile(path) => {
+                    summary.record(ChangeKind::Deleted, path, tree_stats(&self.base.join(path)));
+                }
+                Diff::Excluded(path) => {
+                    summary.record(
+                        ChangeKind::Excluded,
+                        path,
+                        tree_stats(&self.upper.join(path)),
+                    );
+                }
+                // directories themselves are not counted, only the files in them
+                Diff::NewDir(_) | Diff::ModifiedDir(_) => continue,
+            }
+        }
+
+        summary
+    }
+
     /// Generate a list of changes made in the upper layer
     fn diff(&self) -> Result<Vec<Diff>> {
+        let _span = timing::span("diff", self.upper.to_string_lossy());
         let mut mods: Vec<Diff> = Vec::new();
         let mut processed_dirs: Vec<PathBuf> = Vec::new();
 
@@ -94,7 +428,13 @@ impl OverlayFS {
             let meta = fs::symlink_metadata(&path)?;
             let file_type = meta.file_type();
 
-            if file_type.is_symlink() {
+            if is_excluded(&rel_path, &self.excludes) {
+                // Drop the whole entry, including everything inside
+                mods.push(Diff::Excluded(rel_path.clone()));
+                if meta.is_dir() {
+                    processed_dirs.push(rel_path.clone());
+                }
+            } else if file_type.is_symlink() {
                 // Just move the symlink
                 mods.push(Diff::Symlink(rel_path.clone()));
             } else if meta.is_dir() {
@@ -150,6 +490,31 @@ impl OverlayFS {
 
         Ok(mods)
     }
+
+    /// Apply the list of changes to the base layer
+    fn apply_diff(&self, mods: &[Diff]) -> Result<()> {
+        let mut links = CopiedLinks::default();
+        // FIXME: use drain_filter in the future
+        // first pass to execute all the deletion actions
+        for i in mods.iter() {
+            match i {
+                Diff::WhiteoutFile(_) | Diff::Excluded(_) => {
+                    overlay_exec_action(i, self, &mut links)?
+                }
+                _ => continue,
+            }
+        }
+        // second pass for everything else
+        for i in mods.iter() {
+            match i {
+                Diff::WhiteoutFile(_) | Diff::Excluded(_) => continue,
+                _ => overlay_exec_action(i, self, &mut links)
+                    .with_context(|| format!("when processing {:?}", i))?,
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl LayerManager for OverlayFS {
@@ -181,6 +546,7 @@ impl LayerManager for OverlayFS {
             upper: inst.join("layers/diff"),
             work: inst.join("layers/diff.tmp"),
             volatile: false,
+            excludes: Vec::new(),
         }))
     }
     fn mount(&mut self, to: &Path) -> Result<()> {
@@ -193,9 +559,7 @@ impl LayerManager for OverlayFS {
             to,
         );
         // create the directories if they don't exist (work directory may be missing)
-        fs::create_dir_all(&self.work)?;
-        fs::create_dir_all(&self.upper)?;
-        fs::create_dir_all(&self.lower)?;
+        self.create_layers()?;
         // check overlay usability
         load_overlayfs_support()?;
         if self.volatile {
@@ -208,7 +572,10 @@ impl LayerManager for OverlayFS {
             ));
         }
         // let's mount them
-        overlay.mount().map_err(|e| anyhow!("{}", e.to_string()))?;
+        overlay
+            .mount()
+            .map_err(|e| anyhow!("{}", e.to_string()))
+            .with_context(|| format!("mount overlay at {}", to.display()))?;
 
         Ok(())
     }
@@ -218,45 +585,136 @@ impl LayerManager for OverlayFS {
         is_mounted(target, OsStr::new("overlay"))
     }
 
+    fn mount_options(&self, target: &Path) -> Result<Option<MountOptions>> {
+        let mountinfo_content: Vec<u8> = fs::read("/proc/self/mountinfo")?;
+        let mut options = None;
+        for mount in Parser::new(&mountinfo_content) {
+            let mount = mount?;
+            // the last one is on top if mounted more than once
+            if mount.fstype == OsStr::new("overlay") && Path::new(&*mount.mount_point) == target {
+                options = Some(MountOptions::parse(&mount.super_options.to_string_lossy()));
+            }
+        }
+
+        Ok(options)
+    }
+
     fn rollback(&mut self) -> Result<()> {
-        fs::remove_dir_all(&self.upper)?;
-        fs::remove_dir_all(&self.work)?;
-        fs::create_dir(&self.upper)?;
-        fs::create_dir(&self.work)?;
+        let _span = timing::span("rollback", self.upper.to_string_lossy());
+        let context = || format!("clear upper layer {}", self.upper.display());
+        self.remove_layer_dir(&self.upper).with_context(context)?;
+        self.remove_layer_dir(&self.work).with_context(context)?;
+        fs::create_dir(&self.upper).with_context(context)?;
+        fs::create_dir(&self.work).with_context(context)?;
 
         Ok(())
     }
 
-    fn commit(&mut self) -> Result<()> {
+    fn rollback_preserving(&mut self, paths: &[PathBuf], dest: &Path) -> Result<()> {
+        let mut links = CopiedLinks::default();
+        for rel_path in relative_paths(paths)? {
+            let upper_path = self.upper.join(&rel_path);
+            if fs::symlink_metadata(&upper_path).is_err() {
+                continue;
+            }
+            let dest_path = dest.join(&rel_path);
+            if let Some(parent) = dest_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // the upper layer is going to be removed anyway, so just move the files
+            move_path(&upper_path, &dest_path, &mut links)?;
+        }
+
+        self.rollback()
+    }
+
+    fn commit(&mut self) -> Result<usize> {
+        let _span = timing::span("commit", self.upper.to_string_lossy());
         if self.volatile {
             // for safety reasons
             nix::unistd::sync();
         }
-        let mods = self.diff()?;
-        // FIXME: use drain_filter in the future
-        // first pass to execute all the deletion actions
-        for i in mods.iter() {
-            match i {
-                Diff::WhiteoutFile(_) => overlay_exec_action(i, self)?,
+        let mods = self
+            .diff()
+            .with_context(|| format!("scan upper layer {}", self.upper.display()))?;
+        self.apply_diff(&mods)
+            .with_context(|| format!("commit upper layer to {}", self.base.display()))?;
+        // clear all the remnant items in the upper layer
+        self.rollback()?;
+
+        Ok(count_excluded(&mods))
+    }
+
+    fn commit_paths(&mut self, paths: &[PathBuf]) -> Result<usize> {
+        let _span = timing::span("commit", self.upper.to_string_lossy());
+        if self.volatile {
+            // for safety reasons
+            nix::unistd::sync();
+        }
+        let prefixes = relative_paths(paths)?;
+        let mods = self
+            .diff()
+            .with_context(|| format!("scan upper layer {}", self.upper.display()))?;
+        let mods = filter_diff(mods, &prefixes);
+        self.apply_diff(&mods)
+            .with_context(|| format!("commit upper layer to {}", self.base.display()))?;
+        // the files have been moved down, only the committed directories remain in the upper layer,
+        // everything else is kept for further work or rollback
+        for m in mods.iter().rev() {
+            match m {
+                // its redirection now points to nothing, and its contents have all been committed
+                Diff::RenamedDir(_, path) => fs::remove_dir(self.upper.join(path))
+                    .with_context(|| format!("clean up renamed directory {}", path.display()))?,
+                // still holding uncommitted changes if not empty
+                Diff::NewDir(path) | Diff::ModifiedDir(path) if has_prefix(path, &prefixes) => {
+                    fs::remove_dir(self.upper.join(path)).ok();
+                }
                 _ => continue,
             }
         }
-        // second pass for everything else
-        for i in mods.iter() {
-            match i {
-                Diff::WhiteoutFile(_) => continue,
-                _ => overlay_exec_action(i, self)
-                    .with_context(|| format!("when processing {:?}", i))?,
+
+        Ok(count_excluded(&mods))
+    }
+
+    fn commit_preview(&self, paths: &[PathBuf]) -> Result<CommitSummary> {
+        let prefixes = relative_paths(paths)?;
+        let mods = self
+            .diff()
+            .with_context(|| format!("scan upper layer {}", self.upper.display()))?;
+        let mods = if prefixes.is_empty() {
+            mods
+        } else {
+            filter_diff(mods, &prefixes)
+        };
+
+        Ok(self.summarize_diff(&mods))
+    }
+
+    fn unmount(&mut self, target: &Path) -> Result<()> {
+        let _span = timing::span("unmount", target.to_string_lossy());
+        match umount2(target, MntFlags::empty()) {
+            Err(nix::errno::Errno::EBUSY) => {
+                let holders = find_mount_holders_in(Path::new("/proc"), target);
+                if !holders.is_empty() {
+                    return Err(MountBusy {
+                        target: target.to_owned(),
+                        holders,
+                    }
+                    .into());
+                }
+                // held from elsewhere (e.g. another mount namespace), detach it lazily
+                umount2(target, MntFlags::MNT_DETACH)
             }
+            result => result,
         }
-        // clear all the remnant items in the upper layer
-        self.rollback()?;
+        .with_context(|| format!("unmount overlay at {}", target.display()))?;
 
         Ok(())
     }
 
-    fn unmount(&mut self, target: &Path) -> Result<()> {
-        umount2(target, MntFlags::MNT_DETACH)?;
+    fn detach(&mut self, target: &Path) -> Result<()> {
+        umount2(target, MntFlags::MNT_DETACH)
+            .with_context(|| format!("detach overlay at {}", target.display()))?;
 
         Ok(())
     }
@@ -269,32 +727,87 @@ impl LayerManager for OverlayFS {
         Ok(self.base.clone())
     }
 
+    fn get_upper_layer(&mut self) -> Result<PathBuf> {
+        Ok(self.upper.clone())
+    }
+
     fn destroy(&mut self) -> Result<()> {
-        fs::remove_dir_all(&self.inst)?;
+        self.remove_layer_dir(&self.inst)
+            .with_context(|| format!("remove instance directory {}", self.inst.display()))?;
 
         Ok(())
     }
 
+    fn set_commit_excludes(&mut self, excludes: &[PathBuf]) {
+        // paths are specified as seen from inside the container
+        self.excludes = excludes
+            .iter()
+            .map(|p| p.strip_prefix("/").unwrap_or(p).to_path_buf())
+            .collect();
+    }
+
     fn set_volatile(&mut self, volatile: bool) -> Result<()> {
         self.volatile = volatile;
 
         Ok(())
     }
+
+    fn integrity_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !self.inst.is_dir() {
+            problems.push(format!(
+                "instance directory {} is missing",
+                self.inst.display()
+            ));
+            return problems;
+        }
+        // the layers are created on the first mount
+        if !self.inst.join("layers").exists() {
+            return problems;
+        }
+        // the work directory is disposable, it is recreated when mounting
+        for (layer, name) in [(&self.lower, "config"), (&self.upper, "upper")] {
+            match fs::symlink_metadata(layer) {
+                Ok(metadata) if metadata.is_dir() => (),
+                Ok(_) => problems.push(format!(
+                    "{} layer {} is not a directory",
+                    name,
+                    layer.display()
+                )),
+                Err(_) => problems.push(format!("{} layer {} is missing", name, layer.display())),
+            }
+        }
+        if self.work.join("work/incompat").exists() {
+            problems.push("the filesystem is marked as unusable, please rollback".to_owned());
+        }
+
+        problems
+    }
+
+    fn repair(&mut self) -> Result<()> {
+        self.create_layers()
+    }
 }
 
 /// is_mounted: check if a path is a mountpoint with corresponding fs_type
 pub(crate) fn is_mounted(mountpoint: &Path, fs_type: &OsStr) -> Result<bool> {
+    Ok(list_mountpoints(fs_type)?.contains(mountpoint))
+}
+
+/// List all the mountpoints with the corresponding fs_type, parsing the mountinfo only once
+pub(crate) fn list_mountpoints(fs_type: &OsStr) -> Result<HashSet<PathBuf>> {
     let mountinfo_content: Vec<u8> = fs::read("/proc/self/mountinfo")?;
     let parser = Parser::new(&mountinfo_content);
+    let mut mountpoints = HashSet::new();
 
     for mount in parser {
         let mount = mount?;
-        if mount.mount_point == mountpoint && mount.fstype == fs_type {
-            return Ok(true);
+        if mount.fstype == fs_type {
+            mountpoints.insert(PathBuf::from(&*mount.mount_point));
         }
     }
 
-    Ok(false)
+    Ok(mountpoints)
 }
 
 /// A convenience function for getting a overlayfs type LayerManager
@@ -310,7 +823,63 @@ fn has_prefix(path: &Path, prefixes: &[PathBuf]) -> bool {
         .any(|prefix| path.strip_prefix(prefix).is_ok())
 }
 
-fn load_overlayfs_support() -> Result<()> {
+/// Check if the path is inside (but not the same as) any of the excluded paths
+#[inline]
+fn is_excluded(path: &Path, excludes: &[PathBuf]) -> bool {
+    excludes
+        .iter()
+        .any(|exclude| path != exclude && path.starts_with(exclude))
+}
+
+/// Convert the paths as seen from inside the container to paths relative to the root
+/// of the filesystem, refusing the root itself and the paths going outside of it
+fn relative_paths(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    paths
+        .iter()
+        .map(|p| {
+            let rel_path = p.strip_prefix("/").unwrap_or(p);
+            if rel_path.as_os_str().is_empty()
+                || !rel_path
+                    .components()
+                    .all(|x| matches!(x, std::path::Component::Normal(_)))
+            {
+                bail!(
+                    "Invalid path {}: only paths below / are allowed",
+                    p.display()
+                );
+            }
+            Ok(rel_path.to_path_buf())
+        })
+        .collect()
+}
+
+#[inline]
+fn count_excluded(mods: &[Diff]) -> usize {
+    mods.iter()
+        .filter(|m| matches!(m, Diff::Excluded(_)))
+        .count()
+}
+
+/// Keep only the changes under the given prefixes (relative to the root of the filesystem).
+/// Parent directories of the prefixes are also kept if they only need to be created
+/// or have their permissions updated, so that the selected changes have a place to land.
+fn filter_diff(mods: Vec<Diff>, prefixes: &[PathBuf]) -> Vec<Diff> {
+    mods.into_iter()
+        .filter(|m| match m {
+            Diff::NewDir(path) | Diff::ModifiedDir(path) => {
+                has_prefix(path, prefixes) || prefixes.iter().any(|p| p.starts_with(path))
+            }
+            Diff::RenamedDir(_, path)
+            | Diff::Symlink(path)
+            | Diff::OverrideDir(path)
+            | Diff::WhiteoutFile(path)
+            | Diff::File(path)
+            | Diff::Excluded(path) => has_prefix(path, prefixes),
+        })
+        .collect()
+}
+
+pub(crate) fn load_overlayfs_support() -> Result<()> {
     if test_overlay_usability().is_err() {
         Command::new("modprobe")
             .arg("overlay")
@@ -338,27 +907,219 @@ pub fn test_overlay_usability() -> Result<()> {
     Err(anyhow!("No overlayfs support detected"))
 }
 
-/// Set permission of to according to from
-#[inline]
-fn sync_permission(from: &Path, to: &Path) -> Result<()> {
-    let from_meta = fs::metadata(from)?;
-    let to_meta = fs::metadata(to)?;
+/// Prefix of the extended attributes used by overlayfs itself, they are never copied down
+const OVERLAY_XATTR_PREFIX: &[u8] = b"trusted.overlay.";
+
+/// Copy the extended attributes, which also hold the POSIX ACLs (`system.posix_acl_*`),
+/// the file capabilities and the SELinux labels (`security.*`).
+/// Without the real root (e.g. in a user namespace), the `trusted.*` attributes and some of
+/// the `security.*` ones can't be set, and the filesystem may not support some namespaces.
+/// Those are skipped with a warning, instead of failing the whole operation.
+fn copy_xattrs(from: &Path, to: &Path) -> Result<()> {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+
+    for name in xattr::list(from)? {
+        if name.as_bytes().starts_with(OVERLAY_XATTR_PREFIX) {
+            continue;
+        }
+        let Some(value) = xattr::get(from, &name)? else {
+            continue;
+        };
+        match xattr::set(to, &name, &value) {
+            Ok(()) => (),
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EPERM | libc::EOPNOTSUPP)) => {
+                if !WARNED.swap(true, Ordering::SeqCst) {
+                    warn!(
+                        "Unable to preserve the extended attribute `{}` of {}: {}. Such attributes are skipped.",
+                        name.to_string_lossy(),
+                        from.display(),
+                        e
+                    );
+                }
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "set the extended attribute `{}` of {}",
+                        name.to_string_lossy(),
+                        to.display()
+                    )
+                })
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy the ownership, extended attributes, permissions and timestamps
+fn copy_metadata(from: &Path, to: &Path) -> Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+    std::os::unix::fs::lchown(to, Some(meta.uid()), Some(meta.gid()))?;
+    copy_xattrs(from, to)?;
+    if !meta.file_type().is_symlink() {
+        // after changing the owner, which clears the setuid and setgid bits,
+        // and after the ACLs, whose mask follows the group permission bits
+        fs::set_permissions(to, fs::Permissions::from_mode(meta.mode()))?;
+    }
+    utimensat(
+        None,
+        to,
+        &TimeSpec::new(meta.atime(), meta.atime_nsec()),
+        &TimeSpec::new(meta.mtime(), meta.mtime_nsec()),
+        UtimensatFlags::NoFollowSymlink,
+    )?;
+
+    Ok(())
+}
+
+/// Copies of the hard-linked files made by `copy_tree`, keyed by the device and inode of the
+/// source, so that the other names of the same file are linked to the copy instead
+#[derive(Default)]
+struct CopiedLinks(HashMap<(u64, u64), PathBuf>);
+
+impl CopiedLinks {
+    /// Follow the copies after the directory (or file) `from` has been renamed to `to`
+    fn relocate(&mut self, from: &Path, to: &Path) {
+        for path in self.0.values_mut() {
+            if let Ok(rest) = path.strip_prefix(from) {
+                *path = if rest.as_os_str().is_empty() {
+                    to.to_owned()
+                } else {
+                    to.join(rest)
+                };
+            }
+        }
+    }
+}
 
-    if from_meta.mode() != to_meta.mode() {
-        to_meta.permissions().set_mode(to_meta.mode());
+/// Move `from` to `to` like `rename`, falling back to copying when they are on different
+/// filesystems (e.g. the upper layer is on a separate mount)
+fn move_path(from: &Path, to: &Path, links: &mut CopiedLinks) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => (),
+        result => return Ok(result?),
+    }
+
+    copy_move(from, to, links)
+}
+
+/// Move `from` to `to` by copying: the copy is made next to the destination and only renamed
+/// over it once complete, so a failure never leaves the destination removed or half-written.
+/// The source is removed last.
+fn copy_move(from: &Path, to: &Path, links: &mut CopiedLinks) -> Result<()> {
+    let dir = to
+        .parent()
+        .ok_or_else(|| anyhow!("{} has no parent directory", to.display()))?;
+    let staging = tempfile::Builder::new()
+        .prefix(".ciel-move-")
+        .tempdir_in(dir)?;
+    let copy = staging.path().join("copy");
+    copy_tree(from, &copy, links)
+        .with_context(|| format!("copy {} to {}", from.display(), to.display()))?;
+    if let Err(e) = fs::rename(&copy, to) {
+        // unlike the rest, replacing a directory with a file (or the other way around)
+        // can't be done at once
+        if !matches!(e.raw_os_error(), Some(libc::EISDIR) | Some(libc::ENOTDIR)) {
+            return Err(e.into());
+        }
+        if fs::symlink_metadata(to)?.is_dir() {
+            fs::remove_dir(to)?;
+        } else {
+            fs::remove_file(to)?;
+        }
+        fs::rename(&copy, to)?;
+    }
+    links.relocate(&copy, to);
+    staging.close()?;
+    if fs::symlink_metadata(from)?.is_dir() {
+        fs::remove_dir_all(from)?;
+    } else {
+        fs::remove_file(from)?;
     }
 
     Ok(())
 }
 
+/// Copy the file, directory or special file along with its ownership, permissions,
+/// extended attributes (see `copy_xattrs`) and timestamps,
+/// the holes in sparse files are preserved, and so are the hard links between the copied files
+fn copy_tree(from: &Path, to: &Path, links: &mut CopiedLinks) -> Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+    let file_type = meta.file_type();
+    if file_type.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()), links)?;
+        }
+    } else if file_type.is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+    } else if file_type.is_file() {
+        // the names moved before are already removed from the source, so the link count
+        // only tells whether more names are to come
+        let key = (meta.dev(), meta.ino());
+        if let Some(copy) = links.0.get(&key) {
+            fs::hard_link(copy, to)?;
+            if meta.nlink() == 1 {
+                links.0.remove(&key);
+            }
+            return Ok(());
+        }
+        copy_sparse(from, to)?;
+        if meta.nlink() > 1 {
+            links.0.insert(key, to.to_owned());
+        }
+    } else {
+        // device nodes, FIFOs and sockets
+        mknod(
+            to,
+            SFlag::from_bits_truncate(meta.mode() & libc::S_IFMT),
+            Mode::from_bits_truncate(meta.mode()),
+            meta.rdev(),
+        )?;
+    }
+
+    copy_metadata(from, to)
+}
+
+/// Copy a regular file, skipping the holes so that sparse files stay sparse
+fn copy_sparse(from: &Path, to: &Path) -> Result<()> {
+    let src = fs::File::open(from)?;
+    let mut dst = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(to)?;
+    let len = src.metadata()?.len() as i64;
+    let mut offset = 0;
+    while offset < len {
+        // filesystems without hole detection report the whole file as data
+        let data = match lseek(src.as_raw_fd(), offset, Whence::SeekData) {
+            Ok(data) => data,
+            // no more data until the end of the file
+            Err(Errno::ENXIO) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let hole = lseek(src.as_raw_fd(), data, Whence::SeekHole)?;
+        (&src).seek(SeekFrom::Start(data as u64))?;
+        dst.seek(SeekFrom::Start(data as u64))?;
+        io::copy(&mut io::Read::take(&src, (hole - data) as u64), &mut dst)?;
+        offset = hole;
+    }
+    // a trailing hole
+    dst.set_len(len as u64)?;
+
+    Ok(())
+}
+
 #[inline]
-fn overlay_exec_action(action: &Diff, overlay: &OverlayFS) -> Result<()> {
+fn overlay_exec_action(action: &Diff, overlay: &OverlayFS, links: &mut CopiedLinks) -> Result<()> {
     match action {
         Diff::Symlink(path) => {
             let upper_path = overlay.upper.join(path);
             let lower_path = overlay.base.join(path);
             // Replace lower dir with upper
-            fs::rename(upper_path, lower_path)?;
+            move_path(&upper_path, &lower_path, links)?;
         }
         Diff::OverrideDir(path) => {
             let upper_path = overlay.upper.join(path);
@@ -371,7 +1132,7 @@ fn overlay_exec_action(action: &Diff, overlay: &OverlayFS) -> Result<()> {
                 // If it's a file, then remove it as well
                 fs::remove_file(&lower_path)?;
             }
-            fs::rename(upper_path, &lower_path)?;
+            move_path(&upper_path, &lower_path, links)?;
         }
         Diff::RenamedDir(from, to) => {
             // TODO: Implement copy down
@@ -384,15 +1145,17 @@ fn overlay_exec_action(action: &Diff, overlay: &OverlayFS) -> Result<()> {
             fs::rename(from_path, to_path)?;
         }
         Diff::NewDir(path) => {
+            let upper_path = overlay.upper.join(path);
             let lower_path = overlay.base.join(path);
-            // Construct lower path
-            fs::create_dir_all(lower_path)?;
+            // Construct lower path, the directory may already exist in the base layer
+            fs::create_dir_all(&lower_path)?;
+            copy_metadata(&upper_path, &lower_path)?;
         }
         Diff::ModifiedDir(path) => {
-            // Do nothing, just sync permission
+            // Do nothing, just sync the ownership, permissions and ACLs
             let upper_path = overlay.upper.join(path);
             let lower_path = overlay.base.join(path);
-            sync_permission(&upper_path, &lower_path)?;
+            copy_metadata(&upper_path, &lower_path)?;
         }
         Diff::WhiteoutFile(path) => {
             let lower_path = overlay.base.join(path);
@@ -408,9 +1171,469 @@ fn overlay_exec_action(action: &Diff, overlay: &OverlayFS) -> Result<()> {
             let upper_path = overlay.upper.join(path);
             let lower_path = overlay.base.join(path);
             // Move upper file to overwrite the lower
-            fs::rename(upper_path, lower_path)?;
+            move_path(&upper_path, &lower_path, links)?;
+        }
+        Diff::Excluded(path) => {
+            let upper_path = overlay.upper.join(path);
+            // Delete it from the upper layer instead of moving it down
+            if fs::symlink_metadata(&upper_path)?.is_dir() {
+                fs::remove_dir_all(&upper_path)?;
+            } else {
+                fs::remove_file(&upper_path)?;
+            }
         }
     }
 
     Ok(())
 }
+
+#[test]
+fn test_filter_diff() {
+    let mods = vec![
+        Diff::ModifiedDir(PathBuf::from("etc")),
+        Diff::ModifiedDir(PathBuf::from("etc/apt")),
+        Diff::File(PathBuf::from("etc/apt/sources.list")),
+        Diff::WhiteoutFile(PathBuf::from("etc/apt/trusted.gpg")),
+        Diff::File(PathBuf::from("etc/hostname")),
+        Diff::NewDir(PathBuf::from("root/build")),
+        Diff::File(PathBuf::from("root/build/junk.o")),
+    ];
+    let filtered = filter_diff(mods, &[PathBuf::from("etc/apt")]);
+    assert_eq!(
+        filtered,
+        vec![
+            Diff::ModifiedDir(PathBuf::from("etc")),
+            Diff::ModifiedDir(PathBuf::from("etc/apt")),
+            Diff::File(PathBuf::from("etc/apt/sources.list")),
+            Diff::WhiteoutFile(PathBuf::from("etc/apt/trusted.gpg")),
+        ]
+    );
+}
+
+#[test]
+fn test_commit_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut man = OverlayFS::from_inst_dir(
+        dir.path().join("dist"),
+        dir.path().to_owned(),
+        "test".into(),
+    )
+    .unwrap();
+    let base = dir.path().join("dist");
+    let upper = dir.path().join("test/layers/diff");
+    fs::create_dir_all(base.join("etc/apt")).unwrap();
+    fs::create_dir_all(upper.join("etc/apt/sources.list.d")).unwrap();
+    fs::create_dir_all(upper.join("root/build")).unwrap();
+    fs::create_dir_all(dir.path().join("test/layers/diff.tmp")).unwrap();
+    fs::write(upper.join("etc/apt/sources.list.d/ciel.list"), "deb").unwrap();
+    fs::write(upper.join("etc/hostname"), "ciel").unwrap();
+    fs::write(upper.join("root/build/junk.o"), "junk").unwrap();
+    for path in ["/", "..", "/etc/../..", "etc/./apt/.."] {
+        assert!(man.commit_paths(&[path.into()]).is_err());
+        assert!(man.commit_preview(&[path.into()]).is_err());
+    }
+    // nothing under the path, nothing is touched
+    man.commit_paths(&["/etc/apt/sources.list.d/ciel.list/x".into()])
+        .unwrap();
+    assert!(upper.join("etc/apt/sources.list.d/ciel.list").is_file());
+    man.commit_paths(&["/etc/apt".into()]).unwrap();
+    assert_eq!(
+        fs::read_to_string(base.join("etc/apt/sources.list.d/ciel.list")).unwrap(),
+        "deb"
+    );
+    assert!(!upper.join("etc/apt").exists());
+    // the other changes are left for further work or rollback
+    assert!(!base.join("etc/hostname").exists());
+    assert!(!base.join("root").exists());
+    assert_eq!(
+        fs::read_to_string(upper.join("etc/hostname")).unwrap(),
+        "ciel"
+    );
+    assert!(upper.join("root/build/junk.o").is_file());
+}
+
+#[test]
+fn test_rollback_preserving() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut man = OverlayFS::from_inst_dir(
+        dir.path().join("dist"),
+        dir.path().to_owned(),
+        "test".into(),
+    )
+    .unwrap();
+    let upper = dir.path().join("test/layers/diff");
+    fs::create_dir_all(upper.join("root")).unwrap();
+    fs::create_dir_all(upper.join("var/log")).unwrap();
+    fs::create_dir_all(dir.path().join("test/layers/diff.tmp")).unwrap();
+    fs::write(upper.join("root/.bash_history"), "ls").unwrap();
+    fs::write(upper.join("var/log/build.log"), "log").unwrap();
+    let dest = dir.path().join("preserved");
+    man.rollback_preserving(&["/root".into(), "/tmp".into()], &dest)
+        .unwrap();
+    assert_eq!(
+        fs::read_to_string(dest.join("root/.bash_history")).unwrap(),
+        "ls"
+    );
+    assert!(!dest.join("tmp").exists());
+    assert!(!dest.join("var").exists());
+    assert_eq!(fs::read_dir(&upper).unwrap().count(), 0);
+}
+
+#[test]
+fn test_copy_tree_sparse() {
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let upper = dir.path().join("upper");
+    fs::create_dir_all(upper.join("srv/db")).unwrap();
+    // a 2 GiB pre-allocated database file with a little data in the middle
+    let mut sparse = fs::File::create(upper.join("srv/db/data.db")).unwrap();
+    sparse.write_all(b"header").unwrap();
+    sparse.write_all_at(b"record", 1 << 30).unwrap();
+    sparse.set_len(2 << 30).unwrap();
+    fs::set_permissions(upper.join("srv/db"), fs::Permissions::from_mode(0o700)).unwrap();
+    std::os::unix::fs::symlink("db/data.db", upper.join("srv/current")).unwrap();
+    // the fallback for `move_path` when the layers are on different filesystems
+    let dist = dir.path().join("dist");
+    copy_tree(&upper, &dist, &mut CopiedLinks::default()).unwrap();
+
+    let copied = dist.join("srv/db/data.db");
+    let meta = fs::metadata(&copied).unwrap();
+    assert_eq!(meta.len(), 2 << 30);
+    // the holes are not allocated, just the blocks with data
+    assert!(meta.blocks() * 512 < 1 << 20);
+    let file = fs::File::open(&copied).unwrap();
+    let mut buf = [0u8; 6];
+    file.read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(&buf, b"header");
+    file.read_exact_at(&mut buf, 1 << 30).unwrap();
+    assert_eq!(&buf, b"record");
+    file.read_exact_at(&mut buf, 3 << 29).unwrap();
+    assert_eq!(buf, [0; 6]);
+    assert_eq!(
+        fs::metadata(dist.join("srv/db")).unwrap().mode() & 0o777,
+        0o700
+    );
+    assert_eq!(
+        fs::read_link(dist.join("srv/current")).unwrap(),
+        Path::new("db/data.db")
+    );
+    assert_eq!(
+        fs::metadata(&copied).unwrap().mtime(),
+        fs::metadata(upper.join("srv/db/data.db")).unwrap().mtime()
+    );
+}
+
+#[test]
+fn test_create_new_instance_fs() {
+    let dir = tempfile::tempdir().unwrap();
+    create_new_instance_fs(dir.path(), Path::new("test")).unwrap();
+    let e = create_new_instance_fs(dir.path(), Path::new("test")).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+}
+
+#[test]
+fn test_commit_excludes() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut man = OverlayFS::from_inst_dir(
+        dir.path().join("dist"),
+        dir.path().to_owned(),
+        "test".into(),
+    )
+    .unwrap();
+    man.set_commit_excludes(&["/tmp".into(), "/var/tmp".into()]);
+    let base = dir.path().join("dist");
+    let upper = dir.path().join("test/layers/diff");
+    fs::create_dir_all(base.join("tmp")).unwrap();
+    fs::create_dir_all(base.join("var/tmp")).unwrap();
+    fs::create_dir_all(upper.join("tmp/session")).unwrap();
+    fs::create_dir_all(upper.join("var/tmp")).unwrap();
+    fs::create_dir_all(upper.join("etc")).unwrap();
+    fs::create_dir_all(dir.path().join("test/layers/diff.tmp")).unwrap();
+    fs::write(upper.join("tmp/session/junk"), "junk").unwrap();
+    fs::write(upper.join("var/tmp/junk"), "junk").unwrap();
+    fs::write(upper.join("etc/hostname"), "ciel").unwrap();
+    assert_eq!(man.commit().unwrap(), 2);
+    assert_eq!(
+        fs::read_to_string(base.join("etc/hostname")).unwrap(),
+        "ciel"
+    );
+    assert!(base.join("tmp").is_dir());
+    assert_eq!(fs::read_dir(base.join("tmp")).unwrap().count(), 0);
+    assert_eq!(fs::read_dir(base.join("var/tmp")).unwrap().count(), 0);
+}
+
+#[test]
+fn test_commit_acls() {
+    // system.posix_acl_access: user::rwx, user:1000:rwx, group::r-x, mask::rwx, other::r-x
+    let mut acl = 2u32.to_le_bytes().to_vec();
+    for (tag, perm, id) in [
+        (0x01u16, 7u16, u32::MAX),
+        (0x02, 7, 1000),
+        (0x04, 5, u32::MAX),
+        (0x10, 7, u32::MAX),
+        (0x20, 5, u32::MAX),
+    ] {
+        acl.extend(tag.to_le_bytes());
+        acl.extend(perm.to_le_bytes());
+        acl.extend(id.to_le_bytes());
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let mut man = OverlayFS::from_inst_dir(
+        dir.path().join("dist"),
+        dir.path().to_owned(),
+        "test".into(),
+    )
+    .unwrap();
+    let base = dir.path().join("dist");
+    let upper = dir.path().join("test/layers/diff");
+    fs::create_dir_all(base.join("var/log")).unwrap();
+    fs::create_dir_all(upper.join("var/log/journal")).unwrap();
+    fs::create_dir_all(dir.path().join("test/layers/diff.tmp")).unwrap();
+    let log_dir = upper.join("var/log");
+    if let Err(e) = xattr::set(&log_dir, "system.posix_acl_access", &acl) {
+        // the filesystem of the temporary directory does not support ACLs
+        assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP));
+        return;
+    }
+    xattr::set(&log_dir, "system.posix_acl_default", &acl).unwrap();
+    xattr::set(&log_dir, "user.ciel-test", b"kept").unwrap();
+    // only the real root can set it, and it must not end up in the base layer
+    let _ = xattr::set(&log_dir, "trusted.overlay.impure", b"y");
+    fs::set_permissions(&log_dir, fs::Permissions::from_mode(0o2775)).unwrap();
+    // the cross-device fallback used when committing and rolling back
+    copy_tree(
+        &log_dir,
+        &dir.path().join("copied"),
+        &mut CopiedLinks::default(),
+    )
+    .unwrap();
+    // an existing directory whose ACLs changed in the instance
+    man.commit().unwrap();
+    for copied in [dir.path().join("copied"), base.join("var/log")] {
+        assert_eq!(
+            xattr::get(&copied, "system.posix_acl_access").unwrap(),
+            Some(acl.clone())
+        );
+        assert_eq!(
+            xattr::get(&copied, "system.posix_acl_default").unwrap(),
+            Some(acl.clone())
+        );
+        assert_eq!(
+            xattr::get(&copied, "user.ciel-test").unwrap().as_deref(),
+            Some(&b"kept"[..])
+        );
+        assert_eq!(xattr::get(&copied, "trusted.overlay.impure").unwrap(), None);
+        assert_eq!(fs::metadata(&copied).unwrap().mode() & 0o7777, 0o2775);
+    }
+    assert!(base.join("var/log/journal").is_dir());
+}
+
+#[test]
+fn test_commit_preview() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut man = OverlayFS::from_inst_dir(
+        dir.path().join("dist"),
+        dir.path().to_owned(),
+        "test".into(),
+    )
+    .unwrap();
+    man.set_commit_excludes(&["/tmp".into()]);
+    let base = dir.path().join("dist");
+    let upper = dir.path().join("test/layers/diff");
+    fs::create_dir_all(base.join("etc")).unwrap();
+    fs::write(base.join("etc/hostname"), "old").unwrap();
+    fs::create_dir_all(upper.join("etc")).unwrap();
+    fs::create_dir_all(upper.join("tmp")).unwrap();
+    fs::create_dir_all(upper.join("usr/bin")).unwrap();
+    fs::write(upper.join("etc/hostname"), "ciel").unwrap();
+    fs::write(upper.join("tmp/junk"), "junk").unwrap();
+    fs::write(upper.join("usr/bin/hello"), "hello").unwrap();
+    fs::write(upper.join("usr/bin/world"), "world").unwrap();
+
+    let summary = man.commit_preview(&[]).unwrap();
+    let stats = |files, bytes| ChangeStats { files, bytes };
+    assert_eq!(summary.by_kind[&ChangeKind::Added], stats(2, 10));
+    assert_eq!(summary.by_kind[&ChangeKind::Modified], stats(1, 4));
+    assert_eq!(summary.by_kind[&ChangeKind::Excluded], stats(1, 4));
+    assert!(!summary.by_kind.contains_key(&ChangeKind::Deleted));
+    assert_eq!(summary.by_directory[Path::new("/usr")], stats(2, 10));
+    assert_eq!(summary.by_directory[Path::new("/etc")], stats(1, 4));
+    // nothing is changed
+    assert_eq!(
+        fs::read_to_string(base.join("etc/hostname")).unwrap(),
+        "old"
+    );
+    assert!(upper.join("tmp/junk").exists());
+
+    let summary = man.commit_preview(&["/etc".into()]).unwrap();
+    assert_eq!(summary.by_directory.len(), 1);
+    assert_eq!(summary.by_kind[&ChangeKind::Modified], stats(1, 4));
+}
+
+#[test]
+fn test_integrity_problems() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut man = OverlayFS::from_inst_dir(
+        dir.path().join("dist"),
+        dir.path().join("instances"),
+        "test".into(),
+    )
+    .unwrap();
+    assert_eq!(man.integrity_problems().len(), 1);
+    // a new instance without any layers is fine
+    fs::create_dir_all(dir.path().join("instances/test")).unwrap();
+    assert!(man.integrity_problems().is_empty());
+    // e.g. after copying the workspace without the empty directories
+    fs::create_dir_all(dir.path().join("instances/test/layers/diff")).unwrap();
+    let problems = man.integrity_problems();
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].starts_with("config layer"));
+    man.repair().unwrap();
+    assert!(man.integrity_problems().is_empty());
+    assert!(dir.path().join("instances/test/layers/diff.tmp").is_dir());
+    fs::create_dir_all(
+        dir.path()
+            .join("instances/test/layers/diff.tmp/work/incompat"),
+    )
+    .unwrap();
+    assert_eq!(man.integrity_problems().len(), 1);
+}
+
+#[test]
+fn test_protected_base_layer() {
+    let dir = tempfile::tempdir().unwrap();
+    let dist = dir.path().join("container/dist");
+    fs::create_dir_all(dist.join("usr/bin")).unwrap();
+    fs::write(dist.join("usr/bin/bash"), "bash").unwrap();
+    fs::create_dir_all(dir.path().join("container/instances")).unwrap();
+    // an instance name escaping the instances directory points at the parent of dist
+    let mut man = OverlayFS::from_inst_dir(
+        dist.clone(),
+        dir.path().join("container/instances"),
+        "..".into(),
+    )
+    .unwrap();
+    let err = man.destroy().unwrap_err();
+    assert!(err.downcast_ref::<ProtectedLayer>().is_some());
+    assert!(dist.join("usr/bin/bash").exists());
+
+    // the upper layer must never be the base layer itself
+    let mut man = OverlayFS {
+        inst: dir.path().join("test"),
+        base: dist.clone(),
+        lower: dir.path().join("test/layers/local"),
+        upper: dist.clone(),
+        work: dir.path().join("test/layers/diff.tmp"),
+        volatile: false,
+        excludes: Vec::new(),
+    };
+    let err = man.rollback().unwrap_err();
+    assert!(err.downcast_ref::<ProtectedLayer>().is_some());
+    assert!(dist.join("usr/bin/bash").exists());
+
+    // regular instances are still removable
+    fs::create_dir_all(dir.path().join("container/instances/test/layers")).unwrap();
+    let mut man = OverlayFS::from_inst_dir(
+        dist.clone(),
+        dir.path().join("container/instances"),
+        "test".into(),
+    )
+    .unwrap();
+    man.destroy().unwrap();
+    assert!(!dir.path().join("container/instances/test").exists());
+    assert!(dist.exists());
+}
+
+#[test]
+fn test_find_mount_holders() {
+    use std::os::unix::fs::symlink;
+
+    let dir = tempfile::tempdir().unwrap();
+    let proc = dir.path().join("proc");
+    let target = Path::new("/ciel/main");
+    let process = |pid: &str, comm: &str| {
+        let path = proc.join(pid);
+        fs::create_dir_all(path.join("fd")).unwrap();
+        fs::write(path.join("comm"), format!("{}\n", comm)).unwrap();
+        symlink("/", path.join("root")).unwrap();
+        path
+    };
+    symlink("/ciel/main/tree", process("42", "bash").join("cwd")).unwrap();
+    symlink("/home", process("7", "sleep").join("cwd")).unwrap();
+    let vim = process("1234", "vim");
+    symlink("/home", vim.join("cwd")).unwrap();
+    symlink("/ciel/main/etc/.hosts.swp", vim.join("fd/3")).unwrap();
+    // not a process
+    fs::create_dir_all(proc.join("sys")).unwrap();
+    // a different directory with the same prefix
+    symlink("/ciel/main2", process("99", "less").join("cwd")).unwrap();
+    let holders = find_mount_holders_in(&proc, target);
+    assert_eq!(
+        holders,
+        vec![
+            MountHolder {
+                pid: 42,
+                comm: "bash".to_string()
+            },
+            MountHolder {
+                pid: 1234,
+                comm: "vim".to_string()
+            }
+        ]
+    );
+    let err = MountBusy {
+        target: target.to_owned(),
+        holders,
+    };
+    assert!(err
+        .to_string()
+        .starts_with("/ciel/main is busy, still used by: 42 (bash), 1234 (vim)."));
+}
+
+#[test]
+fn test_mount_options() {
+    let options = MountOptions::parse(
+        "rw,lowerdir=/ws/.ciel/container/instances/a/layers/local:/ws/.ciel/container/dist,upperdir=/ws/odd\\,dir/diff,workdir=/ws/work,volatile,userxattr,index=off",
+    );
+    assert_eq!(options.to_string(), "rw,volatile,userxattr,index=off");
+    assert!(options.is_volatile());
+    let options = MountOptions::parse("rw,lowerdir=/a,upperdir=/b,workdir=/c");
+    assert_eq!(options.to_string(), "rw");
+    assert!(!options.is_volatile());
+}
+
+#[test]
+fn test_copy_move() {
+    let dir = tempfile::tempdir().unwrap();
+    let upper = dir.path().join("upper");
+    let lower = dir.path().join("lower");
+    fs::create_dir_all(upper.join("d")).unwrap();
+    fs::write(upper.join("d/a"), b"linked").unwrap();
+    fs::hard_link(upper.join("d/a"), upper.join("d/b")).unwrap();
+    fs::write(upper.join("x"), b"also linked").unwrap();
+    fs::hard_link(upper.join("x"), upper.join("y")).unwrap();
+    fs::create_dir_all(lower.join("d")).unwrap();
+    // a directory replaced by a file
+    fs::create_dir_all(lower.join("x")).unwrap();
+    fs::write(lower.join("y"), b"old").unwrap();
+
+    let mut links = CopiedLinks::default();
+    for name in ["d", "x", "y"] {
+        copy_move(&upper.join(name), &lower.join(name), &mut links).unwrap();
+    }
+    let ino = |path: &str| fs::metadata(lower.join(path)).unwrap().ino();
+    assert_eq!(ino("d/a"), ino("d/b"));
+    assert_eq!(ino("x"), ino("y"));
+    assert_ne!(ino("d/a"), ino("x"));
+    assert_eq!(fs::read(lower.join("y")).unwrap(), b"also linked");
+    assert_eq!(fs::read_dir(&upper).unwrap().count(), 0);
+    // no leftovers of the copies next to the destination
+    let mut names = fs::read_dir(&lower)
+        .unwrap()
+        .map(|x| x.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, vec!["d", "x", "y"]);
+}
@@ -0,0 +1,253 @@
+//! Import debs built elsewhere into the local repository
+
+use anyhow::{anyhow, bail, Result};
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use super::{monitor, scan};
+use crate::common::sha256sum;
+
+/// How the imported debs are placed into the repository
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    Copy,
+    Move,
+    Hardlink,
+}
+
+/// Outcome of `import_debs`, paths of the imported debs are relative to `debs/`
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: Vec<String>,
+    /// Debs identical to the ones already in the repository
+    pub skipped: Vec<String>,
+    /// Source paths that could not be imported, along with the reason
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+enum ImportStatus {
+    Imported(String),
+    Skipped(String),
+}
+
+/// Value of the field in the control file of a deb
+fn control_field<'a>(control: &'a str, field: &str) -> Option<&'a str> {
+    control.lines().find_map(|line| {
+        line.strip_prefix(field)?
+            .strip_prefix(':')
+            .map(|x| x.trim())
+    })
+}
+
+/// Whether the package name follows the Debian policy, `[a-z0-9][a-z0-9+.-]+`
+fn is_valid_package_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|x| x.is_ascii_lowercase() || x.is_ascii_digit())
+        && name.len() >= 2
+        && chars.all(|x| x.is_ascii_lowercase() || x.is_ascii_digit() || "+.-".contains(x))
+}
+
+/// Path of the deb in the repository, relative to `debs/`, e.g. `c/curl_8.0-0_amd64.deb`
+fn repo_path_of(deb: &Path) -> Result<String> {
+    let (control, _) = scan::open_deb_simple(File::open(deb)?)?;
+    let control = String::from_utf8(control)?;
+    let field = |name| {
+        control_field(&control, name)
+            .filter(|x| !x.is_empty())
+            .ok_or_else(|| anyhow!("control file has no {} field", name))
+    };
+    let package = field("Package")?;
+    let version = field("Version")?;
+    let arch = field("Architecture")?;
+    // the epoch is not part of the file name
+    let version = version.split_once(':').map_or(version, |(_, x)| x);
+    if !is_valid_package_name(package) {
+        bail!("invalid package name: {}", package);
+    }
+    if version.contains('/') || arch.contains('/') {
+        bail!("invalid package version or architecture");
+    }
+
+    Ok(format!(
+        "{}/{}_{}_{}.deb",
+        &package[..1],
+        package,
+        version,
+        arch
+    ))
+}
+
+fn place_deb(from: &Path, to: &Path, mode: ImportMode) -> Result<()> {
+    if mode == ImportMode::Hardlink {
+        return Ok(fs::hard_link(from, to)?);
+    }
+    if mode == ImportMode::Move {
+        match fs::rename(from, to) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    // copy under a temporary name first, the monitors only pick up the complete debs
+    let mut tmp_path = to.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    if let Err(e) = fs::copy(from, &tmp_path).and_then(|_| fs::rename(&tmp_path, to)) {
+        fs::remove_file(&tmp_path).ok();
+        return Err(e.into());
+    }
+    if mode == ImportMode::Move {
+        fs::remove_file(from)?;
+    }
+
+    Ok(())
+}
+
+fn import_single_deb(debs_dir: &Path, deb: &Path, mode: ImportMode) -> Result<ImportStatus> {
+    if !deb.is_file() {
+        bail!("not a regular file");
+    }
+    let rel_path = repo_path_of(deb).map_err(|e| anyhow!("not a readable deb package: {}", e))?;
+    let target = debs_dir.join(&rel_path);
+    if target.exists() {
+        if sha256sum(File::open(deb)?)? == sha256sum(File::open(&target)?)? {
+            return Ok(ImportStatus::Skipped(rel_path));
+        }
+        bail!("a different {} is already in the repository", rel_path);
+    }
+    fs::create_dir_all(target.parent().unwrap())?;
+    place_deb(deb, &target, mode)?;
+    // the build metadata follows the deb
+    let metadata = scan::metadata_path(deb);
+    if metadata.is_file() {
+        place_deb(&metadata, &scan::metadata_path(&target), mode)?;
+    }
+
+    Ok(ImportStatus::Imported(rel_path))
+}
+
+/// Import the debs (or the debs in the directories) into the repository under `root`,
+/// the index is refreshed once if anything is imported.
+/// A failed deb does not stop the others, see `ImportReport::failed`.
+pub fn import_debs<P: AsRef<Path>>(
+    root: &Path,
+    paths: &[P],
+    mode: ImportMode,
+) -> Result<ImportReport> {
+    let debs_dir = root.join("debs");
+    let mut report = ImportReport::default();
+    let mut debs = Vec::new();
+    for path in paths {
+        let path = path.as_ref();
+        if path.is_dir() {
+            debs.extend(
+                scan::collect_all_packages(path)?
+                    .into_iter()
+                    .map(|x| x.into_path()),
+            );
+        } else {
+            debs.push(path.to_owned());
+        }
+    }
+    for deb in debs {
+        match import_single_deb(&debs_dir, &deb, mode) {
+            Ok(ImportStatus::Imported(x)) => report.imported.push(x),
+            Ok(ImportStatus::Skipped(x)) => report.skipped.push(x),
+            Err(e) => report.failed.push((deb, e.to_string())),
+        }
+    }
+    if !report.imported.is_empty() {
+        monitor::refresh_exclusive(root)?;
+    }
+
+    Ok(report)
+}
+
+/// Move (or link) the debs from the repository under `from` into the one under `to`,
+/// e.g. after toggling `branch-exclusive-output`. Both indexes are refreshed.
+pub fn migrate_output(from: &Path, to: &Path, mode: ImportMode) -> Result<ImportReport> {
+    let debs_dir = from.join("debs");
+    if !debs_dir.is_dir() {
+        bail!("{} does not exist", debs_dir.display());
+    }
+    let report = import_debs(to, &[debs_dir], mode)?;
+    if mode == ImportMode::Move && !report.imported.is_empty() {
+        monitor::refresh_exclusive(from)?;
+    }
+
+    Ok(report)
+}
+
+#[test]
+fn test_import_debs() {
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/scan-debs");
+    let tmp = tempfile::tempdir().unwrap();
+    let root = tmp.path().join("OUTPUT");
+    let bogus = tmp.path().join("bogus_1.0-0_all.deb");
+    fs::write(&bogus, b"not a deb").unwrap();
+    let report = import_debs(&root, &[source.clone(), bogus], ImportMode::Copy).unwrap();
+    assert_eq!(report.imported.len(), 3);
+    assert!(report.skipped.is_empty());
+    assert_eq!(report.failed.len(), 1);
+    assert!(root.join("debs/c/ciel-test-xz_1.0-0_all.deb").is_file());
+    let index = fs::read_to_string(root.join("debs/Packages")).unwrap();
+    assert!(index.contains("Filename: c/ciel-test-zstd_1.0-0_all.deb"));
+    // importing the same debs again does nothing
+    let deb = source.join("ciel-test-gzip_1.0-0_all.deb");
+    let report = import_debs(&root, &[&deb], ImportMode::Hardlink).unwrap();
+    assert!(report.imported.is_empty());
+    assert_eq!(report.skipped, vec!["c/ciel-test-gzip_1.0-0_all.deb"]);
+    // but a different deb with the same name fails
+    let other = tmp.path().join("other.deb");
+    fs::copy(&deb, &other).unwrap();
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&other)
+        .and_then(|mut f| std::io::Write::write_all(&mut f, b"\n"))
+        .unwrap();
+    fs::remove_file(root.join("debs/c/ciel-test-xz_1.0-0_all.deb")).unwrap();
+    let moved = tmp.path().join("moved.deb");
+    fs::copy(source.join("ciel-test-xz_1.0-0_all.deb"), &moved).unwrap();
+    let report = import_debs(&root, &[&other, &moved], ImportMode::Move).unwrap();
+    assert_eq!(report.imported, vec!["c/ciel-test-xz_1.0-0_all.deb"]);
+    assert_eq!(report.failed.len(), 1);
+    assert!(!moved.exists());
+}
+
+#[test]
+fn test_migrate_output() {
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/scan-debs");
+    let tmp = tempfile::tempdir().unwrap();
+    let shared = tmp.path().join("OUTPUT");
+    let branch = tmp.path().join("OUTPUT-stable");
+    import_debs(&shared, &[source], ImportMode::Copy).unwrap();
+    let deb = shared.join("debs/c/ciel-test-gzip_1.0-0_all.deb");
+    scan::write_metadata(&deb, &[("Tree-Commit", "abcdef")]).unwrap();
+    let report = migrate_output(&shared, &branch, ImportMode::Move).unwrap();
+    assert_eq!(report.imported.len(), 3);
+    assert!(!deb.exists());
+    let index = fs::read_to_string(branch.join("debs/Packages")).unwrap();
+    assert!(index.contains("X-Ciel-Tree-Commit: abcdef"));
+    let index = fs::read_to_string(shared.join("debs/Packages")).unwrap();
+    assert!(index.is_empty());
+    assert!(migrate_output(
+        &tmp.path().join("OUTPUT-nonexistent"),
+        &branch,
+        ImportMode::Move
+    )
+    .is_err());
+}
+
+#[test]
+fn test_package_name() {
+    for name in ["curl", "g++", "libc6.1-dev", "7zip"] {
+        assert!(is_valid_package_name(name), "{}", name);
+    }
+    for name in ["", "a", "Curl", "-foo", "ça", "日本", "foo/bar", "foo_bar"] {
+        assert!(!is_valid_package_name(name), "{}", name);
+    }
+}
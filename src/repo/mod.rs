@@ -1,28 +1,59 @@
 //! Local repository
 
-use crate::info;
+use crate::{info, timing, warn};
 use anyhow::Result;
 use console::style;
 use sha2::{Digest, Sha256};
 use std::io::Write;
-use std::{fs, io, path::Path};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
 use time::{format_description::FormatItem, macros::format_description, OffsetDateTime};
 
+mod import;
 mod monitor;
 mod scan;
+mod serve;
+mod verify;
 
+pub use import::{import_debs, migrate_output, ImportMode, ImportReport};
 pub use monitor::start_monitor;
+pub use scan::{collect_all_packages, write_metadata};
+pub use serve::serve_repo;
+pub use verify::{verify_repo, VerifyReport};
+
+/// Outcome of `refresh_repo`
+#[derive(Debug, Default)]
+pub struct RefreshReport {
+    /// Number of debs in the index
+    pub indexed: usize,
+    /// Files left out of the index because they could not be read or are not debs,
+    /// along with the reason
+    pub skipped: Vec<(PathBuf, String)>,
+}
 
 /// Debian 822 date: "%a, %d %b %Y %H:%M:%S %z"
 const DEB822_DATE: &[FormatItem] = format_description!("[weekday repr:short], [day] [month repr:short] [year] [hour repr:24]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]");
 
-fn generate_release(path: &Path) -> Result<String> {
+/// Date of the existing `Release` file, if it is later than `now`
+fn release_date_after(path: &Path, now: OffsetDateTime) -> Option<OffsetDateTime> {
+    let release = fs::read_to_string(path.join("Release")).ok()?;
+    let date = release
+        .lines()
+        .find_map(|line| line.strip_prefix("Date: "))
+        .and_then(|x| OffsetDateTime::parse(x, &DEB822_DATE).ok())?;
+
+    (date > now).then_some(date)
+}
+
+fn generate_release(path: &Path, now: OffsetDateTime) -> Result<String> {
     let mut f = fs::File::open(path.join("Packages"))?;
     let mut hasher = Sha256::new();
     io::copy(&mut f, &mut hasher)?;
     let result = hasher.finalize();
     let meta = f.metadata()?;
-    let timestamp = OffsetDateTime::now_utc().format(&DEB822_DATE)?;
+    let timestamp = now.format(&DEB822_DATE)?;
 
     Ok(format!(
         "Date: {}\nSHA256:\n {:x} {} Packages\n",
@@ -32,20 +63,52 @@ fn generate_release(path: &Path) -> Result<String> {
     ))
 }
 
-/// Refresh the local repository (Update Packages file)
-pub fn refresh_repo(root: &Path) -> Result<()> {
+/// Refresh the local repository (Update Packages file), behind the lock shared with the
+/// monitors and the other builds, so that the index is never written twice at the same time.
+/// Unreadable debs do not fail the refresh, they are left out of the index and reported instead.
+pub fn refresh_repo(root: &Path) -> Result<RefreshReport> {
+    monitor::refresh_exclusive(root)
+}
+
+/// Write the index of the local repository, the caller must hold the lock of the repository
+fn refresh_index(root: &Path) -> Result<RefreshReport> {
+    let _span = timing::span("repo-refresh", root.to_string_lossy());
     let path = root.join("debs");
     fs::create_dir_all(&path)?;
     let mut output = fs::File::create(path.join("Packages"))?;
-    let entries = scan::collect_all_packages(&path)?;
+    let (entries, stray) = scan::collect_repo_files(&path)?;
     info!("Scanning {} packages...", entries.len());
-    output.write_all(&scan::scan_packages_simple(&entries, &path))?;
+    let (index, mut skipped) = scan::scan_packages_simple(&entries, &path);
+    let indexed = entries.len() - skipped.len();
+    for file in stray {
+        warn!("Skipping {}: not a deb package", file.display());
+        skipped.push((file, "not a deb package".to_string()));
+    }
+    output.write_all(&index)?;
     println!();
 
-    let release = generate_release(&path)?;
+    let now = OffsetDateTime::now_utc();
+    if let Some(date) = release_date_after(&path, now) {
+        warn!(
+            "The existing index is dated {}, which is in the future. The clock of the host is probably behind, and apt may reject the index as not valid yet.",
+            date.format(&DEB822_DATE)?
+        );
+    }
+    let release = generate_release(&path, now)?;
     let mut release_file = fs::File::create(path.join("Release"))?;
     release_file.write_all(release.as_bytes())?;
 
+    Ok(RefreshReport { indexed, skipped })
+}
+
+/// Create the repository under `root` with an empty but valid index if it has no index yet,
+/// otherwise apt fails on the missing `Packages` file of a fresh (e.g. branch-exclusive) output
+pub fn ensure_repo(root: &Path) -> Result<()> {
+    if root.join("debs/Packages").is_file() {
+        return Ok(());
+    }
+    refresh_repo(root)?;
+
     Ok(())
 }
 
@@ -62,9 +125,109 @@ pub fn init_repo(repo_root: &Path, rootfs: &Path) -> Result<()> {
     Ok(())
 }
 
-/// Uninitialize the repository
+/// Uninitialize the repository, it is fine if the repository is not initialized
 pub fn deinit_repo(rootfs: &Path) -> Result<()> {
-    Ok(fs::remove_file(
-        rootfs.join("etc/apt/sources.list.d/ciel-local.list"),
-    )?)
+    match fs::remove_file(rootfs.join("etc/apt/sources.list.d/ciel-local.list")) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[test]
+fn test_ensure_repo() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("OUTPUT-new-branch");
+    let rootfs = dir.path().join("rootfs");
+    ensure_repo(&root).unwrap();
+    assert_eq!(fs::read(root.join("debs/Packages")).unwrap(), b"");
+    let release = fs::read_to_string(root.join("debs/Release")).unwrap();
+    assert!(release
+        .contains(" e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855 0 Packages"));
+    // an existing index is kept
+    fs::write(root.join("debs/Packages"), "Package: a\n").unwrap();
+    ensure_repo(&root).unwrap();
+    assert_eq!(
+        fs::read_to_string(root.join("debs/Packages")).unwrap(),
+        "Package: a\n"
+    );
+    // the sources list of the instance points to a valid repository
+    fs::remove_dir_all(&root).unwrap();
+    init_repo(&root, &rootfs).unwrap();
+    assert!(root.join("debs/Packages").is_file());
+    assert_eq!(
+        fs::read_to_string(rootfs.join("etc/apt/sources.list.d/ciel-local.list")).unwrap(),
+        "deb [trusted=yes] file:///debs/ /"
+    );
+    deinit_repo(&rootfs).unwrap();
+    assert!(!rootfs
+        .join("etc/apt/sources.list.d/ciel-local.list")
+        .exists());
+    // disabling twice is not an error
+    deinit_repo(&rootfs).unwrap();
+}
+
+#[test]
+fn test_release_date_after() {
+    let dir = tempfile::tempdir().unwrap();
+    let now = OffsetDateTime::now_utc();
+    assert!(release_date_after(dir.path(), now).is_none());
+    fs::write(dir.path().join("Packages"), "").unwrap();
+    let later = now + time::Duration::hours(3);
+    fs::write(
+        dir.path().join("Release"),
+        generate_release(dir.path(), later).unwrap(),
+    )
+    .unwrap();
+    let date = release_date_after(dir.path(), now).unwrap();
+    assert_eq!(date.unix_timestamp(), later.unix_timestamp());
+    assert!(release_date_after(dir.path(), later + time::Duration::seconds(1)).is_none());
+}
+
+#[test]
+fn test_refresh_skips_broken_debs() {
+    let dir = tempfile::tempdir().unwrap();
+    let debs = dir.path().join("debs");
+    let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/scan-debs");
+    fs::create_dir_all(debs.join("c")).unwrap();
+    fs::copy(
+        testdata.join("ciel-test-gzip_1.0-0_all.deb"),
+        debs.join("c/ciel-test-gzip_1.0-0_all.deb"),
+    )
+    .unwrap();
+    let deb = fs::read(testdata.join("ciel-test-xz_1.0-0_all.deb")).unwrap();
+    fs::write(debs.join("c/ciel-test-xz_1.0-0_all.deb"), &deb[..100]).unwrap();
+    fs::write(debs.join("c/empty_1.0-0_all.deb"), b"").unwrap();
+    fs::write(debs.join("c/notes.txt"), b"not a deb").unwrap();
+    let report = refresh_repo(dir.path()).unwrap();
+    assert_eq!(report.indexed, 1);
+    let mut skipped = report
+        .skipped
+        .iter()
+        .map(|(path, _)| path.strip_prefix(&debs).unwrap().to_str().unwrap())
+        .collect::<Vec<_>>();
+    skipped.sort();
+    assert_eq!(
+        skipped,
+        [
+            "c/ciel-test-xz_1.0-0_all.deb",
+            "c/empty_1.0-0_all.deb",
+            "c/notes.txt"
+        ]
+    );
+    let reason = |name: &str| {
+        report
+            .skipped
+            .iter()
+            .find(|(path, _)| path.ends_with(name))
+            .map(|(_, reason)| reason.as_str())
+            .unwrap()
+    };
+    assert_eq!(reason("notes.txt"), "not a deb package");
+    // refreshing again must not count the index and the lock as stray files
+    let report = refresh_repo(dir.path()).unwrap();
+    assert_eq!(report.skipped.len(), 3);
+    let index = fs::read_to_string(debs.join("Packages")).unwrap();
+    assert!(index.starts_with("Package: ciel-test-gzip\n"));
+    assert_eq!(index.matches("Package: ").count(), 1);
+    assert!(debs.join("Release").is_file());
 }
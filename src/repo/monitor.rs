@@ -13,7 +13,7 @@ use std::{
     time::Duration,
 };
 
-use super::refresh_repo;
+use super::{refresh_index, RefreshReport};
 
 const LOCK_FILE: &str = "debs/fresh.lock";
 
@@ -49,18 +49,26 @@ impl Drop for FreshLockGuard {
     }
 }
 
-fn refresh_once(pool_path: &Path) -> Result<()> {
+fn lock_fresh(pool_path: &Path) -> Result<FreshLockGuard> {
     let lock_file = pool_path.join(LOCK_FILE);
     let f = match File::options().read(true).write(true).open(&lock_file) {
         Ok(f) => f,
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => File::create(&lock_file)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            std::fs::create_dir_all(pool_path.join("debs"))?;
+            File::create(&lock_file)?
+        }
         Err(e) => return Err(e.into()),
     };
-    let mut guarded = FreshLockGuard::new(f)?;
+
+    FreshLockGuard::new(f)
+}
+
+pub(super) fn refresh_once(pool_path: &Path) -> Result<()> {
+    let mut guarded = lock_fresh(pool_path)?;
     let mut buf = [0u8; 1];
     guarded.read_exact(&mut buf)?;
     if buf[0] != b'1' {
-        refresh_repo(pool_path)?;
+        refresh_index(pool_path)?;
         guarded.rewind()?;
         guarded.write_all("1".as_bytes())?;
     }
@@ -68,6 +76,18 @@ fn refresh_once(pool_path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Refresh the repository even if it is fresh, behind the lock shared with the monitors,
+/// so that concurrent builds never write the index at the same time
+pub(super) fn refresh_exclusive(pool_path: &Path) -> Result<RefreshReport> {
+    let mut guarded = lock_fresh(pool_path)?;
+    let report = refresh_index(pool_path)?;
+    guarded.set_len(0)?;
+    guarded.rewind()?;
+    guarded.write_all("1".as_bytes())?;
+
+    Ok(report)
+}
+
 pub fn start_monitor(pool_path: &Path, stop_token: Receiver<()>) -> Result<()> {
     // ensure lock exists
     let lock_path = pool_path.join(LOCK_FILE);
@@ -1,27 +1,41 @@
-use crate::error;
+use crate::common::sha256sum;
+use crate::warn;
 use anyhow::{anyhow, Result};
 use ar::Archive as ArArchive;
 use console::style;
-use faster_hex::hex_string;
 use flate2::read::GzDecoder;
 use rayon::prelude::*;
-use sha2::{Digest, Sha256};
 use std::io::SeekFrom;
 use std::{
+    fmt,
     fs::File,
     io::{Read, Seek, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 use tar::Archive as TarArchive;
 use walkdir::{DirEntry, WalkDir};
 use xz2::read::XzDecoder;
 
+#[derive(Debug)]
 enum TarFormat {
+    Plain,
     Xzip,
     Gzip,
     Zstd,
 }
 
+/// A member of the deb is compressed in a format we do not understand
+#[derive(Debug)]
+pub struct UnsupportedCompression(String);
+
+impl fmt::Display for UnsupportedCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unsupported compression of member `{}`", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedCompression {}
+
 fn collect_control<R: Read>(reader: R) -> Result<Vec<u8>> {
     let mut tar = TarArchive::new(reader);
     for entry in tar.entries()? {
@@ -36,42 +50,130 @@ fn collect_control<R: Read>(reader: R) -> Result<Vec<u8>> {
     Err(anyhow!("Could not read control file"))
 }
 
-fn open_compressed_control<R: Read>(reader: R, format: &TarFormat) -> Result<Vec<u8>> {
-    match format {
-        TarFormat::Xzip => collect_control(XzDecoder::new(reader)),
-        TarFormat::Gzip => collect_control(GzDecoder::new(reader)),
-        TarFormat::Zstd => collect_control(zstd::stream::read::Decoder::new(reader)?),
+/// Sum up the installed size of the data archive in KiB, the same way dpkg-gencontrol does
+fn collect_installed_size<R: Read>(reader: R) -> Result<u64> {
+    let mut tar = TarArchive::new(reader);
+    let mut size = 0;
+    for entry in tar.entries()? {
+        let entry = entry?;
+        size += if entry.header().entry_type().is_file() {
+            entry.size().div_ceil(1024)
+        } else {
+            1
+        };
     }
+
+    Ok(size)
+}
+
+fn decompress<'a, R: Read + 'a>(reader: R, format: &TarFormat) -> Result<Box<dyn Read + 'a>> {
+    Ok(match format {
+        TarFormat::Plain => Box::new(reader),
+        TarFormat::Xzip => Box::new(XzDecoder::new(reader)),
+        TarFormat::Gzip => Box::new(GzDecoder::new(reader)),
+        TarFormat::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+    })
 }
 
 fn determine_format(format: &[u8]) -> Result<TarFormat> {
-    if format.ends_with(b".xz") {
+    if format.ends_with(b".tar") {
+        Ok(TarFormat::Plain)
+    } else if format.ends_with(b".xz") {
         Ok(TarFormat::Xzip)
     } else if format.ends_with(b".gz") {
         Ok(TarFormat::Gzip)
     } else if format.ends_with(b".zst") {
         Ok(TarFormat::Zstd)
     } else {
-        Err(anyhow!("Unknown format: {:?}", format))
+        Err(UnsupportedCompression(String::from_utf8_lossy(format).into_owned()).into())
     }
 }
 
-fn open_deb_simple<R: Read>(reader: R) -> Result<Vec<u8>> {
+#[inline]
+fn has_field(control: &[u8], field: &[u8]) -> bool {
+    control.split(|c| *c == b'\n').any(|line| {
+        line.strip_prefix(field)
+            .is_some_and(|x| x.starts_with(b":"))
+    })
+}
+
+/// Returns the control file, and the installed size if the control file does not have one
+pub(super) fn open_deb_simple<R: Read>(reader: R) -> Result<(Vec<u8>, Option<u64>)> {
     let mut deb = ArArchive::new(reader);
+    let mut control = None;
     while let Some(entry) = deb.next_entry() {
         if entry.is_err() {
             continue;
         }
         let entry = entry?;
-        let filename = entry.header().identifier();
+        let filename = entry.header().identifier().to_owned();
         if filename.starts_with(b"control.tar") {
-            let format = determine_format(filename)?;
-            let control = open_compressed_control(entry, &format)?;
-            return Ok(control);
+            let format = determine_format(&filename)?;
+            let buf = collect_control(decompress(entry, &format)?)?;
+            if has_field(&buf, b"Installed-Size") {
+                return Ok((buf, None));
+            }
+            control = Some(buf);
+        } else if filename.starts_with(b"data.tar") {
+            // control.tar always comes before data.tar in a valid deb
+            let Some(control) = control else {
+                break;
+            };
+            let format = determine_format(&filename)?;
+            let installed_size = collect_installed_size(decompress(entry, &format)?)?;
+            return Ok((control, Some(installed_size)));
         }
     }
 
-    Err(anyhow!("data archive not found or format unsupported"))
+    control
+        .map(|control| (control, None))
+        .ok_or_else(|| anyhow!("control archive not found"))
+}
+
+/// Extension of the sidecar files holding the build metadata of the debs
+const METADATA_EXTENSION: &str = "ciel-meta";
+
+/// Path of the metadata sidecar of the deb, e.g. `foo_1.0-0_amd64.deb.ciel-meta`
+pub(super) fn metadata_path(deb: &Path) -> PathBuf {
+    let mut path = deb.as_os_str().to_owned();
+    path.push(".");
+    path.push(METADATA_EXTENSION);
+
+    PathBuf::from(path)
+}
+
+/// Write the build metadata of the deb into its sidecar file,
+/// each field is added to the stanza of the deb in the index as `X-Ciel-<name>`
+pub fn write_metadata(deb: &Path, fields: &[(&str, &str)]) -> Result<()> {
+    let mut data = String::new();
+    for (name, value) in fields {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(anyhow!("Invalid metadata field name: {:?}", name));
+        }
+        data.push_str(&format!("{}: {}\n", name, value.replace('\n', " ")));
+    }
+    std::fs::write(metadata_path(deb), data)?;
+
+    Ok(())
+}
+
+/// Read the `X-Ciel-*` fields from the sidecar of the deb.
+/// A sidecar older than the deb belongs to a previous build and is ignored
+fn read_metadata(deb: &Path) -> Vec<u8> {
+    let path = metadata_path(deb);
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|x| x.modified()).ok();
+    if modified(&path) < modified(deb) {
+        return Vec::new();
+    }
+    let data = std::fs::read_to_string(path).unwrap_or_default();
+    let mut fields = Vec::new();
+    for line in data.lines() {
+        if let Some((name, value)) = line.split_once(": ") {
+            fields.extend(format!("X-Ciel-{}: {}\n", name, value).as_bytes());
+        }
+    }
+
+    fields
 }
 
 fn scan_single_deb_simple<P: AsRef<Path>>(path: P, root: P) -> Result<Vec<u8>> {
@@ -79,12 +181,19 @@ fn scan_single_deb_simple<P: AsRef<Path>>(path: P, root: P) -> Result<Vec<u8>> {
     let sha256 = sha256sum(&mut f)?;
     let actual_size = f.stream_position()?;
     f.seek(SeekFrom::Start(0))?;
-    let mut control = open_deb_simple(f)?;
+    let (mut control, installed_size) = open_deb_simple(f)?;
     control.reserve(128);
-    if control.ends_with(&b"\n\n"[..]) {
+    while control.ends_with(&b"\n\n"[..]) {
         control.pop();
     }
+    if !control.ends_with(b"\n") {
+        control.push(b'\n');
+    }
     let rel_path = path.as_ref().strip_prefix(root)?;
+    if let Some(installed_size) = installed_size {
+        control.extend(format!("Installed-Size: {}\n", installed_size).as_bytes());
+    }
+    control.extend(read_metadata(path.as_ref()));
     control.extend(format!("Size: {}\n", actual_size).as_bytes());
     control.extend(format!("Filename: {}\n", rel_path.to_string_lossy()).as_bytes());
     control.extend(b"SHA256: ");
@@ -94,14 +203,6 @@ fn scan_single_deb_simple<P: AsRef<Path>>(path: P, root: P) -> Result<Vec<u8>> {
     Ok(control)
 }
 
-/// Calculate the Sha256 checksum of the given stream
-pub fn sha256sum<R: Read>(mut reader: R) -> Result<String> {
-    let mut hasher = Sha256::new();
-    std::io::copy(&mut reader, &mut hasher)?;
-
-    Ok(hex_string(&hasher.finalize()))
-}
-
 #[inline]
 fn is_tarball(entry: &DirEntry) -> bool {
     entry
@@ -111,33 +212,121 @@ fn is_tarball(entry: &DirEntry) -> bool {
         .unwrap_or(false)
 }
 
-pub fn scan_packages_simple(entries: &[DirEntry], root: &Path) -> Vec<u8> {
-    entries
+/// Generate the index of the debs, along with the debs that could not be read
+/// (e.g. truncated by an interrupted build) and the reason. These are left out of the index.
+pub fn scan_packages_simple(
+    entries: &[DirEntry],
+    root: &Path,
+) -> (Vec<u8>, Vec<(PathBuf, String)>) {
+    let results = entries
         .par_iter()
-        .map(|entry| -> Vec<u8> {
+        .map(|entry| {
             let path = entry.path();
             print!(".");
             std::io::stderr().flush().ok();
-            match scan_single_deb_simple(path, root) {
-                Ok(entry) => entry,
-                Err(err) => {
-                    error!("{:?}", err);
-                    Vec::new()
-                }
-            }
+            scan_single_deb_simple(path, root).map_err(|err| {
+                warn!("Skipping {}: {:#}", path.display(), err);
+                (path.to_owned(), format!("{:#}", err))
+            })
         })
-        .flatten()
-        .collect()
+        .collect::<Vec<_>>();
+    let mut index = Vec::new();
+    let mut skipped = Vec::new();
+    for result in results {
+        match result {
+            Ok(entry) => index.extend(entry),
+            Err(failure) => skipped.push(failure),
+        }
+    }
+
+    (index, skipped)
 }
 
+/// Index and lock files of the repository itself, relative to the debs directory
+const REPO_FILES: &[&str] = &["Packages", "Release", "fresh.lock"];
+
 pub fn collect_all_packages<P: AsRef<Path>>(path: P) -> Result<Vec<DirEntry>> {
+    Ok(collect_repo_files(path)?.0)
+}
+
+/// Collect the debs under `path`, along with the stray files that are neither debs
+/// nor the index of the repository
+pub fn collect_repo_files<P: AsRef<Path>>(path: P) -> Result<(Vec<DirEntry>, Vec<PathBuf>)> {
+    let path = path.as_ref();
     let mut files = Vec::new();
-    for entry in WalkDir::new(path.as_ref()) {
+    let mut stray = Vec::new();
+    for entry in WalkDir::new(path) {
         let entry = entry?;
         if is_tarball(&entry) {
             files.push(entry);
+        } else if entry.file_type().is_file()
+            && !REPO_FILES
+                .iter()
+                .any(|name| entry.path() == path.join(name))
+        {
+            stray.push(entry.into_path());
         }
     }
 
-    Ok(files)
+    Ok((files, stray))
+}
+
+#[test]
+fn test_determine_format() {
+    assert!(matches!(
+        determine_format(b"control.tar"),
+        Ok(TarFormat::Plain)
+    ));
+    assert!(matches!(
+        determine_format(b"data.tar.zst"),
+        Ok(TarFormat::Zstd)
+    ));
+    let err = determine_format(b"data.tar.lz4").unwrap_err();
+    assert!(err.is::<UnsupportedCompression>());
+}
+
+#[test]
+fn test_scan_compressions() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+    let entries = collect_all_packages(root.join("scan-debs")).unwrap();
+    assert_eq!(entries.len(), 3);
+    let (index, skipped) = scan_packages_simple(&entries, &root);
+    assert!(skipped.is_empty());
+    let index = String::from_utf8(index).unwrap();
+    for compression in ["gzip", "xz", "zstd"] {
+        let name = format!("ciel-test-{}", compression);
+        let stanza = index
+            .split("\n\n")
+            .find(|x| x.starts_with(&format!("Package: {}\n", name)))
+            .unwrap();
+        assert!(stanza.contains("\nInstalled-Size: 9\n"));
+        assert!(stanza.contains(&format!("\nFilename: scan-debs/{}_1.0-0_all.deb\n", name)));
+    }
+}
+
+#[test]
+fn test_scan_metadata() {
+    let dir = tempfile::tempdir().unwrap();
+    let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/scan-debs");
+    for name in ["ciel-test-gzip_1.0-0_all.deb", "ciel-test-xz_1.0-0_all.deb"] {
+        std::fs::copy(testdata.join(name), dir.path().join(name)).unwrap();
+    }
+    write_metadata(
+        &dir.path().join("ciel-test-gzip_1.0-0_all.deb"),
+        &[("Built-By", "builder"), ("Instance", "main")],
+    )
+    .unwrap();
+    let entries = collect_all_packages(dir.path()).unwrap();
+    let index = String::from_utf8(scan_packages_simple(&entries, dir.path()).0).unwrap();
+    let stanza = |name: &str| {
+        index
+            .split("\n\n")
+            .find(|x| x.starts_with(&format!("Package: {}\n", name)))
+            .unwrap()
+            .to_owned()
+    };
+    let with = stanza("ciel-test-gzip");
+    assert!(with.contains("\nX-Ciel-Built-By: builder\nX-Ciel-Instance: main\n"));
+    assert!(with.contains("\nSHA256: "));
+    assert!(!stanza("ciel-test-xz").contains("X-Ciel-"));
 }
@@ -0,0 +1,183 @@
+use anyhow::Result;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    sync::mpsc::Receiver,
+    thread::{self, sleep},
+    time::Duration,
+};
+
+use super::monitor::refresh_once;
+
+/// Files that are regenerated before being served, if the repository is stale
+const INDEX_FILES: &[&str] = &["Packages", "Release"];
+
+/// Decode the `%XX` escapes in a request path (apt escapes `~` and `+` in file names)
+fn percent_decode(path: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            decoded.push(b);
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Map a request path to a path relative to the repository directory,
+/// `None` if the path escapes the repository or lacks the access token
+fn resolve_request_path(path: &str, token: Option<&str>) -> Option<PathBuf> {
+    let path = percent_decode(path.split(['?', '#']).next()?)?;
+    let mut path = path.strip_prefix('/')?;
+    if let Some(token) = token {
+        path = path.strip_prefix(token)?.strip_prefix('/')?;
+    }
+    let path = Path::new(path);
+    if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    Some(path.to_owned())
+}
+
+fn write_status(stream: &mut TcpStream, status: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    )
+}
+
+fn handle_request(mut stream: TcpStream, root: &Path, token: Option<&str>) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // skip the headers, nothing in them is needed
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method, path),
+        _ => return Ok(write_status(&mut stream, "400 Bad Request")?),
+    };
+    if method != "GET" && method != "HEAD" {
+        return Ok(write_status(&mut stream, "405 Method Not Allowed")?);
+    }
+    let path = match resolve_request_path(path, token) {
+        Some(path) => path,
+        None => return Ok(write_status(&mut stream, "404 Not Found")?),
+    };
+    if INDEX_FILES.iter().any(|x| path == Path::new(x)) {
+        // serve the current index if it can't be refreshed
+        refresh_once(root).ok();
+    }
+    let file = match File::open(root.join("debs").join(&path)) {
+        Ok(file) if file.metadata()?.is_file() => file,
+        _ => return Ok(write_status(&mut stream, "404 Not Found")?),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: application/octet-stream\r\nConnection: close\r\n\r\n",
+        file.metadata()?.len()
+    )?;
+    if method == "GET" {
+        io::copy(&mut BufReader::new(file), &mut stream)?;
+    }
+
+    Ok(())
+}
+
+/// Serve the local repository under `root` read-only until a stop signal is received.
+/// With a token, only the requests under `/<token>/` are answered
+pub fn serve_repo(
+    listener: TcpListener,
+    root: &Path,
+    token: Option<&str>,
+    stop_token: Receiver<()>,
+) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    loop {
+        if stop_token.try_recv().is_ok() {
+            return Ok(());
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+                let root = root.to_owned();
+                let token = token.map(str::to_owned);
+                thread::spawn(move || handle_request(stream, &root, token.as_deref()).ok());
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                sleep(Duration::from_millis(100));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[test]
+fn test_resolve_request_path() {
+    assert_eq!(
+        resolve_request_path("/Packages", None),
+        Some(PathBuf::from("Packages"))
+    );
+    assert_eq!(
+        resolve_request_path("/a/foo_1.0%7e1_amd64.deb", None),
+        Some(PathBuf::from("a/foo_1.0~1_amd64.deb"))
+    );
+    assert_eq!(resolve_request_path("/../config.toml", None), None);
+    assert_eq!(resolve_request_path("/a/%2e%2e/%2e%2e/x", None), None);
+    assert_eq!(resolve_request_path("/", None), None);
+    assert_eq!(
+        resolve_request_path("/secret/Release", Some("secret")),
+        Some(PathBuf::from("Release"))
+    );
+    assert_eq!(resolve_request_path("/Release", Some("secret")), None);
+    assert_eq!(resolve_request_path("/secretRelease", Some("secret")), None);
+}
+
+#[test]
+fn test_serve_repo() {
+    use std::io::Read;
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("debs/a")).unwrap();
+    std::fs::write(dir.path().join("debs/fresh.lock"), "1").unwrap();
+    std::fs::write(dir.path().join("debs/Packages"), "Package: a\n").unwrap();
+    std::fs::write(dir.path().join("debs/a/a_1_all.deb"), "deb").unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let root = dir.path().to_owned();
+    let server = thread::spawn(move || serve_repo(listener, &root, Some("t"), rx));
+    let get = |method: &str, path: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: test\r\n\r\n", method, path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let response = get("GET", "/t/Packages");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with("\r\n\r\nPackage: a\n"));
+    let response = get("HEAD", "/t/a/a_1_all.deb");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("Content-Length: 3\r\n"));
+    assert!(response.ends_with("\r\n\r\n"));
+    assert!(get("GET", "/Packages").starts_with("HTTP/1.1 404"));
+    assert!(get("GET", "/t/a").starts_with("HTTP/1.1 404"));
+    assert!(get("PUT", "/t/Packages").starts_with("HTTP/1.1 405"));
+    tx.send(()).unwrap();
+    server.join().unwrap().unwrap();
+}
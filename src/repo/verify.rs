@@ -0,0 +1,170 @@
+//! Verify the local repository against its index
+
+use anyhow::Result;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::{collections::HashSet, fs, fs::File, path::Path};
+
+use super::scan::collect_all_packages;
+use crate::common::sha256sum;
+
+/// Discrepancies found between the `Packages` index and the debs on disk
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    /// Number of index entries checked
+    pub checked: usize,
+    /// Debs whose content does not match the checksum/size in the index
+    pub mismatched: Vec<String>,
+    /// Debs referenced by the index but missing from the disk
+    pub missing: Vec<String>,
+    /// Debs present on the disk but absent from the index
+    pub unindexed: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty() && self.unindexed.is_empty()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct IndexEntry {
+    filename: String,
+    sha256: Option<String>,
+    size: Option<u64>,
+}
+
+enum EntryStatus {
+    Ok,
+    Missing,
+    Mismatched,
+}
+
+/// Parse the Filename/SHA256/Size fields of each stanza in the `Packages` file
+fn parse_index(data: &str) -> Vec<IndexEntry> {
+    let mut entries = Vec::new();
+    for stanza in data.split("\n\n") {
+        let mut filename = None;
+        let mut sha256 = None;
+        let mut size = None;
+        for line in stanza.lines() {
+            if let Some((key, value)) = line.split_once(':') {
+                match key {
+                    "Filename" => filename = Some(value.trim().to_owned()),
+                    "SHA256" => sha256 = Some(value.trim().to_owned()),
+                    "Size" => size = value.trim().parse().ok(),
+                    _ => (),
+                }
+            }
+        }
+        if let Some(filename) = filename {
+            entries.push(IndexEntry {
+                filename,
+                sha256,
+                size,
+            });
+        }
+    }
+
+    entries
+}
+
+fn check_entry(path: &Path, entry: &IndexEntry) -> Result<EntryStatus> {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(EntryStatus::Missing),
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(size) = entry.size {
+        if f.metadata()?.len() != size {
+            return Ok(EntryStatus::Mismatched);
+        }
+    }
+    if let Some(sha256) = &entry.sha256 {
+        if &sha256sum(f)? != sha256 {
+            return Ok(EntryStatus::Mismatched);
+        }
+    }
+
+    Ok(EntryStatus::Ok)
+}
+
+/// Re-hash every deb referenced by the `Packages` file and compare the index with the disk
+pub fn verify_repo(root: &Path) -> Result<VerifyReport> {
+    let path = root.join("debs");
+    let index = fs::read_to_string(path.join("Packages"))?;
+    let entries = parse_index(&index);
+    let statuses = entries
+        .par_iter()
+        .map(|entry| check_entry(&path.join(&entry.filename), entry))
+        .collect::<Result<Vec<_>>>()?;
+    let mut report = VerifyReport {
+        checked: entries.len(),
+        ..Default::default()
+    };
+    for (entry, status) in entries.iter().zip(statuses) {
+        match status {
+            EntryStatus::Ok => (),
+            EntryStatus::Missing => report.missing.push(entry.filename.clone()),
+            EntryStatus::Mismatched => report.mismatched.push(entry.filename.clone()),
+        }
+    }
+    let indexed = entries
+        .iter()
+        .map(|x| x.filename.as_str())
+        .collect::<HashSet<_>>();
+    for deb in collect_all_packages(&path)? {
+        let rel_path = deb.path().strip_prefix(&path)?.to_string_lossy();
+        if !indexed.contains(rel_path.as_ref()) {
+            report.unindexed.push(rel_path.into_owned());
+        }
+    }
+
+    Ok(report)
+}
+
+#[test]
+fn test_parse_index() {
+    let index =
+        "Package: a\nSize: 12\nFilename: a/a.deb\nSHA256: 00ff\n\nPackage: b\nFilename: b.deb\n\n";
+    assert_eq!(
+        parse_index(index),
+        vec![
+            IndexEntry {
+                filename: "a/a.deb".to_owned(),
+                sha256: Some("00ff".to_owned()),
+                size: Some(12),
+            },
+            IndexEntry {
+                filename: "b.deb".to_owned(),
+                sha256: None,
+                size: None,
+            }
+        ]
+    );
+}
+
+#[test]
+fn test_verify_repo() {
+    let dir = tempfile::tempdir().unwrap();
+    let debs = dir.path().join("debs");
+    let deb_name = "aosc-os-feature-data_20241017.1-0_noarch.deb";
+    let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/simple-repo/debs/a");
+    fs::create_dir_all(debs.join("a")).unwrap();
+    fs::copy(testdata.join(deb_name), debs.join("a").join(deb_name)).unwrap();
+    super::refresh_repo(dir.path()).unwrap();
+    let report = verify_repo(dir.path()).unwrap();
+    assert_eq!(report.checked, 1);
+    assert!(report.is_ok());
+
+    // corrupt the deb and add an extra one that is not in the index
+    fs::write(debs.join("a").join(deb_name), b"corrupted").unwrap();
+    fs::copy(testdata.join(deb_name), debs.join(deb_name)).unwrap();
+    let report = verify_repo(dir.path()).unwrap();
+    assert_eq!(report.mismatched, vec![format!("a/{}", deb_name)]);
+    assert_eq!(report.unindexed, vec![deb_name.to_owned()]);
+
+    fs::remove_file(debs.join("a").join(deb_name)).unwrap();
+    let report = verify_repo(dir.path()).unwrap();
+    assert_eq!(report.missing, vec![format!("a/{}", deb_name)]);
+}
@@ -0,0 +1,147 @@
+//! Timing of the slow operations (mounting, committing, building, ...)
+//!
+//! The operations are wrapped in spans, which are recorded with their duration when they end.
+//! The records are logged as they happen with `--log-timings` (or `RUST_LOG=debug`),
+//! and `ciel build --timings` sums them up by phase.
+
+use console::style;
+use std::{
+    io::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+static RECORDS: Mutex<Vec<Record>> = Mutex::new(Vec::new());
+
+/// A finished span
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub phase: &'static str,
+    /// The instance, package or path the operation was about
+    pub target: String,
+    pub elapsed: Duration,
+}
+
+/// A timed operation, recorded when dropped (also on errors)
+pub struct Span {
+    phase: &'static str,
+    target: String,
+    start: Instant,
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let record = Record {
+            phase: self.phase,
+            target: std::mem::take(&mut self.target),
+            elapsed: self.start.elapsed(),
+        };
+        if log_enabled() {
+            eprintln!(
+                "{} phase={} target={} elapsed={:.3}s",
+                style("timing:").dim().for_stderr(),
+                record.phase,
+                record.target,
+                record.elapsed.as_secs_f64()
+            );
+        }
+        if let Ok(mut records) = RECORDS.lock() {
+            records.push(record);
+        }
+    }
+}
+
+/// Start timing an operation, which ends when the returned span is dropped
+pub fn span(phase: &'static str, target: impl Into<String>) -> Span {
+    Span {
+        phase,
+        target: target.into(),
+        start: Instant::now(),
+    }
+}
+
+/// Whether the `RUST_LOG` directives ask for debug output
+fn is_debug_filter(filter: &str) -> bool {
+    filter.split(',').any(|directive| {
+        let level = directive.rsplit('=').next().unwrap_or_default().trim();
+        level.eq_ignore_ascii_case("debug") || level.eq_ignore_ascii_case("trace")
+    })
+}
+
+fn log_enabled() -> bool {
+    crate::common::env_flag("CIEL_LOG_TIMINGS")
+        || std::env::var("RUST_LOG").is_ok_and(|x| is_debug_filter(&x))
+}
+
+/// Total time spent in each phase and the number of spans,
+/// in the order in which the phases first finished
+fn summarize(records: &[Record]) -> Vec<(&'static str, usize, Duration)> {
+    let mut phases: Vec<(&'static str, usize, Duration)> = Vec::new();
+    for record in records {
+        match phases.iter_mut().find(|x| x.0 == record.phase) {
+            Some(phase) => {
+                phase.1 += 1;
+                phase.2 += record.elapsed;
+            }
+            None => phases.push((record.phase, 1, record.elapsed)),
+        }
+    }
+
+    phases
+}
+
+/// Print the time spent in each phase so far
+pub fn print_summary() {
+    use tabwriter::TabWriter;
+
+    let records = match RECORDS.lock() {
+        Ok(records) => records.clone(),
+        Err(_) => return,
+    };
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(&mut formatter, "PHASE\tCOUNT\tTOTAL\tAVERAGE").ok();
+    for (phase, count, total) in summarize(&records) {
+        writeln!(
+            &mut formatter,
+            "{}\t{}\t{:.1}s\t{:.1}s",
+            phase,
+            count,
+            total.as_secs_f64(),
+            total.as_secs_f64() / count as f64
+        )
+        .ok();
+    }
+    formatter.flush().ok();
+}
+
+#[test]
+fn test_timing_summary() {
+    let record = |phase, secs| Record {
+        phase,
+        target: "main".to_owned(),
+        elapsed: Duration::from_secs(secs),
+    };
+    let records = [
+        record("mount", 1),
+        record("update-os", 10),
+        record("acbs", 100),
+        record("update-os", 20),
+    ];
+    assert_eq!(
+        summarize(&records),
+        vec![
+            ("mount", 1, Duration::from_secs(1)),
+            ("update-os", 2, Duration::from_secs(30)),
+            ("acbs", 1, Duration::from_secs(100)),
+        ]
+    );
+    assert!(is_debug_filter("debug"));
+    assert!(is_debug_filter("warn,ciel=trace"));
+    assert!(!is_debug_filter("info"));
+    drop(span("test", "main"));
+    assert!(RECORDS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|x| x.phase == "test" && x.target == "main"));
+}